@@ -296,6 +296,11 @@ async fn main() -> Result<(), TensorRtLlmBackendError> {
         true,
         max_client_batch_size,
         usage_stats,
+        None,
+        16,
+        1024,
+        3600,
+        10,
     )
     .await?;
     Ok(())
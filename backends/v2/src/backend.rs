@@ -5,7 +5,7 @@ use async_trait::async_trait;
 use nohash_hasher::IntMap;
 use std::sync::Arc;
 use text_generation_router::infer::{Backend, GeneratedText, InferError, InferStreamResponse};
-use text_generation_router::validation::ValidGenerateRequest;
+use text_generation_router::validation::{ValidGenerateRequest, ValidGrammar, ValidationError};
 use text_generation_router::{FinishReason, PrefillToken, Token};
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::{mpsc, Notify};
@@ -74,6 +74,11 @@ impl Backend for BackendV2 {
         &self,
         request: ValidGenerateRequest,
     ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        // Context-free grammars are only part of the v3 protocol
+        if matches!(request.parameters.grammar, Some(ValidGrammar::Cfg(_))) {
+            return Err(ValidationError::Grammar.into());
+        }
+
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = mpsc::unbounded_channel();
 
@@ -372,6 +372,8 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             Some(grammar) => match grammar {
                 ValidGrammar::Json(grammar_string) => (grammar_string, GrammarType::Json),
                 ValidGrammar::Regex(grammar_string) => (grammar_string, GrammarType::Regex),
+                // Rejected by `BackendV2::schedule`, the v2 shards have no CFG support
+                ValidGrammar::Cfg(_) => (String::new(), GrammarType::None),
             },
         };
 
@@ -1,4 +1,6 @@
 use clap::{Parser, Subcommand};
+use text_generation_router::infer::fim::FimMode;
+use text_generation_router::infer::reasoning::ReasoningMarkers;
 use text_generation_router::{server, usage_stats};
 use text_generation_router_v3::{connect_backend, V3Error};
 use thiserror::Error;
@@ -22,6 +24,8 @@ struct Args {
     max_input_tokens: Option<usize>,
     #[clap(long, env)]
     max_total_tokens: Option<usize>,
+    #[clap(long, env)]
+    default_max_new_tokens: Option<u32>,
     #[clap(default_value = "1.2", long, env)]
     waiting_served_ratio: f32,
     #[clap(default_value = "4096", long, env)]
@@ -43,6 +47,10 @@ struct Args {
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
     #[clap(long, env)]
+    chat_template: Option<String>,
+    #[clap(long, env)]
+    disable_generation_config: bool,
+    #[clap(long, env)]
     revision: Option<String>,
     #[clap(long, env, value_enum)]
     trust_remote_code: bool,
@@ -70,6 +78,26 @@ struct Args {
     max_client_batch_size: usize,
     #[clap(default_value = "on", long, env)]
     usage_stats: usage_stats::UsageStatsLevel,
+    #[clap(long, env)]
+    batch_api_dir: Option<String>,
+    #[clap(default_value = "16", long, env)]
+    batch_api_concurrency: usize,
+    #[clap(default_value = "1024", long, env)]
+    max_stored_jobs: usize,
+    #[clap(default_value = "3600", long, env)]
+    job_retention_seconds: u64,
+    #[clap(long, env)]
+    job_callback_hosts: Vec<String>,
+    #[clap(default_value = "10", long, env)]
+    stream_resume_ttl_seconds: u64,
+    #[clap(default_value = "psm", long, env)]
+    fim_mode: FimMode,
+    #[clap(default_value = "auto", long, env)]
+    reasoning_parser: ReasoningMarkers,
+    #[clap(default_value = "0.5", long, env)]
+    watermark_gamma: f64,
+    #[clap(long, env)]
+    watermark_detectable: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -90,6 +118,7 @@ async fn main() -> Result<(), RouterError> {
         max_top_n_tokens,
         max_input_tokens,
         max_total_tokens,
+        default_max_new_tokens,
         waiting_served_ratio,
         max_batch_prefill_tokens,
         max_batch_total_tokens,
@@ -100,6 +129,8 @@ async fn main() -> Result<(), RouterError> {
         master_shard_uds_path,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template,
+        disable_generation_config,
         revision,
         trust_remote_code,
         validation_workers,
@@ -114,6 +145,16 @@ async fn main() -> Result<(), RouterError> {
         disable_grammar_support,
         max_client_batch_size,
         usage_stats,
+        batch_api_dir,
+        batch_api_concurrency,
+        max_stored_jobs,
+        job_retention_seconds,
+        job_callback_hosts,
+        stream_resume_ttl_seconds,
+        fim_mode,
+        reasoning_parser,
+        watermark_gamma,
+        watermark_detectable,
     } = args;
 
     if let Some(Commands::PrintSchema) = command {
@@ -131,6 +172,11 @@ async fn main() -> Result<(), RouterError> {
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if default_max_new_tokens == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`default_max_new_tokens` must be > 0".to_string(),
+        ));
+    }
     if let Some(max_batch_size) = max_batch_size {
         if max_batch_size == 0 {
             return Err(RouterError::ArgumentValidation(
@@ -195,10 +241,13 @@ async fn main() -> Result<(), RouterError> {
         max_top_n_tokens,
         max_input_tokens,
         max_total_tokens,
+        default_max_new_tokens,
         validation_workers,
         api_key,
         tokenizer_name,
         tokenizer_config_path,
+        chat_template,
+        disable_generation_config,
         revision,
         trust_remote_code,
         hostname,
@@ -210,6 +259,16 @@ async fn main() -> Result<(), RouterError> {
         disable_grammar_support,
         max_client_batch_size,
         usage_stats,
+        batch_api_dir,
+        batch_api_concurrency,
+        max_stored_jobs,
+        job_retention_seconds,
+        job_callback_hosts,
+        stream_resume_ttl_seconds,
+        fim_mode,
+        reasoning_parser,
+        watermark_gamma,
+        watermark_detectable,
     )
     .await?;
     Ok(())
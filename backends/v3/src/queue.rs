@@ -508,6 +508,7 @@ impl From<ValidParameters> for NextTokenChooserParameters {
             Some(grammar) => match grammar {
                 ValidGrammar::Json(grammar_string) => (grammar_string, GrammarType::Json),
                 ValidGrammar::Regex(grammar_string) => (grammar_string, GrammarType::Regex),
+                ValidGrammar::Cfg(grammar_string) => (grammar_string, GrammarType::Cfg),
             },
         };
 
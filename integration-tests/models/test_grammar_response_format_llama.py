@@ -97,6 +97,10 @@ async def test_grammar_response_format_llama_error_if_tools_not_installed(
     # 422 means the server was unable to process the request because it contains invalid data.
     assert response.status_code == 422
     assert response.json() == {
-        "error": "Tool error: Grammar and tools are mutually exclusive",
-        "error_type": "tool_error",
+        "error": {
+            "message": "Tool error: Grammar and tools are mutually exclusive",
+            "type": "invalid_request_error",
+            "param": "tools",
+            "code": "tool_error",
+        }
     }
@@ -379,6 +379,24 @@ impl std::fmt::Display for UsageStatsLevel {
     }
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum FimMode {
+    /// Prefix, suffix, middle
+    Psm,
+    /// Suffix, prefix, middle
+    Spm,
+}
+
+impl std::fmt::Display for FimMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // To keep in track with `server`.
+        match self {
+            FimMode::Psm => write!(f, "psm"),
+            FimMode::Spm => write!(f, "spm"),
+        }
+    }
+}
+
 /// App Configuration
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -497,6 +515,13 @@ struct Args {
     #[clap(long, env)]
     max_total_tokens: Option<usize>,
 
+    /// The maximum number of tokens generated by the requests that do not set
+    /// `max_new_tokens` (`max_tokens` in the OpenAI API). By default, these
+    /// requests generate until the context window is full: up to
+    /// `max_total_tokens` minus the length of their prompt.
+    #[clap(long, env)]
+    default_max_new_tokens: Option<u32>,
+
     /// This represents the ratio of waiting queries vs running queries where
     /// you want to start considering pausing the running queries to include the waiting
     /// ones into the same batch.
@@ -647,11 +672,20 @@ struct Args {
     #[clap(long, env)]
     api_key: Option<String>,
 
+    /// Share of the vocabulary in the green list of the watermark, also used by
+    /// `/watermark/detect`.
     #[clap(long, env)]
     watermark_gamma: Option<f32>,
     #[clap(long, env)]
     watermark_delta: Option<f32>,
 
+    /// Draw the green lists of the watermark on the CPU so that `/watermark/detect` can replay
+    /// them. The endpoint is disabled without it. This changes the watermark of the texts
+    /// generated on GPUs, and watermarked requests pay a `randperm` over the vocabulary and a
+    /// host to device copy per token.
+    #[clap(long, env)]
+    watermark_detectable: bool,
+
     /// Enable ngrok tunneling
     #[clap(long, env)]
     ngrok: bool,
@@ -669,6 +703,21 @@ struct Args {
     #[clap(long, env)]
     tokenizer_config_path: Option<String>,
 
+    /// The chat template to use instead of the one of the model: the path of a `.jinja` file or
+    /// of a `chat_template.json` file, or the template itself. The files must exist, a value
+    /// ending with `.jinja` or `.json` is never used as a template. By default, the template is
+    /// read from `chat_template.jinja`, `chat_template.json`, `tokenizer_config.json` or
+    /// `processor_config.json`, in that order.
+    #[clap(long, env)]
+    chat_template: Option<String>,
+
+    /// Ignore the `generation_config.json` of the model in the router. By default, its
+    /// `temperature`, `top_p`, `top_k`, `repetition_penalty` and `max_new_tokens` are used for the
+    /// parameters left unset by the requests. Its end of sequence tokens are read by the model
+    /// server and always stop the generation.
+    #[clap(long, env)]
+    disable_generation_config: bool,
+
     /// Disable outlines grammar constrained generation.
     /// This is a feature that allows you to generate text that follows a specific grammar.
     #[clap(long, env)]
@@ -692,6 +741,53 @@ struct Args {
     /// Defaul is on.
     #[clap(default_value = "on", long, env)]
     usage_stats: UsageStatsLevel,
+
+    /// Enable the OpenAI compatible Batch API (`/v1/files` and `/v1/batches`) and store the
+    /// uploaded files, batch progress and outputs in this directory.
+    /// Batches interrupted by a restart are resumed from this directory.
+    #[clap(long, env)]
+    batch_api_dir: Option<String>,
+
+    /// The maximum number of batch requests sent concurrently, across all the batches.
+    /// It must be lower than `max_concurrent_requests`. Batch requests yield to the online
+    /// requests: they only start while more than half of `max_concurrent_requests` is free,
+    /// and wait for the load to go down otherwise.
+    #[clap(default_value = "16", long, env)]
+    batch_api_concurrency: usize,
+
+    /// The maximum number of jobs of the asynchronous job API (`/jobs`) kept in memory.
+    /// New jobs are rejected when the store is full of running or retained jobs.
+    #[clap(default_value = "1024", long, env)]
+    max_stored_jobs: usize,
+
+    /// How long the result of a finished job is kept, in seconds.
+    #[clap(default_value = "3600", long, env)]
+    job_retention_seconds: u64,
+
+    /// Hosts the asynchronous jobs can send their `callback_url` requests to.
+    /// Callbacks to any other host are refused, and all of them when none is given.
+    #[clap(long, env)]
+    job_callback_hosts: Vec<String>,
+
+    /// How long a streaming request keeps running after its client disconnected, in seconds.
+    /// Clients can resume the stream with `GET /streams/{x-request-id}` and the `Last-Event-ID`
+    /// header during that time, as long as they are not more than 4096 events behind.
+    /// Set to 0 to cancel requests as soon as the client disconnects.
+    #[clap(default_value = "10", long, env)]
+    stream_resume_ttl_seconds: u64,
+
+    /// Order of the fill-in-the-middle prompts built for the `suffix` of `/v1/completions`:
+    /// prefix-suffix-middle ("psm") or suffix-prefix-middle ("spm").
+    /// The FIM tokens are found in the tokenizer, a `completion_template` in
+    /// `tokenizer_config.json` takes precedence and defines its own order.
+    #[clap(default_value = "psm", long, env)]
+    fim_mode: FimMode,
+
+    /// Markers of the reasoning of thinking models, moved from the `content` to the
+    /// `reasoning_content` of the chat completions: "auto" looks for the markers of known models
+    /// in the tokenizer, "none" leaves the reasoning in the content, "<start>,<end>" sets them.
+    #[clap(default_value = "auto", long, env)]
+    reasoning_parser: String,
 }
 
 #[derive(Debug)]
@@ -719,6 +815,7 @@ fn shard_manager(
     disable_custom_kernels: bool,
     watermark_gamma: Option<f32>,
     watermark_delta: Option<f32>,
+    watermark_detectable: bool,
     cuda_graphs: Vec<usize>,
     cuda_memory_fraction: f32,
     rope_scaling: Option<RopeScaling>,
@@ -919,6 +1016,11 @@ fn shard_manager(
         envs.push(("WATERMARK_DELTA".into(), watermark_delta.to_string().into()))
     }
 
+    // Watermark generator replayed by the router
+    if watermark_detectable {
+        envs.push(("WATERMARK_CPU_GENERATOR".into(), "true".into()))
+    }
+
     // Start process
     tracing::info!("Starting shard");
     let mut p = match Command::new("text-generation-server")
@@ -1356,6 +1458,7 @@ fn spawn_shards(
         let disable_custom_kernels = args.disable_custom_kernels;
         let watermark_gamma = args.watermark_gamma;
         let watermark_delta = args.watermark_delta;
+        let watermark_detectable = args.watermark_detectable;
         let cuda_graphs_clone = cuda_graphs.clone();
         let cuda_memory_fraction = args.cuda_memory_fraction;
         let rope_scaling = args.rope_scaling;
@@ -1381,6 +1484,7 @@ fn spawn_shards(
                 disable_custom_kernels,
                 watermark_gamma,
                 watermark_delta,
+                watermark_detectable,
                 cuda_graphs_clone,
                 cuda_memory_fraction,
                 rope_scaling,
@@ -1492,6 +1596,12 @@ fn spawn_webserver(
             max_total_tokens.to_string(),
         ]);
     }
+    if let Some(default_max_new_tokens) = args.default_max_new_tokens {
+        router_args.extend_from_slice(&[
+            "--default-max-new-tokens".to_string(),
+            default_max_new_tokens.to_string(),
+        ]);
+    }
 
     // Pass usage stats flags to router
     router_args.push("--usage-stats".to_string());
@@ -1502,12 +1612,61 @@ fn spawn_webserver(
         router_args.push("--disable-grammar-support".to_string());
     }
 
+    // Batch API
+    if let Some(ref batch_api_dir) = args.batch_api_dir {
+        router_args.push("--batch-api-dir".to_string());
+        router_args.push(batch_api_dir.to_string());
+    }
+    router_args.push("--batch-api-concurrency".to_string());
+    router_args.push(args.batch_api_concurrency.to_string());
+
+    // Job API
+    router_args.push("--max-stored-jobs".to_string());
+    router_args.push(args.max_stored_jobs.to_string());
+    router_args.push("--job-retention-seconds".to_string());
+    router_args.push(args.job_retention_seconds.to_string());
+    for host in args.job_callback_hosts.into_iter() {
+        router_args.push("--job-callback-hosts".to_string());
+        router_args.push(host);
+    }
+
+    // Resumable streams
+    router_args.push("--stream-resume-ttl-seconds".to_string());
+    router_args.push(args.stream_resume_ttl_seconds.to_string());
+
+    // Fill-in-the-middle
+    router_args.push("--fim-mode".to_string());
+    router_args.push(args.fim_mode.to_string());
+
+    // Reasoning parser
+    router_args.push("--reasoning-parser".to_string());
+    router_args.push(args.reasoning_parser.to_string());
+
+    // Watermark detection
+    if let Some(watermark_gamma) = args.watermark_gamma {
+        router_args.push("--watermark-gamma".to_string());
+        router_args.push(watermark_gamma.to_string());
+    }
+    if args.watermark_detectable {
+        router_args.push("--watermark-detectable".to_string());
+    }
+
     // Tokenizer config path
     if let Some(ref tokenizer_config_path) = args.tokenizer_config_path {
         router_args.push("--tokenizer-config-path".to_string());
         router_args.push(tokenizer_config_path.to_string());
     }
 
+    // Chat template
+    if let Some(ref chat_template) = args.chat_template {
+        router_args.push("--chat-template".to_string());
+        router_args.push(chat_template.to_string());
+    }
+
+    if args.disable_generation_config {
+        router_args.push("--disable-generation-config".to_string());
+    }
+
     // Model optional max batch total tokens
     if let Some(max_batch_total_tokens) = args.max_batch_total_tokens {
         router_args.push("--max-batch-total-tokens".to_string());
@@ -1776,6 +1935,12 @@ fn main() -> Result<(), LauncherError> {
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if args.batch_api_dir.is_some() && args.batch_api_concurrency >= args.max_concurrent_requests {
+        return Err(LauncherError::ArgumentValidation(format!(
+            "`batch_api_concurrency` must be < `max_concurrent_requests`. Given: {} and {}",
+            args.batch_api_concurrency, args.max_concurrent_requests
+        )));
+    }
     if args.trust_remote_code {
         tracing::warn!(
             "`trust_remote_code` is set. Trusting that model `{}` do not contain malicious code.",
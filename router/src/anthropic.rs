@@ -0,0 +1,649 @@
+/// Anthropic Messages API compatibility
+use crate::infer::Infer;
+use crate::server::{
+    chat_completion_chunks, format_tool_call, generate_internal, generate_stream_internal,
+    parse_tool_call, ComputeType,
+};
+use crate::validation::ValidationError;
+use crate::{
+    ChatCompletionDelta, ChatRequest, ErrorResponse, FunctionDefinition, FunctionName, InferError,
+    Info, Message, MessageChunk, MessageContent, StreamOptions, Tool, ToolCall, ToolChoice,
+    ToolType, Url,
+};
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct MessagesRequest {
+    #[schema(example = "tgi")]
+    pub model: Option<String>,
+    pub messages: Vec<AnthropicMessage>,
+    /// System prompt, a string or a list of text blocks
+    #[serde(default)]
+    #[schema(nullable = true, value_type = String, example = "You are a helpful assistant.")]
+    pub system: Option<AnthropicContent>,
+    #[schema(example = 1024)]
+    pub max_tokens: u32,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    #[schema(nullable = true, example = 1.0)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.95)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tools: Option<Vec<AnthropicTool>>,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tool_choice: Option<AnthropicToolChoice>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct AnthropicMessage {
+    #[schema(example = "user")]
+    pub role: String,
+    #[schema(value_type = String, example = "What is Deep Learning?")]
+    pub content: AnthropicContent,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub(crate) enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: ImageSource,
+    },
+    ToolUse {
+        // Tool calls are matched by position, the ids are only part of the schema
+        #[allow(dead_code)]
+        id: String,
+        name: String,
+        #[schema(value_type = Object)]
+        input: Value,
+    },
+    ToolResult {
+        #[allow(dead_code)]
+        tool_use_id: String,
+        #[serde(default)]
+        #[schema(nullable = true, value_type = String)]
+        content: Option<AnthropicContent>,
+        #[serde(default)]
+        is_error: bool,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct AnthropicTool {
+    #[schema(example = "get_weather")]
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[schema(value_type = Object)]
+    pub input_schema: Value,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum AnthropicToolChoice {
+    Auto,
+    Any,
+    Tool { name: String },
+    None,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct MessagesResponse {
+    #[schema(example = "msg_6f1a2c0e9b8d4a3f8e7d6c5b4a392817")]
+    pub id: String,
+    #[schema(example = "message")]
+    pub r#type: String,
+    #[schema(example = "assistant")]
+    pub role: String,
+    pub content: Vec<ResponseBlock>,
+    pub model: String,
+    #[schema(nullable = true, example = "end_turn")]
+    pub stop_reason: Option<String>,
+    #[schema(nullable = true, example = "null")]
+    pub stop_sequence: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ResponseBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        #[schema(value_type = Object)]
+        input: Value,
+    },
+}
+
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub(crate) struct AnthropicUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+impl TryFrom<MessagesRequest> for ChatRequest {
+    type Error = InferError;
+
+    fn try_from(req: MessagesRequest) -> Result<Self, Self::Error> {
+        let mut messages = Vec::new();
+        if let Some(system) = req.system {
+            messages.push(Message {
+                role: "system".to_string(),
+                content: MessageContent::SingleText(text_content(system)),
+                name: None,
+            });
+        }
+        for message in req.messages {
+            messages.extend(convert_message(message)?);
+        }
+
+        let tools = req.tools.map(|tools| {
+            tools
+                .into_iter()
+                .map(|tool| Tool {
+                    r#type: "function".to_string(),
+                    function: FunctionDefinition {
+                        description: tool.description,
+                        name: tool.name,
+                        arguments: tool.input_schema,
+                    },
+                })
+                .collect()
+        });
+        let tool_choice = ToolChoice(req.tool_choice.map(|choice| match choice {
+            AnthropicToolChoice::Auto => ToolType::OneOf,
+            AnthropicToolChoice::Any => ToolType::Required,
+            AnthropicToolChoice::Tool { name } => ToolType::Function(FunctionName { name }),
+            AnthropicToolChoice::None => ToolType::NoTool,
+        }));
+
+        Ok(ChatRequest {
+            model: req.model,
+            messages,
+            frequency_penalty: None,
+            logit_bias: None,
+            logprobs: None,
+            top_logprobs: None,
+            max_tokens: Some(req.max_tokens),
+            n: None,
+            presence_penalty: None,
+            stop: req.stop_sequences,
+            stream: req.stream,
+            seed: None,
+            temperature: req.temperature,
+            top_p: req.top_p,
+            tools,
+            tool_prompt: None,
+            tool_choice,
+            response_format: None,
+            guideline: None,
+            documents: None,
+            continue_final_message: false,
+            echo: false,
+            include_reasoning: true,
+            max_reasoning_tokens: None,
+            truncation_strategy: None,
+            chat_template: None,
+            // The usage is needed for the `message_delta` event
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+        })
+    }
+}
+
+fn text_content(content: AnthropicContent) -> String {
+    match content {
+        AnthropicContent::Text(text) => text,
+        AnthropicContent::Blocks(blocks) => blocks
+            .into_iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Convert an Anthropic message into chat messages
+///
+/// Tool results become `tool` messages, and tool uses are rendered in the format produced by
+/// the tool grammar.
+fn convert_message(message: AnthropicMessage) -> Result<Vec<Message>, InferError> {
+    let blocks = match message.content {
+        AnthropicContent::Text(text) => {
+            return Ok(vec![Message {
+                role: message.role,
+                content: MessageContent::SingleText(text),
+                name: None,
+            }])
+        }
+        AnthropicContent::Blocks(blocks) => blocks,
+    };
+
+    let mut messages = Vec::new();
+    let mut chunks = Vec::new();
+    for block in blocks {
+        match block {
+            ContentBlock::Text { text } => chunks.push(MessageChunk::Text { text }),
+            ContentBlock::Image { source } => {
+                let url = match source {
+                    ImageSource::Base64 { media_type, data } => {
+                        format!("data:{media_type};base64,{data}")
+                    }
+                    ImageSource::Url { url } => url,
+                };
+                chunks.push(MessageChunk::ImageUrl {
+                    image_url: Url { url },
+                });
+            }
+            ContentBlock::ToolUse { name, input, .. } => {
+                let text = format_tool_call(name, input);
+                chunks.push(MessageChunk::Text { text });
+            }
+            ContentBlock::ToolResult {
+                content, is_error, ..
+            } => {
+                let mut text = content.map(text_content).unwrap_or_default();
+                if is_error {
+                    text = format!("Error: {text}");
+                }
+                messages.push(Message {
+                    role: "tool".to_string(),
+                    content: MessageContent::SingleText(text),
+                    name: None,
+                });
+            }
+        }
+    }
+
+    if !chunks.is_empty() {
+        // Tool results answer the previous assistant message and come first
+        let content = match chunks.as_slice() {
+            [MessageChunk::Text { text }] => MessageContent::SingleText(text.clone()),
+            _ => MessageContent::MultipleChunks(chunks),
+        };
+        messages.push(Message {
+            role: message.role,
+            content,
+            name: None,
+        });
+    }
+    if messages.is_empty() {
+        return Err(InferError::ValidationError(ValidationError::EmptyInput));
+    }
+    Ok(messages)
+}
+
+fn stop_reason(finish_reason: &str) -> String {
+    match finish_reason {
+        "length" => "max_tokens",
+        "stop_sequence" => "stop_sequence",
+        _ => "end_turn",
+    }
+    .to_string()
+}
+
+/// The stop sequence that ended the generation, if any
+fn stop_sequence(text: &str, stop_sequences: &[String]) -> Option<String> {
+    stop_sequences
+        .iter()
+        .find(|stop| text.ends_with(stop.as_str()))
+        .cloned()
+}
+
+fn tool_use_block(tool_call: ToolCall) -> ResponseBlock {
+    ResponseBlock::ToolUse {
+        id: format!("toolu_{}", uuid::Uuid::new_v4().simple()),
+        name: tool_call.function.name,
+        input: tool_call.function.arguments,
+    }
+}
+
+fn event(name: &str, data: Value) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(data)
+        .unwrap_or_else(|e| InferError::StreamSerializationError(e.to_string()).into())
+}
+
+/// Generate a message with the Anthropic Messages API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/messages",
+request_body = MessagesRequest,
+responses(
+(status = 200, description = "Generated Message", body = MessagesResponse),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+(status = 500, description = "Incomplete generation", body = ErrorResponse,
+example = json ! ({"error": "Incomplete generation"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(
+        total_time,
+        validation_time,
+        queue_time,
+        inference_time,
+        time_per_token,
+        seed,
+    )
+)]
+pub(crate) async fn anthropic_messages(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    Json(req): Json<MessagesRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    metrics::counter!("tgi_request_count").increment(1);
+
+    let stream = req.stream;
+    let stop_sequences = req.stop_sequences.clone().unwrap_or_default();
+    let model = req.model.clone().unwrap_or_else(|| info.model_id.clone());
+    let chat = ChatRequest::try_from(req)?;
+    let (generate_request, using_tools) = chat.try_into_generate(&infer)?;
+    let id = format!("msg_{}", uuid::Uuid::new_v4().simple());
+
+    if !stream {
+        let (headers, Json(generation)) =
+            generate_internal(Extension(infer), compute_type, Json(generate_request), span).await?;
+        // Unwrap is safe here, chat requests always ask for details
+        let details = generation.details.unwrap();
+
+        let (tool_calls, output) = if using_tools {
+            parse_tool_call(&generation.generated_text)?
+        } else {
+            (None, Some(generation.generated_text))
+        };
+        let mut content = Vec::new();
+        let mut stop_sequence_hit = None;
+        if let Some(text) = output {
+            stop_sequence_hit = stop_sequence(&text, &stop_sequences);
+            content.push(ResponseBlock::Text { text });
+        }
+        let stop_reason = match tool_calls {
+            Some(tool_calls) => {
+                content.extend(tool_calls.into_iter().map(tool_use_block));
+                "tool_use".to_string()
+            }
+            None => stop_reason(&details.finish_reason.format(true)),
+        };
+
+        let response = MessagesResponse {
+            id,
+            r#type: "message".to_string(),
+            role: "assistant".to_string(),
+            content,
+            model,
+            stop_reason: Some(stop_reason),
+            stop_sequence: stop_sequence_hit,
+            usage: AnthropicUsage {
+                input_tokens: details.prefill.len() as u32,
+                output_tokens: details.generated_tokens,
+            },
+        };
+        return Ok((headers, Json(response)).into_response());
+    }
+
+    let system_fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
+    let (headers, response_stream) =
+        generate_stream_internal(infer, compute_type, Json(generate_request), span).await;
+    let chunks = chat_completion_chunks(
+        response_stream,
+        using_tools,
+        None,
+        false,
+        Some(StreamOptions {
+            include_usage: true,
+        }),
+        system_fingerprint,
+        model.clone(),
+    )?;
+
+    let response_stream = async_stream::stream! {
+        let message = MessagesResponse {
+            id,
+            r#type: "message".to_string(),
+            role: "assistant".to_string(),
+            content: vec![],
+            model,
+            stop_reason: None,
+            stop_sequence: None,
+            usage: AnthropicUsage::default(),
+        };
+        yield Ok::<Event, Infallible>(event("message_start", serde_json::json!({"type": "message_start", "message": message})));
+
+        let mut chunks = Box::pin(chunks);
+        let mut text = String::new();
+        let mut text_block_open = false;
+        let mut tool_buffer = String::new();
+        let mut finish_reason = None;
+        let mut usage = AnthropicUsage::default();
+        let mut output_tokens = 0;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    yield Ok(event("error", serde_json::json!({
+                        "type": "error",
+                        "error": {"type": err.error_type(), "message": err.to_string()},
+                    })));
+                    return;
+                }
+            };
+            output_tokens += 1;
+            if let Some(chunk_usage) = chunk.usage {
+                usage.input_tokens = chunk_usage.prompt_tokens;
+                usage.output_tokens = chunk_usage.completion_tokens;
+            }
+            let Some(choice) = chunk.choices.into_iter().next() else {
+                continue;
+            };
+            if choice.finish_reason.is_some() {
+                finish_reason = choice.finish_reason;
+            }
+            match choice.delta {
+                ChatCompletionDelta::Chat(message) if !message.content.is_empty() => {
+                    if !text_block_open {
+                        text_block_open = true;
+                        yield Ok(event("content_block_start", serde_json::json!({
+                            "type": "content_block_start",
+                            "index": 0,
+                            "content_block": {"type": "text", "text": ""},
+                        })));
+                    }
+                    text.push_str(&message.content);
+                    yield Ok(event("content_block_delta", serde_json::json!({
+                        "type": "content_block_delta",
+                        "index": 0,
+                        "delta": {"type": "text_delta", "text": message.content},
+                    })));
+                }
+                ChatCompletionDelta::Chat(_) => {}
+                ChatCompletionDelta::Tool(delta) => {
+                    tool_buffer.push_str(&delta.tool_calls.function.arguments);
+                }
+            }
+        }
+        if text_block_open {
+            yield Ok(event("content_block_stop", serde_json::json!({"type": "content_block_stop", "index": 0})));
+        }
+
+        let mut stop = finish_reason.as_deref().map(stop_reason).unwrap_or_else(|| "end_turn".to_string());
+        if !tool_buffer.is_empty() {
+            // The tool call is sent once complete, as a single `input_json_delta`
+            let tool_calls = match parse_tool_call(&tool_buffer) {
+                Ok((tool_calls, _)) => tool_calls.unwrap_or_default(),
+                Err(err) => {
+                    yield Ok(event("error", serde_json::json!({
+                        "type": "error",
+                        "error": {"type": err.error_type(), "message": err.to_string()},
+                    })));
+                    return;
+                }
+            };
+            let first_index = text_block_open as usize;
+            for (index, tool_call) in tool_calls.into_iter().enumerate() {
+                let index = first_index + index;
+                let ResponseBlock::ToolUse { id, name, input } = tool_use_block(tool_call) else {
+                    unreachable!()
+                };
+                yield Ok(event("content_block_start", serde_json::json!({
+                    "type": "content_block_start",
+                    "index": index,
+                    "content_block": {"type": "tool_use", "id": id, "name": name, "input": {}},
+                })));
+                yield Ok(event("content_block_delta", serde_json::json!({
+                    "type": "content_block_delta",
+                    "index": index,
+                    "delta": {"type": "input_json_delta", "partial_json": input.to_string()},
+                })));
+                yield Ok(event("content_block_stop", serde_json::json!({"type": "content_block_stop", "index": index})));
+                stop = "tool_use".to_string();
+            }
+        }
+
+        if usage.output_tokens == 0 {
+            usage.output_tokens = output_tokens;
+        }
+        let stop_sequence_hit = (stop == "stop_sequence")
+            .then(|| stop_sequence(&text, &stop_sequences))
+            .flatten();
+        yield Ok(event("message_delta", serde_json::json!({
+            "type": "message_delta",
+            "delta": {"stop_reason": stop, "stop_sequence": stop_sequence_hit},
+            "usage": usage,
+        })));
+        yield Ok(event("message_stop", serde_json::json!({"type": "message_stop"})));
+    };
+
+    let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
+    Ok((headers, sse).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_request_into_chat_request() {
+        let req: MessagesRequest = serde_json::from_value(serde_json::json!({
+            "model": "tgi",
+            "max_tokens": 64,
+            "system": [{"type": "text", "text": "Be brief."}],
+            "tool_choice": {"type": "tool", "name": "get_weather"},
+            "tools": [{"name": "get_weather", "input_schema": {"type": "object"}}],
+            "messages": [
+                {"role": "user", "content": "What is the weather in Paris?"},
+                {"role": "assistant", "content": [
+                    {"type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": {"city": "Paris"}}
+                ]},
+                {"role": "user", "content": [
+                    {"type": "tool_result", "tool_use_id": "toolu_1", "content": "Sunny"},
+                    {"type": "text", "text": "Thanks!"}
+                ]}
+            ]
+        }))
+        .unwrap();
+
+        let chat = ChatRequest::try_from(req).unwrap();
+        assert_eq!(chat.max_tokens, Some(64));
+        assert_eq!(
+            chat.tool_choice,
+            ToolChoice(Some(ToolType::Function(FunctionName {
+                name: "get_weather".to_string()
+            })))
+        );
+        assert_eq!(chat.tools.unwrap()[0].function.name, "get_weather");
+
+        let messages: Vec<_> = chat
+            .messages
+            .into_iter()
+            .map(|message| (message.role, message.content))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    "system".to_string(),
+                    MessageContent::SingleText("Be brief.".to_string())
+                ),
+                (
+                    "user".to_string(),
+                    MessageContent::SingleText("What is the weather in Paris?".to_string())
+                ),
+                (
+                    "assistant".to_string(),
+                    MessageContent::SingleText(
+                        r#"{"function":{"_name":"get_weather","city":"Paris"}}"#.to_string()
+                    )
+                ),
+                (
+                    "tool".to_string(),
+                    MessageContent::SingleText("Sunny".to_string())
+                ),
+                (
+                    "user".to_string(),
+                    MessageContent::SingleText("Thanks!".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stop_reason() {
+        assert_eq!(stop_reason("length"), "max_tokens");
+        assert_eq!(stop_reason("stop"), "end_turn");
+        assert_eq!(stop_reason("stop_sequence"), "stop_sequence");
+        assert_eq!(
+            stop_sequence("Hello\n\nHuman:", &["Human:".to_string()]),
+            Some("Human:".to_string())
+        );
+    }
+}
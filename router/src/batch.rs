@@ -0,0 +1,1322 @@
+/// OpenAI compatible Batch API for offline workloads
+use crate::infer::Infer;
+use crate::resumable::ResumableStreams;
+use crate::server::{chat_completions, completions, generate_internal, ComputeType, OpenAIError};
+use crate::{ChatRequest, CompletionRequest, ErrorResponse, GenerateRequest, Info};
+use axum::extract::{Extension, Multipart, Path};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+pub(crate) const GENERATE_ENDPOINT: &str = "/generate";
+pub(crate) const CHAT_COMPLETIONS_ENDPOINT: &str = "/v1/chat/completions";
+pub(crate) const COMPLETIONS_ENDPOINT: &str = "/v1/completions";
+
+/// Interval at which a waiting request checks the free permits of the server
+const CAPACITY_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct FileObject {
+    #[schema(example = "file-6f1a2c0e9b8d4a3f8e7d6c5b4a392817")]
+    pub id: String,
+    #[schema(example = "file")]
+    pub object: String,
+    #[schema(example = 1024)]
+    pub bytes: u64,
+    #[schema(example = 1706270835)]
+    pub created_at: u64,
+    #[schema(example = "requests.jsonl")]
+    pub filename: String,
+    #[schema(example = "batch")]
+    pub purpose: String,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, ToSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub(crate) struct BatchRequestCounts {
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct BatchError {
+    #[schema(example = "invalid_url")]
+    pub code: String,
+    #[schema(example = "`url` must be /v1/chat/completions")]
+    pub message: String,
+    #[schema(nullable = true, example = 1)]
+    pub line: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct BatchErrors {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<BatchError>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, ToSchema)]
+pub(crate) struct Batch {
+    #[schema(example = "batch_6f1a2c0e9b8d4a3f8e7d6c5b4a392817")]
+    pub id: String,
+    #[schema(example = "batch")]
+    pub object: String,
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    #[schema(nullable = true)]
+    pub errors: Option<BatchErrors>,
+    pub input_file_id: String,
+    #[schema(example = "24h")]
+    pub completion_window: String,
+    pub status: BatchStatus,
+    pub output_file_id: String,
+    pub error_file_id: String,
+    pub created_at: u64,
+    pub in_progress_at: Option<u64>,
+    pub finalizing_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub cancelling_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+    pub request_counts: BatchRequestCounts,
+    #[schema(nullable = true)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct CreateBatchRequest {
+    /// The ID of an uploaded file that contains the requests of the batch.
+    pub input_file_id: String,
+    /// The endpoint used for all the requests of the batch.
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    /// Only `24h` is supported. Batches are not expired.
+    #[serde(default = "default_completion_window")]
+    #[schema(example = "24h")]
+    pub completion_window: String,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+fn default_completion_window() -> String {
+    "24h".to_string()
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct BatchList {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<Batch>,
+    pub has_more: bool,
+}
+
+/// A line of the batch input file
+#[derive(Deserialize)]
+struct BatchRequestInput {
+    custom_id: String,
+    #[serde(default = "default_method")]
+    method: String,
+    url: String,
+    body: Value,
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+/// A line of the batch output or error file
+#[derive(Serialize, Deserialize)]
+struct BatchRequestOutput {
+    id: String,
+    custom_id: String,
+    response: Option<BatchResponse>,
+    error: Option<BatchError>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchResponse {
+    status_code: u16,
+    request_id: String,
+    body: Value,
+}
+
+/// Shared state of the Batch API
+#[derive(Clone)]
+pub(crate) struct BatchApi {
+    directory: PathBuf,
+    /// Bounds the requests of all the batches, on top of `reserved`
+    permits: Arc<Semaphore>,
+    /// Permits of the server left to the online traffic, batch requests only start while
+    /// more are free
+    reserved: usize,
+    infer: Infer,
+    compute_type: ComputeType,
+    info: Info,
+    streams: ResumableStreams,
+    /// Cancellation flags of the running batches
+    running: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+}
+
+impl BatchApi {
+    /// Create the batch directories and resume the batches interrupted by a restart
+    pub(crate) async fn new(
+        directory: PathBuf,
+        concurrency: usize,
+        infer: Infer,
+        compute_type: ComputeType,
+        info: Info,
+        streams: ResumableStreams,
+    ) -> std::io::Result<Self> {
+        tokio::fs::create_dir_all(directory.join("files")).await?;
+        tokio::fs::create_dir_all(directory.join("batches")).await?;
+
+        let batch_api = Self {
+            directory,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            reserved: info.max_concurrent_requests / 2,
+            infer,
+            compute_type,
+            info,
+            streams,
+            running: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        for mut batch in batch_api.list_batches().await? {
+            match batch.status {
+                BatchStatus::Validating | BatchStatus::InProgress | BatchStatus::Finalizing => {
+                    tracing::info!("Resuming batch {}", batch.id);
+                    batch_api.spawn(batch.id);
+                }
+                BatchStatus::Cancelling => {
+                    batch.status = BatchStatus::Cancelled;
+                    batch.cancelled_at = Some(now());
+                    batch_api.save_batch(&batch).await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(batch_api)
+    }
+
+    fn file_path(&self, file_id: &str) -> PathBuf {
+        self.directory
+            .join("files")
+            .join(format!("{file_id}.jsonl"))
+    }
+
+    fn file_metadata_path(&self, file_id: &str) -> PathBuf {
+        self.directory.join("files").join(format!("{file_id}.json"))
+    }
+
+    fn batch_path(&self, batch_id: &str) -> PathBuf {
+        self.directory
+            .join("batches")
+            .join(format!("{batch_id}.json"))
+    }
+
+    async fn load_file(&self, file_id: &str) -> Option<FileObject> {
+        // Ids are used as file names
+        if !is_valid_id(file_id) {
+            return None;
+        }
+        let content = tokio::fs::read_to_string(self.file_metadata_path(file_id))
+            .await
+            .ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    async fn save_file(&self, file: &FileObject) -> std::io::Result<()> {
+        write_atomic(
+            self.file_metadata_path(&file.id),
+            &serde_json::to_vec(file).map_err(std::io::Error::other)?,
+        )
+        .await
+    }
+
+    async fn load_batch(&self, batch_id: &str) -> Option<Batch> {
+        if !is_valid_id(batch_id) {
+            return None;
+        }
+        let content = tokio::fs::read_to_string(self.batch_path(batch_id))
+            .await
+            .ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    async fn save_batch(&self, batch: &Batch) -> std::io::Result<()> {
+        write_atomic(
+            self.batch_path(&batch.id),
+            &serde_json::to_vec(batch).map_err(std::io::Error::other)?,
+        )
+        .await
+    }
+
+    async fn list_batches(&self) -> std::io::Result<Vec<Batch>> {
+        let mut batches = Vec::new();
+        let mut entries = tokio::fs::read_dir(self.directory.join("batches")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match tokio::fs::read_to_string(&path)
+                .await
+                .ok()
+                .and_then(|content| serde_json::from_str::<Batch>(&content).ok())
+            {
+                Some(batch) => batches.push(batch),
+                None => tracing::warn!("Could not read batch {path:?}"),
+            }
+        }
+        batches.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(batches)
+    }
+
+    /// Run a batch in a background task
+    fn spawn(&self, batch_id: String) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.running
+            .lock()
+            .unwrap()
+            .insert(batch_id.clone(), cancelled.clone());
+
+        let batch_api = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = batch_api.run(&batch_id, cancelled).await {
+                tracing::error!("Batch {batch_id} failed: {err}");
+            }
+            batch_api.running.lock().unwrap().remove(&batch_id);
+        });
+    }
+
+    async fn run(&self, batch_id: &str, cancelled: Arc<AtomicBool>) -> std::io::Result<()> {
+        let mut batch = self
+            .load_batch(batch_id)
+            .await
+            .ok_or_else(|| std::io::Error::other("batch not found"))?;
+        let input_path = self.file_path(&batch.input_file_id);
+
+        // Validate the whole input file before sending any request
+        if batch.status == BatchStatus::Validating {
+            let path = input_path.clone();
+            let endpoint = batch.endpoint.clone();
+            let validation = tokio::task::spawn_blocking(move || validate_input(&path, &endpoint))
+                .await
+                .map_err(std::io::Error::other)?;
+            match validation {
+                Ok(total) => {
+                    batch.request_counts.total = total;
+                    batch.status = BatchStatus::InProgress;
+                    batch.in_progress_at = Some(now());
+                }
+                Err(errors) => {
+                    batch.errors = Some(BatchErrors {
+                        object: "list".to_string(),
+                        data: errors,
+                    });
+                    batch.status = BatchStatus::Failed;
+                    batch.failed_at = Some(now());
+                }
+            }
+            self.save_batch(&batch).await?;
+            if batch.status == BatchStatus::Failed {
+                return Ok(());
+            }
+        }
+
+        // Progress is persisted in the output files, skip the requests already answered
+        let output_path = self.file_path(&batch.output_file_id);
+        let error_path = self.file_path(&batch.error_file_id);
+        let paths = [output_path.clone(), error_path.clone()];
+        let done = tokio::task::spawn_blocking(move || {
+            let mut done = HashSet::new();
+            for path in paths {
+                done.extend(answered_requests(&path)?);
+            }
+            Ok::<_, std::io::Error>(done)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        if batch.status == BatchStatus::InProgress {
+            let (result_sender, mut result_receiver) = mpsc::unbounded_channel();
+
+            // Dispatch the requests in the background and write the results as they arrive
+            let batch_api = self.clone();
+            let endpoint = batch.endpoint.clone();
+            let dispatch_cancelled = cancelled.clone();
+            let dispatch = tokio::spawn(async move {
+                let file = tokio::fs::File::open(input_path).await?;
+                let mut lines = tokio::io::BufReader::new(file).lines();
+                while let Some(line) = lines.next_line().await? {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    if dispatch_cancelled.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // Unwrap is safe here, the file was validated
+                    let input: BatchRequestInput = serde_json::from_str(&line).unwrap();
+                    if done.contains(&input.custom_id) {
+                        continue;
+                    }
+
+                    // Unwrap is safe here, the semaphore is never closed
+                    let permit = batch_api.permits.clone().acquire_owned().await.unwrap();
+                    wait_for_capacity(&batch_api.infer, batch_api.reserved).await;
+                    let batch_api = batch_api.clone();
+                    let endpoint = endpoint.clone();
+                    let result_sender = result_sender.clone();
+                    tokio::spawn(async move {
+                        let output = batch_api.process(&endpoint, input).await;
+                        let _ = result_sender.send(output);
+                        drop(permit);
+                    });
+                }
+                Ok::<(), std::io::Error>(())
+            });
+
+            let mut output_file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&output_path)
+                .await?;
+            let mut error_file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&error_path)
+                .await?;
+
+            while let Some(output) = result_receiver.recv().await {
+                let success = output.error.is_none();
+                let file = if success {
+                    batch.request_counts.completed += 1;
+                    &mut output_file
+                } else {
+                    batch.request_counts.failed += 1;
+                    &mut error_file
+                };
+                let mut line = serde_json::to_vec(&output).map_err(std::io::Error::other)?;
+                line.push(b'\n');
+                file.write_all(&line).await?;
+                // Do not overwrite the status set by the cancel route
+                if cancelled.load(Ordering::SeqCst) && batch.status != BatchStatus::Cancelling {
+                    batch.status = BatchStatus::Cancelling;
+                    batch.cancelling_at = Some(now());
+                }
+                self.save_batch(&batch).await?;
+            }
+            dispatch.await.map_err(std::io::Error::other)??;
+
+            batch.status = BatchStatus::Finalizing;
+            batch.finalizing_at = Some(now());
+            self.save_batch(&batch).await?;
+        }
+
+        // Register the output files so that they can be downloaded
+        for (file_id, path) in [
+            (&batch.output_file_id, &output_path),
+            (&batch.error_file_id, &error_path),
+        ] {
+            if !tokio::fs::try_exists(path).await? {
+                tokio::fs::File::create(path).await?;
+            }
+            self.save_file(&FileObject {
+                id: file_id.clone(),
+                object: "file".to_string(),
+                bytes: tokio::fs::metadata(path).await?.len(),
+                created_at: now(),
+                filename: format!("{file_id}.jsonl"),
+                purpose: "batch_output".to_string(),
+            })
+            .await?;
+        }
+
+        // The batch might have been cancelled while the last requests were running
+        let cancelled = cancelled.load(Ordering::SeqCst)
+            || self
+                .load_batch(batch_id)
+                .await
+                .is_some_and(|b| b.status == BatchStatus::Cancelling);
+        if cancelled {
+            batch.status = BatchStatus::Cancelled;
+            batch.cancelled_at = Some(now());
+        } else {
+            batch.status = BatchStatus::Completed;
+            batch.completed_at = Some(now());
+        }
+        self.save_batch(&batch).await?;
+        tracing::info!("Batch {batch_id} finished with status {:?}", batch.status);
+        Ok(())
+    }
+
+    /// Send a single request of the batch through the regular OpenAI handlers
+    async fn process(&self, endpoint: &str, input: BatchRequestInput) -> BatchRequestOutput {
+        let request_id = format!("batch_req_{}", uuid::Uuid::new_v4().simple());
+        let BatchRequestInput {
+            custom_id, body, ..
+        } = input;
+
+        let (status_code, body) = dispatch(
+            &self.infer,
+            &self.compute_type,
+            &self.info,
+            &self.streams,
+            endpoint,
+            body,
+            self.reserved,
+        )
+        .await;
+
+        let error = (!status_code.is_success()).then(|| BatchError {
+            code: status_code.as_u16().to_string(),
+            message: match body.get("error") {
+                // The OpenAI routes nest the error in an object
+                Some(Value::Object(error)) => error.get("message").and_then(Value::as_str),
+                Some(error) => error.as_str(),
+                None => None,
+            }
+            .unwrap_or("Request failed")
+            .to_string(),
+            line: None,
+        });
+
+        BatchRequestOutput {
+            id: request_id.clone(),
+            custom_id,
+            response: Some(BatchResponse {
+                status_code: status_code.as_u16(),
+                request_id,
+                body,
+            }),
+            error,
+        }
+    }
+}
+
+/// Wait until more than `reserved` permits of the server are free
+pub(crate) async fn wait_for_capacity(infer: &Infer, reserved: usize) {
+    while infer.free_permits() <= reserved {
+        tokio::time::sleep(CAPACITY_POLL).await;
+    }
+}
+
+/// Send a request body to the handler of `endpoint` and return the status and JSON body of
+/// the response. The request is only sent while more than `reserved` permits of the server
+/// are free, and waits again when the online traffic took them first.
+pub(crate) async fn dispatch(
+    infer: &Infer,
+    compute_type: &ComputeType,
+    info: &Info,
+    streams: &ResumableStreams,
+    endpoint: &str,
+    body: Value,
+    reserved: usize,
+) -> (StatusCode, Value) {
+    let response = loop {
+        wait_for_capacity(infer, reserved).await;
+        let response = match endpoint {
+            GENERATE_ENDPOINT => match serde_json::from_value::<GenerateRequest>(body.clone()) {
+                Ok(request) => {
+                    let span = tracing::Span::current();
+                    generate_internal(
+                        Extension(infer.clone()),
+                        compute_type.clone(),
+                        Json(request),
+                        span,
+                    )
+                    .await
+                    .map(IntoResponse::into_response)
+                    .unwrap_or_else(IntoResponse::into_response)
+                }
+                Err(err) => invalid_body(err).into_response(),
+            },
+            CHAT_COMPLETIONS_ENDPOINT => {
+                match serde_json::from_value::<ChatRequest>(body.clone()) {
+                    Ok(mut request) => {
+                        request.stream = false;
+                        chat_completions(
+                            Extension(infer.clone()),
+                            Extension(compute_type.clone()),
+                            Extension(info.clone()),
+                            Extension(streams.clone()),
+                            Json(request),
+                        )
+                        .await
+                        .unwrap_or_else(IntoResponse::into_response)
+                    }
+                    Err(err) => OpenAIError::from(invalid_body(err)).into_response(),
+                }
+            }
+            _ => match serde_json::from_value::<CompletionRequest>(body.clone()) {
+                Ok(mut request) => {
+                    request.stream = false;
+                    completions(
+                        Extension(infer.clone()),
+                        Extension(compute_type.clone()),
+                        Extension(info.clone()),
+                        Extension(streams.clone()),
+                        Json(request),
+                    )
+                    .await
+                    .unwrap_or_else(IntoResponse::into_response)
+                }
+                Err(err) => OpenAIError::from(invalid_body(err)).into_response(),
+            },
+        };
+
+        if response.status() != StatusCode::TOO_MANY_REQUESTS {
+            break response;
+        }
+    };
+
+    let status_code = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or(Value::Null);
+    (status_code, body)
+}
+
+fn invalid_body(err: serde_json::Error) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse {
+            error: format!("Invalid request body: {err}"),
+            error_type: "validation".to_string(),
+            code: "invalid_request_body".to_string(),
+            param: None,
+        }),
+    )
+}
+
+/// Check every line of the input file and return the number of requests
+fn validate_input(path: &std::path::Path, endpoint: &str) -> Result<u64, Vec<BatchError>> {
+    let error = |code: &str, message: String, line: Option<u64>| BatchError {
+        code: code.to_string(),
+        message,
+        line,
+    };
+    let file = File::open(path).map_err(|e| vec![error("file_not_found", e.to_string(), None)])?;
+
+    let mut errors = Vec::new();
+    let mut custom_ids = HashSet::new();
+    let mut total = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line_number = Some(index as u64 + 1);
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(vec![error("invalid_file", e.to_string(), line_number)]),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<BatchRequestInput>(&line) {
+            Ok(input) => {
+                if input.url != endpoint {
+                    errors.push(error(
+                        "invalid_url",
+                        format!("`url` must be {endpoint}. Given: {}", input.url),
+                        line_number,
+                    ));
+                }
+                if input.method != "POST" {
+                    errors.push(error(
+                        "invalid_method",
+                        format!("`method` must be POST. Given: {}", input.method),
+                        line_number,
+                    ));
+                }
+                if !custom_ids.insert(input.custom_id.clone()) {
+                    errors.push(error(
+                        "duplicate_custom_id",
+                        format!("`custom_id` {} is not unique", input.custom_id),
+                        line_number,
+                    ));
+                }
+            }
+            Err(e) => errors.push(error("invalid_json_line", e.to_string(), line_number)),
+        }
+        total += 1;
+    }
+
+    if total == 0 {
+        errors.push(error("empty_file", "The input file is empty".into(), None));
+    }
+    if errors.is_empty() {
+        Ok(total)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Custom ids of the requests already written to an output file
+fn answered_requests(path: &std::path::Path) -> std::io::Result<Vec<String>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut custom_ids = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        // A partially written line is ignored, the request will be sent again
+        if let Ok(output) = serde_json::from_str::<BatchRequestOutput>(&line?) {
+            custom_ids.push(output.custom_id);
+        }
+    }
+    Ok(custom_ids)
+}
+
+async fn write_atomic(path: PathBuf, content: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(tmp, path).await
+}
+
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Current Unix timestamp, in seconds
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+        .as_secs()
+}
+
+fn not_found(error: String) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse {
+            error,
+            error_type: "not_found".to_string(),
+            code: "not_found".to_string(),
+            param: None,
+        }),
+    )
+}
+
+fn io_error(err: std::io::Error) -> (StatusCode, Json<ErrorResponse>) {
+    tracing::error!("Batch storage error: {err}");
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(ErrorResponse {
+            error: err.to_string(),
+            error_type: "storage".to_string(),
+            code: "storage_error".to_string(),
+            param: None,
+        }),
+    )
+}
+
+/// Upload a JSONL file of batch requests
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/files",
+request_body(content = String, content_type = "multipart/form-data",
+description = "A `file` field with the JSONL content and a `purpose` field set to `batch`"),
+responses(
+(status = 200, description = "Uploaded file", body = FileObject),
+(status = 422, description = "Invalid upload", body = ErrorResponse,
+example = json ! ({"error": "Missing `file` field", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+pub(crate) async fn upload_file(
+    Extension(batch_api): Extension<BatchApi>,
+    mut multipart: Multipart,
+) -> Result<Json<FileObject>, (StatusCode, Json<ErrorResponse>)> {
+    let invalid = |error: String| {
+        (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error,
+                error_type: "validation".to_string(),
+                code: "invalid_file".to_string(),
+                param: Some("file".to_string()),
+            }),
+        )
+    };
+
+    let id = format!("file-{}", uuid::Uuid::new_v4().simple());
+    let path = batch_api.file_path(&id);
+    let mut purpose = "batch".to_string();
+    let mut filename = None;
+    let mut bytes = 0;
+
+    while let Some(mut field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| invalid(e.to_string()))?
+    {
+        match field.name() {
+            Some("purpose") => purpose = field.text().await.map_err(|e| invalid(e.to_string()))?,
+            Some("file") => {
+                filename = Some(field.file_name().unwrap_or("input.jsonl").to_string());
+                // Stream the file to disk, batch inputs can be very large
+                let mut file = tokio::fs::File::create(&path).await.map_err(io_error)?;
+                while let Some(chunk) = field.chunk().await.map_err(|e| invalid(e.to_string()))? {
+                    file.write_all(&chunk).await.map_err(io_error)?;
+                    bytes += chunk.len() as u64;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let filename = filename.ok_or_else(|| invalid("Missing `file` field".to_string()))?;
+    if purpose != "batch" {
+        let _ = tokio::fs::remove_file(&path).await;
+        return Err(invalid(format!(
+            "`purpose` must be `batch`. Given: {purpose}"
+        )));
+    }
+
+    let file = FileObject {
+        id,
+        object: "file".to_string(),
+        bytes,
+        created_at: now(),
+        filename,
+        purpose,
+    };
+    batch_api.save_file(&file).await.map_err(io_error)?;
+    Ok(Json(file))
+}
+
+/// Get the metadata of a file
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/files/{file_id}",
+params(("file_id" = String, Path, description = "The ID of the file")),
+responses(
+(status = 200, description = "File metadata", body = FileObject),
+(status = 404, description = "File not found", body = ErrorResponse),
+)
+)]
+#[instrument(skip(batch_api))]
+pub(crate) async fn get_file(
+    Extension(batch_api): Extension<BatchApi>,
+    Path(file_id): Path<String>,
+) -> Result<Json<FileObject>, (StatusCode, Json<ErrorResponse>)> {
+    batch_api
+        .load_file(&file_id)
+        .await
+        .map(Json)
+        .ok_or_else(|| not_found(format!("File {file_id} not found")))
+}
+
+/// Download the content of a file
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/files/{file_id}/content",
+params(("file_id" = String, Path, description = "The ID of the file")),
+responses(
+(status = 200, description = "File content", body = String, content_type = "application/jsonl"),
+(status = 404, description = "File not found", body = ErrorResponse),
+)
+)]
+#[instrument(skip(batch_api))]
+pub(crate) async fn get_file_content(
+    Extension(batch_api): Extension<BatchApi>,
+    Path(file_id): Path<String>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let file = batch_api
+        .load_file(&file_id)
+        .await
+        .ok_or_else(|| not_found(format!("File {file_id} not found")))?;
+    let content = tokio::fs::read(batch_api.file_path(&file.id))
+        .await
+        .map_err(io_error)?;
+    Ok(([(header::CONTENT_TYPE, "application/jsonl")], content).into_response())
+}
+
+/// Create a batch from an uploaded file
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/batches",
+request_body = CreateBatchRequest,
+responses(
+(status = 200, description = "Created batch", body = Batch),
+(status = 404, description = "Input file not found", body = ErrorResponse),
+(status = 422, description = "Invalid endpoint or completion window", body = ErrorResponse,
+example = json ! ({"error": "`endpoint` must be one of /v1/chat/completions, /v1/completions", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+pub(crate) async fn create_batch(
+    Extension(batch_api): Extension<BatchApi>,
+    Json(req): Json<CreateBatchRequest>,
+) -> Result<Json<Batch>, (StatusCode, Json<ErrorResponse>)> {
+    if req.endpoint != CHAT_COMPLETIONS_ENDPOINT && req.endpoint != COMPLETIONS_ENDPOINT {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: format!(
+                    "`endpoint` must be one of {CHAT_COMPLETIONS_ENDPOINT}, {COMPLETIONS_ENDPOINT}"
+                ),
+                error_type: "validation".to_string(),
+                code: "invalid_endpoint".to_string(),
+                param: Some("endpoint".to_string()),
+            }),
+        ));
+    }
+    if req.completion_window != "24h" {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: format!(
+                    "`completion_window` must be 24h. Given: {}",
+                    req.completion_window
+                ),
+                error_type: "validation".to_string(),
+                code: "invalid_completion_window".to_string(),
+                param: Some("completion_window".to_string()),
+            }),
+        ));
+    }
+    let input_file = batch_api
+        .load_file(&req.input_file_id)
+        .await
+        .ok_or_else(|| not_found(format!("File {} not found", req.input_file_id)))?;
+
+    let batch = Batch {
+        id: format!("batch_{}", uuid::Uuid::new_v4().simple()),
+        object: "batch".to_string(),
+        endpoint: req.endpoint,
+        errors: None,
+        input_file_id: input_file.id,
+        completion_window: req.completion_window,
+        status: BatchStatus::Validating,
+        output_file_id: format!("file-{}", uuid::Uuid::new_v4().simple()),
+        error_file_id: format!("file-{}", uuid::Uuid::new_v4().simple()),
+        created_at: now(),
+        in_progress_at: None,
+        finalizing_at: None,
+        completed_at: None,
+        failed_at: None,
+        cancelling_at: None,
+        cancelled_at: None,
+        request_counts: BatchRequestCounts::default(),
+        metadata: req.metadata,
+    };
+    batch_api.save_batch(&batch).await.map_err(io_error)?;
+    batch_api.spawn(batch.id.clone());
+    Ok(Json(batch))
+}
+
+/// List batches, most recent first
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/batches",
+responses((status = 200, description = "Batches", body = BatchList))
+)]
+#[instrument(skip_all)]
+pub(crate) async fn list_batches(
+    Extension(batch_api): Extension<BatchApi>,
+) -> Result<Json<BatchList>, (StatusCode, Json<ErrorResponse>)> {
+    Ok(Json(BatchList {
+        object: "list".to_string(),
+        data: batch_api.list_batches().await.map_err(io_error)?,
+        has_more: false,
+    }))
+}
+
+/// Get the status of a batch
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/v1/batches/{batch_id}",
+params(("batch_id" = String, Path, description = "The ID of the batch")),
+responses(
+(status = 200, description = "Batch", body = Batch),
+(status = 404, description = "Batch not found", body = ErrorResponse),
+)
+)]
+#[instrument(skip(batch_api))]
+pub(crate) async fn get_batch(
+    Extension(batch_api): Extension<BatchApi>,
+    Path(batch_id): Path<String>,
+) -> Result<Json<Batch>, (StatusCode, Json<ErrorResponse>)> {
+    batch_api
+        .load_batch(&batch_id)
+        .await
+        .map(Json)
+        .ok_or_else(|| not_found(format!("Batch {batch_id} not found")))
+}
+
+/// Cancel a batch. The requests already running are completed.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/batches/{batch_id}/cancel",
+params(("batch_id" = String, Path, description = "The ID of the batch")),
+responses(
+(status = 200, description = "Batch", body = Batch),
+(status = 404, description = "Batch not found", body = ErrorResponse),
+)
+)]
+#[instrument(skip(batch_api))]
+pub(crate) async fn cancel_batch(
+    Extension(batch_api): Extension<BatchApi>,
+    Path(batch_id): Path<String>,
+) -> Result<Json<Batch>, (StatusCode, Json<ErrorResponse>)> {
+    let mut batch = batch_api
+        .load_batch(&batch_id)
+        .await
+        .ok_or_else(|| not_found(format!("Batch {batch_id} not found")))?;
+
+    if matches!(
+        batch.status,
+        BatchStatus::Validating | BatchStatus::InProgress | BatchStatus::Finalizing
+    ) {
+        let running = batch_api.running.lock().unwrap().get(&batch_id).cloned();
+        if let Some(cancelled) = running {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+        batch.status = BatchStatus::Cancelling;
+        batch.cancelling_at = Some(now());
+        batch_api.save_batch(&batch).await.map_err(io_error)?;
+    }
+    Ok(Json(batch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{mock_infer, mock_info, MockBackend};
+
+    fn write_input(name: &str, lines: &[Value]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tgi-batch-{name}-{}.jsonl", now()));
+        let content = lines
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_validate_batch_input() {
+        let path = write_input(
+            "valid",
+            &[
+                serde_json::json!({"custom_id": "a", "method": "POST", "url": "/v1/chat/completions", "body": {"messages": []}}),
+                serde_json::json!({"custom_id": "b", "url": "/v1/chat/completions", "body": {"messages": []}}),
+            ],
+        );
+        assert_eq!(validate_input(&path, CHAT_COMPLETIONS_ENDPOINT).unwrap(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_validate_batch_input_errors() {
+        let path = write_input(
+            "invalid",
+            &[
+                serde_json::json!({"custom_id": "a", "url": "/v1/completions", "body": {}}),
+                serde_json::json!({"custom_id": "a", "url": "/v1/chat/completions", "body": {}}),
+                serde_json::json!({"url": "/v1/chat/completions", "body": {}}),
+            ],
+        );
+        let errors = validate_input(&path, CHAT_COMPLETIONS_ENDPOINT).unwrap_err();
+        let codes: Vec<_> = errors.iter().map(|e| e.code.as_str()).collect();
+        assert_eq!(
+            codes,
+            vec!["invalid_url", "duplicate_custom_id", "invalid_json_line"]
+        );
+        assert_eq!(errors[2].line, Some(3));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    async fn batch_api(directory: &std::path::Path, backend: MockBackend) -> BatchApi {
+        BatchApi::new(
+            directory.to_path_buf(),
+            2,
+            mock_infer(backend, 64, 8),
+            ComputeType("test".to_string()),
+            mock_info(64),
+            ResumableStreams::new(Duration::from_secs(60)),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn batch(id: &str, status: BatchStatus, total: u64, completed: u64) -> Batch {
+        Batch {
+            id: id.to_string(),
+            object: "batch".to_string(),
+            endpoint: CHAT_COMPLETIONS_ENDPOINT.to_string(),
+            errors: None,
+            input_file_id: "file-input".to_string(),
+            completion_window: "24h".to_string(),
+            status,
+            output_file_id: "file-output".to_string(),
+            error_file_id: "file-errors".to_string(),
+            created_at: now(),
+            in_progress_at: Some(now()),
+            finalizing_at: None,
+            completed_at: None,
+            failed_at: None,
+            cancelling_at: None,
+            cancelled_at: None,
+            request_counts: BatchRequestCounts {
+                total,
+                completed,
+                failed: 0,
+            },
+            metadata: None,
+        }
+    }
+
+    /// Write the input file and the state of a batch as a previous process left them
+    fn write_batch(directory: &std::path::Path, batch: &Batch, answered: &[&str]) {
+        std::fs::create_dir_all(directory.join("files")).unwrap();
+        std::fs::create_dir_all(directory.join("batches")).unwrap();
+        let line = |custom_id: &str, body: Value| {
+            serde_json::json!({"custom_id": custom_id, "url": CHAT_COMPLETIONS_ENDPOINT, "body": body})
+                .to_string()
+        };
+        let messages = serde_json::json!({"messages": [{"role": "user", "content": "Hi"}]});
+        let input = [
+            line("a", messages.clone()),
+            line("b", messages),
+            line("c", serde_json::json!({"messages": "Hi"})),
+        ];
+        std::fs::write(directory.join("files/file-input.jsonl"), input.join("\n")).unwrap();
+
+        let output: Vec<String> = answered
+            .iter()
+            .map(|custom_id| {
+                serde_json::to_string(&BatchRequestOutput {
+                    id: format!("batch_req_{custom_id}"),
+                    custom_id: custom_id.to_string(),
+                    response: None,
+                    error: None,
+                })
+                .unwrap()
+                    + "\n"
+            })
+            .collect();
+        std::fs::write(directory.join("files/file-output.jsonl"), output.concat()).unwrap();
+        std::fs::write(
+            directory.join(format!("batches/{}.json", batch.id)),
+            serde_json::to_vec(batch).unwrap(),
+        )
+        .unwrap();
+    }
+
+    async fn wait_for_batch(batch_api: &BatchApi, batch_id: &str) -> Batch {
+        for _ in 0..500 {
+            let batch = batch_api.load_batch(batch_id).await.unwrap();
+            if matches!(
+                batch.status,
+                BatchStatus::Completed | BatchStatus::Failed | BatchStatus::Cancelled
+            ) {
+                return batch;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Batch {batch_id} did not finish");
+    }
+
+    fn custom_ids(path: PathBuf) -> Vec<String> {
+        let mut custom_ids = answered_requests(&path).unwrap();
+        custom_ids.sort();
+        custom_ids
+    }
+
+    #[tokio::test]
+    async fn test_resume_batch_after_restart() {
+        let directory =
+            std::env::temp_dir().join(format!("tgi-batch-{}", uuid::Uuid::new_v4().simple()));
+        // The previous process answered `a` before stopping
+        write_batch(
+            &directory,
+            &batch("batch_resumed", BatchStatus::InProgress, 3, 1),
+            &["a"],
+        );
+
+        let backend = MockBackend::new(|_| "Hello there".to_string());
+        let batch_api = batch_api(&directory, backend.clone()).await;
+        let batch = wait_for_batch(&batch_api, "batch_resumed").await;
+
+        assert_eq!(batch.status, BatchStatus::Completed);
+        // `a` is not sent again and `c` is rejected before reaching the backend
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+        assert_eq!(batch.request_counts.total, 3);
+        assert_eq!(batch.request_counts.completed, 2);
+        assert_eq!(batch.request_counts.failed, 1);
+        assert_eq!(
+            custom_ids(batch_api.file_path("file-output")),
+            vec!["a", "b"]
+        );
+        assert_eq!(custom_ids(batch_api.file_path("file-errors")), vec!["c"]);
+        assert!(batch_api.load_file("file-errors").await.is_some());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_process_error_message() {
+        let directory =
+            std::env::temp_dir().join(format!("tgi-batch-{}", uuid::Uuid::new_v4().simple()));
+        let backend = MockBackend::new(|_| "Hello there".to_string());
+        let batch_api = batch_api(&directory, backend.clone()).await;
+        let input = BatchRequestInput {
+            custom_id: "a".to_string(),
+            method: default_method(),
+            url: CHAT_COMPLETIONS_ENDPOINT.to_string(),
+            body: serde_json::json!({
+                "messages": [{"role": "user", "content": "Hi"}],
+                "temperature": -1.0,
+            }),
+        };
+        let output = batch_api.process(CHAT_COMPLETIONS_ENDPOINT, input).await;
+
+        assert_eq!(output.response.unwrap().status_code, 422);
+        let error = output.error.unwrap();
+        assert_eq!(error.code, "422");
+        assert_eq!(
+            error.message,
+            "Input validation error: `temperature` must be strictly positive"
+        );
+        assert!(backend.requests.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batch_yields_to_online_requests() {
+        let directory =
+            std::env::temp_dir().join(format!("tgi-batch-{}", uuid::Uuid::new_v4().simple()));
+        let backend = MockBackend::new(|_| "Hello there".to_string());
+        let batch_api = batch_api(&directory, backend.clone()).await;
+
+        // The online requests hold half of the permits
+        let mut permits = Vec::new();
+        for _ in 0..batch_api.reserved {
+            let request = GenerateRequest {
+                inputs: "Hi".to_string(),
+                add_special_tokens: true,
+                parameters: Default::default(),
+            };
+            let (permit, _, _) = batch_api.infer.generate_stream(request).await.unwrap();
+            permits.push(permit);
+        }
+        write_batch(
+            &directory,
+            &batch("batch_waiting", BatchStatus::InProgress, 3, 1),
+            &["a"],
+        );
+        batch_api.spawn("batch_waiting".to_string());
+        tokio::time::sleep(CAPACITY_POLL * 3).await;
+        assert_eq!(backend.requests.lock().unwrap().len(), batch_api.reserved);
+
+        drop(permits);
+        let batch = wait_for_batch(&batch_api, "batch_waiting").await;
+        assert_eq!(batch.status, BatchStatus::Completed);
+        assert_eq!(
+            backend.requests.lock().unwrap().len(),
+            batch_api.reserved + 1
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_create_batch_completion_window() {
+        let directory =
+            std::env::temp_dir().join(format!("tgi-batch-{}", uuid::Uuid::new_v4().simple()));
+        let batch_api = batch_api(&directory, MockBackend::new(|_| String::new())).await;
+        let request = CreateBatchRequest {
+            input_file_id: "file-input".to_string(),
+            endpoint: CHAT_COMPLETIONS_ENDPOINT.to_string(),
+            completion_window: "1h".to_string(),
+            metadata: None,
+        };
+        let (status_code, Json(err)) = create_batch(Extension(batch_api), Json(request))
+            .await
+            .unwrap_err();
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.code, "invalid_completion_window");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_batch() {
+        let directory =
+            std::env::temp_dir().join(format!("tgi-batch-{}", uuid::Uuid::new_v4().simple()));
+        // A batch cancelled before a restart is not resumed
+        write_batch(
+            &directory,
+            &batch("batch_cancelling", BatchStatus::Cancelling, 3, 1),
+            &["a"],
+        );
+        let backend = MockBackend::new(|_| "Hello there".to_string());
+        let batch_api = batch_api(&directory, backend.clone()).await;
+        let cancelled = batch_api.load_batch("batch_cancelling").await.unwrap();
+        assert_eq!(cancelled.status, BatchStatus::Cancelled);
+        assert!(cancelled.cancelled_at.is_some());
+
+        // A cancelled batch stops sending requests and keeps its counts
+        write_batch(
+            &directory,
+            &batch("batch_cancelled", BatchStatus::InProgress, 3, 1),
+            &["a"],
+        );
+        batch_api
+            .run("batch_cancelled", Arc::new(AtomicBool::new(true)))
+            .await
+            .unwrap();
+        let cancelled = batch_api.load_batch("batch_cancelled").await.unwrap();
+        assert_eq!(cancelled.status, BatchStatus::Cancelled);
+        assert_eq!(cancelled.request_counts.completed, 1);
+        assert_eq!(cancelled.request_counts.failed, 0);
+        assert!(backend.requests.lock().unwrap().is_empty());
+        assert!(batch_api.load_file("file-output").await.is_some());
+
+        let Json(cancelled) = cancel_batch(
+            Extension(batch_api.clone()),
+            Path("batch_cancelled".to_string()),
+        )
+        .await
+        .unwrap();
+        // Finished batches cannot be cancelled again
+        assert_eq!(cancelled.status, BatchStatus::Cancelled);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,831 @@
+/// Context-free grammars of the `cfg` grammar type
+///
+/// Grammars are written in GBNF (llama.cpp) or Lark syntax. Both are parsed and checked here so
+/// that mistakes are reported to the client instead of failing in the shards, which only
+/// understand Lark: GBNF grammars are converted before being forwarded. The shards build an LALR(1)
+/// parser, so grammars with conflicts Lark cannot resolve are rejected too.
+use super::lalr::check_lalr;
+use crate::validation::ValidationError;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+
+/// A GBNF grammar has at least one `name ::= ...` rule, a sequence Lark never uses
+static GBNF_RULE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)^\s*[a-zA-Z0-9_-]+\s*::=").unwrap());
+
+/// Validate a GBNF or Lark grammar and return it in Lark syntax
+pub(crate) fn compile_cfg(grammar: &str) -> Result<String, ValidationError> {
+    let lark = if GBNF_RULE.is_match(grammar) {
+        let rules = Parser::new(grammar).gbnf()?;
+        gbnf_to_lark(&rules)
+    } else {
+        grammar.to_string()
+    };
+    // Converted grammars are parsed again, to check what the shards will build
+    check_lalr(&Parser::new(&lark).lark()?)?;
+    Ok(lark)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Expr {
+    Literal(String),
+    /// A regular expression matching a single item, without its delimiters
+    Regex(String),
+    Ref(String),
+    Seq(Vec<Expr>),
+    Alt(Vec<Expr>),
+    Repeat(Box<Expr>, u32, Option<u32>),
+}
+
+/// Rules and terminals of a Lark grammar
+pub(super) struct LarkGrammar {
+    pub(super) rules: HashMap<String, Expr>,
+    /// Priorities of the rules that set one
+    pub(super) priorities: HashMap<String, i64>,
+    pub(super) ignored: Vec<Expr>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    expr: Expr,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Self {
+        Self { src, pos: 0 }
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> ValidationError {
+        let line = self.src[..self.pos].matches('\n').count() + 1;
+        ValidationError::InvalidGrammar(format!("line {line}: {message}"))
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ValidationError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{token}`")))
+        }
+    }
+
+    /// Skip blanks and comments, and newlines when `newlines` is set
+    fn space(&mut self, comment: &str, newlines: bool) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t') => {
+                    self.bump();
+                }
+                Some('\r' | '\n') if newlines => {
+                    self.bump();
+                }
+                _ if self.rest().starts_with(comment) => {
+                    let end = self.rest().find('\n').unwrap_or(self.rest().len());
+                    self.pos += end;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn word(&mut self, is_word_char: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&is_word_char) {
+            self.bump();
+        }
+        &self.src[start..self.pos]
+    }
+
+    fn number(&mut self) -> Result<u32, ValidationError> {
+        let digits = self.word(|c| c.is_ascii_digit());
+        digits.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    /// Decode the escape sequence following a backslash
+    fn escape(&mut self) -> Result<char, ValidationError> {
+        let hex = |parser: &mut Self, len: usize| {
+            let digits = parser.rest().get(..len).unwrap_or_default();
+            let c = u32::from_str_radix(digits, 16)
+                .ok()
+                .filter(|_| digits.len() == len)
+                .and_then(char::from_u32)
+                .ok_or_else(|| parser.error(format!("invalid escape `{digits}`")))?;
+            parser.pos += len;
+            Ok(c)
+        };
+        match self.bump() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('x') => hex(self, 2),
+            Some('u') => hex(self, 4),
+            Some('U') => hex(self, 8),
+            Some(c @ ('\\' | '"' | '[' | ']' | '-' | '^' | '/')) => Ok(c),
+            Some(c) => Err(self.error(format!("unknown escape `\\{c}`"))),
+            None => Err(self.error("unterminated escape")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ValidationError> {
+        self.expect("\"")?;
+        let mut string = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(self.escape()?),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    // GBNF, as parsed by llama.cpp: a newline ends a rule unless it follows a `|` or is nested
+    // in parentheses
+
+    fn gbnf(mut self) -> Result<Vec<Rule>, ValidationError> {
+        let mut rules: Vec<Rule> = Vec::new();
+        self.space("#", true);
+        while self.peek().is_some() {
+            let name = self.word(is_gbnf_char).to_string();
+            if name.is_empty() {
+                return Err(self.error("expected a rule name"));
+            }
+            if rules.iter().any(|rule| rule.name == name) {
+                return Err(self.error(format!("rule `{name}` is defined more than once")));
+            }
+            self.space("#", false);
+            self.expect("::=")?;
+            self.space("#", true);
+            let expr = self.gbnf_alternates(false)?;
+            match self.peek() {
+                Some('\r' | '\n') | None => {}
+                Some(c) => return Err(self.error(format!("unexpected `{c}`"))),
+            }
+            rules.push(Rule { name, expr });
+            self.space("#", true);
+        }
+
+        if !rules.iter().any(|rule| rule.name == "root") {
+            return Err(ValidationError::InvalidGrammar(
+                "GBNF grammars must define a `root` rule".to_string(),
+            ));
+        }
+        let names: HashSet<&str> = rules.iter().map(|rule| rule.name.as_str()).collect();
+        for rule in &rules {
+            let mut references = Vec::new();
+            rule.expr.references(&mut references);
+            if let Some(undefined) = references
+                .iter()
+                .find(|name| !names.contains(name.as_str()))
+            {
+                return Err(ValidationError::InvalidGrammar(format!(
+                    "rule `{}` references the undefined rule `{undefined}`",
+                    rule.name
+                )));
+            }
+        }
+        Ok(rules)
+    }
+
+    fn gbnf_alternates(&mut self, nested: bool) -> Result<Expr, ValidationError> {
+        let mut alternatives = vec![self.gbnf_sequence(nested)?];
+        while self.eat("|") {
+            self.space("#", true);
+            alternatives.push(self.gbnf_sequence(nested)?);
+        }
+        Ok(Expr::alt(alternatives))
+    }
+
+    fn gbnf_sequence(&mut self, nested: bool) -> Result<Expr, ValidationError> {
+        let mut items = Vec::new();
+        loop {
+            let item = match self.peek() {
+                Some('"') => Expr::Literal(self.string()?),
+                Some('[') => Expr::Regex(self.gbnf_class()?),
+                Some('.') => {
+                    self.bump();
+                    Expr::Regex("(?s:.)".to_string())
+                }
+                Some('(') => {
+                    self.bump();
+                    self.space("#", true);
+                    let expr = self.gbnf_alternates(true)?;
+                    self.space("#", true);
+                    self.expect(")")?;
+                    expr
+                }
+                Some(c) if is_gbnf_char(c) => Expr::Ref(self.word(is_gbnf_char).to_string()),
+                _ => break,
+            };
+            self.space("#", nested);
+            items.push(self.gbnf_repetition(item, nested)?);
+        }
+        Ok(Expr::seq(items))
+    }
+
+    fn gbnf_repetition(&mut self, mut item: Expr, nested: bool) -> Result<Expr, ValidationError> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.bump();
+                    self.space("#", false);
+                    let min = self.number()?;
+                    self.space("#", false);
+                    let max = if self.eat(",") {
+                        self.space("#", false);
+                        if self.peek() == Some('}') {
+                            None
+                        } else {
+                            Some(self.number()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    self.space("#", false);
+                    if self.peek() != Some('}') {
+                        return Err(self.error("expected `}`"));
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        return Err(self.error(format!("invalid repetition {{{min},{max:?}}}")));
+                    }
+                    (min, max)
+                }
+                _ => return Ok(item),
+            };
+            self.bump();
+            self.space("#", nested);
+            item = Expr::Repeat(Box::new(item), min, max);
+        }
+    }
+
+    /// Parse a character class into an equivalent regular expression
+    fn gbnf_class(&mut self) -> Result<String, ValidationError> {
+        self.expect("[")?;
+        let mut class = String::from("[");
+        if self.eat("^") {
+            class.push('^');
+        }
+        loop {
+            let start = match self.bump() {
+                Some(']') => break,
+                Some('\\') => self.escape()?,
+                Some(c) => c,
+                None => return Err(self.error("unterminated character class")),
+            };
+            push_class_char(&mut class, start);
+            if self.rest().starts_with('-') && !self.rest().starts_with("-]") {
+                self.bump();
+                let end = match self.bump() {
+                    Some('\\') => self.escape()?,
+                    Some(c) => c,
+                    None => return Err(self.error("unterminated character class")),
+                };
+                if end < start {
+                    return Err(self.error(format!("invalid range `{start}-{end}`")));
+                }
+                class.push('-');
+                push_class_char(&mut class, end);
+            }
+        }
+        if class == "[" || class == "[^" {
+            return Err(self.error("empty character class"));
+        }
+        class.push(']');
+        Ok(class)
+    }
+
+    // Lark: only checked, the grammar is forwarded as is
+
+    fn lark(mut self) -> Result<LarkGrammar, ValidationError> {
+        let mut rules = HashMap::new();
+        let mut priorities = HashMap::new();
+        let mut defined = HashSet::new();
+        let mut ignored = Vec::new();
+
+        loop {
+            self.space("//", true);
+            if self.peek().is_none() {
+                break;
+            }
+            if self.eat("%") {
+                let directive = self.word(|c| c.is_ascii_alphabetic());
+                self.space("//", false);
+                match directive {
+                    "import" => defined.extend(self.lark_import()?),
+                    "declare" => loop {
+                        let name = self.word(is_lark_char);
+                        if name.is_empty() {
+                            break;
+                        }
+                        defined.insert(name.to_string());
+                        self.space("//", false);
+                    },
+                    "ignore" => ignored.push(self.lark_alternates(false)?),
+                    _ => return Err(self.error(format!("unsupported directive `%{directive}`"))),
+                }
+            } else {
+                let _ = self.eat("?") || self.eat("!");
+                let name = self.word(is_lark_char).to_string();
+                if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                    return Err(self.error("expected a rule or terminal name"));
+                }
+                if self.peek() == Some('{') {
+                    return Err(self.error("templates are not supported"));
+                }
+                if self.eat(".") {
+                    let sign = if self.eat("-") { -1 } else { 1 };
+                    priorities.insert(name.clone(), sign * i64::from(self.number()?));
+                }
+                self.space("//", false);
+                self.expect(":")?;
+                self.space("//", false);
+                let expr = self.lark_alternates(false)?;
+                if !defined.insert(name.clone()) {
+                    return Err(self.error(format!("`{name}` is defined more than once")));
+                }
+                rules.insert(name, expr);
+            }
+            match self.peek() {
+                Some('\r' | '\n') | None => {}
+                Some(c) => return Err(self.error(format!("unexpected `{c}`"))),
+            }
+        }
+
+        if !rules.contains_key("start") {
+            return Err(ValidationError::InvalidGrammar(
+                "Lark grammars must define a `start` rule".to_string(),
+            ));
+        }
+        let check = |name: Option<&str>, expr: &Expr| {
+            let mut references = Vec::new();
+            expr.references(&mut references);
+            let subject = match name {
+                Some(name) => format!("`{name}`"),
+                None => "`%ignore`".to_string(),
+            };
+            for reference in references {
+                if !defined.contains(&reference) {
+                    return Err(ValidationError::InvalidGrammar(format!(
+                        "{subject} references the undefined `{reference}`"
+                    )));
+                }
+                if name.map_or(true, is_terminal) && !is_terminal(&reference) {
+                    return Err(ValidationError::InvalidGrammar(format!(
+                        "{subject} is a terminal and cannot reference the rule `{reference}`"
+                    )));
+                }
+            }
+            Ok(())
+        };
+        for (name, expr) in &rules {
+            check(Some(name), expr)?;
+        }
+        for expr in &ignored {
+            check(None, expr)?;
+        }
+        Ok(LarkGrammar {
+            rules,
+            priorities,
+            ignored,
+        })
+    }
+
+    /// Parse the rest of an `%import` and return the imported names
+    fn lark_import(&mut self) -> Result<Vec<String>, ValidationError> {
+        let path = self.word(|c| is_lark_char(c) || c == '.');
+        let last = path.rsplit('.').next().unwrap_or_default().to_string();
+        self.space("//", false);
+        if self.eat("->") {
+            self.space("//", false);
+            let alias = self.word(is_lark_char);
+            self.space("//", false);
+            return Ok(vec![alias.to_string()]);
+        }
+        if self.eat("(") {
+            let mut names = Vec::new();
+            loop {
+                self.space("//", true);
+                let name = self.word(is_lark_char);
+                if name.is_empty() {
+                    return Err(self.error("expected a name to import"));
+                }
+                names.push(name.to_string());
+                self.space("//", true);
+                if self.eat(")") {
+                    self.space("//", false);
+                    return Ok(names);
+                }
+                self.expect(",")?;
+            }
+        }
+        if last.is_empty() {
+            return Err(self.error("expected a name to import"));
+        }
+        Ok(vec![last])
+    }
+
+    /// Alternatives can continue on a line starting with `|`
+    fn lark_alternates(&mut self, nested: bool) -> Result<Expr, ValidationError> {
+        let mut alternatives = vec![self.lark_sequence(nested)?];
+        loop {
+            let pos = self.pos;
+            self.space("//", true);
+            if self.eat("|") {
+                self.space("//", nested);
+                alternatives.push(self.lark_sequence(nested)?);
+            } else {
+                if !nested {
+                    self.pos = pos;
+                }
+                return Ok(Expr::alt(alternatives));
+            }
+        }
+    }
+
+    fn lark_sequence(&mut self, nested: bool) -> Result<Expr, ValidationError> {
+        let mut items = Vec::new();
+        loop {
+            let item = match self.peek() {
+                Some('"') => {
+                    let string = self.string()?;
+                    self.eat("i");
+                    if self.eat("..") {
+                        let end = self.string()?;
+                        if string.chars().count() != 1 || end.chars().count() != 1 {
+                            return Err(self.error("ranges must be between single characters"));
+                        }
+                        Expr::Regex(format!("[{string}-{end}]"))
+                    } else if string.is_empty() {
+                        return Err(self.error("empty strings are not allowed"));
+                    } else {
+                        Expr::Literal(string)
+                    }
+                }
+                Some('/') if !self.rest().starts_with("//") => Expr::Regex(self.lark_regex()?),
+                Some(open @ ('(' | '[')) => {
+                    self.bump();
+                    self.space("//", true);
+                    let expr = self.lark_alternates(true)?;
+                    self.space("//", true);
+                    if open == '(' {
+                        self.expect(")")?;
+                        expr
+                    } else {
+                        self.expect("]")?;
+                        Expr::Repeat(Box::new(expr), 0, Some(1))
+                    }
+                }
+                Some(c) if is_lark_char(c) => Expr::Ref(self.word(is_lark_char).to_string()),
+                _ => break,
+            };
+            self.space("//", nested);
+            items.push(self.lark_repetition(item, nested)?);
+        }
+        // Alias of the alternative in the parse tree
+        if self.eat("->") {
+            self.space("//", false);
+            if self.word(is_lark_char).is_empty() {
+                return Err(self.error("expected an alias after `->`"));
+            }
+            self.space("//", nested);
+        }
+        Ok(Expr::seq(items))
+    }
+
+    fn lark_repetition(&mut self, mut item: Expr, nested: bool) -> Result<Expr, ValidationError> {
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('~') => {
+                    self.bump();
+                    self.space("//", false);
+                    let min = self.number()?;
+                    let max = if self.eat("..") { self.number()? } else { min };
+                    if max < min {
+                        return Err(self.error(format!("invalid repetition ~ {min}..{max}")));
+                    }
+                    self.space("//", nested);
+                    item = Expr::Repeat(Box::new(item), min, Some(max));
+                    continue;
+                }
+                _ => return Ok(item),
+            };
+            self.bump();
+            self.space("//", nested);
+            item = Expr::Repeat(Box::new(item), min, max);
+        }
+    }
+
+    /// Parse a `/regex/flags` literal and check that the regex compiles
+    ///
+    /// The check uses the syntax of the `regex` crate, a subset of the Python syntax of the
+    /// shards: look-arounds and backreferences are rejected.
+    fn lark_regex(&mut self) -> Result<String, ValidationError> {
+        self.expect("/")?;
+        let mut pattern = String::new();
+        loop {
+            match self.bump() {
+                Some('/') => break,
+                Some('\\') => match self.bump() {
+                    Some('/') => pattern.push('/'),
+                    Some(c) => {
+                        pattern.push('\\');
+                        pattern.push(c);
+                    }
+                    None => return Err(self.error("unterminated regular expression")),
+                },
+                Some('\n') | None => return Err(self.error("unterminated regular expression")),
+                Some(c) => pattern.push(c),
+            }
+        }
+        let flags = self.word(|c| "imslux".contains(c));
+        let inline: String = flags.chars().filter(|c| "imsx".contains(*c)).collect();
+        if !inline.is_empty() {
+            pattern = format!("(?{inline}:{pattern})");
+        }
+        Regex::new(&pattern)
+            .map_err(|err| self.error(format!("invalid regular expression {err}")))?;
+        Ok(pattern)
+    }
+}
+
+impl Expr {
+    fn alt(mut alternatives: Vec<Expr>) -> Self {
+        if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            Expr::Alt(alternatives)
+        }
+    }
+
+    fn seq(mut items: Vec<Expr>) -> Self {
+        if items.len() == 1 {
+            items.remove(0)
+        } else {
+            Expr::Seq(items)
+        }
+    }
+
+    fn references(&self, references: &mut Vec<String>) {
+        match self {
+            Expr::Ref(name) => references.push(name.clone()),
+            Expr::Seq(exprs) | Expr::Alt(exprs) => {
+                exprs.iter().for_each(|expr| expr.references(references))
+            }
+            Expr::Repeat(expr, ..) => expr.references(references),
+            Expr::Literal(_) | Expr::Regex(_) => {}
+        }
+    }
+
+    fn to_lark(&self, names: &HashMap<&str, String>) -> String {
+        match self {
+            // Lark does not allow empty strings
+            Expr::Literal(string) if string.is_empty() => String::new(),
+            Expr::Literal(string) => lark_string(string),
+            Expr::Regex(pattern) => format!("/{}/", pattern.replace('/', "\\/")),
+            Expr::Ref(name) => names[name.as_str()].clone(),
+            Expr::Seq(items) => items
+                .iter()
+                .map(|item| match item {
+                    Expr::Alt(_) => format!("({})", item.to_lark(names)),
+                    _ => item.to_lark(names),
+                })
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            Expr::Alt(alternatives) => alternatives
+                .iter()
+                .map(|alternative| alternative.to_lark(names))
+                .collect::<Vec<_>>()
+                .join(" | "),
+            Expr::Repeat(_, _, Some(0)) => String::new(),
+            Expr::Repeat(item, min, max) => {
+                let item = match item.as_ref() {
+                    Expr::Seq(_) | Expr::Alt(_) | Expr::Repeat(..) => {
+                        format!("({})", item.to_lark(names))
+                    }
+                    _ => item.to_lark(names),
+                };
+                if item.is_empty() {
+                    return item;
+                }
+                match (min, max) {
+                    (0, None) => format!("{item}*"),
+                    (1, None) => format!("{item}+"),
+                    (0, Some(1)) => format!("{item}?"),
+                    (min, None) => format!("{item} ~ {min} {item}*"),
+                    (min, Some(max)) if min == max => format!("{item} ~ {min}"),
+                    (min, Some(max)) => format!("{item} ~ {min}..{max}"),
+                }
+            }
+        }
+    }
+}
+
+fn is_gbnf_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn is_lark_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Terminals are uppercase, rules are lowercase
+pub(super) fn is_terminal(name: &str) -> bool {
+    name.trim_start_matches('_')
+        .starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+fn push_class_char(class: &mut String, c: char) {
+    match c {
+        '\\' | ']' | '[' | '^' | '-' => {
+            class.push('\\');
+            class.push(c);
+        }
+        '\n' => class.push_str("\\n"),
+        '\r' => class.push_str("\\r"),
+        '\t' => class.push_str("\\t"),
+        // Control characters are below U+00A0, `\xHH` is understood by Python and Rust
+        c if c.is_control() => class.push_str(&format!("\\x{:02x}", c as u32)),
+        c => class.push(c),
+    }
+}
+
+fn lark_string(string: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Rename the GBNF rules to valid lowercase Lark rules, `root` becoming `start`
+fn gbnf_to_lark(rules: &[Rule]) -> String {
+    let mut names: HashMap<&str, String> = HashMap::from([("root", "start".to_string())]);
+    let mut used: HashSet<String> = HashSet::from(["start".to_string()]);
+    for rule in rules.iter().filter(|rule| rule.name != "root") {
+        let mut name = rule.name.to_ascii_lowercase().replace('-', "_");
+        if !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+            name = format!("r{name}");
+        }
+        while used.contains(&name) {
+            name.push('_');
+        }
+        used.insert(name.clone());
+        names.insert(&rule.name, name);
+    }
+
+    let mut lark = String::new();
+    for rule in rules {
+        lark.push_str(&names[rule.name.as_str()]);
+        lark.push_str(": ");
+        lark.push_str(&rule.expr.to_lark(&names));
+        lark.push('\n');
+    }
+    lark
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(grammar: &str) -> String {
+        match compile_cfg(grammar) {
+            Err(ValidationError::InvalidGrammar(err)) => err,
+            other => panic!("expected an invalid grammar, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_gbnf_to_lark() {
+        let gbnf = r#"
+# A list of words
+root   ::= "[" ws item ("," ws item)* "]"
+item   ::= word-char+ | "\"" [^"\\\n]* "\""
+word-char ::= [a-zA-Z0-9_-] | .
+ws ::= ([ \t]
+       | "\n"){0,2}
+Start ::= "x"{2,}
+"#;
+        assert_eq!(
+            compile_cfg(gbnf).unwrap(),
+            r#"start: "[" ws item ("," ws item)* "]"
+item: word_char+ | "\"" /[^"\\\n]/* "\""
+word_char: /[a-zA-Z0-9_\-]/ | /(?s:.)/
+ws: (/[ \t]/ | "\n") ~ 0..2
+start_: "x" ~ 2 "x"*
+"#
+        );
+
+        assert!(error("rot ::= \"a\"").contains("`root`"));
+        assert!(error("root ::= a\na ::= b").contains("undefined rule `b`"));
+        assert!(error("root ::= \"a\"\nroot ::= \"b\"").starts_with("line 2"));
+        assert!(error("root ::= \"a\" ) \"b\"").contains("unexpected `)`"));
+        assert!(error("root ::= \"a\"\n  | \"b\"").starts_with("line 2"));
+        assert!(error("root ::= [z-a]").contains("invalid range"));
+        assert!(error("root ::= \"a\"{3,1}").contains("invalid repetition"));
+        assert!(error("root ::= \"a").contains("unterminated string"));
+
+        // Control characters are escaped for Python
+        assert_eq!(
+            compile_cfg(r#"root ::= [\x00-\x1f\u007f]"#).unwrap(),
+            "start: /[\\x00-\\x1f\\x7f]/\n"
+        );
+    }
+
+    #[test]
+    fn test_lark() {
+        let lark = r#"
+// Arithmetic
+?start: sum
+?sum: product
+    | sum "+" product   -> add
+product: atom ("*" atom)*
+atom: NUMBER | "(" sum ")" | [SIGN] NAME
+SIGN: "+" | "-"
+NAME: /[a-z_]+/i
+DIGITS.2: "0".."9" ~ 1..3
+
+%import common.NUMBER
+%import common (WS, CR)
+%ignore WS
+"#;
+        assert_eq!(compile_cfg(lark).unwrap(), lark);
+
+        assert!(error("sum: NUMBER\n%import common.NUMBER").contains("`start`"));
+        assert!(error("start: value").contains("undefined `value`"));
+        assert!(error("start: A\nA: b\nb: \"b\"").contains("cannot reference the rule `b`"));
+        assert!(error("start: \"a\"\nstart: \"b\"").starts_with("line 2"));
+        assert!(error("start: /a(/").contains("invalid regular expression"));
+        assert!(error("start: /a(?=b)/").contains("invalid regular expression"));
+        assert!(error("start: \"ab\"..\"z\"").contains("single characters"));
+        assert!(error("start: \"a\"\n%ignore ws").contains("`%ignore`"));
+        assert!(error("start: \"a\"\n%override start: \"b\"").contains("%override"));
+    }
+
+    #[test]
+    fn test_lalr() {
+        // Shift/reduce conflicts are resolved by Lark, the dangling else shifts
+        let lark = "start: \"if\" start \"else\" start | \"if\" start | \"x\"";
+        assert!(compile_cfg(lark).is_ok());
+        // Higher priorities resolve reduce/reduce conflicts
+        assert!(compile_cfg("start: a | b\na.2: \"x\"\nb: \"x\"").is_ok());
+
+        assert!(error("start: a | b\na: \"x\"\nb: \"x\"").contains("not LALR(1)"));
+        // The anonymous `"x"` is the `X` terminal
+        assert!(error("start: a | b\na: X\nb: \"x\"\nX: \"x\"").contains("not LALR(1)"));
+        // Optional items are alternatives of their rule, identical ones are merged like Lark does
+        assert!(compile_cfg("start: a \";\"\na: \"x\" \",\"? \",\"?").is_ok());
+        // Converted GBNF grammars are checked too, both rules repeat the same digits
+        let gbnf = "root ::= int | float\nint ::= [0-9]+\nfloat ::= [0-9]+ \".\"?";
+        assert!(error(gbnf).contains("not LALR(1)"));
+
+        assert!(error("start: /a*/").contains("empty string"));
+        assert!(error("start: A\nA: \"a\"? B*\nB: \"b\"").contains("`A`"));
+        assert!(error("start: \"a\"\n%ignore /\\s*/").contains("`%ignore`"));
+        // Unused terminals are not built
+        assert!(compile_cfg("start: \"a\"\nA: /a*/").is_ok());
+    }
+}
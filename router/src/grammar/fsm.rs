@@ -0,0 +1,56 @@
+/// Byte automata of the regular grammars, built to bound the size of the automata of the shards
+use crate::validation::ValidationError;
+use regex_automata::dfa::{dense, StartKind};
+use regex_automata::nfa::thompson;
+use regex_automata::util::syntax;
+use regex_automata::MatchKind;
+
+/// Size limit, in bytes, of the byte automata of a regular expression. The token automata of the
+/// shards keep a subset of the states of the same expression, so this bounds them too.
+const MAX_AUTOMATON_SIZE: usize = 16 << 20;
+
+pub(crate) type Dfa = dense::DFA<Vec<u32>>;
+
+/// Check the syntax of a regular expression
+pub(crate) fn check_syntax(regex: &str) -> Result<(), ValidationError> {
+    syntax::parse(regex)
+        .map(|_| ())
+        .map_err(|err| ValidationError::InvalidGrammar(format!("invalid regex: {err}")))
+}
+
+/// Compile a regular expression to an automaton matching whole texts byte by byte
+pub(crate) fn build_dfa(regex: &str) -> Result<Dfa, ValidationError> {
+    check_syntax(regex)?;
+    dense::Builder::new()
+        .configure(
+            dense::Config::new()
+                .match_kind(MatchKind::All)
+                .start_kind(StartKind::Anchored)
+                .dfa_size_limit(Some(MAX_AUTOMATON_SIZE))
+                .determinize_size_limit(Some(MAX_AUTOMATON_SIZE)),
+        )
+        .thompson(thompson::Config::new().nfa_size_limit(Some(MAX_AUTOMATON_SIZE)))
+        // Without the end anchor, the automaton would keep reporting the match of a prefix
+        .build(&format!(r"(?:{regex})\z"))
+        // The syntax is valid, only the size limits are left
+        .map_err(|err| ValidationError::InvalidGrammar(format!("the grammar is too large: {err}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_dfa() {
+        assert!(matches!(
+            build_dfa("(a"),
+            Err(ValidationError::InvalidGrammar(err)) if err.starts_with("invalid regex")
+        ));
+        assert!(matches!(
+            build_dfa("[a-z]{1000}{1000}"),
+            Err(ValidationError::InvalidGrammar(err)) if err.contains("too large")
+        ));
+        // Hundreds of repetitions are fine
+        assert!(build_dfa(r#""[^"\\]{0,500}""#).is_ok());
+    }
+}
@@ -0,0 +1,421 @@
+/// JSON schemas to regular expressions, the same conversion as `build_regex_from_schema` of outlines
+///
+/// The shards still convert the schemas with outlines, the regular expression only bounds the size
+/// of their automaton.
+use crate::validation::ValidationError;
+use jsonschema::{Draft, JSONSchema};
+use serde_json::{Map, Value};
+
+const WHITESPACE: &str = r"[\n ]*";
+const STRING_INNER: &str = r#"(?:[^"\\\x00-\x1f\x7f-\x9f]|\\.)"#;
+const INTEGER: &str = r"(-)?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"((-)?(0|[1-9][0-9]*))(\.[0-9]+)?([eE][+-][0-9]+)?";
+const BOOLEAN: &str = r"(true|false)";
+const NULL: &str = r"null";
+const DATE_TIME: &str = r#""(-?(?:[1-9][0-9]*)?[0-9]{4})-(1[0-2]|0[1-9])-(3[01]|0[1-9]|[12][0-9])T(2[0-3]|[01][0-9]):([0-5][0-9]):([0-5][0-9])(\.[0-9]{3})?(Z)?""#;
+const DATE: &str = r#""(?:[0-9]{4})-(?:0[1-9]|1[0-2])-(?:0[1-9]|[1-2][0-9]|3[0-1])""#;
+const TIME: &str = r#""(2[0-3]|[01][0-9]):([0-5][0-9]):([0-5][0-9])(\.[0-9]+)?(Z)?""#;
+const UUID: &str =
+    r#""[a-fA-F0-9]{8}-[a-fA-F0-9]{4}-[a-fA-F0-9]{4}-[a-fA-F0-9]{4}-[a-fA-F0-9]{12}""#;
+
+/// Nesting limit of the schemas, recursive schemas have no regular expression
+const MAX_DEPTH: usize = 32;
+
+fn invalid(message: impl Into<String>) -> ValidationError {
+    ValidationError::InvalidGrammar(message.into())
+}
+
+/// Check a JSON schema, sent as an object or as a string
+pub(crate) fn parse_schema(schema: Value) -> Result<Value, ValidationError> {
+    let schema = match schema {
+        // if value is a string, we need to parse it again to make sure its
+        // a valid json
+        Value::String(s) => {
+            serde_json::from_str(&s).map_err(|e| ValidationError::InvalidGrammar(e.to_string()))
+        }
+        Value::Object(_) => Ok(schema),
+        _ => Err(ValidationError::Grammar),
+    }?;
+
+    // Check if the json is a valid JSONSchema
+    JSONSchema::options()
+        .with_draft(Draft::Draft202012)
+        .compile(&schema)
+        .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
+    Ok(schema)
+}
+
+/// Convert a JSON schema to the regular expression of the JSON values it accepts
+pub(crate) fn schema_to_regex(schema: &Value) -> Result<String, ValidationError> {
+    Converter { root: schema }.to_regex(schema, 0)
+}
+
+struct Converter<'a> {
+    /// Resolves the `$ref`
+    root: &'a Value,
+}
+
+impl Converter<'_> {
+    fn to_regex(&self, instance: &Value, depth: usize) -> Result<String, ValidationError> {
+        if depth > MAX_DEPTH {
+            return Err(invalid(format!(
+                "the schema is recursive or nested more than {MAX_DEPTH} times"
+            )));
+        }
+        let Some(instance) = instance.as_object() else {
+            return Err(invalid(format!("unsupported schema {instance}")));
+        };
+        let subschemas = |key: &str| -> Result<Vec<String>, ValidationError> {
+            instance
+                .get(key)
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid(format!("`{key}` must be an array")))?
+                .iter()
+                .map(|subschema| self.to_regex(subschema, depth + 1))
+                .collect()
+        };
+
+        if let Some(properties) = instance.get("properties").and_then(Value::as_object) {
+            let required = instance
+                .get("required")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            return self.properties(properties, required, depth);
+        }
+        if instance.contains_key("allOf") {
+            return Ok(format!("({})", subschemas("allOf")?.concat()));
+        }
+        if instance.contains_key("anyOf") {
+            return Ok(format!("({})", subschemas("anyOf")?.join("|")));
+        }
+        if instance.contains_key("oneOf") {
+            let patterns: Vec<String> = subschemas("oneOf")?
+                .into_iter()
+                .map(|pattern| format!("(?:{pattern})"))
+                .collect();
+            return Ok(format!("({})", patterns.join("|")));
+        }
+        if instance.contains_key("prefixItems") {
+            let separator = format!("{WHITESPACE},{WHITESPACE}");
+            let items = subschemas("prefixItems")?.join(&separator);
+            return Ok(format!(r"\[{WHITESPACE}{items}{WHITESPACE}\]"));
+        }
+        if let Some(choices) = instance.get("enum") {
+            let choices = choices
+                .as_array()
+                .ok_or_else(|| invalid("`enum` must be an array"))?
+                .iter()
+                .map(literal)
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(format!("({})", choices.join("|")));
+        }
+        if let Some(constant) = instance.get("const") {
+            return literal(constant);
+        }
+        if let Some(reference) = instance.get("$ref") {
+            let resolved = reference
+                .as_str()
+                .and_then(|reference| reference.strip_prefix('#'))
+                .and_then(|pointer| self.root.pointer(pointer))
+                .ok_or_else(|| invalid(format!("cannot resolve the reference {reference}")))?;
+            return self.to_regex(resolved, depth + 1);
+        }
+        match instance.get("type") {
+            Some(Value::String(instance_type)) => self.typed(instance_type, instance, depth),
+            // Objects are excluded, their properties are unknown
+            Some(Value::Array(types)) => {
+                let patterns = types
+                    .iter()
+                    .filter(|instance_type| instance_type.as_str() != Some("object"))
+                    .map(|instance_type| {
+                        self.to_regex(&serde_json::json!({ "type": instance_type }), depth + 1)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("({})", patterns.join("|")))
+            }
+            _ => Err(invalid(format!(
+                "unsupported schema {}",
+                Value::Object(instance.clone())
+            ))),
+        }
+    }
+
+    /// The properties are expected in their order, the optional ones can be skipped
+    fn properties(
+        &self,
+        properties: &Map<String, Value>,
+        required: &[Value],
+        depth: usize,
+    ) -> Result<String, ValidationError> {
+        let subregexes = properties
+            .iter()
+            .map(|(name, value)| {
+                Ok(format!(
+                    r#"{WHITESPACE}"{}"{WHITESPACE}:{WHITESPACE}{}"#,
+                    regex::escape(name),
+                    self.to_regex(value, depth + 1)?
+                ))
+            })
+            .collect::<Result<Vec<_>, ValidationError>>()?;
+        let is_required: Vec<bool> = properties
+            .keys()
+            .map(|name| required.iter().any(|required| required == name.as_str()))
+            .collect();
+
+        let mut regex = String::from(r"\{");
+        if let Some(last_required) = is_required.iter().rposition(|required| *required) {
+            // The properties before the last required one are followed by a comma, the ones
+            // after it are preceded by a comma
+            for (i, subregex) in subregexes.iter().enumerate() {
+                let subregex = match i.cmp(&last_required) {
+                    std::cmp::Ordering::Less => format!("{subregex}{WHITESPACE},"),
+                    std::cmp::Ordering::Equal => subregex.clone(),
+                    std::cmp::Ordering::Greater => format!("{WHITESPACE},{subregex}"),
+                };
+                if is_required[i] {
+                    regex.push_str(&subregex);
+                } else {
+                    regex.push_str(&format!("({subregex})?"));
+                }
+            }
+        } else {
+            // Without required properties, every property can be the last one
+            let patterns: Vec<String> = (0..subregexes.len())
+                .map(|i| {
+                    let mut pattern = String::new();
+                    for subregex in &subregexes[..i] {
+                        pattern.push_str(&format!("({subregex}{WHITESPACE},)?"));
+                    }
+                    pattern.push_str(&subregexes[i]);
+                    for subregex in &subregexes[i + 1..] {
+                        pattern.push_str(&format!("({WHITESPACE},{subregex})?"));
+                    }
+                    pattern
+                })
+                .collect();
+            regex.push_str(&format!("({})?", patterns.join("|")));
+        }
+        regex.push_str(&format!(r"{WHITESPACE}\}}"));
+        Ok(regex)
+    }
+
+    fn typed(
+        &self,
+        instance_type: &str,
+        instance: &Map<String, Value>,
+        depth: usize,
+    ) -> Result<String, ValidationError> {
+        let integer = |key: &str| -> Result<Option<u64>, ValidationError> {
+            instance
+                .get(key)
+                .map(|value| {
+                    value
+                        .as_u64()
+                        .ok_or_else(|| invalid(format!("`{key}` must be a positive integer")))
+                })
+                .transpose()
+        };
+
+        match instance_type {
+            "string" => {
+                let (min, max) = (integer("minLength")?, integer("maxLength")?);
+                if min.is_some() || max.is_some() {
+                    let min = min.unwrap_or(0);
+                    let max = max.map(|max| max.to_string()).unwrap_or_default();
+                    if max.parse().is_ok_and(|max: u64| max < min) {
+                        return Err(invalid("`maxLength` must be greater than `minLength`"));
+                    }
+                    return Ok(format!(r#""{STRING_INNER}{{{min},{max}}}""#));
+                }
+                if let Some(pattern) = instance.get("pattern").and_then(Value::as_str) {
+                    let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+                    let pattern = pattern.strip_suffix('$').unwrap_or(pattern);
+                    return Ok(format!(r#"("{pattern}")"#));
+                }
+                match instance.get("format").and_then(Value::as_str) {
+                    None => Ok(format!(r#""{STRING_INNER}*""#)),
+                    Some("date-time") => Ok(DATE_TIME.to_string()),
+                    Some("date") => Ok(DATE.to_string()),
+                    Some("time") => Ok(TIME.to_string()),
+                    Some("uuid") => Ok(UUID.to_string()),
+                    Some(format) => Err(invalid(format!("unsupported string format `{format}`"))),
+                }
+            }
+            "number" => Ok(NUMBER.to_string()),
+            "integer" => Ok(INTEGER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            "array" => {
+                let Some(repeats) = repeats(integer("minItems")?, integer("maxItems")?) else {
+                    return Ok(format!(r"\[{WHITESPACE}\]"));
+                };
+                let allow_empty = if integer("minItems")?.unwrap_or(0) == 0 {
+                    "?"
+                } else {
+                    ""
+                };
+                let items = match instance.get("items") {
+                    Some(items) => self.to_regex(items, depth + 1)?,
+                    // Arrays of objects are excluded when the items are unknown
+                    None => [INTEGER, NUMBER, BOOLEAN, NULL]
+                        .iter()
+                        .map(|pattern| pattern.to_string())
+                        .chain([format!(r#""{STRING_INNER}*""#)])
+                        .collect::<Vec<_>>()
+                        .join("|"),
+                };
+                Ok(format!(
+                    r"\[{WHITESPACE}(({items})(,{WHITESPACE}({items})){repeats}){allow_empty}{WHITESPACE}\]"
+                ))
+            }
+            "object" => {
+                let Some(repeats) = repeats(integer("minProperties")?, integer("maxProperties")?)
+                else {
+                    return Ok(format!(r"\{{{WHITESPACE}\}}"));
+                };
+                let allow_empty = if integer("minProperties")?.unwrap_or(0) == 0 {
+                    "?"
+                } else {
+                    ""
+                };
+                let value = match instance.get("additionalProperties") {
+                    Some(Value::Bool(false)) => return Ok(format!(r"\{{{WHITESPACE}\}}")),
+                    None | Some(Value::Bool(true)) => {
+                        // Unconstrained values, the objects are nested up to `depth` (a key
+                        // of outlines, 2 by default) to keep the regular expression finite
+                        let nesting = instance.get("depth").and_then(Value::as_u64).unwrap_or(2);
+                        let mut values = vec![
+                            serde_json::json!({"type": "string"}),
+                            serde_json::json!({"type": "number"}),
+                            serde_json::json!({"type": "boolean"}),
+                            serde_json::json!({"type": "null"}),
+                        ];
+                        if nesting > 0 {
+                            values
+                                .push(serde_json::json!({"type": "object", "depth": nesting - 1}));
+                        }
+                        self.to_regex(&serde_json::json!({ "anyOf": values }), depth + 1)?
+                    }
+                    Some(schema) => self.to_regex(schema, depth + 1)?,
+                };
+                let key_value = format!(r#""{STRING_INNER}*"{WHITESPACE}:{WHITESPACE}{value}"#);
+                Ok(format!(
+                    r"\{{{WHITESPACE}({key_value}({WHITESPACE},{WHITESPACE}{key_value}){repeats}){allow_empty}{WHITESPACE}\}}"
+                ))
+            }
+            _ => Err(invalid(format!("unsupported type `{instance_type}`"))),
+        }
+    }
+}
+
+/// Repetitions of the items after the first one, `None` when there can be no item
+fn repeats(min: Option<u64>, max: Option<u64>) -> Option<String> {
+    let min = min.unwrap_or(0).saturating_sub(1);
+    match max {
+        None => Some(format!("{{{min},}}")),
+        Some(0) => None,
+        Some(max) => Some(format!("{{{min},{}}}", max - 1)),
+    }
+}
+
+/// Escaped JSON of a scalar value
+fn literal(value: &Value) -> Result<String, ValidationError> {
+    match value {
+        Value::Array(_) | Value::Object(_) => Err(invalid(format!(
+            "unsupported value {value} in `enum` or `const`"
+        ))),
+        _ => Ok(regex::escape(&value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use serde_json::json;
+
+    fn matcher(schema: Value) -> Regex {
+        let regex = schema_to_regex(&schema).unwrap();
+        Regex::new(&format!("^(?:{regex})$")).unwrap()
+    }
+
+    #[test]
+    fn test_properties() {
+        let regex = matcher(json!({
+            "properties": {
+                "name": {"type": "string", "maxLength": 3},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", 1.5, null]}, "maxItems": 2}
+            },
+            "required": ["name"]
+        }));
+        assert!(regex.is_match(r#"{"name": "bob"}"#));
+        assert!(regex.is_match(r#"{"age": 3, "name": "bob", "tags": ["a", null]}"#));
+        assert!(regex.is_match("{\n  \"age\": -3,\n  \"name\": \"\"\n}"));
+        assert!(!regex.is_match(r#"{"age": 3}"#));
+        assert!(!regex.is_match(r#"{"name": "bobby"}"#));
+        assert!(!regex.is_match(r#"{"name": "bob", "age": 3}"#));
+        assert!(!regex.is_match(r#"{"name": "bob", "tags": ["a", 1.5, null]}"#));
+
+        // Without required properties, any subset in order
+        let regex = matcher(json!({
+            "properties": {"a": {"type": "boolean"}, "b": {"const": "x"}}
+        }));
+        assert!(regex.is_match("{}"));
+        assert!(regex.is_match(r#"{"b": "x"}"#));
+        assert!(regex.is_match(r#"{"a": true, "b": "x"}"#));
+        assert!(!regex.is_match(r#"{"b": "x", "a": true}"#));
+    }
+
+    #[test]
+    fn test_references() {
+        let regex = matcher(json!({
+            "$defs": {"point": {
+                "properties": {"x": {"type": "number"}, "y": {"type": "number"}},
+                "required": ["x", "y"]
+            }},
+            "properties": {
+                "at": {"$ref": "#/$defs/point"},
+                "when": {"anyOf": [{"type": "string", "format": "date"}, {"type": "null"}]}
+            },
+            "required": ["at", "when"]
+        }));
+        assert!(regex.is_match(r#"{"at": {"x": 1.5, "y": -2e+10}, "when": "2024-02-29"}"#));
+        assert!(regex.is_match(r#"{"at": {"x": 0, "y": 1}, "when": null}"#));
+        assert!(!regex.is_match(r#"{"at": {"x": 0}, "when": null}"#));
+
+        let recursive = json!({
+            "$defs": {"node": {"properties": {"next": {"$ref": "#/$defs/node"}}}},
+            "properties": {"head": {"$ref": "#/$defs/node"}}
+        });
+        assert!(matches!(
+            schema_to_regex(&recursive),
+            Err(ValidationError::InvalidGrammar(err)) if err.contains("recursive")
+        ));
+        let unsupported = json!({"properties": {"a": {"type": "string", "format": "email"}}});
+        assert!(matches!(
+            schema_to_regex(&unsupported),
+            Err(ValidationError::InvalidGrammar(err)) if err.contains("`email`")
+        ));
+    }
+
+    #[test]
+    fn test_roots() {
+        let regex = matcher(json!({"type": "object"}));
+        assert!(regex.is_match(r#"{"a": 1, "b": {"c": null}}"#));
+        assert!(!regex.is_match("[1]"));
+
+        let regex = matcher(json!({"type": "array", "items": {"type": "integer"}}));
+        assert!(regex.is_match("[1, 2, 3]"));
+        assert!(!regex.is_match(r#"{"a": 1}"#));
+
+        let regex = matcher(json!({
+            "$defs": {"name": {"type": "string"}},
+            "anyOf": [{"$ref": "#/$defs/name"}, {"type": "null"}]
+        }));
+        assert!(regex.is_match(r#""bob""#));
+        assert!(regex.is_match("null"));
+        assert!(!regex.is_match("1"));
+    }
+}
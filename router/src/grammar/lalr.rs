@@ -0,0 +1,581 @@
+/// LALR(1) check of Lark grammars
+///
+/// The shards build a Lark parser with `parser="lalr"` for every `cfg` request, while building the
+/// batch. Lark refuses reduce/reduce conflicts and terminals matching the empty string, which would
+/// fail the whole batch, so both are looked for here. The rules are expanded to BNF the way Lark
+/// does: optional items become alternatives of their rule and `*` or `+` repetitions become
+/// recursive rules, shared by identical repeated items. Shift/reduce conflicts are resolved by Lark
+/// and accepted.
+use super::cfg::{is_terminal, Expr, LarkGrammar};
+use crate::validation::ValidationError;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+/// Lark expands bounded repetitions to alternatives below this bound, and to extra rules above
+const REPEAT_BREAK_THRESHOLD: u32 = 50;
+/// Alternatives of a single rule after expansion
+const MAX_ALTERNATIVES: usize = 4096;
+/// States of the LR(0) automaton
+const MAX_STATES: usize = 8192;
+
+/// The end of the input, looked ahead by the final reduction
+const END: usize = 0;
+/// Placeholder lookahead used to find the lookaheads propagated between states
+const PROPAGATED: usize = usize::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Symbol {
+    Terminal(usize),
+    Rule(usize),
+}
+
+/// A production and the position of the dot in it
+type Item = (usize, usize);
+
+struct Production {
+    rule: usize,
+    symbols: Vec<Symbol>,
+}
+
+/// Check that the shards can build the LALR(1) parser and the lexer of a grammar
+pub(super) fn check_lalr(grammar: &LarkGrammar) -> Result<(), ValidationError> {
+    let mut bnf = Bnf::new(grammar);
+    let Some(()) = bnf.expand_rules()? else {
+        // Too large to be analyzed, any error is left to the shards
+        return Ok(());
+    };
+    bnf.check_terminals()?;
+    let Some(automaton) = Automaton::new(&bnf) else {
+        return Ok(());
+    };
+    automaton.check_reductions(&bnf)
+}
+
+/// Grammar expanded to plain productions
+struct Bnf<'a> {
+    grammar: &'a LarkGrammar,
+    rules: Vec<String>,
+    rule_ids: HashMap<String, usize>,
+    /// Anonymous literals and regular expressions are named by their source, like `"+"` or `/a/`
+    terminals: Vec<String>,
+    terminal_ids: HashMap<String, usize>,
+    /// Named terminals defined by a single literal or regular expression, which Lark reuses for the
+    /// identical anonymous ones
+    named_patterns: HashMap<String, String>,
+    /// Recursive rules of the repeated items, keyed by the item
+    repetitions: HashMap<String, usize>,
+    productions: Vec<Production>,
+    /// Productions of each rule
+    alternatives: Vec<Vec<usize>>,
+}
+
+impl<'a> Bnf<'a> {
+    fn new(grammar: &'a LarkGrammar) -> Self {
+        let named_patterns = grammar
+            .rules
+            .iter()
+            .filter(|(name, _)| is_terminal(name))
+            .filter_map(|(name, expr)| Some((pattern(expr)?, name.clone())))
+            .collect();
+        let mut bnf = Self {
+            grammar,
+            rules: Vec::new(),
+            rule_ids: HashMap::new(),
+            terminals: Vec::new(),
+            terminal_ids: HashMap::new(),
+            named_patterns,
+            repetitions: HashMap::new(),
+            productions: Vec::new(),
+            alternatives: Vec::new(),
+        };
+        bnf.terminal("$END".to_string());
+        // The augmented rule `$root: start`, whose reduction accepts the input
+        let root = bnf.new_rule("$root".to_string());
+        let start = bnf.rule("start");
+        bnf.add_production(root, vec![Symbol::Rule(start)]);
+        bnf
+    }
+
+    fn terminal(&mut self, name: String) -> Symbol {
+        let name = self.named_patterns.get(&name).cloned().unwrap_or(name);
+        let id = match self.terminal_ids.get(&name) {
+            Some(id) => *id,
+            None => {
+                self.terminals.push(name.clone());
+                self.terminal_ids.insert(name, self.terminals.len() - 1);
+                self.terminals.len() - 1
+            }
+        };
+        Symbol::Terminal(id)
+    }
+
+    fn new_rule(&mut self, name: String) -> usize {
+        self.rules.push(name.clone());
+        self.alternatives.push(Vec::new());
+        self.rule_ids.insert(name, self.rules.len() - 1);
+        self.rules.len() - 1
+    }
+
+    fn rule(&mut self, name: &str) -> usize {
+        match self.rule_ids.get(name) {
+            Some(id) => *id,
+            None => self.new_rule(name.to_string()),
+        }
+    }
+
+    fn add_production(&mut self, rule: usize, symbols: Vec<Symbol>) {
+        self.productions.push(Production { rule, symbols });
+        self.alternatives[rule].push(self.productions.len() - 1);
+    }
+
+    /// Expand the rules reachable from `start`, `None` when they are too large
+    fn expand_rules(&mut self) -> Result<Option<()>, ValidationError> {
+        let mut expanded = 1;
+        while expanded < self.rules.len() {
+            let name = self.rules[expanded].clone();
+            let rule = expanded;
+            expanded += 1;
+            // Repetitions get their productions when created
+            let Some(expr) = self.grammar.rules.get(&name) else {
+                continue;
+            };
+            let Some(alternatives) = self.expand(expr)? else {
+                return Ok(None);
+            };
+            for symbols in alternatives {
+                self.add_production(rule, symbols);
+            }
+        }
+        Ok(Some(()))
+    }
+
+    /// Alternatives of an expression, without duplicates
+    fn expand(&mut self, expr: &Expr) -> Result<Option<Vec<Vec<Symbol>>>, ValidationError> {
+        let alternatives = match expr {
+            Expr::Literal(string) => vec![vec![self.terminal(format!("{string:?}"))]],
+            Expr::Regex(regex) => {
+                let name = format!("/{regex}/");
+                if matches_empty(regex) {
+                    return Err(empty_terminal(&name));
+                }
+                vec![vec![self.terminal(name)]]
+            }
+            Expr::Ref(name) if !is_terminal(name) && self.grammar.rules.contains_key(name) => {
+                vec![vec![Symbol::Rule(self.rule(name))]]
+            }
+            // Named, imported and declared terminals
+            Expr::Ref(name) => vec![vec![self.terminal(name.clone())]],
+            Expr::Seq(items) => {
+                let mut sequences = vec![Vec::new()];
+                for item in items {
+                    let Some(alternatives) = self.expand(item)? else {
+                        return Ok(None);
+                    };
+                    sequences = product(&sequences, &alternatives);
+                    if sequences.len() > MAX_ALTERNATIVES {
+                        return Ok(None);
+                    }
+                }
+                sequences
+            }
+            Expr::Alt(items) => {
+                let mut alternatives = Vec::new();
+                for item in items {
+                    let Some(expanded) = self.expand(item)? else {
+                        return Ok(None);
+                    };
+                    alternatives.extend(expanded);
+                }
+                alternatives
+            }
+            // `*` and `+`, `~` always has an upper bound
+            Expr::Repeat(item, min, None) => {
+                let Some(rule) = self.repetition(item)? else {
+                    return Ok(None);
+                };
+                let mut alternatives = vec![vec![Symbol::Rule(rule)]];
+                if *min == 0 {
+                    alternatives.push(Vec::new());
+                }
+                alternatives
+            }
+            Expr::Repeat(_, _, Some(max)) if *max >= REPEAT_BREAK_THRESHOLD => return Ok(None),
+            Expr::Repeat(item, min, Some(max)) => {
+                let Some(once) = self.expand(item)? else {
+                    return Ok(None);
+                };
+                let mut alternatives = Vec::new();
+                let mut repeated = vec![Vec::new()];
+                for count in 0..=*max {
+                    if count >= *min {
+                        alternatives.extend(repeated.iter().cloned());
+                    }
+                    repeated = product(&repeated, &once);
+                    if alternatives.len() + repeated.len() > MAX_ALTERNATIVES {
+                        return Ok(None);
+                    }
+                }
+                alternatives
+            }
+        };
+        let mut seen = HashSet::new();
+        Ok(Some(
+            alternatives
+                .into_iter()
+                .filter(|symbols| seen.insert(symbols.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Recursive rule `rule: item | rule item` of a repeated item
+    fn repetition(&mut self, item: &Expr) -> Result<Option<usize>, ValidationError> {
+        let key = format!("{item:?}");
+        if let Some(rule) = self.repetitions.get(&key) {
+            return Ok(Some(*rule));
+        }
+        let Some(once) = self.expand(item)? else {
+            return Ok(None);
+        };
+        let rule = self.new_rule(format!("__repeat_{}", self.repetitions.len()));
+        self.repetitions.insert(key, rule);
+        for symbols in &once {
+            self.add_production(rule, symbols.clone());
+        }
+        for symbols in product(&[vec![Symbol::Rule(rule)]], &once) {
+            self.add_production(rule, symbols);
+        }
+        Ok(Some(rule))
+    }
+
+    /// Lark refuses the named terminals matching the empty string, when they are used
+    fn check_terminals(&self) -> Result<(), ValidationError> {
+        for name in &self.terminals {
+            if let Some(expr) = self.grammar.rules.get(name) {
+                if self.terminal_matches_empty(expr, &mut HashSet::new()) {
+                    return Err(empty_terminal(name));
+                }
+            }
+        }
+        for expr in &self.grammar.ignored {
+            if self.terminal_matches_empty(expr, &mut HashSet::new()) {
+                return Err(empty_terminal("%ignore"));
+            }
+        }
+        Ok(())
+    }
+
+    fn terminal_matches_empty<'b>(&'b self, expr: &'b Expr, seen: &mut HashSet<&'b str>) -> bool {
+        match expr {
+            Expr::Literal(string) => string.is_empty(),
+            Expr::Regex(regex) => matches_empty(regex),
+            // Recursive terminals are refused by Lark anyway
+            Expr::Ref(name) => match self.grammar.rules.get(name) {
+                Some(expr) if seen.insert(name) => self.terminal_matches_empty(expr, seen),
+                _ => false,
+            },
+            Expr::Seq(items) => items
+                .iter()
+                .all(|item| self.terminal_matches_empty(item, seen)),
+            Expr::Alt(items) => items
+                .iter()
+                .any(|item| self.terminal_matches_empty(item, seen)),
+            Expr::Repeat(item, min, max) => {
+                *min == 0 || *max == Some(0) || self.terminal_matches_empty(item, seen)
+            }
+        }
+    }
+
+    fn display(&self, production: usize) -> String {
+        let production = &self.productions[production];
+        let mut display = format!("{}:", self.rules[production.rule]);
+        for symbol in &production.symbols {
+            display.push(' ');
+            match symbol {
+                Symbol::Terminal(terminal) => display.push_str(&self.terminals[*terminal]),
+                Symbol::Rule(rule) => display.push_str(&self.rules[*rule]),
+            }
+        }
+        display
+    }
+}
+
+/// LR(0) automaton with the LALR(1) lookaheads of its kernel items
+struct Automaton {
+    kernels: Vec<Vec<Item>>,
+    transitions: Vec<HashMap<Symbol, usize>>,
+    lookaheads: HashMap<(usize, Item), BTreeSet<usize>>,
+    nullable: Vec<bool>,
+    first: Vec<BTreeSet<usize>>,
+}
+
+impl Automaton {
+    /// Build the automaton, `None` when it has too many states
+    fn new(bnf: &Bnf) -> Option<Self> {
+        let (nullable, first) = first_sets(bnf);
+        let mut automaton = Self {
+            kernels: vec![vec![(0, 0)]],
+            transitions: Vec::new(),
+            lookaheads: HashMap::new(),
+            nullable,
+            first,
+        };
+
+        let mut states = HashMap::from([(vec![(0, 0)], 0)]);
+        let mut state = 0;
+        while state < automaton.kernels.len() {
+            let mut gotos: BTreeMap<Symbol, Vec<Item>> = BTreeMap::new();
+            for (production, dot) in closure(bnf, &automaton.kernels[state]) {
+                if let Some(symbol) = bnf.productions[production].symbols.get(dot) {
+                    gotos
+                        .entry(*symbol)
+                        .or_default()
+                        .push((production, dot + 1));
+                }
+            }
+            let mut transitions = HashMap::new();
+            for (symbol, mut kernel) in gotos {
+                kernel.sort_unstable();
+                let next = match states.get(&kernel) {
+                    Some(next) => *next,
+                    None => {
+                        if automaton.kernels.len() >= MAX_STATES {
+                            return None;
+                        }
+                        states.insert(kernel.clone(), automaton.kernels.len());
+                        automaton.kernels.push(kernel);
+                        automaton.kernels.len() - 1
+                    }
+                };
+                transitions.insert(symbol, next);
+            }
+            automaton.transitions.push(transitions);
+            state += 1;
+        }
+
+        automaton.propagate_lookaheads(bnf);
+        Some(automaton)
+    }
+
+    /// Lookaheads of the kernel items, spontaneous or propagated from the previous states
+    fn propagate_lookaheads(&mut self, bnf: &Bnf) {
+        let mut propagations: HashMap<(usize, Item), Vec<(usize, Item)>> = HashMap::new();
+        self.lookaheads.insert((0, (0, 0)), BTreeSet::from([END]));
+        for state in 0..self.kernels.len() {
+            for kernel in self.kernels[state].clone() {
+                let items = self.closure(bnf, vec![(kernel, BTreeSet::from([PROPAGATED]))]);
+                for ((production, dot), lookaheads) in items {
+                    let Some(symbol) = bnf.productions[production].symbols.get(dot) else {
+                        continue;
+                    };
+                    let target = (self.transitions[state][symbol], (production, dot + 1));
+                    for lookahead in lookaheads {
+                        if lookahead == PROPAGATED {
+                            propagations
+                                .entry((state, kernel))
+                                .or_default()
+                                .push(target);
+                        } else {
+                            self.lookaheads.entry(target).or_default().insert(lookahead);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut queue: VecDeque<_> = self.lookaheads.keys().copied().collect();
+        while let Some(source) = queue.pop_front() {
+            let Some(targets) = propagations.get(&source) else {
+                continue;
+            };
+            let lookaheads = self.lookaheads[&source].clone();
+            for target in targets {
+                let entry = self.lookaheads.entry(*target).or_default();
+                let len = entry.len();
+                entry.extend(&lookaheads);
+                if entry.len() > len {
+                    queue.push_back(*target);
+                }
+            }
+        }
+    }
+
+    /// LR(1) closure of items with their lookaheads
+    fn closure(
+        &self,
+        bnf: &Bnf,
+        items: Vec<(Item, BTreeSet<usize>)>,
+    ) -> BTreeMap<Item, BTreeSet<usize>> {
+        let mut queue: VecDeque<Item> = items.iter().map(|(item, _)| *item).collect();
+        let mut closure: BTreeMap<Item, BTreeSet<usize>> = items.into_iter().collect();
+        while let Some((production, dot)) = queue.pop_front() {
+            let symbols = &bnf.productions[production].symbols;
+            let Some(Symbol::Rule(rule)) = symbols.get(dot) else {
+                continue;
+            };
+            let mut lookaheads = BTreeSet::new();
+            let mut nullable = true;
+            for symbol in &symbols[dot + 1..] {
+                match symbol {
+                    Symbol::Terminal(terminal) => {
+                        lookaheads.insert(*terminal);
+                        nullable = false;
+                    }
+                    Symbol::Rule(rule) => {
+                        lookaheads.extend(&self.first[*rule]);
+                        nullable = self.nullable[*rule];
+                    }
+                }
+                if !nullable {
+                    break;
+                }
+            }
+            if nullable {
+                lookaheads.extend(&closure[&(production, dot)]);
+            }
+            for alternative in &bnf.alternatives[*rule] {
+                let added = !closure.contains_key(&(*alternative, 0));
+                let entry = closure.entry((*alternative, 0)).or_default();
+                let len = entry.len();
+                entry.extend(&lookaheads);
+                if added || entry.len() > len {
+                    queue.push_back((*alternative, 0));
+                }
+            }
+        }
+        closure
+    }
+
+    /// Lark refuses two reductions on the same lookahead, unless a rule has a higher priority
+    fn check_reductions(&self, bnf: &Bnf) -> Result<(), ValidationError> {
+        for (state, kernel) in self.kernels.iter().enumerate() {
+            let items = kernel
+                .iter()
+                .map(|item| {
+                    let lookaheads = self.lookaheads.get(&(state, *item)).cloned();
+                    (*item, lookaheads.unwrap_or_default())
+                })
+                .collect();
+            let mut reductions: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+            for ((production, dot), lookaheads) in self.closure(bnf, items) {
+                if production != 0 && dot == bnf.productions[production].symbols.len() {
+                    for lookahead in lookaheads {
+                        reductions.entry(lookahead).or_default().insert(production);
+                    }
+                }
+            }
+            for (lookahead, productions) in reductions {
+                if productions.len() < 2 {
+                    continue;
+                }
+                let mut priorities: Vec<_> = productions
+                    .iter()
+                    .map(|production| {
+                        let rule = &bnf.rules[bnf.productions[*production].rule];
+                        (
+                            bnf.grammar.priorities.get(rule).copied().unwrap_or(0),
+                            *production,
+                        )
+                    })
+                    .collect();
+                priorities.sort_by(|a, b| b.0.cmp(&a.0));
+                if priorities[0].0 > priorities[1].0 {
+                    continue;
+                }
+                return Err(ValidationError::InvalidGrammar(format!(
+                    "`{}` and `{}` can both be reduced before `{}`, the grammar is not LALR(1)",
+                    bnf.display(priorities[0].1),
+                    bnf.display(priorities[1].1),
+                    bnf.terminals[lookahead],
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// LR(0) closure of the kernel of a state
+fn closure(bnf: &Bnf, kernel: &[Item]) -> Vec<Item> {
+    let mut items = kernel.to_vec();
+    let mut closed = HashSet::new();
+    let mut i = 0;
+    while i < items.len() {
+        let (production, dot) = items[i];
+        if let Some(Symbol::Rule(rule)) = bnf.productions[production].symbols.get(dot) {
+            if closed.insert(*rule) {
+                items.extend(
+                    bnf.alternatives[*rule]
+                        .iter()
+                        .map(|alternative| (*alternative, 0)),
+                );
+            }
+        }
+        i += 1;
+    }
+    items
+}
+
+/// Whether each rule can be empty, and the terminals it can start with
+fn first_sets(bnf: &Bnf) -> (Vec<bool>, Vec<BTreeSet<usize>>) {
+    let mut nullable = vec![false; bnf.rules.len()];
+    let mut first = vec![BTreeSet::new(); bnf.rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for production in &bnf.productions {
+            let mut terminals = BTreeSet::new();
+            let mut empty = true;
+            for symbol in &production.symbols {
+                match symbol {
+                    Symbol::Terminal(terminal) => {
+                        terminals.insert(*terminal);
+                        empty = false;
+                    }
+                    Symbol::Rule(rule) => {
+                        terminals.extend(&first[*rule]);
+                        empty = nullable[*rule];
+                    }
+                }
+                if !empty {
+                    break;
+                }
+            }
+            let len = first[production.rule].len();
+            first[production.rule].extend(terminals);
+            if first[production.rule].len() > len || (empty && !nullable[production.rule]) {
+                nullable[production.rule] |= empty;
+                changed = true;
+            }
+        }
+    }
+    (nullable, first)
+}
+
+/// Source of the terminals defined by a single literal or regular expression
+fn pattern(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Literal(string) => Some(format!("{string:?}")),
+        Expr::Regex(regex) => Some(format!("/{regex}/")),
+        _ => None,
+    }
+}
+
+fn product(prefixes: &[Vec<Symbol>], suffixes: &[Vec<Symbol>]) -> Vec<Vec<Symbol>> {
+    prefixes
+        .iter()
+        .flat_map(|prefix| {
+            suffixes
+                .iter()
+                .map(move |suffix| prefix.iter().chain(suffix).copied().collect())
+        })
+        .collect()
+}
+
+fn matches_empty(regex: &str) -> bool {
+    Regex::new(&format!("^(?:{regex})$")).is_ok_and(|regex| regex.is_match(""))
+}
+
+fn empty_terminal(name: &str) -> ValidationError {
+    ValidationError::InvalidGrammar(format!(
+        "`{name}` matches the empty string, Lark terminals cannot"
+    ))
+}
@@ -0,0 +1,222 @@
+/// Grammars constraining the generation
+///
+/// Grammars are checked here before being forwarded to the shards: JSON schemas as they are,
+/// choices as regular expressions and context-free grammars in Lark syntax. The byte automata of
+/// the regular expressions are built to bound the size of the automata of the shards, JSON schemas
+/// being converted to a regular expression for that purpose only. Results are cached, rejections
+/// included.
+mod cfg;
+mod fsm;
+mod json;
+mod lalr;
+
+use crate::validation::{ValidGrammar, ValidationError};
+use crate::GrammarType;
+use json::{parse_schema, schema_to_regex};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// Same number of grammars as the FSM cache of the shards
+const CACHE_SIZE: usize = 32;
+/// Grammars compiled at the same time, each on a blocking thread
+const MAX_CONCURRENT_COMPILATIONS: usize = 4;
+
+#[derive(Clone, Debug)]
+pub(crate) struct GrammarCompiler {
+    /// Compiled grammars or the message of their rejection, keyed by their serialized request
+    cache: Arc<Mutex<LruCache<String, Result<ValidGrammar, String>>>>,
+    compilations: Arc<Semaphore>,
+}
+
+impl GrammarCompiler {
+    pub(crate) fn new() -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(CACHE_SIZE).unwrap(),
+            ))),
+            compilations: Arc::new(Semaphore::new(MAX_CONCURRENT_COMPILATIONS)),
+        }
+    }
+
+    /// Validate a grammar and compile it for the shards
+    pub(crate) async fn compile(
+        &self,
+        grammar: GrammarType,
+    ) -> Result<ValidGrammar, ValidationError> {
+        let key = serde_json::to_string(&grammar)
+            .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+        if let Some(compiled) = self.cached(&key) {
+            return compiled;
+        }
+
+        let _permit = self
+            .compilations
+            .acquire()
+            .await
+            .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+        // The same grammar may have been compiled while waiting
+        if let Some(compiled) = self.cached(&key) {
+            return compiled;
+        }
+        // Building the automata is CPU bound
+        let compiled = tokio::task::spawn_blocking(move || compile(grammar))
+            .await
+            .map_err(|err| ValidationError::InvalidGrammar(err.to_string()))?;
+        match &compiled {
+            Ok(grammar) => self.cache_result(key, Ok(grammar.clone())),
+            Err(ValidationError::InvalidGrammar(err)) => self.cache_result(key, Err(err.clone())),
+            Err(_) => {}
+        }
+        compiled
+    }
+
+    fn cached(&self, key: &str) -> Option<Result<ValidGrammar, ValidationError>> {
+        let mut cache = self.cache.lock().unwrap();
+        let compiled = cache.get(key)?.clone();
+        Some(compiled.map_err(ValidationError::InvalidGrammar))
+    }
+
+    fn cache_result(&self, key: String, compiled: Result<ValidGrammar, String>) {
+        self.cache.lock().unwrap().put(key, compiled);
+    }
+}
+
+fn compile(grammar: GrammarType) -> Result<ValidGrammar, ValidationError> {
+    match grammar {
+        GrammarType::Json(json) => {
+            let schema = parse_schema(json)?;
+            // Schemas the conversion does not support are left to outlines, unchecked
+            if let Ok(regex) = schema_to_regex(&schema) {
+                fsm::build_dfa(&regex)?;
+            }
+            // Serialize json to string
+            Ok(ValidGrammar::Json(serde_json::to_string(&schema).map_err(
+                |e| ValidationError::InvalidGrammar(e.to_string()),
+            )?))
+        }
+        GrammarType::Regex(regex) => {
+            fsm::build_dfa(&regex)?;
+            Ok(ValidGrammar::Regex(regex))
+        }
+        GrammarType::Choice(choices) => {
+            let regex = choice_regex(&choices)?;
+            fsm::build_dfa(&regex)?;
+            Ok(ValidGrammar::Regex(regex))
+        }
+        GrammarType::Cfg(grammar) => Ok(ValidGrammar::Cfg(cfg::compile_cfg(&grammar)?)),
+    }
+}
+
+/// Compile the choices into an alternation of escaped literals
+fn choice_regex(choices: &[String]) -> Result<String, ValidationError> {
+    if choices.is_empty() {
+        return Err(ValidationError::InvalidGrammar(
+            "`choice` must contain at least one string".to_string(),
+        ));
+    }
+    let mut regex = String::from("(");
+    for (i, choice) in choices.iter().enumerate() {
+        if i > 0 {
+            regex.push('|');
+        }
+        for c in choice.chars() {
+            if r"\.+*?()|[]{}^$".contains(c) {
+                regex.push('\\');
+            }
+            regex.push(c);
+        }
+    }
+    regex.push(')');
+    Ok(regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_choice_regex() {
+        let choices = vec![
+            "yes".to_string(),
+            "no (maybe)".to_string(),
+            "1+1=2?".to_string(),
+        ];
+        let regex = choice_regex(&choices).unwrap();
+        assert_eq!(regex, r"(yes|no \(maybe\)|1\+1=2\?)");
+
+        let regex = Regex::new(&format!("^{regex}$")).unwrap();
+        for choice in &choices {
+            assert!(regex.is_match(choice));
+        }
+        assert!(!regex.is_match("no maybe"));
+        assert!(!regex.is_match("11=2"));
+
+        assert!(matches!(
+            choice_regex(&[]),
+            Err(ValidationError::InvalidGrammar(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_compile_cache() {
+        let compiler = GrammarCompiler::new();
+        for _ in 0..2 {
+            for regex in ["a", "(ab)+"] {
+                let grammar = compiler
+                    .compile(GrammarType::Regex(regex.to_string()))
+                    .await
+                    .unwrap();
+                assert!(matches!(grammar, ValidGrammar::Regex(compiled) if compiled == regex));
+            }
+            // Rejections are cached too
+            let err = compiler
+                .compile(GrammarType::Regex("[a-z]{1000}{1000}".to_string()))
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(err, ValidationError::InvalidGrammar(err) if err.contains("too large"))
+            );
+        }
+        assert_eq!(compiler.cache.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_compile_json() {
+        let compiler = GrammarCompiler::new();
+        // Forwarded to the shards as JSON, including the schemas the conversion does not support
+        for schema in [
+            json!({"properties": {"b": {"type": "string"}, "a": {"type": "integer"}}}),
+            json!({"properties": {"a": {"type": "string", "format": "email"}}}),
+        ] {
+            let grammar = compiler
+                .compile(GrammarType::Json(schema.clone()))
+                .await
+                .unwrap();
+            assert_eq!(grammar, ValidGrammar::Json(schema.to_string()));
+        }
+        let schema = json!({"type": "string"}).to_string();
+        let grammar = compiler
+            .compile(GrammarType::Json(Value::String(schema.clone())))
+            .await
+            .unwrap();
+        assert_eq!(grammar, ValidGrammar::Json(schema));
+
+        let too_large = json!({
+            "type": "array",
+            "items": {"type": "string", "maxLength": 1000},
+            "maxItems": 1000
+        });
+        assert!(matches!(
+            compiler.compile(GrammarType::Json(too_large)).await,
+            Err(ValidationError::InvalidGrammar(err)) if err.contains("too large")
+        ));
+        assert!(matches!(
+            compiler.compile(GrammarType::Json(json!(1))).await,
+            Err(ValidationError::Grammar)
+        ));
+    }
+}
@@ -1,5 +1,8 @@
 use crate::infer::InferError;
-use crate::{ChatTemplateInputs, Message, MessageChunk, TextMessage, TokenizerConfigToken, Tool};
+use crate::validation::ValidationError;
+use crate::{
+    ChatTemplateInputs, Document, Message, MessageChunk, TextMessage, TokenizerConfigToken, Tool,
+};
 use minijinja::{Environment, ErrorKind, Template};
 use minijinja_contrib::pycompat;
 use std::collections::HashSet;
@@ -15,6 +18,7 @@ pub(crate) struct ChatTemplate {
     bos_token: Option<String>,
     eos_token: Option<String>,
     use_default_tool_template: bool,
+    use_default_document_template: bool,
     variables: HashSet<String>,
 }
 
@@ -41,12 +45,15 @@ impl ChatTemplate {
         // check if the `tools` variable is used in the template
         let use_default_tool_template = !variables.contains("tools");
         tracing::debug!("Use default tool template: {}", use_default_tool_template);
+        // check if the `documents` variable is used in the template
+        let use_default_document_template = !variables.contains("documents");
 
         Self {
             template,
             bos_token: bos_token.map(|token| token.as_str().to_string()),
             eos_token: eos_token.map(|token| token.as_str().to_string()),
             use_default_tool_template,
+            use_default_document_template,
             variables,
         }
     }
@@ -54,14 +61,30 @@ impl ChatTemplate {
     pub(crate) fn apply(
         &self,
         guideline: Option<&str>,
+        documents: Option<Vec<Document>>,
         mut messages: Vec<Message>,
         tools_and_prompt: Option<(Vec<Tool>, String)>,
+        continue_final_message: bool,
     ) -> Result<String, InferError> {
         // check if guideline is expected but not provided
         if self.variables.contains("guideline") && guideline.is_none() {
             return Err(InferError::MissingTemplateVariable("guideline".to_string()));
         }
 
+        // if the `documents` variable is not used in the template, the documents are appended to
+        // the last user message
+        let documents = match documents {
+            Some(documents) if self.use_default_document_template => {
+                if let Some(last_message) = messages.iter_mut().rev().find(|m| m.role == "user") {
+                    last_message.content.push(MessageChunk::Text {
+                        text: default_document_template(&documents),
+                    });
+                }
+                None
+            }
+            documents => documents,
+        };
+
         let tools = match tools_and_prompt {
             Some((tools, tool_prompt)) => {
                 // check if the `tools` variable is used in the template
@@ -84,18 +107,66 @@ impl ChatTemplate {
         };
 
         let messages: Vec<TextMessage> = messages.into_iter().map(|c| c.into()).collect();
+        let final_message = match messages.last() {
+            Some(message) if message.role == "assistant" => Some(message.content.clone()),
+            _ if continue_final_message => {
+                return Err(InferError::ValidationError(
+                    ValidationError::ContinueFinalMessage(
+                        "requires the last message to be from the assistant",
+                    ),
+                ))
+            }
+            _ => None,
+        };
 
-        self.template
+        let rendered = self
+            .template
             .render(ChatTemplateInputs {
                 guideline,
                 messages,
                 bos_token: self.bos_token.as_deref(),
                 eos_token: self.eos_token.as_deref(),
-                add_generation_prompt: true,
+                add_generation_prompt: !continue_final_message,
                 tools,
+                documents,
             })
-            .map_err(InferError::TemplateError)
+            .map_err(InferError::TemplateError)?;
+
+        // the final message is left open by cutting what the template renders after it, as
+        // transformers does
+        match final_message.filter(|_| continue_final_message) {
+            Some(final_message) => {
+                let final_message = final_message.trim();
+                let end = rendered
+                    .rfind(final_message)
+                    .map(|start| start + final_message.len())
+                    .ok_or(InferError::ValidationError(
+                        ValidationError::ContinueFinalMessage(
+                            "is not supported by the chat template, the final message is not rendered verbatim",
+                        ),
+                    ))?;
+                Ok(rendered[..end].to_string())
+            }
+            None => Ok(rendered),
+        }
+    }
+}
+
+/// Render the documents for the templates that do not use them
+fn default_document_template(documents: &[Document]) -> String {
+    let mut text = String::from("\n---\nDocuments:");
+    for (i, document) in documents.iter().enumerate() {
+        text.push_str(&format!("\n\n[{}]", i + 1));
+        if let Some(title) = &document.title {
+            text.push_str(&format!(" {title}"));
+        }
+        if let Some(metadata) = &document.metadata {
+            text.push_str(&format!(" {metadata}"));
+        }
+        text.push('\n');
+        text.push_str(&document.text);
     }
+    text
 }
 
 // tests
@@ -104,7 +175,8 @@ mod tests {
     use crate::infer::chat_template::raise_exception;
     use crate::infer::ChatTemplate;
     use crate::{
-        ChatTemplateInputs, Message, MessageContent, TextMessage, TokenizerConfigToken, Tool,
+        ChatTemplateInputs, Document, Message, MessageContent, TextMessage, TokenizerConfigToken,
+        Tool,
     };
     use minijinja::Environment;
 
@@ -142,18 +214,22 @@ mod tests {
                 TextMessage {
                     role: "user".to_string(),
                     content: "Hi!".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "Hello how can I help?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "user".to_string(),
                     content: "What is Deep Learning?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "magic!".to_string(),
+                    reasoning_content: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -204,22 +280,27 @@ mod tests {
                 TextMessage {
                     role: "user".to_string(),
                     content: "Hi!".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "user".to_string(),
                     content: "Hi again!".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "Hello how can I help?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "user".to_string(),
                     content: "What is Deep Learning?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "magic!".to_string(),
+                    reasoning_content: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -275,18 +356,22 @@ mod tests {
                 TextMessage {
                     role: "user".to_string(),
                     content: "Hi!".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "Hello how can I help?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "user".to_string(),
                     content: "What is Deep Learning?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "magic!".to_string(),
+                    reasoning_content: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -326,18 +411,22 @@ mod tests {
                 TextMessage {
                     role: "user".to_string(),
                     content: "Hi!".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "Hello how can I help?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "user".to_string(),
                     content: "What is Deep Learning?".to_string(),
+                    reasoning_content: None,
                 },
                 TextMessage {
                     role: "assistant".to_string(),
                     content: "magic!".to_string(),
+                    reasoning_content: None,
                 },
             ],
             bos_token: Some("[BOS]"),
@@ -363,14 +452,17 @@ mod tests {
             TextMessage {
                 role: "user".to_string(),
                 content: "Hello, how are you?".to_string(),
+                reasoning_content: None,
             },
             TextMessage {
                 role: "assistant".to_string(),
                 content: "I'm doing great. How can I help you today?".to_string(),
+                reasoning_content: None,
             },
             TextMessage {
                 role: "user".to_string(),
                 content: "I'd like to show off how chat templating works!".to_string(),
+                reasoning_content: None,
             },
         ];
 
@@ -378,6 +470,7 @@ mod tests {
             role: "system".to_string(),
             content: "You are a friendly chatbot who always responds in the style of a pirate"
                 .to_string(),
+            reasoning_content: None,
         }]
         .iter()
         .chain(&example_chat)
@@ -520,10 +613,12 @@ mod tests {
                         TextMessage {
                             role: "system".to_string(),
                             content: "You are a friendly chatbot who always responds in the style of a pirate".to_string(),
+                            reasoning_content: None,
                         },
                         TextMessage {
                             role: "user".to_string(),
                             content: "How many helicopters can a human eat in one sitting?".to_string(),
+                            reasoning_content: None,
                         },
                     ],
                     add_generation_prompt: true,
@@ -825,7 +920,7 @@ mod tests {
             },
         ];
 
-        let result = ct.apply(None, msgs, None);
+        let result = ct.apply(None, None, msgs, None, false);
 
         match result {
             Ok(_) => panic!("Should have failed since no guideline is provided"),
@@ -867,7 +962,7 @@ mod tests {
         let tools: Vec<Tool> = serde_json::from_str(&tools_string).unwrap();
         let tool_prompt = "This default prompt will be used".to_string();
         let tools_and_prompt = Some((tools, tool_prompt));
-        let result = ct.apply(None, msgs, tools_and_prompt);
+        let result = ct.apply(None, None, msgs, tools_and_prompt, false);
         let expected = "<s>[INST] I'd like to show off how chat templating works! [/INST]Great! How can I help you today?</s> [INST] Just testing\n---\n[{\"type\":\"function\",\"function\":{\"description\":\"Get the current weather\",\"name\":\"get_current_weather\",\"arguments\":{\"properties\":{\"format\":{\"description\":\"The temperature unit to use. Infer this from the users location.\",\"enum\":[\"celsius\",\"fahrenheit\"],\"type\":\"string\"},\"location\":{\"description\":\"The city and state, e.g. San Francisco, CA\",\"type\":\"string\"}},\"required\":[\"location\",\"format\"],\"type\":\"object\"}}}]\nThis default prompt will be used [/INST]".to_string();
         assert_eq!(result.unwrap(), expected);
     }
@@ -901,8 +996,91 @@ mod tests {
         let tools: Vec<Tool> = serde_json::from_str(&tools_string).unwrap();
         let tool_prompt = "This default prompt will be used".to_string();
         let tools_and_prompt = Some((tools, tool_prompt));
-        let result = ct.apply(None, msgs, tools_and_prompt);
+        let result = ct.apply(None, None, msgs, tools_and_prompt, false);
         let expected = "<s><|start_header_id|>system<|end_header_id|>\n\nEnvironment: ipython\nCutting Knowledge Date: December 2023\nToday Date: 26 Jul 2024\n\nYoure a helpful assistant! Answer the users question best you can.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\nGiven the following functions, please respond with a JSON for a function call with its proper arguments that best answers the given prompt.\n\nRespond in the format {\"name\": function name, \"parameters\": dictionary of argument name and its value}.Do not use variables.\n\n{\n    \"function\": {\n        \"arguments\": {\n            \"properties\": {\n                \"format\": {\n                    \"description\": \"The temperature unit to use. Infer this from the users location.\",\n                    \"enum\": [\n                        \"celsius\",\n                        \"fahrenheit\"\n                    ],\n                    \"type\": \"string\"\n                },\n                \"location\": {\n                    \"description\": \"The city and state, e.g. San Francisco, CA\",\n                    \"type\": \"string\"\n                }\n            },\n            \"required\": [\n                \"location\",\n                \"format\"\n            ],\n            \"type\": \"object\"\n        },\n        \"description\": \"Get the current weather\",\n        \"name\": \"get_current_weather\"\n    },\n    \"type\": \"function\"\n}\n\nWhat is the weather like in Brooklyn, New York?\n---\nThis default prompt will be used<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n".to_string();
         assert_eq!(result.unwrap(), expected);
     }
+
+    #[test]
+    fn test_chat_template_with_documents() {
+        let documents = vec![
+            Document {
+                title: Some("Tall penguins".to_string()),
+                text: "Emperor penguins are the tallest.".to_string(),
+                metadata: None,
+            },
+            Document {
+                title: None,
+                text: "Emperor penguins only live in Antarctica.".to_string(),
+                metadata: Some(serde_json::json!({"source": "penguins.txt"})),
+            },
+        ];
+        let msgs = vec![Message {
+            name: None,
+            role: "user".to_string(),
+            content: MessageContent::SingleText("Where do the tallest penguins live?".to_string()),
+        }];
+
+        // simplified from the `rag` template of CohereForAI/c4ai-command-r-v01
+        let ct = ChatTemplate::new(
+            "{% for message in messages %}{{ message['content'] }}{% endfor %}{% for document in documents %}\nDocument: {{ loop.index0 }}\n{% for key, value in document.items() %}{{ key }}: {{ value }}\n{% endfor %}{% endfor %}".to_string(),
+            None,
+            None,
+        );
+        let result = ct.apply(None, Some(documents.clone()), msgs.clone(), None, false);
+        let expected = "Where do the tallest penguins live?\nDocument: 0\ntext: Emperor penguins are the tallest.\ntitle: Tall penguins\n\nDocument: 1\nmetadata: {\"source\": \"penguins.txt\"}\ntext: Emperor penguins only live in Antarctica.\n";
+        assert_eq!(result.unwrap(), expected);
+
+        // templates without `documents` get them in the last message
+        let ct = ChatTemplate::new(
+            "{% for message in messages %}[INST] {{ message['content'] }} [/INST]{% endfor %}"
+                .to_string(),
+            None,
+            None,
+        );
+        let result = ct.apply(None, Some(documents), msgs, None, false);
+        let expected = "[INST] Where do the tallest penguins live?\n---\nDocuments:\n\n[1] Tall penguins\nEmperor penguins are the tallest.\n\n[2] {\"source\":\"penguins.txt\"}\nEmperor penguins only live in Antarctica. [/INST]";
+        assert_eq!(result.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_chat_template_continue_final_message() {
+        let ct = ChatTemplate::new(
+            "{% for message in messages %}<|{{ message['role'] }}|>{{ message['content'] }}<|end|>{% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}".to_string(),
+            None,
+            None,
+        );
+        let mut msgs = vec![
+            Message {
+                name: None,
+                role: "user".to_string(),
+                content: MessageContent::SingleText("List three colors as JSON".to_string()),
+            },
+            Message {
+                name: None,
+                role: "assistant".to_string(),
+                content: MessageContent::SingleText("```json\n".to_string()),
+            },
+        ];
+
+        let result = ct.apply(None, None, msgs.clone(), None, true);
+        assert_eq!(
+            result.unwrap(),
+            "<|user|>List three colors as JSON<|end|><|assistant|>```json"
+        );
+
+        // the final message is closed without `continue_final_message`
+        let result = ct.apply(None, None, msgs.clone(), None, false);
+        assert_eq!(
+            result.unwrap(),
+            "<|user|>List three colors as JSON<|end|><|assistant|>```json\n<|end|><|assistant|>"
+        );
+
+        msgs.pop();
+        let result = ct.apply(None, None, msgs, None, true);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Input validation error: `continue_final_message` requires the last message to be from the assistant"
+        );
+    }
 }
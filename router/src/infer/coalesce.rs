@@ -0,0 +1,333 @@
+use crate::infer::{Backend, InferError, InferStreamResponse};
+use crate::validation::{Chunk, ValidGenerateRequest, ValidGrammar};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+type ResponseSender = mpsc::UnboundedSender<Result<InferStreamResponse, InferError>>;
+
+/// Deduplicates identical in-flight deterministic requests
+///
+/// When a greedy request is scheduled while an identical one is already being generated,
+/// the new caller is attached to the existing generation instead of being sent to the backend.
+/// Tokens are fanned out to every subscriber, and late subscribers are replayed the responses
+/// they missed.
+#[derive(Clone, Default)]
+pub(crate) struct Coalescer {
+    in_flight: Arc<Mutex<HashMap<CoalescingKey, InFlight>>>,
+}
+
+struct InFlight {
+    /// Responses already sent, replayed to late subscribers
+    history: Vec<InferStreamResponse>,
+    subscribers: Vec<ResponseSender>,
+}
+
+impl Coalescer {
+    /// Schedule `request` on `backend`, or subscribe to an identical in-flight request
+    pub(crate) fn schedule(
+        &self,
+        request: ValidGenerateRequest,
+        backend: &(dyn Backend + Send + Sync),
+    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        let key = match CoalescingKey::new(&request) {
+            Some(key) => key,
+            // Sampled requests are never coalesced
+            None => return backend.schedule(request),
+        };
+
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+
+        // The lock is held while scheduling so that two identical requests arriving at the
+        // same time cannot both reach the backend
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(entry) = in_flight.get_mut(&key) {
+            for response in &entry.history {
+                // Unwrap is safe here, we own the receiver
+                response_tx.send(Ok(response.clone())).unwrap();
+            }
+            entry.subscribers.push(response_tx);
+            metrics::counter!("tgi_request_coalesced").increment(1);
+            tracing::debug!("Coalesced request with an identical in-flight request");
+            return Ok(UnboundedReceiverStream::new(response_rx));
+        }
+
+        let generation_stream = backend.schedule(request)?;
+        in_flight.insert(
+            key.clone(),
+            InFlight {
+                history: Vec::new(),
+                subscribers: vec![response_tx],
+            },
+        );
+        drop(in_flight);
+
+        tokio::spawn(fan_out(self.in_flight.clone(), key, generation_stream));
+
+        Ok(UnboundedReceiverStream::new(response_rx))
+    }
+}
+
+/// Forward the backend responses to all subscribers of an in-flight request
+async fn fan_out(
+    in_flight: Arc<Mutex<HashMap<CoalescingKey, InFlight>>>,
+    key: CoalescingKey,
+    mut generation_stream: UnboundedReceiverStream<Result<InferStreamResponse, InferError>>,
+) {
+    while let Some(response) = generation_stream.next().await {
+        let mut in_flight = in_flight.lock().unwrap();
+        let Some(entry) = in_flight.get_mut(&key) else {
+            return;
+        };
+
+        let done = match response {
+            Ok(response) => {
+                let end = matches!(response, InferStreamResponse::End { .. });
+                entry
+                    .subscribers
+                    .retain(|subscriber| subscriber.send(Ok(response.clone())).is_ok());
+                entry.history.push(response);
+                end
+            }
+            Err(err) => {
+                // Every subscriber reports the original error
+                let err = Arc::new(err);
+                for subscriber in &entry.subscribers {
+                    let _ = subscriber.send(Err(InferError::Coalesced(err.clone())));
+                }
+                true
+            }
+        };
+
+        // Stop generating when every subscriber is gone so that the backend can
+        // cancel the request
+        if done || entry.subscribers.is_empty() {
+            in_flight.remove(&key);
+            return;
+        }
+    }
+
+    // The backend closed the stream early, dropping the senders closes every subscriber stream
+    in_flight.lock().unwrap().remove(&key);
+}
+
+/// Everything that can influence the output of a deterministic request
+///
+/// Floats are stored as their bit representation so that the key can be hashed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CoalescingKey {
+    inputs: Vec<Chunk>,
+    input_ids: Option<Vec<u32>>,
+    truncate: u32,
+    add_special_tokens: bool,
+    decoder_input_details: bool,
+    top_n_tokens: u32,
+    adapter_id: Option<String>,
+    repetition_penalty: u32,
+    frequency_penalty: u32,
+    watermark: bool,
+    grammar: Option<ValidGrammar>,
+    max_new_tokens: u32,
+    stop_sequences: Vec<String>,
+    ignore_eos_token: bool,
+}
+
+impl CoalescingKey {
+    /// Returns `None` if the request output is not deterministic
+    fn new(request: &ValidGenerateRequest) -> Option<Self> {
+        let parameters = &request.parameters;
+        // Mirrors the shards: any logits warper turns on sampling
+        let greedy = !parameters.do_sample
+            && parameters.temperature == 1.0
+            && parameters.top_k == 0
+            && parameters.top_p == 1.0
+            && parameters.typical_p == 1.0;
+        if !greedy {
+            return None;
+        }
+
+        Some(Self {
+            inputs: request.inputs.clone(),
+            input_ids: request.input_ids.as_ref().map(|ids| ids.to_vec()),
+            truncate: request.truncate,
+            add_special_tokens: request.add_special_tokens,
+            decoder_input_details: request.decoder_input_details,
+            top_n_tokens: request.top_n_tokens,
+            adapter_id: request.adapter_id.clone(),
+            repetition_penalty: parameters.repetition_penalty.to_bits(),
+            frequency_penalty: parameters.frequency_penalty.to_bits(),
+            watermark: parameters.watermark,
+            grammar: parameters.grammar.clone(),
+            max_new_tokens: request.stopping_parameters.max_new_tokens,
+            stop_sequences: request.stopping_parameters.stop_sequences.clone(),
+            ignore_eos_token: request.stopping_parameters.ignore_eos_token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::GeneratedText;
+    use crate::validation::{ValidParameters, ValidStoppingParameters, ValidationError};
+    use crate::{FinishReason, Token};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::Instant;
+
+    #[derive(Default)]
+    struct MockBackend {
+        scheduled: AtomicUsize,
+        senders: Mutex<Vec<ResponseSender>>,
+    }
+
+    #[async_trait]
+    impl Backend for MockBackend {
+        fn schedule(
+            &self,
+            _request: ValidGenerateRequest,
+        ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError>
+        {
+            self.scheduled.fetch_add(1, Ordering::SeqCst);
+            let (sender, receiver) = mpsc::unbounded_channel();
+            self.senders.lock().unwrap().push(sender);
+            Ok(UnboundedReceiverStream::new(receiver))
+        }
+
+        async fn health(&self, _current_health: bool) -> bool {
+            true
+        }
+    }
+
+    fn request(do_sample: bool) -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: vec![Chunk::Text("Hello".to_string())],
+            input_ids: None,
+            input_length: 1,
+            truncate: 10,
+            add_special_tokens: true,
+            decoder_input_details: false,
+            parameters: ValidParameters {
+                temperature: 1.0,
+                top_k: 0,
+                top_p: 1.0,
+                typical_p: 1.0,
+                do_sample,
+                seed: 0,
+                repetition_penalty: 1.0,
+                frequency_penalty: 0.0,
+                watermark: false,
+                grammar: None,
+            },
+            stopping_parameters: ValidStoppingParameters {
+                max_new_tokens: 2,
+                stop_sequences: vec![],
+                ignore_eos_token: false,
+            },
+            top_n_tokens: 0,
+            adapter_id: None,
+        }
+    }
+
+    fn token(id: u32) -> Token {
+        Token {
+            id,
+            text: id.to_string(),
+            logprob: -1.0,
+            special: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_greedy_requests() {
+        let backend = MockBackend::default();
+        let coalescer = Coalescer::default();
+
+        let mut first = coalescer.schedule(request(false), &backend).unwrap();
+        let sender = backend.senders.lock().unwrap()[0].clone();
+        sender
+            .send(Ok(InferStreamResponse::Intermediate {
+                token: token(1),
+                top_tokens: vec![],
+            }))
+            .unwrap();
+        assert!(matches!(
+            first.next().await,
+            Some(Ok(InferStreamResponse::Intermediate { .. }))
+        ));
+
+        // The late subscriber is attached to the in-flight request and gets the missed token
+        let mut second = coalescer.schedule(request(false), &backend).unwrap();
+        assert_eq!(backend.scheduled.load(Ordering::SeqCst), 1);
+
+        let now = Instant::now();
+        sender
+            .send(Ok(InferStreamResponse::End {
+                token: token(2),
+                top_tokens: vec![],
+                generated_text: GeneratedText {
+                    text: "12".to_string(),
+                    generated_tokens: 2,
+                    finish_reason: FinishReason::Length,
+                    seed: None,
+                },
+                start: now,
+                queued: now,
+            }))
+            .unwrap();
+
+        assert!(matches!(
+            first.next().await,
+            Some(Ok(InferStreamResponse::End { .. }))
+        ));
+        match second.next().await {
+            Some(Ok(InferStreamResponse::Intermediate { token, .. })) => assert_eq!(token.id, 1),
+            _ => panic!("Expected the replayed intermediate token"),
+        }
+        assert!(matches!(
+            second.next().await,
+            Some(Ok(InferStreamResponse::End { .. }))
+        ));
+
+        // The request is finished, an identical request reaches the backend again
+        let _third = coalescer.schedule(request(false), &backend).unwrap();
+        assert_eq!(backend.scheduled.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_errors_keep_their_variant() {
+        let backend = MockBackend::default();
+        let coalescer = Coalescer::default();
+
+        let mut first = coalescer.schedule(request(false), &backend).unwrap();
+        let mut second = coalescer.schedule(request(false), &backend).unwrap();
+        let sender = backend.senders.lock().unwrap()[0].clone();
+        sender
+            .send(Err(InferError::ValidationError(
+                ValidationError::InputLength(4, 5),
+            )))
+            .unwrap();
+
+        for stream in [&mut first, &mut second] {
+            let Some(Err(InferError::Coalesced(err))) = stream.next().await else {
+                panic!("Expected the shared error");
+            };
+            assert!(matches!(
+                *err,
+                InferError::ValidationError(ValidationError::InputLength(4, 5))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sampled_requests_are_not_coalesced() {
+        let backend = MockBackend::default();
+        let coalescer = Coalescer::default();
+
+        let _first = coalescer.schedule(request(true), &backend).unwrap();
+        let _second = coalescer.schedule(request(true), &backend).unwrap();
+        assert_eq!(backend.scheduled.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,155 @@
+/// Fill-in-the-middle prompts for the `suffix` of `/v1/completions`
+use crate::infer::InferError;
+use crate::Tokenizer;
+use clap::ValueEnum;
+use minijinja::{context, Environment, Template};
+use serde::Serialize;
+
+/// Prefix, suffix and middle markers of the known code models
+const FIM_TOKENS: [[&str; 3]; 3] = [
+    // StarCoder, Granite Code
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    // Qwen2.5-Coder, CodeGemma
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    // DeepSeek Coder
+    ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+];
+
+/// Order of the parts of a fill-in-the-middle prompt
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, ValueEnum)]
+pub enum FimMode {
+    /// `<prefix>{prefix}<suffix>{suffix}<middle>`
+    #[default]
+    Psm,
+    /// `<suffix>{suffix}<prefix>{prefix}<middle>`
+    Spm,
+}
+
+#[derive(Clone)]
+pub(crate) enum FimTemplate {
+    /// Markers found in the vocabulary of the tokenizer
+    Tokens {
+        prefix: String,
+        suffix: String,
+        middle: String,
+        mode: FimMode,
+    },
+    /// `completion_template` of `tokenizer_config.json`, rendered with `prefix` and `suffix`
+    Template(Template<'static, 'static>),
+}
+
+impl FimTemplate {
+    /// Use `completion_template` when it is set, or look for known markers in the tokenizer
+    pub(crate) fn new(
+        tokenizer: &Tokenizer,
+        completion_template: Option<String>,
+        mode: FimMode,
+    ) -> Option<Self> {
+        if let Some(template) = completion_template.and_then(compile) {
+            tracing::info!("Using the `completion_template` for fill-in-the-middle");
+            return Some(template);
+        }
+        let fim = match tokenizer {
+            Tokenizer::Rust(tokenizer) => {
+                Self::detect(|token| tokenizer.token_to_id(token).is_some(), mode)
+            }
+            // Special tokens of python tokenizers are not inspected
+            Tokenizer::Python { .. } => None,
+        };
+        match &fim {
+            Some(Self::Tokens { prefix, .. }) => {
+                tracing::info!("Using the {prefix} tokens for fill-in-the-middle ({mode:?})")
+            }
+            _ => tracing::debug!("No fill-in-the-middle tokens found"),
+        }
+        fim
+    }
+
+    fn detect(has_token: impl Fn(&str) -> bool, mode: FimMode) -> Option<Self> {
+        FIM_TOKENS
+            .iter()
+            .find(|tokens| tokens.iter().all(|token| has_token(token)))
+            .map(|[prefix, suffix, middle]| Self::Tokens {
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+                middle: middle.to_string(),
+                mode,
+            })
+    }
+
+    pub(crate) fn apply(&self, prefix: &str, suffix: &str) -> Result<String, InferError> {
+        match self {
+            Self::Tokens {
+                prefix: prefix_token,
+                suffix: suffix_token,
+                middle: middle_token,
+                mode,
+            } => Ok(match mode {
+                FimMode::Psm => {
+                    format!("{prefix_token}{prefix}{suffix_token}{suffix}{middle_token}")
+                }
+                FimMode::Spm => {
+                    format!("{suffix_token}{suffix}{prefix_token}{prefix}{middle_token}")
+                }
+            }),
+            Self::Template(template) => template
+                .render(context! { prefix, suffix })
+                .map_err(InferError::TemplateError),
+        }
+    }
+}
+
+/// Compile a `completion_template`, it must at least use the `suffix`
+fn compile(template: String) -> Option<FimTemplate> {
+    let env = Box::new(Environment::new());
+    // leaking env and template as read-only, static resources for performance.
+    let template = match Box::leak(env).template_from_str(Box::leak(template.into_boxed_str())) {
+        Ok(template) => template,
+        Err(err) => {
+            tracing::warn!("Ignoring the invalid `completion_template`: {err}");
+            return None;
+        }
+    };
+    if !template.undeclared_variables(false).contains("suffix") {
+        tracing::warn!("Ignoring the `completion_template`, it does not use `suffix`");
+        return None;
+    }
+    Some(FimTemplate::Template(template))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fim_tokens() {
+        let vocab = ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"];
+        let has_token = |token: &str| vocab.contains(&token);
+
+        let fim = FimTemplate::detect(has_token, FimMode::Psm).unwrap();
+        assert_eq!(
+            fim.apply("def f(", "):").unwrap(),
+            "<|fim_prefix|>def f(<|fim_suffix|>):<|fim_middle|>"
+        );
+        let fim = FimTemplate::detect(has_token, FimMode::Spm).unwrap();
+        assert_eq!(
+            fim.apply("def f(", "):").unwrap(),
+            "<|fim_suffix|>):<|fim_prefix|>def f(<|fim_middle|>"
+        );
+
+        assert!(FimTemplate::detect(|token| token == "<fim_prefix>", FimMode::Psm).is_none());
+    }
+
+    #[test]
+    fn test_completion_template() {
+        let fim = compile("<PRE> {{ prefix }} <SUF>{{ suffix }} <MID>".to_string()).unwrap();
+        assert_eq!(
+            fim.apply("def f(", "):").unwrap(),
+            "<PRE> def f( <SUF>): <MID>"
+        );
+
+        // Templates that ignore the suffix cannot fill in the middle
+        assert!(compile("{{ prefix }}".to_string()).is_none());
+        assert!(compile("{{ prefix ".to_string()).is_none());
+    }
+}
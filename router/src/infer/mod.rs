@@ -1,19 +1,26 @@
 // pub(crate) mod v2;
 mod chat_template;
+mod coalesce;
+pub mod fim;
+pub mod reasoning;
 pub mod tool_grammar;
 
 use crate::validation::{ValidGenerateRequest, Validation, ValidationError};
-use crate::Tool;
 use crate::{
-    ChatTemplateVersions, FinishReason, GenerateRequest, HubProcessorConfig, HubTokenizerConfig,
-    Message, PrefillToken, Token,
+    ChatTemplateVersions, FinishReason, GenerateRequest, GenerationDefaults, HubProcessorConfig,
+    HubTokenizerConfig, Message, PrefillToken, Token,
 };
+use crate::{Document, Tool};
 use async_stream::stream;
 use async_trait::async_trait;
 use chat_template::ChatTemplate;
+use coalesce::Coalescer;
+use fim::FimTemplate;
 use futures::future::try_join_all;
 use futures::Stream;
 use minijinja::ErrorKind;
+use reasoning::{ReasoningParser, ReasoningSplitter};
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use thiserror::Error;
@@ -23,6 +30,11 @@ use tokio_stream::wrappers::UnboundedReceiverStream;
 use tokio_stream::StreamExt;
 use tracing::instrument;
 
+/// Name of the chat template used by default
+const DEFAULT_CHAT_TEMPLATE: &str = "default";
+/// Name of the chat template used with tools
+const TOOL_USE_CHAT_TEMPLATE: &str = "tool_use";
+
 #[async_trait]
 pub trait Backend {
     fn schedule(
@@ -40,8 +52,16 @@ pub struct Infer {
     validation: Validation,
     /// Request backend
     backend: Arc<dyn Backend + Send + Sync>,
-    /// Chat template
-    chat_template: Option<ChatTemplate>,
+    /// Identical in-flight greedy requests
+    coalescer: Coalescer,
+    /// Chat templates, keyed by name
+    chat_templates: BTreeMap<String, ChatTemplate>,
+    /// Fill-in-the-middle template
+    fim_template: Option<FimTemplate>,
+    /// Defaults of the parameters of the model
+    generation_defaults: GenerationDefaults,
+    /// Markers of the reasoning of thinking models
+    reasoning_parser: Option<ReasoningParser>,
     /// Inference limit
     limit_concurrent_requests: Arc<Semaphore>,
     /// Backend health
@@ -56,18 +76,34 @@ impl Infer {
         max_concurrent_requests: usize,
         tokenizer_config: HubTokenizerConfig,
         processor_config: HubProcessorConfig,
+        fim_template: Option<FimTemplate>,
+        generation_defaults: GenerationDefaults,
+        reasoning_parser: Option<ReasoningParser>,
     ) -> Self {
-        let chat_template = tokenizer_config
+        let templates = match tokenizer_config
             .chat_template
             .or(processor_config.chat_template)
-            .and_then(|t| match t {
-                ChatTemplateVersions::Single(template) => Some(template),
-                ChatTemplateVersions::Multiple(templates) => templates
-                    .into_iter()
-                    .find(|t| t.name == "default")
-                    .map(|t| t.template),
+        {
+            Some(ChatTemplateVersions::Single(template)) => {
+                vec![(DEFAULT_CHAT_TEMPLATE.to_string(), template)]
+            }
+            Some(ChatTemplateVersions::Multiple(templates)) => templates
+                .into_iter()
+                .map(|t| (t.name, t.template))
+                .collect(),
+            None => Vec::new(),
+        };
+        let chat_templates = templates
+            .into_iter()
+            .map(|(name, template)| {
+                let template = ChatTemplate::new(
+                    template,
+                    tokenizer_config.bos_token.clone(),
+                    tokenizer_config.eos_token.clone(),
+                );
+                (name, template)
             })
-            .map(|t| ChatTemplate::new(t, tokenizer_config.bos_token, tokenizer_config.eos_token));
+            .collect();
 
         // Inference limit with a semaphore
         let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
@@ -78,17 +114,26 @@ impl Infer {
         Self {
             validation,
             backend: Arc::new(backend),
-            chat_template,
+            coalescer: Coalescer::default(),
+            chat_templates,
+            fim_template,
+            generation_defaults,
+            reasoning_parser,
             limit_concurrent_requests: semaphore,
             backend_health,
         }
     }
 
+    /// Number of requests that can still be scheduled before the server is overloaded
+    pub(crate) fn free_permits(&self) -> usize {
+        self.limit_concurrent_requests.available_permits()
+    }
+
     /// Add a new request to the queue and return a stream of InferStreamResponse
     #[instrument(skip_all)]
     pub(crate) async fn generate_stream<'a>(
         &'a self,
-        request: GenerateRequest,
+        mut request: GenerateRequest,
     ) -> Result<
         (
             OwnedSemaphorePermit,
@@ -109,6 +154,7 @@ impl Infer {
             })?;
 
         // Validate request
+        self.generation_defaults.apply(&mut request.parameters);
         let valid_request = self.validation.validate(request).await.map_err(|err| {
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
@@ -116,7 +162,9 @@ impl Infer {
         })?;
 
         let input_length = valid_request.input_length;
-        let mut generation_stream = self.backend.schedule(valid_request)?;
+        let mut generation_stream = self
+            .coalescer
+            .schedule(valid_request, self.backend.as_ref())?;
 
         // Wrap generation stream to update the backend health if the stream contains an error
         let final_stream = stream! {
@@ -153,18 +201,105 @@ impl Infer {
         Ok(encoding.0)
     }
 
+    /// Whether the prompt of a request, its new tokens and `reserved_tokens` fit the context window
+    pub(crate) async fn fits_context(
+        &self,
+        request: GenerateRequest,
+        reserved_tokens: u32,
+    ) -> Result<bool, InferError> {
+        let max_new_tokens = request.parameters.max_new_tokens.unwrap_or(0) + reserved_tokens;
+        let encoding = self.tokenize(request).await?;
+        Ok(self.validation.fits_context(encoding.len(), max_new_tokens))
+    }
+
+    /// Defaults of the parameters of the model
+    pub(crate) fn generation_defaults(&self) -> &GenerationDefaults {
+        &self.generation_defaults
+    }
+
+    /// Split the reasoning of thinking models from the answer of a chat request, unless a grammar
+    /// constrains the output
+    pub(crate) fn reasoning_splitter(
+        &self,
+        request: &GenerateRequest,
+        include_reasoning: bool,
+    ) -> Option<ReasoningSplitter> {
+        if request.parameters.grammar.is_some() {
+            return None;
+        }
+        self.reasoning_parser
+            .as_ref()
+            .map(|parser| parser.splitter(&request.inputs, include_reasoning))
+    }
+
+    /// Names of the chat templates
+    pub(crate) fn chat_template_names(&self) -> Vec<String> {
+        self.chat_templates.keys().cloned().collect()
+    }
+
     /// Apply the chat template to the chat request
+    ///
+    /// The template is picked by name, `tool_use` when tools are given and the model has one,
+    /// `default` otherwise
     #[instrument(skip_all)]
     pub(crate) fn apply_chat_template(
         &self,
+        chat_template: Option<&str>,
         guideline: Option<String>,
+        documents: Option<Vec<Document>>,
         messages: Vec<Message>,
         tools_and_prompt: Option<(Vec<Tool>, String)>,
+        continue_final_message: bool,
+    ) -> Result<String, InferError> {
+        let name = match chat_template {
+            Some(name) => name,
+            None if tools_and_prompt.is_some()
+                && self.chat_templates.contains_key(TOOL_USE_CHAT_TEMPLATE) =>
+            {
+                TOOL_USE_CHAT_TEMPLATE
+            }
+            None => DEFAULT_CHAT_TEMPLATE,
+        };
+        self.chat_templates
+            .get(name)
+            .ok_or_else(|| {
+                let detail = if chat_template.is_some() {
+                    format!("no chat template named `{name}`")
+                } else {
+                    "the model has no default chat template".to_string()
+                };
+                InferError::TemplateError(minijinja::Error::new(
+                    ErrorKind::TemplateNotFound,
+                    detail,
+                ))
+            })?
+            .apply(
+                guideline.as_deref(),
+                documents,
+                messages,
+                tools_and_prompt,
+                continue_final_message,
+            )
+            .map_err(|e| {
+                metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
+                tracing::error!("{e}");
+                e
+            })
+    }
+
+    /// Build a fill-in-the-middle prompt
+    #[instrument(skip_all)]
+    pub(crate) fn apply_fim_template(
+        &self,
+        prefix: &str,
+        suffix: &str,
     ) -> Result<String, InferError> {
-        self.chat_template
+        self.fim_template
             .as_ref()
-            .ok_or_else(|| InferError::TemplateError(ErrorKind::TemplateNotFound.into()))?
-            .apply(guideline.as_deref(), messages, tools_and_prompt)
+            .ok_or(InferError::ValidationError(
+                ValidationError::FimNotSupported,
+            ))?
+            .apply(prefix, suffix)
             .map_err(|e| {
                 metrics::counter!("tgi_request_failure", "err" => "template").increment(1);
                 tracing::error!("{e}");
@@ -296,7 +431,7 @@ impl Infer {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct GeneratedText {
     pub text: String,
     pub generated_tokens: u32,
@@ -304,7 +439,7 @@ pub struct GeneratedText {
     pub seed: Option<u64>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum InferStreamResponse {
     // Optional first message
     Prefill(Vec<PrefillToken>),
@@ -357,6 +492,11 @@ pub enum InferError {
     ToolError(String),
     #[error("Stream event serialization error")]
     StreamSerializationError(String),
+    #[error("Output does not match the schema: {0}")]
+    SchemaMismatch(String),
+    /// Error of an in-flight request shared with the requests coalesced with it
+    #[error(transparent)]
+    Coalesced(Arc<InferError>),
 }
 
 impl InferError {
@@ -371,6 +511,31 @@ impl InferError {
             InferError::MissingTemplateVariable(_) => "missing_template_variable",
             InferError::ToolError(_) => "tool_error",
             InferError::StreamSerializationError(_) => "stream_serialization_error",
+            InferError::SchemaMismatch(_) => "schema_mismatch",
+            InferError::Coalesced(err) => err.error_type(),
+        }
+    }
+
+    /// Stable machine-readable code of the error
+    pub(crate) fn code(&self) -> &str {
+        match self {
+            InferError::ValidationError(err) => err.code(),
+            InferError::Coalesced(err) => err.code(),
+            _ => self.error_type(),
+        }
+    }
+
+    /// Name of the request parameter that caused the error
+    pub(crate) fn param(&self) -> Option<&str> {
+        match self {
+            InferError::ValidationError(err) => err.param(),
+            InferError::TemplateError(_) | InferError::MissingTemplateVariable(_) => {
+                Some("messages")
+            }
+            InferError::ToolError(_) => Some("tools"),
+            InferError::SchemaMismatch(_) => Some("response_format"),
+            InferError::Coalesced(err) => err.param(),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,290 @@
+/// Separation of the reasoning of thinking models from their answer
+use crate::{TextMessage, Tokenizer};
+use std::str::FromStr;
+
+/// Start and end markers of the reasoning of the known thinking models
+const REASONING_MARKERS: [[&str; 2]; 2] = [
+    // DeepSeek-R1, QwQ, Qwen3
+    ["<think>", "</think>"],
+    // Command A Reasoning
+    ["<|START_THINKING|>", "<|END_THINKING|>"],
+];
+
+/// Markers of the reasoning set with `--reasoning-parser`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ReasoningMarkers {
+    /// Look for the markers of the known thinking models in the tokenizer
+    #[default]
+    Auto,
+    /// Leave the reasoning in the content
+    Disabled,
+    /// `<start>,<end>`
+    Custom { start: String, end: String },
+}
+
+impl FromStr for ReasoningMarkers {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(Self::Auto),
+            "none" => Ok(Self::Disabled),
+            _ => match value.split_once(',') {
+                Some((start, end)) if !start.is_empty() && !end.is_empty() => Ok(Self::Custom {
+                    start: start.to_string(),
+                    end: end.to_string(),
+                }),
+                _ => Err(format!(
+                    "expected `auto`, `none` or `<start>,<end>`, got `{value}`"
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ReasoningParser {
+    start: String,
+    end: String,
+}
+
+impl ReasoningParser {
+    /// Use the markers set on the command line, or look for known markers in the tokenizer
+    pub(crate) fn new(tokenizer: &Tokenizer, markers: ReasoningMarkers) -> Option<Self> {
+        let parser = match markers {
+            ReasoningMarkers::Auto => match tokenizer {
+                Tokenizer::Rust(tokenizer) => {
+                    Self::detect(|token| tokenizer.token_to_id(token).is_some())
+                }
+                // Special tokens of python tokenizers are not inspected
+                Tokenizer::Python { .. } => None,
+            },
+            ReasoningMarkers::Disabled => None,
+            ReasoningMarkers::Custom { start, end } => Some(Self { start, end }),
+        };
+        match &parser {
+            Some(Self { start, end }) => {
+                tracing::info!("Separating the reasoning between {start} and {end}")
+            }
+            None => tracing::debug!("No reasoning markers found"),
+        }
+        parser
+    }
+
+    fn detect(has_token: impl Fn(&str) -> bool) -> Option<Self> {
+        REASONING_MARKERS
+            .iter()
+            .find(|markers| markers.iter().all(|marker| has_token(marker)))
+            .map(|[start, end]| Self {
+                start: start.to_string(),
+                end: end.to_string(),
+            })
+    }
+
+    /// Split the output generated for `prompt`. The output starts in the reasoning when the
+    /// prompt leaves a start marker open, as the templates forcing the models to think do.
+    pub(crate) fn splitter(&self, prompt: &str, include_reasoning: bool) -> ReasoningSplitter {
+        let state = match (prompt.rfind(&self.start), prompt.rfind(&self.end)) {
+            (Some(start), end) if end.map_or(true, |end| end < start) => State::Reasoning,
+            _ => State::Start,
+        };
+        ReasoningSplitter {
+            parser: self.clone(),
+            include_reasoning,
+            state,
+            buffer: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    /// Waiting for the start marker
+    Start,
+    Reasoning,
+    /// The whitespace following the end marker is trimmed
+    Content {
+        trim_start: bool,
+    },
+}
+
+/// Reasoning and content of a piece of output
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Split {
+    pub reasoning: String,
+    pub content: String,
+}
+
+/// Incremental split of the output of a request
+#[derive(Clone, Debug)]
+pub(crate) struct ReasoningSplitter {
+    parser: ReasoningParser,
+    include_reasoning: bool,
+    state: State,
+    /// Output held back while it may be the beginning of a marker
+    buffer: String,
+}
+
+impl ReasoningSplitter {
+    /// Split the next piece of output, `last` flushes the text held back
+    pub(crate) fn push(&mut self, text: &str, last: bool) -> Split {
+        self.buffer.push_str(text);
+        let mut split = Split::default();
+        loop {
+            match self.state {
+                State::Start => {
+                    let output = self.buffer.trim_start();
+                    if let Some(reasoning) = output.strip_prefix(&self.parser.start) {
+                        self.buffer = reasoning.to_string();
+                        self.state = State::Reasoning;
+                    } else if self.parser.start.starts_with(output) && !last {
+                        return split;
+                    } else {
+                        self.state = State::Content { trim_start: false };
+                    }
+                }
+                State::Reasoning => match self.buffer.find(&self.parser.end) {
+                    Some(index) => {
+                        split.reasoning.push_str(&self.buffer[..index]);
+                        self.buffer.drain(..index + self.parser.end.len());
+                        self.state = State::Content { trim_start: true };
+                    }
+                    None => {
+                        let held = if last {
+                            0
+                        } else {
+                            partial_marker(&self.buffer, &self.parser.end)
+                        };
+                        let held = self.buffer.split_off(self.buffer.len() - held);
+                        split
+                            .reasoning
+                            .push_str(&std::mem::replace(&mut self.buffer, held));
+                        return split;
+                    }
+                },
+                State::Content { trim_start } => {
+                    let content = std::mem::take(&mut self.buffer);
+                    let content = if trim_start {
+                        content.trim_start()
+                    } else {
+                        &content
+                    };
+                    if !content.is_empty() {
+                        self.state = State::Content { trim_start: false };
+                    }
+                    split.content.push_str(content);
+                    return split;
+                }
+            }
+        }
+    }
+
+    /// Move the reasoning of a streamed message to its `reasoning_content`
+    pub(crate) fn split_message(&mut self, message: &mut TextMessage, last: bool) {
+        let split = self.push(&message.content, last);
+        message.content = split.content;
+        message.reasoning_content =
+            (self.include_reasoning && !split.reasoning.is_empty()).then_some(split.reasoning);
+    }
+
+    /// Split a complete output into its reasoning, unless it is excluded, and its content
+    pub(crate) fn split(mut self, output: &str) -> (Option<String>, String) {
+        let split = self.push(output, true);
+        let reasoning =
+            (self.include_reasoning && !split.reasoning.is_empty()).then_some(split.reasoning);
+        (reasoning, split.content)
+    }
+
+    /// Whether `output` stops in the middle of the reasoning
+    pub(crate) fn unfinished(&self, output: &str) -> bool {
+        let mut splitter = self.clone();
+        splitter.push(output, true);
+        splitter.state == State::Reasoning
+    }
+
+    pub(crate) fn end_marker(&self) -> &str {
+        &self.parser.end
+    }
+}
+
+/// Length of the longest end of `text` that is the beginning of `marker`
+fn partial_marker(text: &str, marker: &str) -> usize {
+    (1..marker.len())
+        .rev()
+        .filter(|&length| marker.is_char_boundary(length))
+        .find(|&length| text.ends_with(&marker[..length]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser() -> ReasoningParser {
+        ReasoningParser::detect(|token| token == "<think>" || token == "</think>").unwrap()
+    }
+
+    #[test]
+    fn test_reasoning_markers() {
+        assert_eq!("auto".parse(), Ok(ReasoningMarkers::Auto));
+        assert_eq!("none".parse(), Ok(ReasoningMarkers::Disabled));
+        assert_eq!(
+            "<reasoning>,</reasoning>".parse(),
+            Ok(ReasoningMarkers::Custom {
+                start: "<reasoning>".to_string(),
+                end: "</reasoning>".to_string(),
+            })
+        );
+        assert!("<reasoning>".parse::<ReasoningMarkers>().is_err());
+
+        assert!(ReasoningParser::detect(|token| token == "<think>").is_none());
+    }
+
+    #[test]
+    fn test_split_reasoning() {
+        let splitter = parser().splitter("<|user|>Hi<|assistant|>", true);
+        assert_eq!(
+            splitter
+                .clone()
+                .split("<think>\nA greeting.\n</think>\n\nHello!"),
+            (Some("\nA greeting.\n".to_string()), "Hello!".to_string())
+        );
+        assert_eq!(
+            splitter.clone().split("Hello!"),
+            (None, "Hello!".to_string())
+        );
+        assert!(splitter.unfinished("<think>\nA greeting"));
+        assert!(!splitter.unfinished("Hello!"));
+
+        // The template opened the reasoning
+        let splitter = parser().splitter("<|user|>Hi<|assistant|><think>\n", true);
+        assert_eq!(
+            splitter.split("A greeting.\n</think>\n\nHello!"),
+            (Some("A greeting.\n".to_string()), "Hello!".to_string())
+        );
+
+        // The reasoning is dropped
+        let splitter = parser().splitter("<|user|>Hi<|assistant|>", false);
+        assert_eq!(
+            splitter.split("<think>A greeting.</think>Hello!"),
+            (None, "Hello!".to_string())
+        );
+    }
+
+    #[test]
+    fn test_split_reasoning_stream() {
+        let mut splitter = parser().splitter("", true);
+        let tokens = [
+            "<", "think>", "A gree", "ting.</", "think", ">\n\n", "Hel", "lo!",
+        ];
+        let splits: Vec<Split> = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| splitter.push(token, i == tokens.len() - 1))
+            .collect();
+        let reasoning: Vec<&str> = splits.iter().map(|s| s.reasoning.as_str()).collect();
+        let content: Vec<&str> = splits.iter().map(|s| s.content.as_str()).collect();
+        assert_eq!(reasoning, ["", "", "A gree", "ting.", "", "", "", ""]);
+        assert_eq!(content, ["", "", "", "", "", "", "Hel", "lo!"]);
+    }
+}
@@ -31,7 +31,7 @@ impl ToolGrammar {
 
         let mut tools = tools.clone();
 
-        // add the no_tool function to the tools
+        // add the no_tool function to the tools, unless a tool must be called
         let no_tool = Tool {
             r#type: "function".to_string(),
             function: FunctionDefinition {
@@ -49,14 +49,16 @@ impl ToolGrammar {
                 }),
             },
         };
-        tools.push(no_tool);
+        if tool_choice != ToolType::Required {
+            tools.push(no_tool);
+        }
 
         // if tools are provided and no tool_choice we default to the OneOf
         let tools_to_use = match tool_choice {
             ToolType::Function(function) => {
                 vec![Self::find_tool_by_name(&tools, &function.name)?]
             }
-            ToolType::OneOf => tools.clone(),
+            ToolType::OneOf | ToolType::Required => tools.clone(),
             ToolType::NoTool => return Ok((tools, None)),
         };
 
@@ -121,3 +123,89 @@ impl ToolGrammar {
         Ok((tools, Some(tool_schema)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FunctionName;
+
+    fn tools() -> Vec<Tool> {
+        ["get_current_weather", "get_n_day_weather_forecast"]
+            .into_iter()
+            .map(|name| Tool {
+                r#type: "function".to_string(),
+                function: FunctionDefinition {
+                    name: name.to_string(),
+                    description: None,
+                    arguments: json!({
+                        "type": "object",
+                        "properties": {"location": {"type": "string"}},
+                        "required": ["location"]
+                    }),
+                },
+            })
+            .collect()
+    }
+
+    /// Names of the tools of the prompt and of the functions the grammar allows
+    fn apply(tool_choice: ToolType) -> (Vec<String>, Option<Vec<String>>) {
+        let (tools, schema) = ToolGrammar::apply(tools(), ToolChoice(Some(tool_choice))).unwrap();
+        let tools = tools.into_iter().map(|tool| tool.function.name).collect();
+        let functions = schema.map(|schema| {
+            let schema = serde_json::to_value(schema).unwrap();
+            let functions = schema["$functions"].as_object().unwrap();
+            schema["properties"]["function"]["anyOf"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|function| {
+                    let name = function["$ref"].as_str().unwrap();
+                    let name = name.strip_prefix("#/$functions/").unwrap().to_string();
+                    assert!(functions.contains_key(&name));
+                    name
+                })
+                .collect()
+        });
+        (tools, functions)
+    }
+
+    #[test]
+    fn test_tool_choice_grammar() {
+        let all = vec![
+            "get_current_weather".to_string(),
+            "get_n_day_weather_forecast".to_string(),
+        ];
+        let with_no_tool = [all.clone(), vec!["no_tool".to_string()]].concat();
+
+        // `auto` can answer with a message through `no_tool`
+        assert_eq!(
+            apply(ToolType::OneOf),
+            (with_no_tool.clone(), Some(with_no_tool.clone()))
+        );
+        assert_eq!(
+            ToolGrammar::apply(tools(), ToolChoice(None)).unwrap(),
+            ToolGrammar::apply(tools(), ToolChoice(Some(ToolType::OneOf))).unwrap()
+        );
+
+        // `required` must call one of the tools
+        assert_eq!(apply(ToolType::Required), (all.clone(), Some(all)));
+
+        let forecast = ToolType::Function(FunctionName {
+            name: "get_n_day_weather_forecast".to_string(),
+        });
+        assert_eq!(
+            apply(forecast),
+            (
+                with_no_tool.clone(),
+                Some(vec!["get_n_day_weather_forecast".to_string()])
+            )
+        );
+
+        assert_eq!(apply(ToolType::NoTool), (with_no_tool, None));
+
+        let unknown = ToolType::Function(FunctionName {
+            name: "get_time".to_string(),
+        });
+        assert!(ToolGrammar::apply(tools(), ToolChoice(Some(unknown))).is_err());
+    }
+}
@@ -0,0 +1,414 @@
+/// Asynchronous job API for long generations
+use crate::batch::{
+    dispatch, now, wait_for_capacity, CHAT_COMPLETIONS_ENDPOINT, COMPLETIONS_ENDPOINT,
+    GENERATE_ENDPOINT,
+};
+use crate::infer::Infer;
+use crate::resumable::ResumableStreams;
+use crate::server::ComputeType;
+use crate::{ErrorResponse, Info};
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+/// Timeout of the request sent to the callback URL
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct JobRequest {
+    /// The endpoint that would have received `body`.
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    /// A `/generate`, `/v1/chat/completions` or `/v1/completions` request body.
+    /// Streaming is disabled.
+    #[schema(value_type = Object, example = json ! ({"messages": [{"role": "user", "content": "Write a long story"}]}))]
+    pub body: Value,
+    /// The job is POSTed to this URL once it is finished. Its host must be allowed with
+    /// `--job-callback-hosts`.
+    #[serde(default)]
+    #[schema(nullable = true, example = "https://example.com/callback")]
+    pub callback_url: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, ToSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    Queued,
+    /// The request was sent to the model
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct Job {
+    #[schema(example = "job_6f1a2c0e9b8d4a3f8e7d6c5b4a392817")]
+    pub id: String,
+    #[schema(example = "job")]
+    pub object: String,
+    #[schema(example = "/v1/chat/completions")]
+    pub endpoint: String,
+    pub status: JobStatus,
+    #[schema(example = 1706270835)]
+    pub created_at: u64,
+    #[schema(nullable = true, example = 1706270978)]
+    pub completed_at: Option<u64>,
+    /// Status code of the underlying request
+    #[schema(nullable = true, example = 200)]
+    pub status_code: Option<u16>,
+    /// Response body of the underlying request
+    #[schema(nullable = true, value_type = Object)]
+    pub result: Option<Value>,
+}
+
+struct StoredJob {
+    job: Job,
+    finished_at: Option<Instant>,
+}
+
+/// Bounded in-memory store of the jobs
+///
+/// Finished jobs are kept for `retention` and new jobs are rejected when the store is full.
+#[derive(Clone)]
+pub(crate) struct JobStore {
+    jobs: Arc<Mutex<HashMap<String, StoredJob>>>,
+    max_jobs: usize,
+    retention: Duration,
+    /// Hosts the callbacks can be sent to, callbacks are refused when empty
+    callback_hosts: Arc<Vec<String>>,
+}
+
+impl JobStore {
+    pub(crate) fn new(max_jobs: usize, retention: Duration, callback_hosts: Vec<String>) -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            max_jobs,
+            retention,
+            callback_hosts: Arc::new(callback_hosts),
+        }
+    }
+
+    fn insert(&self, job: Job) -> Result<(), Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= self.max_jobs {
+            let retention = self.retention;
+            jobs.retain(|_, stored| !is_expired(stored, retention));
+        }
+        if jobs.len() >= self.max_jobs {
+            return Err(job);
+        }
+        jobs.insert(
+            job.id.clone(),
+            StoredJob {
+                job,
+                finished_at: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs
+            .get(id)
+            .is_some_and(|stored| is_expired(stored, self.retention))
+        {
+            jobs.remove(id);
+            return None;
+        }
+        jobs.get(id).map(|stored| stored.job.clone())
+    }
+
+    fn start(&self, id: &str) {
+        if let Some(stored) = self.jobs.lock().unwrap().get_mut(id) {
+            stored.job.status = JobStatus::Running;
+        }
+    }
+
+    fn finish(&self, id: &str, status_code: StatusCode, result: Value) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let stored = jobs.get_mut(id)?;
+        stored.finished_at = Some(Instant::now());
+        stored.job.status = if status_code.is_success() {
+            JobStatus::Completed
+        } else {
+            JobStatus::Failed
+        };
+        stored.job.completed_at = Some(now());
+        stored.job.status_code = Some(status_code.as_u16());
+        stored.job.result = Some(result);
+        Some(stored.job.clone())
+    }
+}
+
+impl JobStore {
+    /// Only http(s) URLs of the allowed hosts are called back, the router must not be usable to
+    /// reach internal services
+    fn callback_url(&self, callback_url: &str) -> Result<reqwest::Url, String> {
+        let url = reqwest::Url::parse(callback_url).map_err(|err| err.to_string())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(format!(
+                "the scheme must be http or https. Given: {}",
+                url.scheme()
+            ));
+        }
+        let host = url.host_str().unwrap_or_default();
+        if !self
+            .callback_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        {
+            return Err(format!(
+                "the host must be allowed with `--job-callback-hosts`. Given: {host}"
+            ));
+        }
+        Ok(url)
+    }
+}
+
+fn is_expired(stored: &StoredJob, retention: Duration) -> bool {
+    stored
+        .finished_at
+        .is_some_and(|finished_at| finished_at.elapsed() > retention)
+}
+
+/// POST the finished job to the callback URL
+async fn send_callback(callback_url: reqwest::Url, job: &Job) {
+    // Redirects could lead to a host that is not allowed
+    let client = match reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            tracing::warn!("Callback of job {} failed: {err}", job.id);
+            return;
+        }
+    };
+    let result = client
+        .post(callback_url)
+        .header("Content-Type", "application/json")
+        .body(serde_json::to_string(job).unwrap())
+        .timeout(CALLBACK_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(err) = result {
+        tracing::warn!("Callback of job {} failed: {err}", job.id);
+    }
+}
+
+/// Submit a generate, chat or completions request and return a job id immediately
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/jobs",
+request_body = JobRequest,
+responses(
+(status = 202, description = "Queued job", body = Job),
+(status = 422, description = "Invalid endpoint or callback URL", body = ErrorResponse,
+example = json ! ({"error": "`endpoint` must be one of /generate, /v1/chat/completions, /v1/completions", "error_type": "validation"})),
+(status = 429, description = "Job store is full", body = ErrorResponse,
+example = json ! ({"error": "Job store is full", "error_type": "overloaded"})),
+)
+)]
+#[instrument(skip_all)]
+pub(crate) async fn create_job(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    Extension(streams): Extension<ResumableStreams>,
+    Extension(store): Extension<JobStore>,
+    Json(req): Json<JobRequest>,
+) -> Result<(StatusCode, Json<Job>), (StatusCode, Json<ErrorResponse>)> {
+    if ![
+        GENERATE_ENDPOINT,
+        CHAT_COMPLETIONS_ENDPOINT,
+        COMPLETIONS_ENDPOINT,
+    ]
+    .contains(&req.endpoint.as_str())
+    {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: format!(
+                    "`endpoint` must be one of {GENERATE_ENDPOINT}, {CHAT_COMPLETIONS_ENDPOINT}, {COMPLETIONS_ENDPOINT}"
+                ),
+                error_type: "validation".to_string(),
+                code: "invalid_endpoint".to_string(),
+                param: Some("endpoint".to_string()),
+            }),
+        ));
+    }
+
+    let callback_url = match req.callback_url {
+        Some(callback_url) => Some(store.callback_url(&callback_url).map_err(|err| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ErrorResponse {
+                    error: format!("Invalid `callback_url`: {err}"),
+                    error_type: "validation".to_string(),
+                    code: "invalid_callback_url".to_string(),
+                    param: Some("callback_url".to_string()),
+                }),
+            )
+        })?),
+        None => None,
+    };
+
+    let job = Job {
+        id: format!("job_{}", uuid::Uuid::new_v4().simple()),
+        object: "job".to_string(),
+        endpoint: req.endpoint,
+        status: JobStatus::Queued,
+        created_at: now(),
+        completed_at: None,
+        status_code: None,
+        result: None,
+    };
+    if store.insert(job.clone()).is_err() {
+        metrics::counter!("tgi_request_failure", "err" => "job_store_full").increment(1);
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse {
+                error: "Job store is full".to_string(),
+                error_type: "overloaded".to_string(),
+                code: "job_store_full".to_string(),
+                param: None,
+            }),
+        ));
+    }
+
+    let id = job.id.clone();
+    let endpoint = job.endpoint.clone();
+    let body = req.body;
+    tokio::spawn(async move {
+        // Jobs stay queued until the server has a free permit
+        wait_for_capacity(&infer, 0).await;
+        store.start(&id);
+        let (status_code, result) =
+            dispatch(&infer, &compute_type, &info, &streams, &endpoint, body, 0).await;
+        let finished = store.finish(&id, status_code, result);
+        if let (Some(callback_url), Some(job)) = (callback_url, finished) {
+            send_callback(callback_url, &job).await;
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Get the status of a job, and its result once finished
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/jobs/{job_id}",
+params(("job_id" = String, Path, description = "The ID of the job")),
+responses(
+(status = 200, description = "Job", body = Job),
+(status = 404, description = "Job not found or expired", body = ErrorResponse,
+example = json ! ({"error": "Job job_6f1a2c0e9b8d4a3f8e7d6c5b4a392817 not found", "error_type": "not_found"})),
+)
+)]
+#[instrument(skip(store))]
+pub(crate) async fn get_job(
+    Extension(store): Extension<JobStore>,
+    Path(job_id): Path<String>,
+) -> Result<Json<Job>, (StatusCode, Json<ErrorResponse>)> {
+    store.get(&job_id).map(Json).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Job {job_id} not found"),
+                error_type: "not_found".to_string(),
+                code: "not_found".to_string(),
+                param: None,
+            }),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: &str) -> Job {
+        Job {
+            id: id.to_string(),
+            object: "job".to_string(),
+            endpoint: GENERATE_ENDPOINT.to_string(),
+            status: JobStatus::Queued,
+            created_at: now(),
+            completed_at: None,
+            status_code: None,
+            result: None,
+        }
+    }
+
+    #[test]
+    fn test_job_store_is_bounded() {
+        let store = JobStore::new(2, Duration::from_secs(60), vec![]);
+        store.insert(job("a")).unwrap();
+        store.insert(job("b")).unwrap();
+        // Running and retained jobs are never evicted
+        assert!(store.insert(job("c")).is_err());
+        store.finish("a", StatusCode::OK, Value::Null).unwrap();
+        assert!(store.insert(job("c")).is_err());
+
+        let finished = store.get("a").unwrap();
+        assert_eq!(finished.status, JobStatus::Completed);
+        assert_eq!(finished.status_code, Some(200));
+    }
+
+    #[test]
+    fn test_job_store_retention() {
+        let store = JobStore::new(1, Duration::ZERO, vec![]);
+        store.insert(job("a")).unwrap();
+        store
+            .finish("a", StatusCode::UNPROCESSABLE_ENTITY, Value::Null)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+
+        // The expired job makes room for the new one
+        store.insert(job("b")).unwrap();
+        assert!(store.get("a").is_none());
+        assert_eq!(store.get("b").unwrap().status, JobStatus::Queued);
+    }
+
+    #[test]
+    fn test_callback_url() {
+        let store = JobStore::new(1, Duration::ZERO, vec!["hooks.example.com".to_string()]);
+        assert!(store
+            .callback_url("https://hooks.example.com/tgi?job=1")
+            .is_ok());
+        assert!(store.callback_url("http://HOOKS.example.com/").is_ok());
+        assert!(store.callback_url("file:///etc/passwd").is_err());
+        assert!(store
+            .callback_url("http://169.254.169.254/latest/meta-data")
+            .is_err());
+        assert!(store.callback_url("not a url").is_err());
+
+        // Callbacks are refused unless hosts are allowed
+        let store = JobStore::new(1, Duration::ZERO, vec![]);
+        assert!(store.callback_url("https://hooks.example.com/").is_err());
+    }
+
+    #[test]
+    fn test_job_status() {
+        let store = JobStore::new(1, Duration::from_secs(60), vec![]);
+        store.insert(job("a")).unwrap();
+        store.start("a");
+        assert_eq!(store.get("a").unwrap().status, JobStatus::Running);
+        store
+            .finish("a", StatusCode::TOO_MANY_REQUESTS, Value::Null)
+            .unwrap();
+        assert_eq!(store.get("a").unwrap().status, JobStatus::Failed);
+    }
+}
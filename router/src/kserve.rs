@@ -182,6 +182,8 @@ pub async fn kserve_model_infer(
                     Json(ErrorResponse {
                         error: e.to_string(),
                         error_type: "utf8".to_string(),
+                        code: "invalid_utf8".to_string(),
+                        param: Some("inputs".to_string()),
                     }),
                 )
             })
@@ -194,6 +196,8 @@ pub async fn kserve_model_infer(
             Json(ErrorResponse {
                 error: "Inputs and outputs length mismatch".to_string(),
                 error_type: "length mismatch".to_string(),
+                code: "length_mismatch".to_string(),
+                param: Some("outputs".to_string()),
             }),
         ));
     }
@@ -227,6 +231,8 @@ pub async fn kserve_model_infer(
                             Json(ErrorResponse {
                                 error: "Incomplete generation".into(),
                                 error_type: "Incomplete generation".into(),
+                                code: "incomplete_generation".to_string(),
+                                param: None,
                             }),
                         )
                     })
@@ -1,6 +1,11 @@
 /// Text Generation Inference Webserver
+#[cfg(feature = "anthropic")]
+mod anthropic;
+mod batch;
 pub mod config;
+mod grammar;
 pub mod infer;
+mod jobs;
 pub mod server;
 pub mod validation;
 
@@ -8,15 +13,21 @@ pub mod validation;
 mod kserve;
 pub mod logging;
 
+mod ollama;
+mod resumable;
 mod sagemaker;
+mod score;
 pub mod usage_stats;
 mod vertex;
+mod watermark;
+mod websocket;
 
 use crate::infer::{Infer, InferError};
 use crate::server::prepare_chat_input;
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use tokenizers::Encoding;
 use tracing::warn;
 use utoipa::ToSchema;
@@ -199,6 +210,89 @@ impl HubProcessorConfig {
     }
 }
 
+/// `generation_config.json` of a model
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct HubGenerationConfig {
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub repetition_penalty: Option<f32>,
+    pub max_new_tokens: Option<u32>,
+}
+
+impl HubGenerationConfig {
+    pub fn from_file<P: AsRef<Path>>(filename: P) -> Option<Self> {
+        std::fs::read_to_string(filename)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+}
+
+/// Defaults of the generation parameters, from the `generation_config.json` of the model
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub struct GenerationDefaults {
+    /// Used by the requests that sample
+    #[schema(nullable = true, example = 0.6)]
+    pub temperature: Option<f32>,
+    /// Used by the requests that sample
+    #[schema(nullable = true, example = 0.9)]
+    pub top_p: Option<f32>,
+    /// Used by the requests that sample
+    #[schema(nullable = true, example = 50)]
+    pub top_k: Option<i32>,
+    #[schema(nullable = true, example = 1.1)]
+    pub repetition_penalty: Option<f32>,
+    /// Used by the chat and completions requests without `max_tokens`, instead of the tokens left
+    /// in the context window
+    #[schema(nullable = true, example = 512)]
+    pub max_new_tokens: Option<u32>,
+}
+
+impl GenerationDefaults {
+    /// The end of sequence tokens of the config are left to the shards, they stop on their ids
+    pub fn new(config: HubGenerationConfig) -> Self {
+        // The sampling parameters are only meant for sampling, and `top_p` >= 1 disables it
+        let sampling = config.do_sample == Some(true);
+        Self {
+            temperature: config.temperature.filter(|_| sampling),
+            top_p: config.top_p.filter(|top_p| sampling && *top_p < 1.0),
+            top_k: config.top_k.filter(|top_k| sampling && *top_k > 0),
+            repetition_penalty: config.repetition_penalty,
+            max_new_tokens: config.max_new_tokens,
+        }
+    }
+
+    /// Fill the parameters left unset by a request, greedy requests stay greedy
+    pub(crate) fn apply(&self, parameters: &mut GenerateParameters) {
+        let sampling = parameters.do_sample
+            || parameters.temperature.is_some()
+            || parameters.top_k.is_some()
+            || parameters.top_p.is_some()
+            || parameters.typical_p.is_some();
+        if sampling {
+            parameters.temperature = parameters.temperature.or(self.temperature);
+            parameters.top_p = parameters.top_p.or(self.top_p);
+            parameters.top_k = parameters.top_k.or(self.top_k);
+        }
+        parameters.repetition_penalty = parameters.repetition_penalty.or(self.repetition_penalty);
+    }
+}
+
+/// Standalone `chat_template.json` of a repository
+#[derive(Debug, Clone, Deserialize)]
+pub struct HubChatTemplateConfig {
+    pub chat_template: ChatTemplateVersions,
+}
+
+impl HubChatTemplateConfig {
+    pub fn from_file<P: AsRef<Path>>(filename: P) -> Option<Self> {
+        std::fs::read_to_string(filename)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 #[serde(tag = "type", content = "value")]
@@ -213,6 +307,96 @@ pub(crate) enum GrammarType {
     Json(serde_json::Value),
     #[serde(rename = "regex")]
     Regex(String),
+    /// The output is exactly one of the given strings.
+    #[serde(rename = "choice")]
+    #[schema(example = json ! (["positive", "negative", "neutral"]))]
+    Choice(Vec<String>),
+    /// A context-free grammar in [GBNF](https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md)
+    /// or [Lark](https://lark-parser.readthedocs.io/en/stable/grammar.html) syntax.
+    ///
+    /// GBNF grammars start from the `root` rule, Lark grammars from the `start` rule.
+    #[serde(rename = "cfg")]
+    #[schema(example = "root ::= \"yes\" | \"no\"")]
+    Cfg(String),
+}
+
+/// The `response_format` of a chat request, in the shape of the OpenAI API or as a grammar
+#[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
+#[cfg_attr(test, derive(PartialEq))]
+#[serde(untagged)]
+pub(crate) enum ResponseFormat {
+    // The grammars come first, `json_object` with a value is a JSON schema
+    Grammar(GrammarType),
+    OpenAI(OpenAIResponseFormat),
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
+#[cfg_attr(test, derive(PartialEq))]
+#[serde(tag = "type", deny_unknown_fields)]
+pub(crate) enum OpenAIResponseFormat {
+    /// Unconstrained text
+    #[serde(rename = "text")]
+    Text,
+    /// Any JSON object
+    #[serde(rename = "json_object")]
+    JsonObject,
+    /// JSON matching a schema
+    #[serde(rename = "json_schema")]
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema, Serialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub(crate) struct JsonSchemaFormat {
+    #[schema(example = "weather")]
+    pub name: String,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub description: Option<String>,
+    /// Schema of the JSON output, any JSON object when unset
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"type": "object", "properties": {"location": {"type": "string"}}}))]
+    pub schema: Option<serde_json::Value>,
+    /// Validate the output against the schema and fail the request if it does not match
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = true)]
+    pub strict: Option<bool>,
+}
+
+impl ResponseFormat {
+    /// The grammar constraining the generation, if any
+    pub(crate) fn into_grammar(self) -> Option<GrammarType> {
+        match self {
+            ResponseFormat::OpenAI(OpenAIResponseFormat::Text) => None,
+            ResponseFormat::OpenAI(OpenAIResponseFormat::JsonSchema {
+                json_schema:
+                    JsonSchemaFormat {
+                        schema: Some(schema),
+                        ..
+                    },
+            }) => Some(GrammarType::Json(schema)),
+            // `json_object`, or a `json_schema` without schema
+            ResponseFormat::OpenAI(_) => {
+                Some(GrammarType::Json(serde_json::json!({"type": "object"})))
+            }
+            ResponseFormat::Grammar(grammar) => Some(grammar),
+        }
+    }
+
+    /// The schema the output must match when `strict` is set
+    pub(crate) fn strict_schema(&self) -> Option<&serde_json::Value> {
+        match self {
+            ResponseFormat::OpenAI(OpenAIResponseFormat::JsonSchema {
+                json_schema:
+                    JsonSchemaFormat {
+                        schema,
+                        strict: Some(true),
+                        ..
+                    },
+            }) => schema.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, ToSchema)]
@@ -240,10 +424,19 @@ pub struct Info {
     pub max_input_tokens: usize,
     #[schema(example = "2048")]
     pub max_total_tokens: usize,
+    /// Cap of the tokens generated by the requests without `max_new_tokens`, which otherwise
+    /// generate until the context window is full
+    #[schema(nullable = true, example = "null")]
+    pub default_max_new_tokens: Option<u32>,
     #[schema(example = "2")]
     pub validation_workers: usize,
     #[schema(example = "32")]
     pub max_client_batch_size: usize,
+    /// Names of the chat templates
+    #[schema(example = json!(["default", "tool_use", "rag"]))]
+    pub chat_templates: Vec<String>,
+    /// Defaults of the parameters left unset by the requests
+    pub generation_defaults: GenerationDefaults,
 
     /// Router Info
     #[schema(example = "text-generation-router")]
@@ -330,9 +523,10 @@ pub(crate) struct GenerateParameters {
     #[schema(default = "false", example = true)]
     pub do_sample: bool,
 
-    /// Maximum number of tokens to generate.
-    #[serde(default = "default_max_new_tokens")]
-    #[schema(nullable = true, default = "100", example = "20")]
+    /// Maximum number of tokens to generate. Defaults to the tokens left in the context window,
+    /// within the cap set by the server.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "20")]
     pub max_new_tokens: Option<u32>,
 
     /// Whether to prepend the prompt to the generated text
@@ -391,10 +585,6 @@ pub(crate) struct GenerateParameters {
     pub adapter_id: Option<String>,
 }
 
-fn default_max_new_tokens() -> Option<u32> {
-    Some(100)
-}
-
 fn default_parameters() -> GenerateParameters {
     GenerateParameters {
         best_of: None,
@@ -405,7 +595,7 @@ fn default_parameters() -> GenerateParameters {
         top_p: None,
         typical_p: None,
         do_sample: true,
-        max_new_tokens: default_max_new_tokens(),
+        max_new_tokens: None,
         return_full_text: None,
         stop: Vec::new(),
         truncate: None,
@@ -461,9 +651,11 @@ pub struct CompletionRequest {
     #[schema(example = "What is Deep Learning?")]
     pub prompt: Prompt,
 
-    /// The maximum number of tokens that can be generated in the chat completion.
+    /// The maximum number of tokens that can be generated in the chat completion. Defaults to the
+    /// `max_new_tokens` of the generation config of the model, or to the tokens left in the
+    /// context window within the cap set by the server.
     #[serde(default)]
-    #[schema(default = "32")]
+    #[schema(nullable = true, example = "32")]
     pub max_tokens: Option<u32>,
 
     /// What sampling temperature to use, between 0 and 2. Higher values like 0.8 will make the output more random, while
@@ -484,9 +676,11 @@ pub struct CompletionRequest {
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
 
-    /// The text to append to the prompt. This is useful for completing sentences or generating a paragraph of text.
-    /// please see the completion_template field in the model's tokenizer_config.json file for completion template.
+    /// The text that comes after the completion. The prompt and the suffix are turned into a
+    /// fill-in-the-middle prompt with the FIM tokens of the model, or the `completion_template`
+    /// field of its tokenizer_config.json file.
     #[serde(default)]
+    #[schema(nullable = true, example = "\n    return result")]
     pub suffix: Option<String>,
 
     #[serde(default)]
@@ -502,6 +696,18 @@ pub struct CompletionRequest {
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
     pub stop: Option<Vec<String>>,
+
+    /// Include the log probabilities of the sampled tokens, as well as the log probabilities of
+    /// the `logprobs` most likely tokens at each position.
+    #[serde(default)]
+    #[schema(nullable = true, example = 5)]
+    pub logprobs: Option<u32>,
+
+    /// Echo back the prompt in addition to the completion. With `logprobs`, the prompt tokens and
+    /// their log probabilities are returned as well.
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub echo: bool,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -529,10 +735,80 @@ pub(crate) struct CompletionFinal {
 pub(crate) struct CompletionComplete {
     pub index: u32,
     pub text: String,
-    pub logprobs: Option<Vec<f32>>,
+    pub logprobs: Option<CompletionLogprobs>,
     pub finish_reason: String,
 }
 
+/// Log probabilities in the legacy completions format, one entry per token
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema, PartialEq)]
+pub(crate) struct CompletionLogprobs {
+    #[schema(example = json ! (["Deep", " Learning"]))]
+    pub tokens: Vec<String>,
+    /// `null` for the first prompt token
+    #[schema(example = json ! ([-0.34, -1.2]))]
+    pub token_logprobs: Vec<Option<f32>>,
+    /// `null` for the prompt tokens
+    #[schema(example = json ! ([{"Deep": -0.34, "The": -2.1}, {" Learning": -1.2}]))]
+    pub top_logprobs: Vec<Option<HashMap<String, f32>>>,
+    /// Character offset of each token in the returned text
+    #[schema(example = json ! ([22, 26]))]
+    pub text_offset: Vec<u32>,
+}
+
+impl CompletionLogprobs {
+    /// Append the prompt tokens, the first one has no log probability
+    ///
+    /// `special_tokens_mask` flags the tokens added by the tokenizer, such as BOS, which are not
+    /// part of the echoed prompt. It is ignored when it does not match the prompt tokens.
+    pub(crate) fn push_prefill(
+        &mut self,
+        offset: &mut u32,
+        prefill: &[PrefillToken],
+        special_tokens_mask: &[u32],
+    ) {
+        let matches = special_tokens_mask.len() == prefill.len();
+        for (i, token) in prefill.iter().enumerate() {
+            let logprob = (!token.logprob.is_nan()).then_some(token.logprob);
+            let special = matches && special_tokens_mask[i] == 1;
+            self.push(offset, &token.text, logprob, None, special);
+        }
+    }
+
+    /// Append a generated token and its most likely alternatives
+    pub(crate) fn push_token(&mut self, offset: &mut u32, token: &Token, top_tokens: &[Token]) {
+        let top_logprobs = top_tokens
+            .iter()
+            .map(|top_token| (top_token.text.clone(), top_token.logprob))
+            .collect();
+        let logprob = Some(token.logprob);
+        self.push(
+            offset,
+            &token.text,
+            logprob,
+            Some(top_logprobs),
+            token.special,
+        );
+    }
+
+    /// Special tokens are not part of the text, they do not move the offset
+    fn push(
+        &mut self,
+        offset: &mut u32,
+        text: &str,
+        logprob: Option<f32>,
+        top_logprobs: Option<HashMap<String, f32>>,
+        special: bool,
+    ) {
+        self.tokens.push(text.to_string());
+        self.token_logprobs.push(logprob);
+        self.top_logprobs.push(top_logprobs);
+        self.text_offset.push(*offset);
+        if !special {
+            *offset += text.chars().count() as u32;
+        }
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
 pub(crate) struct Chunk {
     pub id: String,
@@ -552,6 +828,10 @@ pub(crate) struct ChatCompletion {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionComplete>,
     pub usage: Usage,
+    /// Number of messages dropped by the `truncation_strategy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub dropped_messages: Option<u32>,
 }
 
 #[derive(Clone, Deserialize, Serialize, ToSchema)]
@@ -650,10 +930,12 @@ enum CompletionType {
 }
 
 impl ChatCompletion {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         model: String,
         system_fingerprint: String,
         output: Option<String>,
+        reasoning: Option<String>,
         created: u64,
         details: Details,
         return_logprobs: bool,
@@ -663,6 +945,7 @@ impl ChatCompletion {
             (Some(content), None) => OutputMessage::ChatMessage(TextMessage {
                 role: "assistant".into(),
                 content,
+                reasoning_content: reasoning,
             }),
             (None, Some(tool_calls)) => OutputMessage::ToolCall(ToolCallMessage {
                 role: "assistant".to_string(),
@@ -673,6 +956,7 @@ impl ChatCompletion {
                 OutputMessage::ChatMessage(TextMessage {
                     role: "assistant".into(),
                     content: output,
+                    reasoning_content: None,
                 })
             }
             (None, None) => {
@@ -680,6 +964,7 @@ impl ChatCompletion {
                 OutputMessage::ChatMessage(TextMessage {
                     role: "assistant".into(),
                     content: "".to_string(),
+                    reasoning_content: None,
                 })
             }
         };
@@ -700,6 +985,7 @@ impl ChatCompletion {
                 completion_tokens: details.generated_tokens,
                 total_tokens: details.prefill.len() as u32 + details.generated_tokens,
             },
+            dropped_messages: None,
         }
     }
 }
@@ -713,6 +999,10 @@ pub(crate) struct ChatCompletionChunk {
     pub system_fingerprint: String,
     pub choices: Vec<ChatCompletionChoice>,
     pub usage: Option<Usage>,
+    /// Number of messages dropped by the `truncation_strategy`, set on the first chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub dropped_messages: Option<u32>,
 }
 
 #[derive(Clone, Serialize, ToSchema)]
@@ -767,6 +1057,7 @@ impl ChatCompletionChunk {
             (Some(delta), _) => ChatCompletionDelta::Chat(TextMessage {
                 role: "assistant".to_string(),
                 content: delta,
+                reasoning_content: None,
             }),
             (None, Some(tool_calls)) => ChatCompletionDelta::Tool(ToolCallDelta {
                 role: "assistant".to_string(),
@@ -783,6 +1074,7 @@ impl ChatCompletionChunk {
             (None, None) => ChatCompletionDelta::Chat(TextMessage {
                 role: "assistant".to_string(),
                 content: "".to_string(),
+                reasoning_content: None,
             }),
         };
         Self {
@@ -797,6 +1089,7 @@ impl ChatCompletionChunk {
                 finish_reason,
             }],
             usage,
+            dropped_messages: None,
         }
     }
 }
@@ -839,7 +1132,9 @@ pub(crate) struct ChatRequest {
     #[schema(example = "5")]
     pub top_logprobs: Option<u32>,
 
-    /// The maximum number of tokens that can be generated in the chat completion.
+    /// The maximum number of tokens that can be generated in the chat completion. Defaults to the
+    /// `max_new_tokens` of the generation config of the model, or to the tokens left in the
+    /// context window within the cap set by the server.
     #[serde(default)]
     #[schema(example = "32")]
     pub max_tokens: Option<u32>,
@@ -906,13 +1201,53 @@ pub(crate) struct ChatRequest {
     /// NOTE: A request can use `response_format` OR `tools` but not both.
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
-    pub response_format: Option<GrammarType>,
+    pub response_format: Option<ResponseFormat>,
 
     /// A guideline to be used in the chat_template
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub guideline: Option<String>,
 
+    /// Documents grounding the answer, passed to the chat template as `documents`. They are
+    /// appended to the last message when the template does not use them.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub documents: Option<Vec<Document>>,
+
+    /// Leave the final assistant message open for the model to continue it, instead of starting
+    /// a new one
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub continue_final_message: bool,
+
+    /// Prepend the final assistant message to the response when it is continued
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub echo: bool,
+
+    /// Return the reasoning of thinking models in `reasoning_content`, it is dropped otherwise
+    #[serde(default = "default_true")]
+    #[schema(default = "true", example = true)]
+    pub include_reasoning: bool,
+
+    /// The maximum number of tokens thinking models can reason for. The reasoning is then closed
+    /// and the model answers with the tokens left.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "1024")]
+    pub max_reasoning_tokens: Option<u32>,
+
+    /// Make the conversation fit the context window of the model by dropping, or summarizing, its
+    /// oldest turns. The system messages are kept.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "drop_oldest")]
+    pub truncation_strategy: Option<TruncationStrategy>,
+
+    /// Name of the chat template of the model to use, `tool_use` when tools are given and the
+    /// model has one, `default` otherwise. The names are listed in `/info`.
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "rag")]
+    pub chat_template: Option<String>,
+
     /// Options for streaming response. Only set this when you set stream: true.
     #[serde(default)]
     #[schema(nullable = true, example = "null")]
@@ -934,6 +1269,9 @@ impl ChatRequest {
             temperature,
             response_format,
             guideline,
+            documents,
+            continue_final_message,
+            chat_template,
             presence_penalty,
             frequency_penalty,
             top_p,
@@ -942,7 +1280,7 @@ impl ChatRequest {
         } = self;
 
         let repetition_penalty = presence_penalty.map(|x| x + 2.0);
-        let max_new_tokens = max_tokens.or(Some(100));
+        let max_new_tokens = max_tokens.or(infer.generation_defaults().max_new_tokens);
         let tool_prompt = tool_prompt
             .filter(|s| !s.is_empty())
             .unwrap_or_else(default_tool_prompt);
@@ -954,12 +1292,15 @@ impl ChatRequest {
         };
         let (inputs, grammar, using_tools) = prepare_chat_input(
             infer,
-            response_format,
+            response_format.and_then(ResponseFormat::into_grammar),
             tools,
             tool_choice,
             &tool_prompt,
+            chat_template.as_deref(),
             guideline,
+            documents,
             messages,
+            continue_final_message,
         )?;
 
         Ok((
@@ -993,6 +1334,15 @@ impl ChatRequest {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, ToSchema, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TruncationStrategy {
+    /// Drop the oldest turns
+    DropOldest,
+    /// Replace the oldest turns with a summary written by the model
+    Summarize,
+}
+
 #[derive(Clone, Deserialize, ToSchema, Serialize)]
 #[cfg_attr(test, derive(Debug, PartialEq))]
 struct StreamOptions {
@@ -1015,6 +1365,9 @@ pub enum ToolType {
     /// Means the model will not call any tool and instead generates a message.
     #[schema(rename = "none")]
     NoTool,
+    /// Means the model must call one or more tools.
+    #[schema(rename = "required")]
+    Required,
     /// Forces the model to call a specific tool.
     #[schema(rename = "function")]
     Function(FunctionName),
@@ -1034,9 +1387,16 @@ pub struct ToolChoice(pub Option<ToolType>);
 enum ToolTypeDeserializer {
     Null,
     String(String),
+    TypedChoice(TypedChoice),
     ToolType(ToolType),
 }
 
+/// `{"type": "function", "function": {"name": "my_function"}}`
+#[derive(Deserialize)]
+struct TypedChoice {
+    function: FunctionName,
+}
+
 impl From<ToolTypeDeserializer> for ToolChoice {
     fn from(value: ToolTypeDeserializer) -> Self {
         match value {
@@ -1044,8 +1404,12 @@ impl From<ToolTypeDeserializer> for ToolChoice {
             ToolTypeDeserializer::String(s) => match s.as_str() {
                 "none" => ToolChoice(Some(ToolType::NoTool)),
                 "auto" => ToolChoice(Some(ToolType::OneOf)),
+                "required" => ToolChoice(Some(ToolType::Required)),
                 _ => ToolChoice(Some(ToolType::Function(FunctionName { name: s }))),
             },
+            ToolTypeDeserializer::TypedChoice(TypedChoice { function }) => {
+                ToolChoice(Some(ToolType::Function(function)))
+            }
             ToolTypeDeserializer::ToolType(tool_type) => ToolChoice(Some(tool_type)),
         }
     }
@@ -1105,6 +1469,20 @@ pub(crate) struct Tool {
     pub function: FunctionDefinition,
 }
 
+/// A document grounding the answer of the model
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+#[cfg_attr(test, derive(PartialEq))]
+pub(crate) struct Document {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "Tall penguins")]
+    pub title: Option<String>,
+    #[schema(example = "Emperor penguins are the tallest.")]
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json!({"source": "penguins.txt"}))]
+    pub metadata: Option<serde_json::Value>,
+}
+
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub(crate) struct ChatTemplateInputs<'a> {
     messages: Vec<TextMessage>,
@@ -1112,6 +1490,7 @@ pub(crate) struct ChatTemplateInputs<'a> {
     eos_token: Option<&'a str>,
     add_generation_prompt: bool,
     tools: Option<Vec<Tool>>,
+    documents: Option<Vec<Document>>,
     guideline: Option<&'a str>,
 }
 
@@ -1176,6 +1555,10 @@ pub struct TextMessage {
     pub role: String,
     #[schema(example = "My name is David and I")]
     pub content: String,
+    /// Reasoning of thinking models, separated from the `content`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub reasoning_content: Option<String>,
 }
 
 impl From<Message> for TextMessage {
@@ -1193,6 +1576,7 @@ impl From<Message> for TextMessage {
                     .collect::<Vec<_>>()
                     .join(""),
             },
+            reasoning_content: None,
         }
     }
 }
@@ -1250,7 +1634,7 @@ impl From<CompatGenerateRequest> for GenerateRequest {
     }
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Debug, Serialize, ToSchema, Clone)]
 pub struct PrefillToken {
     #[schema(example = 0)]
     pub id: u32,
@@ -1284,7 +1668,7 @@ pub struct SimpleToken {
     stop: usize,
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Debug, Serialize, ToSchema, Clone)]
 #[serde(rename_all(serialize = "snake_case"))]
 #[schema(example = "Length")]
 pub enum FinishReason {
@@ -1380,6 +1764,9 @@ pub(crate) struct StreamDetails {
 
 #[derive(Serialize, ToSchema)]
 pub(crate) struct StreamResponse {
+    /// Prompt tokens, only set on the first token of the internal streams that ask for them
+    #[serde(skip)]
+    pub prefill: Vec<PrefillToken>,
     pub index: u32,
     pub token: Token,
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -1391,9 +1778,34 @@ pub(crate) struct StreamResponse {
 }
 
 #[derive(Serialize, ToSchema)]
+#[cfg_attr(test, derive(Debug))]
 pub(crate) struct ErrorResponse {
     pub error: String,
     pub error_type: String,
+    /// Stable machine-readable error code
+    #[schema(example = "invalid_temperature")]
+    pub code: String,
+    /// Request parameter that caused the error
+    #[schema(nullable = true, example = "temperature")]
+    pub param: Option<String>,
+}
+
+/// Error envelope of the OpenAI compatible routes
+#[derive(Serialize, ToSchema)]
+pub(crate) struct OpenAIErrorResponse {
+    pub error: OpenAIErrorObject,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct OpenAIErrorObject {
+    #[schema(example = "Input validation error: `temperature` must be strictly positive")]
+    pub message: String,
+    #[schema(example = "invalid_request_error")]
+    pub r#type: String,
+    #[schema(nullable = true, example = "temperature")]
+    pub param: Option<String>,
+    #[schema(nullable = true, example = "invalid_temperature")]
+    pub code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -1428,6 +1840,7 @@ impl Default for ModelsInfo {
 mod tests {
     use super::*;
     use serde_json::json;
+    use std::sync::Arc;
 
     pub(crate) fn get_tokenizer() -> Tokenizer {
         let api = hf_hub::api::sync::Api::new().unwrap();
@@ -1436,6 +1849,212 @@ mod tests {
         Tokenizer::Rust(tokenizers::Tokenizer::from_file(filename).unwrap())
     }
 
+    /// Word level tokenizer built without the network, every word or punctuation is a token.
+    /// Only the digits are in the vocabulary, for the grammars of integers.
+    pub(crate) fn get_offline_tokenizer() -> Tokenizer {
+        let tokenizer = r#"{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"[UNK]": 0, "0": 1, "1": 2, "2": 3, "3": 4, "4": 5, "5": 6, "6": 7, "7": 8, "8": 9, "9": 10},
+                "unk_token": "[UNK]"
+            }
+        }"#;
+        Tokenizer::Rust(tokenizer.parse().unwrap())
+    }
+
+    type MockStreamSender = tokio::sync::mpsc::UnboundedSender<
+        Result<crate::infer::InferStreamResponse, crate::infer::InferError>,
+    >;
+
+    /// Backend answering every request with `reply(inputs)`, one token per word, cut at
+    /// `max_new_tokens`
+    #[derive(Clone)]
+    pub(crate) struct MockBackend {
+        reply: Arc<dyn Fn(&str) -> String + Send + Sync>,
+        /// Inputs of the scheduled requests
+        pub(crate) requests: Arc<std::sync::Mutex<Vec<String>>>,
+        /// Streams of the requests that never finish, with `pending`
+        pub(crate) open_streams: Arc<std::sync::Mutex<Vec<MockStreamSender>>>,
+        pending: bool,
+    }
+
+    impl MockBackend {
+        pub(crate) fn new(reply: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+            Self {
+                reply: Arc::new(reply),
+                requests: Default::default(),
+                open_streams: Default::default(),
+                pending: false,
+            }
+        }
+
+        /// Send the reply without ever finishing the requests
+        pub(crate) fn pending(self) -> Self {
+            Self {
+                pending: true,
+                ..self
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl crate::infer::Backend for MockBackend {
+        fn schedule(
+            &self,
+            request: crate::validation::ValidGenerateRequest,
+        ) -> Result<
+            tokio_stream::wrappers::UnboundedReceiverStream<
+                Result<crate::infer::InferStreamResponse, crate::infer::InferError>,
+            >,
+            crate::infer::InferError,
+        > {
+            use crate::infer::{GeneratedText, InferStreamResponse};
+            use crate::validation::ChunksToString;
+
+            let inputs = request.inputs.chunks_to_string();
+            let text = (self.reply)(&inputs);
+            self.requests.lock().unwrap().push(inputs);
+
+            let mut words: Vec<String> = text.split_inclusive(' ').map(String::from).collect();
+            let max_new_tokens = request.stopping_parameters.max_new_tokens as usize;
+            let finish_reason = if words.len() > max_new_tokens {
+                words.truncate(max_new_tokens);
+                FinishReason::Length
+            } else {
+                FinishReason::EndOfSequenceToken
+            };
+            let text = words.concat();
+            let token = |id: usize, text: &str| Token {
+                id: id as u32,
+                text: text.to_string(),
+                logprob: -1.0,
+                special: false,
+            };
+            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            if self.pending {
+                for (id, word) in words.iter().enumerate() {
+                    let _ = sender.send(Ok(InferStreamResponse::Intermediate {
+                        token: token(id, word),
+                        top_tokens: vec![],
+                    }));
+                }
+                self.open_streams.lock().unwrap().push(sender);
+                return Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(
+                    receiver,
+                ));
+            }
+            for (id, word) in words.iter().enumerate().take(words.len().saturating_sub(1)) {
+                let _ = sender.send(Ok(InferStreamResponse::Intermediate {
+                    token: token(id, word),
+                    top_tokens: vec![],
+                }));
+            }
+            let now = tokio::time::Instant::now();
+            let _ = sender.send(Ok(InferStreamResponse::End {
+                token: token(words.len(), words.last().map_or("", String::as_str)),
+                top_tokens: vec![],
+                generated_text: GeneratedText {
+                    text,
+                    generated_tokens: words.len().max(1) as u32,
+                    finish_reason,
+                    seed: None,
+                },
+                start: now,
+                queued: now,
+            }));
+            Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(
+                receiver,
+            ))
+        }
+
+        async fn health(&self, _current_health: bool) -> bool {
+            true
+        }
+    }
+
+    /// Inference over `backend` with a context window of `max_total_tokens` and a chat template
+    /// rendering each message as `<|role|>content`
+    pub(crate) fn mock_infer(
+        backend: MockBackend,
+        max_total_tokens: usize,
+        max_concurrent_requests: usize,
+    ) -> crate::infer::Infer {
+        let tokenizer_config = HubTokenizerConfig {
+            chat_template: Some(ChatTemplateVersions::Single(
+                "{% for message in messages %}<|{{ message['role'] }}|> {{ message['content'] }} {% endfor %}{% if add_generation_prompt %}<|assistant|>{% endif %}".to_string(),
+            )),
+            ..Default::default()
+        };
+        mock_infer_with_tokenizer_config(
+            backend,
+            max_total_tokens,
+            max_concurrent_requests,
+            tokenizer_config,
+        )
+    }
+
+    /// Same as `mock_infer`, with the chat templates and tokens of `tokenizer_config`
+    pub(crate) fn mock_infer_with_tokenizer_config(
+        backend: MockBackend,
+        max_total_tokens: usize,
+        max_concurrent_requests: usize,
+        tokenizer_config: HubTokenizerConfig,
+    ) -> crate::infer::Infer {
+        let validation = crate::validation::Validation::new(
+            1,
+            get_offline_tokenizer(),
+            None,
+            None,
+            1,
+            4,
+            5,
+            max_total_tokens - 1,
+            max_total_tokens,
+            None,
+            false,
+        );
+        crate::infer::Infer::new(
+            backend,
+            validation,
+            max_concurrent_requests,
+            tokenizer_config,
+            HubProcessorConfig::default(),
+            None,
+            GenerationDefaults::default(),
+            None,
+        )
+    }
+
+    pub(crate) fn mock_info(max_total_tokens: usize) -> Info {
+        Info {
+            model_id: "mock".to_string(),
+            model_sha: None,
+            model_pipeline_tag: None,
+            max_concurrent_requests: 8,
+            max_best_of: 1,
+            max_stop_sequences: 4,
+            max_input_tokens: max_total_tokens - 1,
+            max_total_tokens,
+            default_max_new_tokens: None,
+            validation_workers: 1,
+            max_client_batch_size: 4,
+            chat_templates: vec!["default".to_string()],
+            generation_defaults: GenerationDefaults::default(),
+            router: "text-generation-router",
+            version: "0.0.0",
+            sha: None,
+            docker_label: None,
+        }
+    }
+
     #[test]
     fn test_hub_nested_tokens_tokenizer_config() {
         // this is a subset of the tokenizer.json file
@@ -1620,11 +2239,116 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_tool_choice() {
+        let choice =
+            |json: serde_json::Value| -> ToolChoice { serde_json::from_value(json).unwrap() };
+        let function = |name: &str| {
+            ToolChoice(Some(ToolType::Function(FunctionName {
+                name: name.to_string(),
+            })))
+        };
+
+        assert_eq!(choice(json!(null)), ToolChoice(None));
+        assert_eq!(choice(json!("auto")), ToolChoice(Some(ToolType::OneOf)));
+        assert_eq!(choice(json!("none")), ToolChoice(Some(ToolType::NoTool)));
+        assert_eq!(
+            choice(json!("required")),
+            ToolChoice(Some(ToolType::Required))
+        );
+        assert_eq!(
+            choice(json!("get_current_weather")),
+            function("get_current_weather")
+        );
+        assert_eq!(
+            choice(json!({"type": "function", "function": {"name": "get_current_weather"}})),
+            function("get_current_weather")
+        );
+    }
+
+    #[test]
+    fn test_response_format() {
+        let format =
+            |json: serde_json::Value| -> ResponseFormat { serde_json::from_value(json).unwrap() };
+
+        let schema = json!({"type": "array", "items": {"type": "integer"}});
+        let json_schema = format(json!({
+            "type": "json_schema",
+            "json_schema": {"name": "numbers", "schema": schema, "strict": true}
+        }));
+        assert_eq!(json_schema.strict_schema(), Some(&schema));
+        assert_eq!(json_schema.into_grammar(), Some(GrammarType::Json(schema)));
+
+        let json_object = format(json!({"type": "json_object"}));
+        assert_eq!(json_object.strict_schema(), None);
+        assert_eq!(
+            json_object.into_grammar(),
+            Some(GrammarType::Json(json!({"type": "object"})))
+        );
+        let schemaless = format(json!({"type": "json_schema", "json_schema": {"name": "any"}}));
+        assert_eq!(
+            schemaless.into_grammar(),
+            Some(GrammarType::Json(json!({"type": "object"})))
+        );
+        assert_eq!(format(json!({"type": "text"})).into_grammar(), None);
+
+        // The grammars of TGI are still accepted, `json_object` with a value included
+        let value = json!({"properties": {"location": {"type": "string"}}});
+        assert_eq!(
+            format(json!({"type": "json_object", "value": value})).into_grammar(),
+            Some(GrammarType::Json(value.clone()))
+        );
+        assert_eq!(
+            format(json!({"type": "json", "value": value})).into_grammar(),
+            Some(GrammarType::Json(value))
+        );
+    }
+
+    #[test]
+    fn test_generation_defaults() {
+        let config: HubGenerationConfig = serde_json::from_value(json!({
+            "do_sample": true,
+            "temperature": 0.6,
+            "top_p": 1.0,
+            "top_k": 20,
+            "repetition_penalty": 1.1,
+            "eos_token_id": [1, 2]
+        }))
+        .unwrap();
+        let defaults = GenerationDefaults::new(config);
+        assert_eq!(defaults.temperature, Some(0.6));
+        assert_eq!(defaults.top_p, None);
+        assert_eq!(defaults.top_k, Some(20));
+
+        // Greedy requests only get the repetition penalty
+        let mut parameters = GenerateParameters::default();
+        defaults.apply(&mut parameters);
+        assert_eq!(parameters.temperature, None);
+        assert_eq!(parameters.top_k, None);
+        assert_eq!(parameters.repetition_penalty, Some(1.1));
+
+        let mut parameters = GenerateParameters {
+            do_sample: true,
+            top_k: Some(5),
+            ..Default::default()
+        };
+        defaults.apply(&mut parameters);
+        assert_eq!(parameters.temperature, Some(0.6));
+        assert_eq!(parameters.top_k, Some(5));
+
+        // Without `do_sample`, the sampling parameters are not defaults
+        let config: HubGenerationConfig =
+            serde_json::from_value(json!({"temperature": 0.6, "eos_token_id": 2})).unwrap();
+        let defaults = GenerationDefaults::new(config);
+        assert_eq!(defaults.temperature, None);
+    }
+
     #[test]
     fn openai_output() {
         let message = OutputMessage::ChatMessage(TextMessage {
             role: "assistant".to_string(),
             content: "This is the answer".to_string(),
+            reasoning_content: None,
         });
         let serialized = serde_json::to_string(&message).unwrap();
         assert_eq!(
@@ -1652,4 +2376,90 @@ mod tests {
             r#"{"role":"assistant","tool_calls":[{"id":"0","type":"function","function":{"description":null,"name":"myfn","arguments":{"format":"csv"}}}]}"#
         );
     }
+
+    #[test]
+    fn test_completion_logprobs() {
+        let token = |text: &str, logprob: f32| Token {
+            id: 0,
+            text: text.to_string(),
+            logprob,
+            special: false,
+        };
+        let prefill = [
+            PrefillToken {
+                id: 0,
+                text: "Deep".to_string(),
+                logprob: f32::NAN,
+            },
+            PrefillToken {
+                id: 1,
+                text: " Learning".to_string(),
+                logprob: -1.5,
+            },
+        ];
+
+        let mut offset = 0;
+        let mut logprobs = CompletionLogprobs::default();
+        logprobs.push_prefill(&mut offset, &prefill, &[]);
+        logprobs.push_token(
+            &mut offset,
+            &token(" is", -0.5),
+            &[token(" is", -0.5), token(" was", -1.0)],
+        );
+        assert_eq!(offset, 16);
+
+        let serialized = serde_json::to_value(&logprobs).unwrap();
+        assert_eq!(
+            serialized,
+            json!({
+                "tokens": ["Deep", " Learning", " is"],
+                "token_logprobs": [null, -1.5, -0.5],
+                "top_logprobs": [null, null, {" is": -0.5, " was": -1.0}],
+                "text_offset": [0, 4, 13],
+            })
+        );
+    }
+
+    #[test]
+    fn test_completion_logprobs_skip_bos() {
+        let tokenizer: tokenizers::Tokenizer = r#"{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{"id": 0, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Whitespace"},
+            "post_processor": {
+                "type": "TemplateProcessing",
+                "single": [{"SpecialToken": {"id": "<s>", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}],
+                "pair": [{"Sequence": {"id": "A", "type_id": 0}}, {"Sequence": {"id": "B", "type_id": 1}}],
+                "special_tokens": {"<s>": {"id": "<s>", "ids": [0], "tokens": ["<s>"]}}
+            },
+            "decoder": null,
+            "model": {
+                "type": "WordLevel",
+                "vocab": {"<s>": 0, "Deep": 1, "Learning": 2, "[UNK]": 3},
+                "unk_token": "[UNK]"
+            }
+        }"#
+        .parse()
+        .unwrap();
+        let encoding = tokenizer.encode("Deep Learning", true).unwrap();
+        let prefill: Vec<PrefillToken> = ["<s>", "Deep", " Learning"]
+            .iter()
+            .zip(encoding.get_ids())
+            .map(|(text, id)| PrefillToken {
+                id: *id,
+                text: text.to_string(),
+                logprob: -1.0,
+            })
+            .collect();
+
+        let mut offset = 0;
+        let mut logprobs = CompletionLogprobs::default();
+        logprobs.push_prefill(&mut offset, &prefill, encoding.get_special_tokens_mask());
+        // The BOS token is not part of the echoed prompt
+        assert_eq!(logprobs.text_offset, vec![0, 0, 4]);
+        assert_eq!(offset, "Deep Learning".len() as u32);
+    }
 }
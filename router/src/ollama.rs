@@ -0,0 +1,854 @@
+/// Ollama API compatibility
+use crate::infer::Infer;
+use crate::server::{
+    format_tool_call, generate_internal, generate_stream_internal, parse_tool_call, ComputeType,
+};
+use crate::{
+    ChatRequest, ErrorResponse, FinishReason, GenerateParameters, GenerateRequest, GrammarType,
+    InferError, Info, Message, MessageChunk, MessageContent, ResponseFormat, Tool, ToolCall,
+    ToolChoice, Url,
+};
+use axum::body::Body;
+use axum::extract::Extension;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::time::Instant;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+/// Content type of the streamed responses, one JSON object per line
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Sampling options, the options that have no equivalent are ignored
+#[derive(Clone, Debug, Default, Deserialize, ToSchema)]
+pub(crate) struct OllamaOptions {
+    /// Maximum number of tokens to generate, -1 to fill the context
+    #[serde(default)]
+    #[schema(nullable = true, example = 128)]
+    pub num_predict: Option<i32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.8)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 40)]
+    pub top_k: Option<i32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.9)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 1.0)]
+    pub typical_p: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 1.1)]
+    pub repeat_penalty: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 0.0)]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    #[schema(nullable = true, example = 42)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(nullable = true, example = json ! (["\n\n"]))]
+    pub stop: Option<Vec<String>>,
+}
+
+impl OllamaOptions {
+    fn apply(self, parameters: &mut GenerateParameters) {
+        if let Some(num_predict) = self.num_predict {
+            // Negative values fill the context
+            parameters.max_new_tokens = u32::try_from(num_predict).ok();
+        }
+        // Greedy when temperature is 0
+        match self.temperature {
+            Some(temperature) if temperature == 0.0 => {
+                parameters.do_sample = false;
+                parameters.temperature = None;
+            }
+            Some(temperature) => {
+                parameters.do_sample = true;
+                parameters.temperature = Some(temperature);
+            }
+            None => {}
+        }
+        parameters.top_k = self.top_k.or(parameters.top_k);
+        parameters.top_p = self.top_p.or(parameters.top_p);
+        parameters.typical_p = self.typical_p.or(parameters.typical_p);
+        parameters.repetition_penalty = self.repeat_penalty.or(parameters.repetition_penalty);
+        parameters.frequency_penalty = self.frequency_penalty.or(parameters.frequency_penalty);
+        parameters.seed = self.seed.or(parameters.seed);
+        parameters.stop.extend(self.stop.unwrap_or_default());
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct OllamaGenerateRequest {
+    #[serde(default)]
+    #[schema(nullable = true, example = "tgi")]
+    pub model: Option<String>,
+    #[serde(default)]
+    #[schema(example = "Why is the sky blue?")]
+    pub prompt: String,
+    /// Base64 encoded images
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub images: Option<Vec<String>>,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub system: Option<String>,
+    /// Send the prompt as is, without the chat template
+    #[serde(default)]
+    pub raw: bool,
+    /// `json` or a JSON schema
+    #[serde(default)]
+    #[schema(nullable = true, value_type = Object, example = "json")]
+    pub format: Option<Value>,
+    #[serde(default = "crate::default_true")]
+    #[schema(default = true)]
+    pub stream: bool,
+    #[serde(default)]
+    #[schema(nullable = true)]
+    pub options: Option<OllamaOptions>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct OllamaChatRequest {
+    #[serde(default)]
+    #[schema(nullable = true, example = "tgi")]
+    pub model: Option<String>,
+    pub messages: Vec<OllamaMessage>,
+    #[serde(default)]
+    #[schema(nullable = true, example = "null")]
+    pub tools: Option<Vec<Tool>>,
+    /// `json` or a JSON schema
+    #[serde(default)]
+    #[schema(nullable = true, value_type = Object, example = "json")]
+    pub format: Option<Value>,
+    #[serde(default = "crate::default_true")]
+    #[schema(default = true)]
+    pub stream: bool,
+    #[serde(default)]
+    #[schema(nullable = true)]
+    pub options: Option<OllamaOptions>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, PartialEq)]
+pub(crate) struct OllamaMessage {
+    #[schema(example = "user")]
+    pub role: String,
+    #[serde(default)]
+    #[schema(example = "Why is the sky blue?")]
+    pub content: String,
+    /// Base64 encoded images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub images: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub tool_calls: Option<Vec<OllamaToolCall>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, PartialEq)]
+pub(crate) struct OllamaToolCall {
+    pub function: OllamaFunction,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema, PartialEq)]
+pub(crate) struct OllamaFunction {
+    #[schema(example = "get_weather")]
+    pub name: String,
+    #[schema(value_type = Object)]
+    pub arguments: Value,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaStats {
+    /// Time spent on the request, in nanoseconds
+    #[schema(example = 5043500667u64)]
+    pub total_duration: u64,
+    #[schema(example = 26)]
+    pub prompt_eval_count: u32,
+    #[schema(example = 290)]
+    pub eval_count: u32,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaGenerateResponse {
+    #[schema(example = "tgi")]
+    pub model: String,
+    #[schema(example = "2024-01-26T12:07:15.123456Z")]
+    pub created_at: String,
+    #[schema(example = "The sky is blue because")]
+    pub response: String,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "stop")]
+    pub done_reason: Option<String>,
+    #[serde(flatten)]
+    #[schema(nullable = true)]
+    pub stats: Option<OllamaStats>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaChatResponse {
+    #[schema(example = "tgi")]
+    pub model: String,
+    #[schema(example = "2024-01-26T12:07:15.123456Z")]
+    pub created_at: String,
+    pub message: OllamaMessage,
+    pub done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "stop")]
+    pub done_reason: Option<String>,
+    #[serde(flatten)]
+    #[schema(nullable = true)]
+    pub stats: Option<OllamaStats>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaModelDetails {
+    #[schema(example = "")]
+    pub parent_model: String,
+    #[schema(example = "safetensors")]
+    pub format: String,
+    #[schema(example = "text-generation")]
+    pub family: String,
+    #[schema(nullable = true, example = json ! (["text-generation"]))]
+    pub families: Option<Vec<String>>,
+    #[schema(example = "")]
+    pub parameter_size: String,
+    #[schema(example = "")]
+    pub quantization_level: String,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaModel {
+    #[schema(example = "bigscience/blomm-560m")]
+    pub name: String,
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model: String,
+    #[schema(example = "2024-01-26T12:07:15.123456Z")]
+    pub modified_at: String,
+    #[schema(example = 0)]
+    pub size: u64,
+    #[schema(example = "e985a63cdc139290c5f700ff1929f0b5942cced2")]
+    pub digest: String,
+    pub details: OllamaModelDetails,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaTags {
+    pub models: Vec<OllamaModel>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct OllamaShowRequest {
+    /// Only one model is served, the name is not checked
+    #[serde(default, alias = "name")]
+    #[schema(nullable = true, example = "tgi")]
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct OllamaShowResponse {
+    #[schema(example = "FROM bigscience/blomm-560m\nPARAMETER num_ctx 2048\n")]
+    pub modelfile: String,
+    #[schema(example = "num_ctx 2048")]
+    pub parameters: String,
+    pub details: OllamaModelDetails,
+    #[schema(value_type = Object, example = json ! ({"general.name": "bigscience/blomm-560m", "tgi.context_length": 2048}))]
+    pub model_info: serde_json::Map<String, Value>,
+    #[schema(example = json ! (["completion"]))]
+    pub capabilities: Vec<String>,
+}
+
+fn created_at() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+fn done_reason(finish_reason: &FinishReason) -> String {
+    match finish_reason {
+        FinishReason::Length => "length",
+        FinishReason::EndOfSequenceToken | FinishReason::StopSequence => "stop",
+    }
+    .to_string()
+}
+
+/// Ollama sends bare base64 images, the format is guessed to build a data URL
+fn image_url(data: &str) -> String {
+    // A few bytes are enough to recognize the format
+    let prefix = data.get(..64).unwrap_or(data);
+    let mimetype = STANDARD
+        .decode(prefix)
+        .ok()
+        .and_then(|bytes| image::guess_format(&bytes).ok())
+        .map(|format| format.to_mime_type())
+        .unwrap_or("image/png");
+    format!("data:{mimetype};base64,{data}")
+}
+
+/// `format` is either `json` or a JSON schema
+fn grammar(
+    format: Option<Value>,
+) -> Result<Option<GrammarType>, (StatusCode, Json<ErrorResponse>)> {
+    match format {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(format)) if format.is_empty() => Ok(None),
+        Some(Value::String(format)) if format == "json" => Ok(Some(GrammarType::Json(
+            serde_json::json!({"type": "object"}),
+        ))),
+        Some(schema @ Value::Object(_)) => Ok(Some(GrammarType::Json(schema))),
+        Some(format) => Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: format!("`format` must be `json` or a JSON schema, got {format}"),
+                error_type: "validation".to_string(),
+                code: "invalid_format".to_string(),
+                param: Some("format".to_string()),
+            }),
+        )),
+    }
+}
+
+fn convert_message(message: OllamaMessage) -> Message {
+    let mut text = message.content;
+    for tool_call in message.tool_calls.unwrap_or_default() {
+        text.push_str(&format_tool_call(
+            tool_call.function.name,
+            tool_call.function.arguments,
+        ));
+    }
+    let content = match message.images {
+        Some(images) if !images.is_empty() => MessageContent::MultipleChunks(
+            images
+                .iter()
+                .map(|data| MessageChunk::ImageUrl {
+                    image_url: Url {
+                        url: image_url(data),
+                    },
+                })
+                .chain(std::iter::once(MessageChunk::Text { text }))
+                .collect(),
+        ),
+        _ => MessageContent::SingleText(text),
+    };
+    Message {
+        role: message.role,
+        content,
+        name: None,
+    }
+}
+
+fn assistant_message(content: String, tool_calls: Option<Vec<ToolCall>>) -> OllamaMessage {
+    OllamaMessage {
+        role: "assistant".to_string(),
+        content,
+        images: None,
+        tool_calls: tool_calls.map(|tool_calls| {
+            tool_calls
+                .into_iter()
+                .map(|tool_call| OllamaToolCall {
+                    function: OllamaFunction {
+                        name: tool_call.function.name,
+                        arguments: tool_call.function.arguments,
+                    },
+                })
+                .collect()
+        }),
+    }
+}
+
+fn chat_request(
+    messages: Vec<Message>,
+    tools: Option<Vec<Tool>>,
+    response_format: Option<GrammarType>,
+    stream: bool,
+) -> ChatRequest {
+    ChatRequest {
+        model: None,
+        messages,
+        frequency_penalty: None,
+        logit_bias: None,
+        logprobs: None,
+        top_logprobs: None,
+        max_tokens: None,
+        n: None,
+        presence_penalty: None,
+        stop: None,
+        stream,
+        seed: None,
+        temperature: None,
+        top_p: None,
+        tools,
+        tool_prompt: None,
+        tool_choice: ToolChoice::default(),
+        response_format: response_format.map(ResponseFormat::Grammar),
+        guideline: None,
+        documents: None,
+        continue_final_message: false,
+        echo: false,
+        include_reasoning: true,
+        max_reasoning_tokens: None,
+        truncation_strategy: None,
+        chat_template: None,
+        stream_options: None,
+    }
+}
+
+/// Stream newline-delimited JSON, errors are sent as `{"error": ...}` lines
+fn ndjson<T: Serialize>(
+    headers: HeaderMap,
+    stream: impl Stream<Item = Result<T, InferError>> + Send + 'static,
+) -> Response {
+    let lines = stream.map(|item| {
+        let mut line = match item {
+            // Unwrap is safe here, the responses only contain serializable types
+            Ok(item) => serde_json::to_string(&item).unwrap(),
+            Err(err) => {
+                let (_, Json(error)) = <(StatusCode, Json<ErrorResponse>)>::from(err);
+                serde_json::to_string(&error).unwrap()
+            }
+        };
+        line.push('\n');
+        Ok::<String, Infallible>(line)
+    });
+    let mut response = (headers, Body::from_stream(lines)).into_response();
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(NDJSON_CONTENT_TYPE));
+    response
+}
+
+/// Generate a completion with the Ollama API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/api/generate",
+request_body = OllamaGenerateRequest,
+responses(
+(status = 200, description = "Generated Text", body = OllamaGenerateResponse),
+(status = 200, description = "Generated Text Stream", body = OllamaGenerateResponse,
+content_type = "application/x-ndjson"),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+(status = 500, description = "Incomplete generation", body = ErrorResponse,
+example = json ! ({"error": "Incomplete generation"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(
+        total_time,
+        validation_time,
+        queue_time,
+        inference_time,
+        time_per_token,
+        seed,
+    )
+)]
+pub(crate) async fn ollama_generate(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    Json(req): Json<OllamaGenerateRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    let model = req.model.unwrap_or(info.model_id);
+    let grammar = grammar(req.format)?;
+    let mut generate_request = if req.raw {
+        // Images are passed inline, like in `/generate`
+        let mut inputs = String::new();
+        for data in req.images.iter().flatten() {
+            inputs.push_str(&format!("![]({})", image_url(data)));
+        }
+        inputs.push_str(&req.prompt);
+        GenerateRequest {
+            inputs,
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                do_sample: true,
+                details: true,
+                decoder_input_details: !req.stream,
+                grammar,
+                ..Default::default()
+            },
+        }
+    } else {
+        let mut messages = Vec::new();
+        if let Some(system) = req.system {
+            messages.push(Message {
+                role: "system".to_string(),
+                content: MessageContent::SingleText(system),
+                name: None,
+            });
+        }
+        messages.push(convert_message(OllamaMessage {
+            role: "user".to_string(),
+            content: req.prompt,
+            images: req.images,
+            tool_calls: None,
+        }));
+        let (generate_request, _) =
+            chat_request(messages, None, grammar, req.stream).try_into_generate(&infer)?;
+        generate_request
+    };
+    req.options
+        .unwrap_or_default()
+        .apply(&mut generate_request.parameters);
+
+    if !req.stream {
+        let (headers, Json(generation)) =
+            generate_internal(Extension(infer), compute_type, Json(generate_request), span).await?;
+        // Unwrap is safe here, details are always requested
+        let details = generation.details.unwrap();
+        let response = OllamaGenerateResponse {
+            model,
+            created_at: created_at(),
+            response: generation.generated_text,
+            done: true,
+            done_reason: Some(done_reason(&details.finish_reason)),
+            stats: Some(OllamaStats {
+                total_duration: start_time.elapsed().as_nanos() as u64,
+                prompt_eval_count: details.prefill.len() as u32,
+                eval_count: details.generated_tokens,
+            }),
+        };
+        return Ok((headers, Json(response)).into_response());
+    }
+
+    let (headers, response_stream) =
+        generate_stream_internal(infer, compute_type, Json(generate_request), span).await;
+    let response_stream = response_stream.map(move |response| {
+        let response = response?;
+        let text = if response.token.special {
+            String::new()
+        } else {
+            response.token.text
+        };
+        let (done_reason, stats) = match response.details {
+            Some(details) => (
+                Some(done_reason(&details.finish_reason)),
+                Some(OllamaStats {
+                    total_duration: start_time.elapsed().as_nanos() as u64,
+                    prompt_eval_count: details.input_length,
+                    eval_count: details.generated_tokens,
+                }),
+            ),
+            None => (None, None),
+        };
+        Ok(OllamaGenerateResponse {
+            model: model.clone(),
+            created_at: created_at(),
+            response: text,
+            done: stats.is_some(),
+            done_reason,
+            stats,
+        })
+    });
+    Ok(ndjson(headers, response_stream))
+}
+
+/// Generate a chat message with the Ollama API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/api/chat",
+request_body = OllamaChatRequest,
+responses(
+(status = 200, description = "Generated Message", body = OllamaChatResponse),
+(status = 200, description = "Generated Message Stream", body = OllamaChatResponse,
+content_type = "application/x-ndjson"),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+(status = 500, description = "Incomplete generation", body = ErrorResponse,
+example = json ! ({"error": "Incomplete generation"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(
+        total_time,
+        validation_time,
+        queue_time,
+        inference_time,
+        time_per_token,
+        seed,
+    )
+)]
+pub(crate) async fn ollama_chat(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    Json(req): Json<OllamaChatRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let start_time = Instant::now();
+
+    let model = req.model.unwrap_or(info.model_id);
+    let messages = req.messages.into_iter().map(convert_message).collect();
+    let chat = chat_request(messages, req.tools, grammar(req.format)?, req.stream);
+    let (mut generate_request, using_tools) = chat.try_into_generate(&infer)?;
+    req.options
+        .unwrap_or_default()
+        .apply(&mut generate_request.parameters);
+
+    if !req.stream {
+        let (headers, Json(generation)) =
+            generate_internal(Extension(infer), compute_type, Json(generate_request), span).await?;
+        // Unwrap is safe here, chat requests always ask for details
+        let details = generation.details.unwrap();
+        let message = if using_tools {
+            let (tool_calls, content) = parse_tool_call(&generation.generated_text)?;
+            assistant_message(content.unwrap_or_default(), tool_calls)
+        } else {
+            assistant_message(generation.generated_text, None)
+        };
+        let response = OllamaChatResponse {
+            model,
+            created_at: created_at(),
+            message,
+            done: true,
+            done_reason: Some(done_reason(&details.finish_reason)),
+            stats: Some(OllamaStats {
+                total_duration: start_time.elapsed().as_nanos() as u64,
+                prompt_eval_count: details.prefill.len() as u32,
+                eval_count: details.generated_tokens,
+            }),
+        };
+        return Ok((headers, Json(response)).into_response());
+    }
+
+    let (headers, response_stream) =
+        generate_stream_internal(infer, compute_type, Json(generate_request), span).await;
+    let response_stream = async_stream::stream! {
+        let mut response_stream = Box::pin(response_stream);
+        // Tool calls are only sent once complete, in the last message
+        let mut tool_buffer = String::new();
+        while let Some(response) = response_stream.next().await {
+            let response = match response {
+                Ok(response) => response,
+                Err(err) => {
+                    yield Err(err);
+                    return;
+                }
+            };
+            let text = if response.token.special {
+                String::new()
+            } else {
+                response.token.text
+            };
+            let Some(details) = response.details else {
+                if using_tools {
+                    tool_buffer.push_str(&text);
+                } else {
+                    yield Ok(OllamaChatResponse {
+                        model: model.clone(),
+                        created_at: created_at(),
+                        message: assistant_message(text, None),
+                        done: false,
+                        done_reason: None,
+                        stats: None,
+                    });
+                }
+                continue;
+            };
+
+            let message = if using_tools {
+                tool_buffer.push_str(&text);
+                match parse_tool_call(&tool_buffer) {
+                    Ok((tool_calls, content)) => {
+                        assistant_message(content.unwrap_or_default(), tool_calls)
+                    }
+                    Err(err) => {
+                        yield Err(err);
+                        return;
+                    }
+                }
+            } else {
+                assistant_message(text, None)
+            };
+            yield Ok(OllamaChatResponse {
+                model: model.clone(),
+                created_at: created_at(),
+                message,
+                done: true,
+                done_reason: Some(done_reason(&details.finish_reason)),
+                stats: Some(OllamaStats {
+                    total_duration: start_time.elapsed().as_nanos() as u64,
+                    prompt_eval_count: details.input_length,
+                    eval_count: details.generated_tokens,
+                }),
+            });
+        }
+    };
+    Ok(ndjson(headers, response_stream))
+}
+
+fn model_details(info: &Info) -> OllamaModelDetails {
+    let family = info.model_pipeline_tag.clone().unwrap_or_default();
+    OllamaModelDetails {
+        parent_model: String::new(),
+        format: "safetensors".to_string(),
+        families: Some(vec![family.clone()]),
+        family,
+        parameter_size: String::new(),
+        quantization_level: String::new(),
+    }
+}
+
+/// List the served model with the Ollama API
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/api/tags",
+responses((status = 200, description = "Served model", body = OllamaTags))
+)]
+#[instrument(skip(info))]
+pub(crate) async fn ollama_tags(Extension(info): Extension<Info>) -> Json<OllamaTags> {
+    Json(OllamaTags {
+        models: vec![OllamaModel {
+            name: info.model_id.clone(),
+            model: info.model_id.clone(),
+            modified_at: created_at(),
+            size: 0,
+            digest: info.model_sha.clone().unwrap_or_default(),
+            details: model_details(&info),
+        }],
+    })
+}
+
+/// Show the served model with the Ollama API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/api/show",
+request_body = OllamaShowRequest,
+responses((status = 200, description = "Served model", body = OllamaShowResponse))
+)]
+#[instrument(skip_all, fields(model = req.model.as_deref()))]
+pub(crate) async fn ollama_show(
+    Extension(info): Extension<Info>,
+    Json(req): Json<OllamaShowRequest>,
+) -> Json<OllamaShowResponse> {
+    let parameters = format!("num_ctx {}", info.max_total_tokens);
+    let model_info = serde_json::json!({
+        "general.name": info.model_id,
+        "general.revision": info.model_sha,
+        "tgi.context_length": info.max_total_tokens,
+        "tgi.max_input_tokens": info.max_input_tokens,
+        "tgi.max_concurrent_requests": info.max_concurrent_requests,
+        "tgi.max_stop_sequences": info.max_stop_sequences,
+        "tgi.version": info.version,
+    });
+    let Value::Object(model_info) = model_info else {
+        unreachable!()
+    };
+    Json(OllamaShowResponse {
+        modelfile: format!("FROM {}\nPARAMETER {parameters}\n", info.model_id),
+        parameters,
+        details: model_details(&info),
+        model_info,
+        capabilities: vec!["completion".to_string()],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        let options: OllamaOptions = serde_json::from_value(serde_json::json!({
+            "num_predict": -1,
+            "num_ctx": 4096,
+            "temperature": 0.0,
+            "repeat_penalty": 1.1,
+            "stop": ["\n"]
+        }))
+        .unwrap();
+        let mut parameters = GenerateParameters {
+            do_sample: true,
+            temperature: Some(0.7),
+            max_new_tokens: Some(100),
+            ..Default::default()
+        };
+        options.apply(&mut parameters);
+
+        assert_eq!(parameters.max_new_tokens, None);
+        assert!(!parameters.do_sample);
+        assert_eq!(parameters.temperature, None);
+        assert_eq!(parameters.repetition_penalty, Some(1.1));
+        assert_eq!(parameters.stop, vec!["\n".to_string()]);
+    }
+
+    #[test]
+    fn test_convert_message() {
+        // A 1x1 GIF
+        let pixel = "R0lGODlhAQABAIAAAP///wAAACH5BAEAAAAALAAAAAABAAEAAAICRAEAOw==";
+        let message = convert_message(OllamaMessage {
+            role: "user".to_string(),
+            content: "What is this?".to_string(),
+            images: Some(vec![pixel.to_string()]),
+            tool_calls: None,
+        });
+        assert_eq!(
+            message.content,
+            MessageContent::MultipleChunks(vec![
+                MessageChunk::ImageUrl {
+                    image_url: Url {
+                        url: format!("data:image/gif;base64,{pixel}")
+                    }
+                },
+                MessageChunk::Text {
+                    text: "What is this?".to_string()
+                },
+            ])
+        );
+
+        let message = convert_message(OllamaMessage {
+            role: "assistant".to_string(),
+            content: String::new(),
+            images: None,
+            tool_calls: Some(vec![OllamaToolCall {
+                function: OllamaFunction {
+                    name: "get_weather".to_string(),
+                    arguments: serde_json::json!({"city": "Paris"}),
+                },
+            }]),
+        });
+        assert_eq!(
+            message.content,
+            MessageContent::SingleText(
+                r#"{"function":{"_name":"get_weather","city":"Paris"}}"#.to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_grammar() {
+        assert!(grammar(None).unwrap().is_none());
+        assert!(matches!(
+            grammar(Some(Value::String("json".to_string()))).unwrap(),
+            Some(GrammarType::Json(_))
+        ));
+        assert!(grammar(Some(Value::String("yaml".to_string()))).is_err());
+    }
+}
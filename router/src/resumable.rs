@@ -0,0 +1,372 @@
+/// Server-Sent Event streams that survive client disconnects
+use crate::ErrorResponse;
+use axum::extract::{Extension, Path};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::Json;
+use futures::{Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::instrument;
+
+/// Header used to return the id of a resumable stream
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Number of events kept per stream for the clients resuming it
+const MAX_BUFFERED_EVENTS: usize = 4096;
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>;
+
+/// Registry of the in-flight Server-Sent Event streams
+///
+/// Every event gets a monotonically increasing `id`. Streams are driven in the background so
+/// that a client disconnect does not cancel the request right away: events are buffered and the
+/// client can resume from its `Last-Event-ID`. Only the last `capacity` events are buffered.
+/// The request is cancelled if no client reconnects within `ttl`, and the buffer of a finished
+/// stream is kept for `ttl`.
+#[derive(Clone)]
+pub(crate) struct ResumableStreams {
+    streams: Arc<Mutex<HashMap<String, Arc<SharedStream>>>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+struct SharedStream {
+    state: Mutex<StreamState>,
+    /// Notified every time an event is pushed or the stream ends
+    events: watch::Sender<()>,
+    /// Number of connected clients
+    subscribers: watch::Sender<usize>,
+}
+
+#[derive(Default)]
+struct StreamState {
+    /// The last events, `events[i]` has the id `evicted + i + 1`
+    events: VecDeque<Event>,
+    /// Number of events dropped from the front of the buffer
+    evicted: usize,
+    done: bool,
+}
+
+/// Error returned when a stream cannot be resumed
+#[derive(Debug, PartialEq)]
+pub(crate) enum ResumeError {
+    NotFound,
+    /// The events after `Last-Event-ID` are no longer buffered
+    Evicted {
+        oldest: usize,
+    },
+}
+
+impl ResumableStreams {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            capacity: MAX_BUFFERED_EVENTS,
+        }
+    }
+
+    /// Drive `stream` in the background and return its request id and a client stream
+    pub(crate) fn register(
+        &self,
+        stream: impl Stream<Item = Result<Event, Infallible>> + Send + 'static,
+    ) -> (String, impl Stream<Item = Result<Event, Infallible>>) {
+        let request_id = uuid::Uuid::new_v4().simple().to_string();
+        let shared = Arc::new(SharedStream {
+            state: Mutex::new(StreamState::default()),
+            events: watch::Sender::new(()),
+            subscribers: watch::Sender::new(0),
+        });
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), shared.clone());
+
+        // Subscribe before spawning so that the stream is not considered disconnected
+        let subscription = subscribe(shared.clone(), 0);
+        tokio::spawn(self.clone().produce(
+            request_id.clone(),
+            shared,
+            Box::pin(stream) as EventStream,
+        ));
+        (request_id, subscription)
+    }
+
+    /// Resume the stream `request_id` after the event `last_event_id`
+    pub(crate) fn resume(
+        &self,
+        request_id: &str,
+        last_event_id: u64,
+    ) -> Result<impl Stream<Item = Result<Event, Infallible>>, ResumeError> {
+        let shared = self
+            .streams
+            .lock()
+            .unwrap()
+            .get(request_id)
+            .cloned()
+            .ok_or(ResumeError::NotFound)?;
+        let evicted = shared.state.lock().unwrap().evicted;
+        if (last_event_id as usize) < evicted {
+            return Err(ResumeError::Evicted {
+                oldest: evicted + 1,
+            });
+        }
+        Ok(subscribe(shared, last_event_id as usize))
+    }
+
+    async fn produce(self, request_id: String, shared: Arc<SharedStream>, mut stream: EventStream) {
+        let mut subscribers = shared.subscribers.subscribe();
+        let mut deadline = None;
+        loop {
+            if *subscribers.borrow_and_update() == 0 {
+                deadline = deadline.or_else(|| Some(Instant::now() + self.ttl));
+            } else {
+                deadline = None;
+            }
+
+            let event = tokio::select! {
+                event = stream.next() => event,
+                _ = subscribers.changed() => continue,
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    // Dropping the stream cancels the request
+                    tracing::debug!("No client reconnected to stream {request_id}, cancelling");
+                    self.streams.lock().unwrap().remove(&request_id);
+                    return;
+                }
+            };
+
+            let mut state = shared.state.lock().unwrap();
+            match event {
+                Some(Ok(event)) => {
+                    let id = state.evicted + state.events.len() + 1;
+                    state.events.push_back(event.id(id.to_string()));
+                    if state.events.len() > self.capacity {
+                        state.events.pop_front();
+                        state.evicted += 1;
+                    }
+                }
+                Some(Err(infallible)) => match infallible {},
+                None => state.done = true,
+            }
+            let done = state.done;
+            drop(state);
+            shared.events.send_replace(());
+
+            if done {
+                break;
+            }
+        }
+
+        // Keep the buffer around for the clients that disconnected right before the end
+        tokio::time::sleep(self.ttl).await;
+        self.streams.lock().unwrap().remove(&request_id);
+    }
+}
+
+/// Decrements the number of subscribers when a client disconnects
+struct SubscriberGuard(Arc<SharedStream>);
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.0.subscribers.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Client stream replaying the buffered events after `start`, then following the stream
+///
+/// A client falling more than the buffer behind is disconnected, resuming then fails explicitly.
+fn subscribe(
+    shared: Arc<SharedStream>,
+    start: usize,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    shared.subscribers.send_modify(|count| *count += 1);
+    let guard = SubscriberGuard(shared.clone());
+    let mut notifications = shared.events.subscribe();
+
+    async_stream::stream! {
+        let _guard = guard;
+        let mut next = start;
+        loop {
+            notifications.borrow_and_update();
+            let (events, done) = {
+                let state = shared.state.lock().unwrap();
+                if next < state.evicted {
+                    break;
+                }
+                let events: Vec<_> = state
+                    .events
+                    .range((next - state.evicted).min(state.events.len())..)
+                    .cloned()
+                    .collect();
+                (events, state.done)
+            };
+            next += events.len();
+            for event in events {
+                yield Ok(event);
+            }
+            if done || notifications.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Resume a Server-Sent Events stream after a disconnect
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/streams/{request_id}",
+params(
+("request_id" = String, Path, description = "The `x-request-id` header of the stream"),
+("Last-Event-ID" = Option<u64>, Header, description = "The id of the last event received"),
+),
+responses(
+(status = 200, description = "Remaining events", body = String,
+content_type = "text/event-stream"),
+(status = 404, description = "Stream not found or expired", body = ErrorResponse,
+example = json ! ({"error": "Stream 6f1a2c0e9b8d4a3f8e7d6c5b4a392817 not found", "error_type": "not_found"})),
+(status = 410, description = "The events after Last-Event-ID are no longer buffered", body = ErrorResponse,
+example = json ! ({"error": "Stream 6f1a2c0e9b8d4a3f8e7d6c5b4a392817 only buffers the events from id 5000", "error_type": "gone"})),
+)
+)]
+#[instrument(skip(streams, headers))]
+pub(crate) async fn resume_stream(
+    Extension(streams): Extension<ResumableStreams>,
+    Path(request_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<
+    (
+        HeaderMap,
+        Sse<impl Stream<Item = Result<Event, Infallible>>>,
+    ),
+    (StatusCode, Json<ErrorResponse>),
+> {
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let stream = streams
+        .resume(&request_id, last_event_id)
+        .map_err(|err| match err {
+            ResumeError::NotFound => (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse {
+                    error: format!("Stream {request_id} not found"),
+                    error_type: "not_found".to_string(),
+                    code: "not_found".to_string(),
+                    param: None,
+                }),
+            ),
+            ResumeError::Evicted { oldest } => (
+                StatusCode::GONE,
+                Json(ErrorResponse {
+                    error: format!("Stream {request_id} only buffers the events from id {oldest}"),
+                    error_type: "gone".to_string(),
+                    code: "event_evicted".to_string(),
+                    param: Some("Last-Event-ID".to_string()),
+                }),
+            ),
+        })?;
+    tracing::debug!("Resuming stream {request_id} after event {last_event_id}");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(REQUEST_ID_HEADER, request_id.parse().unwrap());
+    headers.insert("X-Accel-Buffering", "no".parse().unwrap());
+    Ok((headers, Sse::new(stream).keep_alive(KeepAlive::default())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    type EventSender = mpsc::UnboundedSender<Result<Event, Infallible>>;
+
+    fn event_stream() -> (
+        EventSender,
+        UnboundedReceiverStream<Result<Event, Infallible>>,
+    ) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (sender, UnboundedReceiverStream::new(receiver))
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_disconnect() {
+        let streams = ResumableStreams::new(Duration::from_secs(60));
+        let (sender, stream) = event_stream();
+        let (request_id, client) = streams.register(stream);
+        let mut client = Box::pin(client);
+
+        sender.send(Ok(Event::default().data("a"))).unwrap();
+        let first = client.next().await.unwrap().unwrap();
+        assert!(format!("{first:?}").contains("id: 1"));
+
+        // The client disconnects, the request keeps running
+        drop(client);
+        sender.send(Ok(Event::default().data("b"))).unwrap();
+        sender.send(Ok(Event::default().data("c"))).unwrap();
+        drop(sender);
+
+        let resumed: Vec<_> = streams.resume(&request_id, 1).unwrap().collect().await;
+        assert_eq!(resumed.len(), 2);
+        assert!(format!("{:?}", resumed[0]).contains("id: 2"));
+        assert!(format!("{:?}", resumed[1]).contains("id: 3"));
+
+        assert_eq!(
+            streams.resume("unknown", 0).err(),
+            Some(ResumeError::NotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_evicted_event() {
+        let streams = ResumableStreams {
+            capacity: 2,
+            ..ResumableStreams::new(Duration::from_secs(60))
+        };
+        let (sender, stream) = event_stream();
+        let (request_id, client) = streams.register(stream);
+        drop(client);
+        for data in ["a", "b", "c", "d"] {
+            sender.send(Ok(Event::default().data(data))).unwrap();
+        }
+        drop(sender);
+
+        // Collecting waits for the end of the stream
+        let resumed: Vec<_> = streams.resume(&request_id, 2).unwrap().collect().await;
+        assert_eq!(resumed.len(), 2);
+        assert!(format!("{:?}", resumed[0]).contains("id: 3"));
+        assert!(format!("{:?}", resumed[1]).contains("id: 4"));
+
+        assert_eq!(
+            streams.resume(&request_id, 1).err(),
+            Some(ResumeError::Evicted { oldest: 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_after_ttl() {
+        let streams = ResumableStreams::new(Duration::from_millis(10));
+        let (sender, stream) = event_stream();
+        let (request_id, client) = streams.register(stream);
+        drop(client);
+
+        // Nobody reconnected, the request stream is dropped
+        tokio::time::timeout(Duration::from_secs(5), sender.closed())
+            .await
+            .unwrap();
+        assert_eq!(
+            streams.resume(&request_id, 0).err(),
+            Some(ResumeError::NotFound)
+        );
+    }
+}
@@ -1,12 +1,12 @@
 use crate::infer::Infer;
+use crate::resumable::ResumableStreams;
 use crate::server::{chat_completions, compat_generate, completions, ComputeType};
 use crate::{
     ChatCompletion, ChatCompletionChunk, ChatRequest, Chunk, CompatGenerateRequest,
-    CompletionFinal, CompletionRequest, ErrorResponse, GenerateResponse, Info, StreamResponse,
+    CompletionFinal, CompletionRequest, GenerateResponse, Info, StreamResponse,
 };
 use axum::extract::Extension;
-use axum::http::StatusCode;
-use axum::response::Response;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
@@ -68,15 +68,29 @@ pub(crate) async fn sagemaker_compatibility(
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
     info: Extension<Info>,
+    streams: Extension<ResumableStreams>,
     Json(req): Json<SagemakerRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Response, Response> {
     match req {
-        SagemakerRequest::Generate(req) => {
-            compat_generate(default_return_full_text, infer, compute_type, Json(req)).await
+        SagemakerRequest::Generate(req) => compat_generate(
+            default_return_full_text,
+            infer,
+            compute_type,
+            streams,
+            Json(req),
+        )
+        .await
+        .map_err(IntoResponse::into_response),
+        // The messages API answers errors in the OpenAI envelope
+        SagemakerRequest::Chat(req) => {
+            chat_completions(infer, compute_type, info, streams, Json(req))
+                .await
+                .map_err(IntoResponse::into_response)
         }
-        SagemakerRequest::Chat(req) => chat_completions(infer, compute_type, info, Json(req)).await,
         SagemakerRequest::Completion(req) => {
-            completions(infer, compute_type, info, Json(req)).await
+            completions(infer, compute_type, info, streams, Json(req))
+                .await
+                .map_err(IntoResponse::into_response)
         }
     }
 }
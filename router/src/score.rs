@@ -0,0 +1,268 @@
+/// Likelihood scoring of texts and candidate continuations
+use crate::infer::Infer;
+use crate::server::{generate_internal, ComputeType};
+use crate::{ErrorResponse, GenerateParameters, GenerateRequest, Info, PrefillToken};
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct ScoreRequest {
+    /// The text to score, or the prompt shared by the `candidates`
+    #[schema(example = "The capital of France is")]
+    pub inputs: String,
+    /// Continuations of `inputs` to score, only their tokens are scored
+    #[serde(default)]
+    #[schema(nullable = true, example = json ! ([" Paris", " Lyon"]))]
+    pub candidates: Option<Vec<String>>,
+    #[serde(default = "default_add_special_tokens")]
+    #[schema(default = "true")]
+    pub add_special_tokens: bool,
+}
+
+fn default_add_special_tokens() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct Score {
+    /// The scored text, `inputs` or a candidate
+    #[schema(example = " Paris")]
+    pub text: String,
+    /// The scored tokens, the first token of a text has no log probability
+    pub tokens: Vec<PrefillToken>,
+    /// Sum of the log probabilities of the tokens
+    #[schema(example = -0.42)]
+    pub total_logprob: f32,
+    /// Mean of the log probabilities of the tokens
+    #[schema(example = -0.42)]
+    pub mean_logprob: f32,
+    /// `exp(-mean_logprob)`
+    #[schema(example = 1.52)]
+    pub perplexity: f32,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ScoreResponse {
+    /// One score per candidate, or a single score for `inputs`
+    pub scores: Vec<Score>,
+}
+
+impl Score {
+    fn new(text: String, tokens: Vec<PrefillToken>) -> Self {
+        let logprobs: Vec<f32> = tokens
+            .iter()
+            .map(|token| token.logprob)
+            .filter(|logprob| !logprob.is_nan())
+            .collect();
+        let total_logprob: f32 = logprobs.iter().sum();
+        let mean_logprob = if logprobs.is_empty() {
+            0.0
+        } else {
+            total_logprob / logprobs.len() as f32
+        };
+        Self {
+            text,
+            tokens,
+            total_logprob,
+            mean_logprob,
+            perplexity: (-mean_logprob).exp(),
+        }
+    }
+}
+
+/// Prefix caching is resolved by the launcher and shared with the router through the environment
+fn prefix_caching() -> bool {
+    std::env::var("PREFIX_CACHING")
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Index of the first token of the candidate in the encoding of `prompt + candidate`
+///
+/// The tokenizer can merge the end of the prompt with the start of the candidate, such a token is
+/// scored with the candidate. Encodings without offsets fall back to the length of the prompt.
+fn candidate_start(encoding: &tokenizers::Encoding, prompt: &str, prompt_length: usize) -> usize {
+    let offsets = encoding.get_offsets();
+    if offsets.len() != encoding.len() {
+        return prompt_length;
+    }
+    offsets
+        .iter()
+        .position(|&(_, end)| end > prompt.len())
+        .unwrap_or(offsets.len())
+}
+
+/// Score a text or candidate continuations of a prompt
+///
+/// The requests only prefill the texts, the token generated by the model is discarded.
+/// When prefix caching is enabled, the first candidate is scored alone so that the other
+/// candidates reuse the cached prompt.
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/score",
+request_body = ScoreRequest,
+responses(
+(status = 200, description = "Scores", body = ScoreResponse),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Number of candidates exceeds the maximum allowed batch size of 4", "error_type": "validation"})),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation", "error_type": "generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded", "error_type": "overloaded"})),
+)
+)]
+#[instrument(skip_all)]
+pub(crate) async fn score(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    Json(req): Json<ScoreRequest>,
+) -> Result<Json<ScoreResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let ScoreRequest {
+        inputs,
+        candidates,
+        add_special_tokens,
+    } = req;
+
+    let prefill = |text: String| {
+        let request = GenerateRequest {
+            inputs: text,
+            add_special_tokens,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(1),
+                details: true,
+                decoder_input_details: true,
+                ..Default::default()
+            },
+        };
+        let infer = Extension(infer.clone());
+        let compute_type = compute_type.clone();
+        let span = span.clone();
+        async move {
+            let (_, Json(response)) =
+                generate_internal(infer, compute_type, Json(request), span).await?;
+            Ok::<_, (StatusCode, Json<ErrorResponse>)>(
+                response
+                    .details
+                    .map(|details| details.prefill)
+                    .unwrap_or_default(),
+            )
+        }
+    };
+
+    let Some(candidates) = candidates else {
+        let tokens = prefill(inputs.clone()).await?;
+        return Ok(Json(ScoreResponse {
+            scores: vec![Score::new(inputs, tokens)],
+        }));
+    };
+
+    if candidates.len() > info.max_client_batch_size {
+        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(ErrorResponse {
+                error: format!(
+                    "Number of candidates exceeds the maximum allowed batch size of {}",
+                    info.max_client_batch_size
+                ),
+                error_type: "validation".to_string(),
+                code: "too_many_candidates".to_string(),
+                param: Some("candidates".to_string()),
+            }),
+        ));
+    }
+
+    let tokenize = |text: String| async {
+        infer
+            .tokenize(GenerateRequest {
+                inputs: text,
+                add_special_tokens,
+                parameters: GenerateParameters::default(),
+            })
+            .await
+            .map_err(<(StatusCode, Json<ErrorResponse>)>::from)
+    };
+    let prompt_length = tokenize(inputs.clone()).await?.len();
+    let texts: Vec<String> = candidates
+        .iter()
+        .map(|candidate| format!("{inputs}{candidate}"))
+        .collect();
+    let starts: Vec<usize> = try_join_all(texts.iter().cloned().map(tokenize))
+        .await?
+        .iter()
+        .map(|encoding| candidate_start(encoding, &inputs, prompt_length))
+        .collect();
+
+    let mut texts = texts.into_iter();
+    let mut prefills = Vec::with_capacity(candidates.len());
+    if prefix_caching() {
+        if let Some(text) = texts.next() {
+            prefills.push(prefill(text).await?);
+        }
+    }
+    prefills.extend(try_join_all(texts.map(&prefill)).await?);
+
+    let scores = candidates
+        .into_iter()
+        .zip(prefills)
+        .zip(starts)
+        .map(|((candidate, tokens), start)| {
+            let tokens = tokens.get(start..).unwrap_or_default().to_vec();
+            Score::new(candidate, tokens)
+        })
+        .collect();
+    Ok(Json(ScoreResponse { scores }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(logprob: f32) -> PrefillToken {
+        PrefillToken {
+            id: 0,
+            text: "a".to_string(),
+            logprob,
+        }
+    }
+
+    #[test]
+    fn test_score() {
+        let score = Score::new(
+            "a".to_string(),
+            vec![token(f32::NAN), token(-1.0), token(-3.0)],
+        );
+        assert_eq!(score.total_logprob, -4.0);
+        assert_eq!(score.mean_logprob, -2.0);
+        assert_eq!(score.perplexity, 2.0f32.exp());
+
+        let score = Score::new(String::new(), vec![]);
+        assert_eq!(score.total_logprob, 0.0);
+        assert_eq!(score.perplexity, 1.0);
+    }
+
+    #[test]
+    fn test_candidate_start() {
+        let crate::Tokenizer::Rust(tokenizer) = crate::tests::get_offline_tokenizer() else {
+            unreachable!()
+        };
+        let prompt = "The capital";
+        let prompt_length = tokenizer.encode(prompt, false).unwrap().len();
+
+        let encoding = tokenizer.encode("The capital city", false).unwrap();
+        assert_eq!(candidate_start(&encoding, prompt, prompt_length), 2);
+
+        // `capital` and `s` are merged, the merged token is scored with the candidate
+        let encoding = tokenizer.encode("The capitals", false).unwrap();
+        assert_eq!(encoding.len(), prompt_length);
+        assert_eq!(candidate_start(&encoding, prompt, prompt_length), 1);
+    }
+}
@@ -1,37 +1,70 @@
+#[cfg(feature = "anthropic")]
+use crate::anthropic::anthropic_messages;
+use crate::batch::{
+    __path_cancel_batch, __path_create_batch, __path_get_batch, __path_get_file,
+    __path_get_file_content, __path_list_batches, __path_upload_file,
+};
 /// HTTP Server logic
+use crate::batch::{
+    cancel_batch, create_batch, get_batch, get_file, get_file_content, list_batches, upload_file,
+    Batch, BatchApi, BatchError, BatchErrors, BatchList, BatchRequestCounts, BatchStatus,
+    CreateBatchRequest, FileObject,
+};
 use crate::config::Config;
+use crate::infer::fim::{FimMode, FimTemplate};
+use crate::infer::reasoning::{ReasoningMarkers, ReasoningParser, ReasoningSplitter};
 use crate::infer::tool_grammar::ToolGrammar;
 use crate::infer::{Backend, Infer, InferError, InferResponse, InferStreamResponse};
+use crate::jobs::{__path_create_job, __path_get_job};
+use crate::jobs::{create_job, get_job, Job, JobRequest, JobStatus, JobStore};
 #[cfg(feature = "kserve")]
 use crate::kserve::{
     kerve_server_metadata, kserve_health_live, kserve_health_ready, kserve_model_infer,
     kserve_model_metadata, kserve_model_metadata_ready,
 };
+use crate::ollama::{
+    OllamaChatRequest, OllamaChatResponse, OllamaFunction, OllamaGenerateRequest,
+    OllamaGenerateResponse, OllamaMessage, OllamaModel, OllamaModelDetails, OllamaOptions,
+    OllamaShowRequest, OllamaShowResponse, OllamaStats, OllamaTags, OllamaToolCall,
+};
+use crate::ollama::{
+    __path_ollama_chat, __path_ollama_generate, __path_ollama_show, __path_ollama_tags,
+};
+use crate::ollama::{ollama_chat, ollama_generate, ollama_show, ollama_tags};
+use crate::resumable::{resume_stream, ResumableStreams, __path_resume_stream, REQUEST_ID_HEADER};
 use crate::sagemaker::{
     sagemaker_compatibility, SagemakerRequest, SagemakerResponse, SagemakerStreamResponse,
     __path_sagemaker_compatibility,
 };
+use crate::score::{Score, ScoreRequest, ScoreResponse, __path_score, score};
 use crate::validation::ValidationError;
 use crate::vertex::vertex_compatibility;
+use crate::watermark::{
+    detect_watermark, WatermarkDetectRequest, WatermarkDetectResponse, WatermarkDetector,
+    __path_detect_watermark,
+};
+use crate::websocket::{__path_websocket, websocket};
 use crate::ChatTokenizeResponse;
+use crate::TruncationStrategy;
 use crate::{
-    usage_stats, BestOfSequence, Details, ErrorResponse, FinishReason, FunctionName,
-    GenerateParameters, GenerateRequest, GenerateResponse, GrammarType, HubModelInfo,
-    HubProcessorConfig, HubTokenizerConfig, Info, Message, MessageChunk, MessageContent,
-    OutputMessage, PrefillToken, SimpleToken, StreamDetails, StreamOptions, StreamResponse,
-    TextMessage, Token, TokenizeResponse, Tokenizer, ToolCallDelta, ToolCallMessage, Url, Usage,
-    Validation,
+    usage_stats, BestOfSequence, ChatTemplateVersions, Details, ErrorResponse, FinishReason,
+    FunctionName, GenerateParameters, GenerateRequest, GenerateResponse, GenerationDefaults,
+    GrammarType, HubChatTemplateConfig, HubGenerationConfig, HubModelInfo, HubProcessorConfig,
+    HubTokenizerConfig, Info, Message, MessageChunk, MessageContent, OutputMessage, PrefillToken,
+    SimpleToken, StreamDetails, StreamOptions, StreamResponse, TextMessage, Token,
+    TokenizeResponse, Tokenizer, ToolCallDelta, ToolCallMessage, Url, Usage, Validation,
 };
 use crate::{
     ChatCompletion, ChatCompletionChoice, ChatCompletionChunk, ChatCompletionComplete,
     ChatCompletionDelta, ChatCompletionLogprob, ChatCompletionLogprobs, ChatCompletionTopLogprob,
     ChatRequest, Chunk, CompatGenerateRequest, Completion, CompletionComplete, CompletionFinal,
-    CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
+    CompletionLogprobs, CompletionRequest, CompletionType, DeltaToolCall, Function, Prompt, Tool,
 };
-use crate::{FunctionDefinition, HubPreprocessorConfig, ToolCall, ToolChoice, ToolType};
-use crate::{ModelInfo, ModelsInfo};
+use crate::{Document, FunctionDefinition, HubPreprocessorConfig, ToolCall, ToolChoice, ToolType};
+use crate::{JsonSchemaFormat, OpenAIResponseFormat, ResponseFormat};
+use crate::{ModelInfo, ModelsInfo, OpenAIErrorObject, OpenAIErrorResponse};
 use async_stream::__private::AsyncStream;
-use axum::extract::Extension;
+use axum::extract::{DefaultBodyLimit, Extension};
 use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
@@ -45,6 +78,7 @@ use futures::TryStreamExt;
 use hf_hub::api::tokio::{Api, ApiBuilder, ApiRepo};
 use hf_hub::{Cache, Repo, RepoType};
 use http::header::AUTHORIZATION;
+use jsonschema::{Draft, JSONSchema};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use pyo3::prelude::*;
 use pyo3::types::IntoPyDict;
@@ -65,7 +99,7 @@ use tracing::{info_span, instrument, Instrument};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
-fn encoding_to_tokens(encoding: &tokenizers::Encoding, input: &str) -> Vec<SimpleToken> {
+pub(crate) fn encoding_to_tokens(encoding: &tokenizers::Encoding, input: &str) -> Vec<SimpleToken> {
     let offsets = encoding.get_offsets();
     let input_ids = encoding.get_ids();
     if offsets.len() == input_ids.len() {
@@ -122,11 +156,12 @@ example = json ! ({"error": "Input validation error"})),
 example = json ! ({"error": "Incomplete generation"})),
 )
 )]
-#[instrument(skip(infer, req))]
+#[instrument(skip(infer, streams, req))]
 pub(crate) async fn compat_generate(
     Extension(default_return_full_text): Extension<bool>,
     infer: Extension<Infer>,
     compute_type: Extension<ComputeType>,
+    streams: Extension<ResumableStreams>,
     Json(mut req): Json<CompatGenerateRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     // default return_full_text given the pipeline_tag
@@ -136,9 +171,11 @@ pub(crate) async fn compat_generate(
 
     // switch on stream
     if req.stream {
-        Ok(generate_stream(infer, compute_type, Json(req.into()))
-            .await
-            .into_response())
+        Ok(
+            generate_stream(infer, compute_type, streams, Json(req.into()))
+                .await
+                .into_response(),
+        )
     } else {
         let (headers, Json(generation)) = generate(infer, compute_type, Json(req.into())).await?;
         // wrap generation inside a Vec to match api-inference
@@ -231,6 +268,8 @@ async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorRe
             Json(ErrorResponse {
                 error: "unhealthy".to_string(),
                 error_type: "healthcheck".to_string(),
+                code: "unhealthy".to_string(),
+                param: None,
             }),
         )),
     }
@@ -465,13 +504,14 @@ seed,
 async fn generate_stream(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
+    Extension(streams): Extension<ResumableStreams>,
     Json(req): Json<GenerateRequest>,
 ) -> (
     HeaderMap,
     Sse<impl Stream<Item = Result<Event, Infallible>>>,
 ) {
     let span = tracing::Span::current();
-    let (headers, response_stream) =
+    let (mut headers, response_stream) =
         generate_stream_internal(infer, compute_type, Json(req), span).await;
 
     let response_stream = async_stream::stream! {
@@ -485,15 +525,44 @@ async fn generate_stream(
         }
     };
 
+    let (request_id, response_stream) = streams.register(response_stream);
+    headers.insert(REQUEST_ID_HEADER, request_id.parse().unwrap());
     let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
     (headers, sse)
 }
 
-async fn generate_stream_internal(
+pub(crate) async fn generate_stream_internal(
+    infer: Infer,
+    compute_type: ComputeType,
+    req: Json<GenerateRequest>,
+    span: tracing::Span,
+) -> (
+    HeaderMap,
+    impl Stream<Item = Result<StreamResponse, InferError>>,
+) {
+    stream_tokens(infer, compute_type, req, span, false).await
+}
+
+/// Same as `generate_stream_internal` but `decoder_input_details` is allowed: the prompt tokens
+/// are attached to the first `StreamResponse`
+pub(crate) async fn generate_stream_with_prefill(
+    infer: Infer,
+    compute_type: ComputeType,
+    req: Json<GenerateRequest>,
+    span: tracing::Span,
+) -> (
+    HeaderMap,
+    impl Stream<Item = Result<StreamResponse, InferError>>,
+) {
+    stream_tokens(infer, compute_type, req, span, true).await
+}
+
+async fn stream_tokens(
     infer: Infer,
     ComputeType(compute_type): ComputeType,
     Json(req): Json<GenerateRequest>,
     span: tracing::Span,
+    allow_prefill: bool,
 ) -> (
     HeaderMap,
     impl Stream<Item = Result<StreamResponse, InferError>>,
@@ -530,7 +599,7 @@ async fn generate_stream_internal(
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
             yield Err(err);
-        } else if req.parameters.decoder_input_details {
+        } else if req.parameters.decoder_input_details && !allow_prefill {
             let err = InferError::from(ValidationError::PrefillDetailsStream);
             metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
             tracing::error!("{err}");
@@ -540,24 +609,26 @@ async fn generate_stream_internal(
                 // Keep permit as long as generate_stream lives
                 Ok((_permit, input_length, response_stream)) => {
                     let mut index = 0;
+                    let mut prefill = Vec::new();
                     let mut response_stream = Box::pin(response_stream);
                     // Server-Sent Event stream
                     while let Some(response) = response_stream.next().await {
-                        index += 1;
                         match response {
                             Ok(response) => {
                                 match response {
-                                    // Prefill is ignored
-                                    InferStreamResponse::Prefill(_) => {}
+                                    // Prefill is only sent with the first token
+                                    InferStreamResponse::Prefill(tokens) => prefill = tokens,
                                     // Yield event for every new token
                                     InferStreamResponse::Intermediate{
                                         token,
                                         top_tokens,
                                     } => {
                                         tracing::debug!(parent: &span, "Token: {:?}", token);
+                                        index += 1;
 
                                         // StreamResponse
                                         let stream_token = StreamResponse {
+                                            prefill: std::mem::take(&mut prefill),
                                             index,
                                             token,
                                             top_tokens,
@@ -574,6 +645,8 @@ async fn generate_stream_internal(
                                         queued,
                                         top_tokens,
                                     } => {
+                                        index += 1;
+
                                         // Token details
                                         let details = match details {
                                             true => Some(StreamDetails {
@@ -621,6 +694,7 @@ async fn generate_stream_internal(
                                         tracing::info!(parent: &span, "Success");
 
                                         let stream_token = StreamResponse {
+                                            prefill: std::mem::take(&mut prefill),
                                             index,
                                             token,
                                             top_tokens,
@@ -662,6 +736,181 @@ async fn generate_stream_internal(
     (headers, stream)
 }
 
+/// `generate_internal` closing the reasoning of thinking models after `budget` tokens: the end
+/// marker is appended to the reasoning and the generation resumes for the answer. The answer is a
+/// second request, the prompt tokens reported are the ones of the original request.
+pub(crate) async fn generate_with_reasoning_budget(
+    infer: Infer,
+    compute_type: ComputeType,
+    Json(req): Json<GenerateRequest>,
+    budget: Option<(u32, ReasoningSplitter)>,
+    span: tracing::Span,
+) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let Some((tokens, splitter)) = budget else {
+        return generate_internal(Extension(infer), compute_type, Json(req), span).await;
+    };
+    let mut reasoning_req = req.clone();
+    reasoning_req.parameters.max_new_tokens = Some(
+        req.parameters
+            .max_new_tokens
+            .map_or(tokens, |max_new_tokens| max_new_tokens.min(tokens)),
+    );
+    let (mut headers, Json(reasoning)) = generate_internal(
+        Extension(infer.clone()),
+        compute_type.clone(),
+        Json(reasoning_req),
+        span.clone(),
+    )
+    .await?;
+    let answer_req = reasoning.details.as_ref().and_then(|details| {
+        reasoning_budget_answer(
+            &req,
+            &splitter,
+            &reasoning.generated_text,
+            &details.finish_reason,
+            details.generated_tokens,
+        )
+    });
+    let Some(answer_req) = answer_req else {
+        return Ok((headers, Json(reasoning)));
+    };
+
+    let (_, Json(answer)) =
+        generate_internal(Extension(infer), compute_type, Json(answer_req), span).await?;
+    let generated_text = format!(
+        "{}{}{}",
+        reasoning.generated_text,
+        splitter.end_marker(),
+        answer.generated_text
+    );
+    // The prefill stays the one of the original prompt
+    let details = match (reasoning.details, answer.details) {
+        (Some(mut details), Some(answer)) => {
+            details.finish_reason = answer.finish_reason;
+            details.generated_tokens += answer.generated_tokens;
+            details.tokens.extend(answer.tokens);
+            details.top_tokens.extend(answer.top_tokens);
+            headers.insert("x-generated-tokens", details.generated_tokens.into());
+            Some(details)
+        }
+        (details, _) => details,
+    };
+    Ok((
+        headers,
+        Json(GenerateResponse {
+            generated_text,
+            details,
+        }),
+    ))
+}
+
+/// `generate_stream_internal` closing the reasoning of thinking models after `budget` tokens, the
+/// end marker is appended to the text of the last reasoning token
+pub(crate) async fn generate_stream_with_reasoning_budget(
+    infer: Infer,
+    compute_type: ComputeType,
+    Json(req): Json<GenerateRequest>,
+    budget: Option<(u32, ReasoningSplitter)>,
+    span: tracing::Span,
+) -> (
+    HeaderMap,
+    impl Stream<Item = Result<StreamResponse, InferError>>,
+) {
+    let mut reasoning_req = req.clone();
+    if let Some((tokens, _)) = &budget {
+        reasoning_req.parameters.max_new_tokens = Some(
+            req.parameters
+                .max_new_tokens
+                .map_or(*tokens, |max_new_tokens| max_new_tokens.min(*tokens)),
+        );
+    }
+    let (headers, reasoning_stream) = generate_stream_internal(
+        infer.clone(),
+        compute_type.clone(),
+        Json(reasoning_req),
+        span.clone(),
+    )
+    .await;
+
+    let stream = async_stream::stream! {
+        let mut reasoning_stream = Box::pin(reasoning_stream);
+        while let Some(response) = reasoning_stream.next().await {
+            let mut response = match response {
+                Ok(response) => response,
+                Err(err) => {
+                    yield Err(err);
+                    continue;
+                }
+            };
+            let answer = match (&response, &budget) {
+                (
+                    StreamResponse { generated_text: Some(text), details: Some(details), .. },
+                    Some((_, splitter)),
+                ) => reasoning_budget_answer(&req, splitter, text, &details.finish_reason, details.generated_tokens)
+                    .map(|answer_req| (answer_req, format!("{text}{}", splitter.end_marker()), details.generated_tokens, details.input_length)),
+                _ => None,
+            };
+            let Some((answer_req, reasoning_text, reasoning_tokens, input_length)) = answer else {
+                yield Ok(response);
+                continue;
+            };
+
+            // The reasoning is closed by its last token and the answer follows it
+            if let Some((_, splitter)) = &budget {
+                response.token.text.push_str(splitter.end_marker());
+            }
+            response.generated_text = None;
+            response.details = None;
+            let index = response.index;
+            yield Ok(response);
+
+            let (_, answer_stream) =
+                generate_stream_internal(infer.clone(), compute_type.clone(), Json(answer_req), span.clone()).await;
+            let mut answer_stream = Box::pin(answer_stream);
+            while let Some(response) = answer_stream.next().await {
+                yield response.map(|mut response| {
+                    response.index += index;
+                    if let Some(text) = response.generated_text.as_mut() {
+                        text.insert_str(0, &reasoning_text);
+                    }
+                    if let Some(details) = response.details.as_mut() {
+                        details.generated_tokens += reasoning_tokens;
+                        details.input_length = input_length;
+                    }
+                    response
+                });
+            }
+        }
+    };
+
+    (headers, stream)
+}
+
+/// The request generating the answer after `output`, when the reasoning budget cut its reasoning
+fn reasoning_budget_answer(
+    req: &GenerateRequest,
+    splitter: &ReasoningSplitter,
+    output: &str,
+    finish_reason: &FinishReason,
+    generated_tokens: u32,
+) -> Option<GenerateRequest> {
+    let tokens_left = req
+        .parameters
+        .max_new_tokens
+        .map(|max_new_tokens| max_new_tokens.saturating_sub(generated_tokens));
+    if !matches!(finish_reason, FinishReason::Length)
+        || tokens_left == Some(0)
+        || !splitter.unfinished(output)
+    {
+        return None;
+    }
+    let mut req = req.clone();
+    req.inputs = format!("{}{output}{}", req.inputs, splitter.end_marker());
+    req.parameters.max_new_tokens = tokens_left;
+    req.parameters.decoder_input_details = false;
+    Some(req)
+}
+
 /// Generate tokens
 #[utoipa::path(
 post,
@@ -674,14 +923,14 @@ content(
 ("application/json" = CompletionFinal),
 ("text/event-stream" = Chunk),
 )),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
-(status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
-(status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+(status = 424, description = "Generation Error", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Request failed during generation", "type": "server_error", "param": null, "code": "generation"}})),
+(status = 429, description = "Model is overloaded", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Model is overloaded", "type": "rate_limit_error", "param": null, "code": "overloaded"}})),
+(status = 422, description = "Input validation error", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Input validation error: `temperature` must be strictly positive", "type": "invalid_request_error", "param": "temperature", "code": "invalid_temperature"}})),
+(status = 500, description = "Incomplete generation", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Incomplete generation", "type": "server_error", "param": null, "code": "incomplete_generation"}})),
 )
 )]
 #[instrument(
@@ -700,8 +949,9 @@ pub(crate) async fn completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
+    Extension(streams): Extension<ResumableStreams>,
     Json(req): Json<CompletionRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Response, OpenAIError> {
     let span = tracing::Span::current();
     metrics::counter!("tgi_request_count").increment(1);
 
@@ -712,10 +962,12 @@ pub(crate) async fn completions(
         stop,
         stream,
         temperature,
+        logprobs,
+        echo,
         ..
     } = req;
 
-    let max_new_tokens = max_tokens.or(Some(100));
+    let max_new_tokens = max_tokens.or(infer.generation_defaults().max_new_tokens);
     let stop = stop.unwrap_or_default();
     // enable greedy only when temperature is 0
     let (do_sample, temperature) = match temperature {
@@ -723,19 +975,6 @@ pub(crate) async fn completions(
         other => (true, other),
     };
 
-    // if suffix is present throw an error
-    if req.suffix.is_some() {
-        metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
-        return Err((
-            StatusCode::UNPROCESSABLE_ENTITY,
-            Json(ErrorResponse {
-                error: "Suffix is not supported and can be achieved by preprocessing the prompt."
-                    .to_string(),
-                error_type: "suffix not supported".to_string(),
-            }),
-        ));
-    }
-
     if req.prompt.0.len() > info.max_client_batch_size {
         metrics::counter!("tgi_request_failure", "err" => "validation").increment(1);
         return Err((
@@ -746,13 +985,25 @@ pub(crate) async fn completions(
                     info.max_client_batch_size
                 ),
                 error_type: "batch size exceeded".to_string(),
+                code: "too_many_prompts".to_string(),
+                param: Some("prompt".to_string()),
             }),
-        ));
+        )
+            .into());
     }
 
-    let generate_requests: Vec<GenerateRequest> = req
-        .prompt
-        .0
+    // a suffix turns the prompts into fill-in-the-middle prompts
+    let prompts = match &req.suffix {
+        Some(suffix) => req
+            .prompt
+            .0
+            .iter()
+            .map(|prompt| infer.apply_fim_template(prompt, suffix))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => req.prompt.0,
+    };
+
+    let generate_requests: Vec<GenerateRequest> = prompts
         .iter()
         .map(|prompt| GenerateRequest {
             inputs: prompt.to_string(),
@@ -772,9 +1023,10 @@ pub(crate) async fn completions(
                 truncate: None,
                 watermark: false,
                 details: true,
-                decoder_input_details: !stream,
+                // The prompt logprobs are only needed in streaming mode when echoing them
+                decoder_input_details: !stream || (echo && logprobs.is_some()),
                 seed,
-                top_n_tokens: None,
+                top_n_tokens: logprobs,
                 grammar: None,
                 adapter_id: model.as_ref().filter(|m| *m != "tgi").map(String::from),
             },
@@ -794,14 +1046,15 @@ pub(crate) async fn completions(
             let infer_clone = infer.clone();
             let compute_type_clone = compute_type.clone();
             let span_clone = span.clone();
+            let prompt = generate_request.inputs.clone();
 
-            // Create a future for each generate_stream_internal call.
+            // Create a future for each generate_stream_with_prefill call.
             let generate_future = async move {
                 let (header_tx, header_rx) = oneshot::channel();
                 let (sse_tx, sse_rx) = tokio::sync::mpsc::unbounded_channel();
 
                 tokio::spawn(async move {
-                    let (headers, response_stream) = generate_stream_internal(
+                    let (headers, response_stream) = generate_stream_with_prefill(
                         infer_clone.clone(),
                         compute_type_clone.clone(),
                         Json(generate_request),
@@ -809,8 +1062,16 @@ pub(crate) async fn completions(
                     )
                     .await;
 
+                    let special_tokens_mask = if echo && logprobs.is_some() {
+                        special_tokens_mask(&infer_clone, &prompt).await
+                    } else {
+                        Vec::new()
+                    };
+
                     let response_stream = async_stream::stream! {
                         let mut response_stream = Box::pin(response_stream);
+                        let mut text_offset = if echo { 0 } else { prompt.chars().count() as u32 };
+                        let mut echoed_prompt = echo.then_some(prompt);
 
                         while let Some(stream_token) = response_stream.next().await {
                             match stream_token {
@@ -822,6 +1083,36 @@ pub(crate) async fn completions(
                                         .unwrap_or_else(|_| std::time::Duration::from_secs(0))
                                         .as_secs();
 
+                                    // The prompt is echoed in its own chunk before the first token
+                                    if let Some(prompt) = echoed_prompt.take() {
+                                        let prompt_logprobs = logprobs.map(|_| {
+                                            let mut prompt_logprobs = CompletionLogprobs::default();
+                                            prompt_logprobs.push_prefill(&mut text_offset, &stream_token.prefill, &special_tokens_mask);
+                                            prompt_logprobs
+                                        });
+                                        let message = Completion::Chunk(Chunk {
+                                            id: String::new(),
+                                            created: current_time,
+                                            choices: vec![CompletionComplete {
+                                                finish_reason: String::new(),
+                                                index: index as u32,
+                                                logprobs: prompt_logprobs,
+                                                text: prompt,
+                                            }],
+                                            model: model_id.clone(),
+                                            system_fingerprint: system_fingerprint.clone(),
+                                        });
+                                        yield Ok(Event::default()
+                                            .json_data(message)
+                                            .unwrap_or_else(|_e| Event::default()));
+                                    }
+
+                                    let token_logprobs = logprobs.map(|_| {
+                                        let mut token_logprobs = CompletionLogprobs::default();
+                                        token_logprobs.push_token(&mut text_offset, &stream_token.token, &stream_token.top_tokens);
+                                        token_logprobs
+                                    });
+
                                     let message = match stream_token.details {
                                         Some(details) => {
                                             let completion_tokens = details.generated_tokens;
@@ -836,7 +1127,7 @@ pub(crate) async fn completions(
                                                 choices: vec![CompletionComplete {
                                                     finish_reason: details.finish_reason.to_string(),
                                                     index: index as u32,
-                                                    logprobs: None,
+                                                    logprobs: token_logprobs,
                                                     text: stream_token.token.text,
                                                 }],
                                                 usage: Usage {
@@ -852,7 +1143,7 @@ pub(crate) async fn completions(
                                             choices: vec![CompletionComplete {
                                                 finish_reason: String::new(),
                                                 index: index as u32,
-                                                logprobs: None,
+                                                logprobs: token_logprobs,
                                                 text: stream_token.token.text,
                                             }],
                                             model: model_id.clone(),
@@ -866,7 +1157,7 @@ pub(crate) async fn completions(
 
                                     yield Ok(event);
                                 }
-                                Err(err) => yield Ok(Event::from(err)),
+                                Err(err) => yield Ok(Event::from(OpenAIError::from(err))),
                             }
                         }
                     };
@@ -902,6 +1193,8 @@ pub(crate) async fn completions(
                     Json(ErrorResponse {
                         error: "Failed to get headers".to_string(),
                         error_type: "headers".to_string(),
+                        code: "internal_error".to_string(),
+                        param: None,
                     }),
                 )
             })?;
@@ -960,6 +1253,8 @@ pub(crate) async fn completions(
             Ok(Event::default().data("[DONE]"))
         }));
 
+        let (request_id, stream) = streams.register(stream);
+        headers.insert(REQUEST_ID_HEADER, request_id.parse().unwrap());
         let sse = Sse::new(stream).keep_alive(KeepAlive::default());
         Ok((headers, sse).into_response())
     } else {
@@ -968,12 +1263,21 @@ pub(crate) async fn completions(
             .unwrap_or_else(|_| std::time::Duration::from_secs(0))
             .as_secs();
 
+        let prompts: Vec<String> = generate_requests
+            .iter()
+            .map(|generate_request| generate_request.inputs.clone())
+            .collect();
         let responses = FuturesUnordered::new();
         for (index, generate_request) in generate_requests.into_iter().enumerate() {
             let infer_clone = infer.clone();
             let compute_type_clone = compute_type.clone();
             let span_clone = span.clone();
             let response_future = async move {
+                let special_tokens_mask = if echo && logprobs.is_some() {
+                    special_tokens_mask(&infer_clone, &generate_request.inputs).await
+                } else {
+                    Vec::new()
+                };
                 let result = generate_internal(
                     Extension(infer_clone),
                     compute_type_clone,
@@ -981,7 +1285,8 @@ pub(crate) async fn completions(
                     span_clone,
                 )
                 .await;
-                result.map(|(headers, generation)| (index, headers, generation))
+                result
+                    .map(|(headers, generation)| (index, headers, generation, special_tokens_mask))
             };
             responses.push(response_future);
         }
@@ -1002,13 +1307,15 @@ pub(crate) async fn completions(
 
         let choices = generate_responses
             .into_iter()
-            .map(|(index, headers, Json(generation))| {
+            .map(|(index, headers, Json(generation), special_tokens_mask)| {
                 let details = generation.details.ok_or((
                     // this should never happen but handle if details are missing unexpectedly
                     StatusCode::INTERNAL_SERVER_ERROR,
                     Json(ErrorResponse {
                         error: "No details in generation".to_string(),
                         error_type: "no details".to_string(),
+                        code: "internal_error".to_string(),
+                        param: None,
                     }),
                 ))?;
 
@@ -1061,11 +1368,38 @@ pub(crate) async fn completions(
                 completion_tokens += details.generated_tokens;
                 total_tokens += details.prefill.len() as u32 + details.generated_tokens;
 
+                let prompt = &prompts[index];
+                let logprobs = logprobs.map(|_| {
+                    let mut text_offset = if echo {
+                        0
+                    } else {
+                        prompt.chars().count() as u32
+                    };
+                    let mut logprobs = CompletionLogprobs::default();
+                    if echo {
+                        logprobs.push_prefill(
+                            &mut text_offset,
+                            &details.prefill,
+                            &special_tokens_mask,
+                        );
+                    }
+                    for (i, token) in details.tokens.iter().enumerate() {
+                        let top_tokens = details.top_tokens.get(i).map_or(&[][..], Vec::as_slice);
+                        logprobs.push_token(&mut text_offset, token, top_tokens);
+                    }
+                    logprobs
+                });
+                let text = if echo {
+                    format!("{prompt}{}", generation.generated_text)
+                } else {
+                    generation.generated_text
+                };
+
                 Ok(CompletionComplete {
                     finish_reason: details.finish_reason.format(true),
                     index: index as u32,
-                    logprobs: None,
-                    text: generation.generated_text,
+                    logprobs,
+                    text,
                 })
             })
             .collect::<Result<Vec<_>, _>>()
@@ -1108,6 +1442,20 @@ pub(crate) async fn completions(
     }
 }
 
+/// Mask of the special tokens the tokenizer adds to a completions prompt, empty when the
+/// prompt cannot be tokenized in the router
+async fn special_tokens_mask(infer: &Infer, prompt: &str) -> Vec<u32> {
+    infer
+        .tokenize(GenerateRequest {
+            inputs: prompt.to_string(),
+            add_special_tokens: true,
+            parameters: GenerateParameters::default(),
+        })
+        .await
+        .map(|encoding| encoding.get_special_tokens_mask().to_vec())
+        .unwrap_or_default()
+}
+
 enum StreamState {
     Buffering,
     BufferTrailing,
@@ -1115,15 +1463,14 @@ enum StreamState {
 }
 
 /// Convert a StreamResponse into an Event to be sent over SSE
-fn create_event_from_stream_token(
+fn create_chunk_from_stream_token(
     stream_token: &StreamResponse,
     logprobs: bool,
     stream_options: Option<StreamOptions>,
     inner_using_tools: bool,
     system_fingerprint: String,
     model_id: String,
-) -> Event {
-    let event = Event::default();
+) -> ChatCompletionChunk {
     let current_time = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_else(|_| std::time::Duration::from_secs(0))
@@ -1169,7 +1516,7 @@ fn create_event_from_stream_token(
         None => (None, None),
     };
 
-    let chat_complete = CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
+    ChatCompletionChunk::new(
         model_id.clone(),
         system_fingerprint.clone(),
         content,
@@ -1178,14 +1525,596 @@ fn create_event_from_stream_token(
         logprobs,
         finish_reason,
         usage,
-    ));
+    )
+}
 
-    event.json_data(chat_complete).unwrap_or_else(|e| {
-        println!("Failed to serialize ChatCompletionChunk: {:?}", e);
-        Event::default()
+/// Convert a token stream into chat completion chunks
+///
+/// When tools are used, the tokens are buffered until the function name is known so that the
+/// `no_tool` function can be streamed back as regular content. The reasoning of thinking models
+/// is moved to the `reasoning_content` of the chunks when a `reasoning` splitter is given.
+pub(crate) fn chat_completion_chunks(
+    response_stream: impl Stream<Item = Result<StreamResponse, InferError>>,
+    using_tools: bool,
+    mut reasoning: Option<ReasoningSplitter>,
+    logprobs: bool,
+    stream_options: Option<StreamOptions>,
+    system_fingerprint: String,
+    model_id: String,
+) -> Result<
+    impl Stream<Item = Result<ChatCompletionChunk, InferError>>,
+    (StatusCode, Json<ErrorResponse>),
+> {
+    // regex to match any function name
+    let function_regex = match Regex::new(r#"\{"function":\{"_name":"([^"]+)""#) {
+        Ok(regex) => regex,
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: format!("Failed to compile regex: {}", e),
+                    error_type: "regex".to_string(),
+                    code: "internal_error".to_string(),
+                    param: None,
+                }),
+            ))
+        }
+    };
+
+    Ok(async_stream::stream! {
+        let mut response_stream = Box::pin(response_stream);
+        let mut buffer = Vec::new();
+        let mut json_buffer = String::new();
+        let mut state = if using_tools {
+            StreamState::Buffering
+        } else {
+            StreamState::Content {
+                skip_close_quote: false,
+            }
+        };
+        let mut response_as_tool = using_tools;
+        while let Some(result) = response_stream.next().await {
+            let stream_token = match result {
+                Ok(stream_token) => stream_token,
+                Err(err) => {
+                    yield Err(err);
+                    continue;
+                }
+            };
+            let token_text = &stream_token.token.text.clone();
+            match state {
+                StreamState::Buffering => {
+                    json_buffer.push_str(&token_text.replace(" ", ""));
+                    buffer.push(stream_token);
+                    if let Some(captures) = function_regex.captures(&json_buffer) {
+                        let function_name = captures[1].to_string();
+                        if function_name == "no_tool" {
+                            state = StreamState::BufferTrailing;
+                            response_as_tool = false;
+                            buffer.clear();
+                            json_buffer.clear();
+                        } else {
+                            state = StreamState::Content {
+                                skip_close_quote: false,
+                            };
+                            // send all the buffered messages
+                            for stream_token in &buffer {
+                                yield Ok(create_chunk_from_stream_token(
+                                    stream_token,
+                                    logprobs,
+                                    stream_options.clone(),
+                                    response_as_tool,
+                                    system_fingerprint.clone(),
+                                    model_id.clone(),
+                                ));
+                            }
+                        }
+                    }
+                }
+                // if we skipped sending the buffer we need to avoid sending the following json key and quotes
+                StreamState::BufferTrailing => {
+                    let infix_text = "\"content\":\"";
+                    json_buffer.push_str(&token_text.replace(" ", ""));
+                    // keep capturing until we find the infix text
+                    match json_buffer.find(infix_text) {
+                        Some(content_key_index) => {
+                            json_buffer =
+                                json_buffer[content_key_index + infix_text.len()..].to_string();
+                        }
+                        None => {
+                            continue;
+                        }
+                    }
+                    // if there is leftover text after removing the infix text, we need to send it
+                    if !json_buffer.is_empty() {
+                        let current_time = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .unwrap_or_else(|_| std::time::Duration::from_secs(0))
+                            .as_secs();
+                        yield Ok(ChatCompletionChunk::new(
+                            model_id.clone(),
+                            system_fingerprint.clone(),
+                            Some(json_buffer.clone()),
+                            None,
+                            current_time,
+                            None,
+                            None,
+                            None,
+                        ));
+                    }
+                    // cleanup the buffers
+                    buffer.clear();
+                    json_buffer.clear();
+                    state = StreamState::Content {
+                        skip_close_quote: true,
+                    };
+                }
+                StreamState::Content { skip_close_quote } => {
+                    if skip_close_quote && token_text.contains('"') {
+                        break;
+                    }
+
+                    // send the content
+                    let mut chunk = create_chunk_from_stream_token(
+                        &stream_token,
+                        logprobs,
+                        stream_options.clone(),
+                        response_as_tool,
+                        system_fingerprint.clone(),
+                        model_id.clone(),
+                    );
+                    if let (Some(splitter), Some(choice)) = (reasoning.as_mut(), chunk.choices.first_mut()) {
+                        if let ChatCompletionDelta::Chat(message) = &mut choice.delta {
+                            splitter.split_message(message, stream_token.details.is_some());
+                        }
+                    }
+                    yield Ok(chunk);
+                }
+            }
+        }
     })
 }
 
+/// Validate the output of a `strict` JSON schema response format
+pub(crate) fn check_strict_schema(schema: &Value, generated_text: &str) -> Result<(), InferError> {
+    let output: Value = serde_json::from_str(generated_text)
+        .map_err(|e| InferError::SchemaMismatch(format!("invalid JSON: {e}")))?;
+    let schema = JSONSchema::options()
+        .with_draft(Draft::Draft202012)
+        .compile(schema)
+        .map_err(|e| InferError::SchemaMismatch(e.to_string()))?;
+    schema.validate(&output).map_err(|errors| {
+        let errors: Vec<String> = errors.map(|e| e.to_string()).collect();
+        InferError::SchemaMismatch(errors.join(", "))
+    })
+}
+
+/// Parse the output of a tool grammar into tool calls, or into a message for `no_tool`
+pub(crate) fn parse_tool_call(
+    generated_text: &str,
+) -> Result<(Option<Vec<ToolCall>>, Option<String>), InferError> {
+    let gen_text_value: Value = serde_json::from_str(generated_text).map_err(|e| {
+        InferError::ToolError(format!(
+            "Failed to parse generated text: {} {:?}",
+            e, generated_text
+        ))
+    })?;
+    let function = gen_text_value.get("function").ok_or(InferError::ToolError(
+        "No function found in generated text".to_string(),
+    ))?;
+
+    let name = function
+        .get("_name")
+        .and_then(Value::as_str)
+        .ok_or(InferError::ToolError(
+            "No _name found in generated text".to_string(),
+        ))?
+        .to_string();
+
+    let mut arguments = function.clone();
+    if let Value::Object(ref mut props) = arguments {
+        props.remove("_name");
+    }
+    match name.as_str() {
+        "no_tool" => {
+            // parse the content message
+            let content_message = arguments
+                .get("content")
+                .and_then(Value::as_str)
+                .ok_or_else(|| {
+                    InferError::ToolError("No `content` found in generated text".to_string())
+                })?
+                .to_string();
+            Ok((None, Some(content_message)))
+        }
+        _ => {
+            let tool_calls = vec![ToolCall {
+                id: "0".to_string(),
+                r#type: "function".to_string(),
+                function: FunctionDefinition {
+                    description: None,
+                    name,
+                    arguments,
+                },
+            }];
+            Ok((Some(tool_calls), None))
+        }
+    }
+}
+
+/// Render a tool call the way the tool grammar generates it, for the conversation history
+pub(crate) fn format_tool_call(name: String, arguments: Value) -> String {
+    let mut function = match arguments {
+        Value::Object(arguments) => arguments,
+        arguments => serde_json::Map::from_iter([("input".to_string(), arguments)]),
+    };
+    function.insert("_name".to_string(), Value::String(name));
+    serde_json::json!({ "function": function }).to_string()
+}
+
+/// Tokens left for the summary of the oldest turns with the `summarize` truncation strategy
+const SUMMARY_MAX_TOKENS: u32 = 256;
+
+/// Instruction of the summary of the oldest turns, followed by their transcript
+const SUMMARY_PROMPT: &str = "Summarize the following conversation in a few sentences, keeping \
+    the facts, names and decisions needed to continue it.";
+
+/// Convert a chat request into a generate request. With a `truncation_strategy`, the oldest turns
+/// of the conversation are dropped until the prompt and `max_tokens` fit the context window, and
+/// the number of dropped messages is returned.
+pub(crate) async fn prepare_chat_request(
+    infer: &Infer,
+    chat: ChatRequest,
+) -> Result<(GenerateRequest, bool, usize), InferError> {
+    let Some(strategy) = chat.truncation_strategy else {
+        let (request, using_tools) = chat.try_into_generate(infer)?;
+        return Ok((request, using_tools, 0));
+    };
+    let (request, using_tools) = chat.clone().try_into_generate(infer)?;
+    if infer.fits_context(request.clone(), 0).await? {
+        return Ok((request, using_tools, 0));
+    }
+
+    // Find the oldest turn the conversation can start from
+    let reserved_tokens = match strategy {
+        TruncationStrategy::DropOldest => 0,
+        TruncationStrategy::Summarize => SUMMARY_MAX_TOKENS,
+    };
+    let starts = turn_starts(&chat.messages);
+    let (mut low, mut high) = (0, starts.len());
+    while low < high {
+        let middle = (low + high) / 2;
+        let (kept, _) = drop_turns(&chat.messages, starts[middle]);
+        let (request, _) = ChatRequest {
+            messages: kept,
+            ..chat.clone()
+        }
+        .try_into_generate(infer)?;
+        if infer.fits_context(request, reserved_tokens).await? {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    // The last turn is kept even when it is too long, validation reports its length
+    let Some(&start) = starts.get(low).or(starts.last()) else {
+        return Ok((request, using_tools, 0));
+    };
+
+    let (kept, dropped) = drop_turns(&chat.messages, start);
+    tracing::debug!("Dropped the {} oldest messages", dropped.len());
+    if strategy == TruncationStrategy::Summarize {
+        match summarized_request(infer, &chat, &kept, &dropped).await {
+            Ok(Some((request, using_tools))) => {
+                return Ok((request, using_tools, dropped.len()));
+            }
+            Ok(None) => {
+                tracing::warn!("The summary does not fit, the oldest turns are dropped without it")
+            }
+            Err(err) => tracing::warn!("The oldest turns are dropped without a summary: {err}"),
+        }
+    }
+    let (request, using_tools) = ChatRequest {
+        messages: kept,
+        ..chat
+    }
+    .try_into_generate(infer)?;
+    Ok((request, using_tools, dropped.len()))
+}
+
+/// Indices of the user messages that start a turn after the first one
+fn turn_starts(messages: &[Message]) -> Vec<usize> {
+    let first = messages.iter().position(|message| message.role != "system");
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| {
+            message.role == "user" && first.is_some_and(|first| first < *index)
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Split the messages into the messages kept when the conversation starts at the turn `start`,
+/// including the system messages, and the dropped ones
+fn drop_turns(messages: &[Message], start: usize) -> (Vec<Message>, Vec<Message>) {
+    let (mut kept, mut dropped) = (Vec::new(), Vec::new());
+    for (index, message) in messages.iter().enumerate() {
+        if index >= start || message.role == "system" {
+            kept.push(message.clone());
+        } else {
+            dropped.push(message.clone());
+        }
+    }
+    (kept, dropped)
+}
+
+/// Add the summary of the dropped turns to the system message
+fn with_summary(mut messages: Vec<Message>, summary: String) -> Vec<Message> {
+    let summary = format!("Summary of the earlier conversation: {summary}");
+    match messages.first_mut() {
+        Some(message) if message.role == "system" => message.content.push(MessageChunk::Text {
+            text: format!("\n\n{summary}"),
+        }),
+        _ => messages.insert(
+            0,
+            Message {
+                role: "system".to_string(),
+                content: MessageContent::SingleText(summary),
+                name: None,
+            },
+        ),
+    }
+    messages
+}
+
+/// Request of the kept messages with the summary of the dropped ones, `None` when it does not fit
+async fn summarized_request(
+    infer: &Infer,
+    chat: &ChatRequest,
+    kept: &[Message],
+    dropped: &[Message],
+) -> Result<Option<(GenerateRequest, bool)>, InferError> {
+    let summary = summarize_turns(infer, dropped).await?;
+    // Fails with the templates rejecting the system role
+    let (request, using_tools) = ChatRequest {
+        messages: with_summary(kept.to_vec(), summary),
+        ..chat.clone()
+    }
+    .try_into_generate(infer)?;
+    // The summary is only bounded in tokens, the text around it can overflow
+    if infer.fits_context(request.clone(), 0).await? {
+        Ok(Some((request, using_tools)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Summarize turns of a conversation with the model
+async fn summarize_turns(infer: &Infer, messages: &[Message]) -> Result<String, InferError> {
+    let transcript: Vec<String> = messages
+        .iter()
+        .map(|message| {
+            let message = TextMessage::from(message.clone());
+            format!("{}: {}", message.role, message.content)
+        })
+        .collect();
+    let transcript = transcript.join("\n\n");
+    let summary_request = |start: usize| -> Result<GenerateRequest, InferError> {
+        let transcript = match start {
+            0 => transcript.clone(),
+            start => format!("…{}", &transcript[start..]),
+        };
+        let message = Message {
+            role: "user".to_string(),
+            content: MessageContent::SingleText(format!("{SUMMARY_PROMPT}\n\n{transcript}")),
+            name: None,
+        };
+        Ok(GenerateRequest {
+            inputs: infer.apply_chat_template(None, None, None, vec![message], None, false)?,
+            add_special_tokens: false,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(SUMMARY_MAX_TOKENS),
+                do_sample: false,
+                ..Default::default()
+            },
+        })
+    };
+
+    // The turns were dropped because they did not fit, only the most recent part of their
+    // transcript is kept, leaving room for the summary
+    let mut request = summary_request(0)?;
+    if !infer.fits_context(request.clone(), 0).await? {
+        let (mut low, mut high) = (0, transcript.len());
+        loop {
+            let mut middle = (low + high + 1) / 2;
+            while !transcript.is_char_boundary(middle) {
+                middle += 1;
+            }
+            if middle >= high {
+                break;
+            }
+            if infer.fits_context(summary_request(middle)?, 0).await? {
+                high = middle;
+            } else {
+                low = middle;
+            }
+        }
+        if high == transcript.len() {
+            return Err(InferError::GenerationError(
+                "the summary prompt does not fit the context window".to_string(),
+            ));
+        }
+        request = summary_request(high)?;
+    }
+
+    let reasoning = infer.reasoning_splitter(&request, false);
+    let summary = infer.generate(request).await?.generated_text.text;
+    let summary = match reasoning {
+        Some(splitter) => splitter.split(&summary).1,
+        None => summary,
+    };
+    // Thinking models can spend the whole budget reasoning
+    let summary = summary.trim();
+    if summary.is_empty() {
+        return Err(InferError::GenerationError(
+            "the summary is empty".to_string(),
+        ));
+    }
+    Ok(summary.to_string())
+}
+
+/// Chat request ready to be generated, shared by the HTTP and the WebSocket endpoints
+pub(crate) struct ChatSetup {
+    generate_request: GenerateRequest,
+    using_tools: bool,
+    /// Only reported when a `truncation_strategy` is set
+    dropped_messages: Option<u32>,
+    reasoning: Option<ReasoningSplitter>,
+    reasoning_budget: Option<(u32, ReasoningSplitter)>,
+    /// The continued message, prepended to the response on request
+    prefill: Option<String>,
+    strict_schema: Option<Value>,
+    logprobs: bool,
+    stream_options: Option<StreamOptions>,
+    system_fingerprint: String,
+    model_id: String,
+}
+
+impl ChatSetup {
+    pub(crate) async fn new(
+        infer: &Infer,
+        info: &Info,
+        chat: ChatRequest,
+    ) -> Result<Self, InferError> {
+        let ChatRequest {
+            stream_options,
+            logprobs,
+            response_format,
+            messages,
+            continue_final_message,
+            echo,
+            include_reasoning,
+            max_reasoning_tokens,
+            truncation_strategy,
+            ..
+        } = chat.clone();
+        let (generate_request, using_tools, dropped_messages) =
+            prepare_chat_request(infer, chat).await?;
+        let reasoning = infer.reasoning_splitter(&generate_request, include_reasoning);
+        let reasoning_budget = match (max_reasoning_tokens, &reasoning) {
+            (Some(tokens), Some(splitter)) => Some((tokens, splitter.clone())),
+            (Some(_), None) => return Err(ValidationError::ReasoningBudget.into()),
+            (None, _) => None,
+        };
+        // The prompt ends with the trimmed message, see `ChatTemplate::apply`
+        let prefill = match messages.last() {
+            Some(message) if continue_final_message && echo => Some(
+                TextMessage::from(message.clone())
+                    .content
+                    .trim()
+                    .to_string(),
+            ),
+            _ => None,
+        };
+
+        Ok(Self {
+            generate_request,
+            using_tools,
+            dropped_messages: truncation_strategy.map(|_| dropped_messages as u32),
+            reasoning,
+            reasoning_budget,
+            prefill,
+            strict_schema: response_format
+                .as_ref()
+                .and_then(ResponseFormat::strict_schema)
+                .cloned(),
+            logprobs: logprobs.unwrap_or_default(),
+            stream_options,
+            system_fingerprint: format!(
+                "{}-{}",
+                info.version,
+                info.docker_label.unwrap_or("native")
+            ),
+            model_id: info.model_id.clone(),
+        })
+    }
+
+    /// Start the generation and stream its chunks. The first chunk reports the dropped messages
+    /// and starts with the prefill, a strict schema mismatch is sent after the last chunk.
+    pub(crate) async fn stream(
+        self,
+        infer: Infer,
+        compute_type: ComputeType,
+        span: tracing::Span,
+    ) -> Result<
+        (
+            HeaderMap,
+            impl Stream<Item = Result<ChatCompletionChunk, InferError>>,
+        ),
+        (StatusCode, Json<ErrorResponse>),
+    > {
+        let (mut headers, response_stream) = generate_stream_with_reasoning_budget(
+            infer,
+            compute_type,
+            Json(self.generate_request),
+            self.reasoning_budget,
+            span,
+        )
+        .await;
+        if let Some(dropped_messages) = self.dropped_messages {
+            headers.insert("x-dropped-messages", dropped_messages.into());
+        }
+
+        // The output is only complete with the last token, a mismatch is sent after it
+        let content_splitter = self.reasoning.clone();
+        let strict_schema = self.strict_schema;
+        let response_stream = async_stream::stream! {
+            let mut response_stream = Box::pin(response_stream);
+            while let Some(response) = response_stream.next().await {
+                let mismatch = match (&response, &strict_schema) {
+                    (Ok(StreamResponse { generated_text: Some(text), .. }), Some(schema)) => {
+                        let content = match &content_splitter {
+                            Some(splitter) => splitter.clone().split(text).1,
+                            None => text.clone(),
+                        };
+                        check_strict_schema(schema, &content).err()
+                    }
+                    _ => None,
+                };
+                yield response;
+                if let Some(err) = mismatch {
+                    yield Err(err);
+                }
+            }
+        };
+
+        let chunks = chat_completion_chunks(
+            response_stream,
+            self.using_tools,
+            self.reasoning,
+            self.logprobs,
+            self.stream_options,
+            self.system_fingerprint,
+            self.model_id,
+        )?;
+        let mut prefill = self.prefill;
+        let mut dropped_messages = self.dropped_messages;
+        let chunks = chunks.map(move |chunk| {
+            chunk.map(|mut chunk| {
+                chunk.dropped_messages = dropped_messages.take();
+                if let (Some(prefill), Some(choice)) = (prefill.take(), chunk.choices.first_mut()) {
+                    if let ChatCompletionDelta::Chat(message) = &mut choice.delta {
+                        message.content.insert_str(0, &prefill);
+                    }
+                }
+                chunk
+            })
+        });
+        Ok((headers, chunks))
+    }
+}
+
 /// Generate tokens
 #[utoipa::path(
 post,
@@ -1198,14 +2127,14 @@ content(
 ("application/json" = ChatCompletion),
 ("text/event-stream" = ChatCompletionChunk),
 )),
-(status = 424, description = "Generation Error", body = ErrorResponse,
-example = json ! ({"error": "Request failed during generation"})),
-(status = 429, description = "Model is overloaded", body = ErrorResponse,
-example = json ! ({"error": "Model is overloaded"})),
-(status = 422, description = "Input validation error", body = ErrorResponse,
-example = json ! ({"error": "Input validation error"})),
-(status = 500, description = "Incomplete generation", body = ErrorResponse,
-example = json ! ({"error": "Incomplete generation"})),
+(status = 424, description = "Generation Error", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Request failed during generation", "type": "server_error", "param": null, "code": "generation"}})),
+(status = 429, description = "Model is overloaded", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Model is overloaded", "type": "rate_limit_error", "param": null, "code": "overloaded"}})),
+(status = 422, description = "Input validation error", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Input validation error: `temperature` must be strictly positive", "type": "invalid_request_error", "param": "temperature", "code": "invalid_temperature"}})),
+(status = 500, description = "Incomplete generation", body = OpenAIErrorResponse,
+example = json ! ({"error": {"message": "Incomplete generation", "type": "server_error", "param": null, "code": "incomplete_generation"}})),
 )
 )]
 #[instrument(
@@ -1224,229 +2153,99 @@ pub(crate) async fn chat_completions(
     Extension(infer): Extension<Infer>,
     Extension(compute_type): Extension<ComputeType>,
     Extension(info): Extension<Info>,
+    Extension(streams): Extension<ResumableStreams>,
     Json(chat): Json<ChatRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+) -> Result<Response, OpenAIError> {
     let span = tracing::Span::current();
     metrics::counter!("tgi_request_count").increment(1);
-    let ChatRequest {
-        stream,
-        stream_options,
-        logprobs,
-        ..
-    } = chat.clone();
-    let (generate_request, using_tools): (GenerateRequest, bool) =
-        chat.try_into_generate(&infer)?;
-
-    let logprobs = logprobs.unwrap_or_default();
+    let stream = chat.stream;
+    let setup = ChatSetup::new(&infer, &info, chat).await?;
 
-    // static values that will be returned in all cases
-    let model_id = info.model_id.clone();
-    let system_fingerprint = format!("{}-{}", info.version, info.docker_label.unwrap_or("native"));
     // switch on stream
     if stream {
-        let (headers, response_stream) =
-            generate_stream_internal(infer, compute_type, Json(generate_request), span).await;
-
-        // regex to match any function name
-        let function_regex = match Regex::new(r#"\{"function":\{"_name":"([^"]+)""#) {
-            Ok(regex) => regex,
-            Err(e) => {
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: format!("Failed to compile regex: {}", e),
-                        error_type: "regex".to_string(),
-                    }),
-                ))
-            }
-        };
-
+        let (mut headers, chunks) = setup.stream(infer, compute_type, span).await?;
         let response_stream = async_stream::stream! {
-            let mut response_stream = Box::pin(response_stream);
-            let mut buffer = Vec::new();
-            let mut json_buffer = String::new();
-            let mut state = if using_tools {
-                StreamState::Buffering
-            } else {
-                StreamState::Content {
-                    skip_close_quote: false,
-                }
-            };
-            let mut response_as_tool = using_tools;
-            while let Some(result) = response_stream.next().await {
-                if let Ok(stream_token) = result {
-                    let token_text = &stream_token.token.text.clone();
-                    match state {
-                        StreamState::Buffering => {
-                            json_buffer.push_str(&token_text.replace(" ", ""));
-                            buffer.push(stream_token);
-                            if let Some(captures) = function_regex.captures(&json_buffer) {
-                                let function_name = captures[1].to_string();
-                                if function_name == "no_tool" {
-                                    state = StreamState::BufferTrailing;
-                                    response_as_tool = false;
-                                    buffer.clear();
-                                    json_buffer.clear();
-                                } else {
-                                    state = StreamState::Content {
-                                        skip_close_quote: false,
-                                    };
-                                    // send all the buffered messages
-                                    for stream_token in &buffer {
-                                        let event = create_event_from_stream_token(
-                                            stream_token,
-                                            logprobs,
-                                            stream_options.clone(),
-                                            response_as_tool,
-                                            system_fingerprint.clone(),
-                                            model_id.clone(),
-                                        );
-                                        yield Ok::<Event, Infallible>(event);
-                                    }
-                                }
-                            }
-                        }
-                        // if we skipped sending the buffer we need to avoid sending the following json key and quotes
-                        StreamState::BufferTrailing => {
-                            let infix_text = "\"content\":\"";
-                            json_buffer.push_str(&token_text.replace(" ", ""));
-                            // keep capturing until we find the infix text
-                            match json_buffer.find(infix_text) {
-                                Some(content_key_index) => {
-                                    json_buffer =
-                                        json_buffer[content_key_index + infix_text.len()..].to_string();
-                                }
-                                None => {
-                                    continue;
-                                }
-                            }
-                            // if there is leftover text after removing the infix text, we need to send it
-                            if !json_buffer.is_empty() {
-                                let event = Event::default();
-                                let current_time = std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap_or_else(|_| std::time::Duration::from_secs(0))
-                                    .as_secs();
-                                let chat_complete =
-                                    CompletionType::ChatCompletionChunk(ChatCompletionChunk::new(
-                                        model_id.clone(),
-                                        system_fingerprint.clone(),
-                                        Some(json_buffer.clone()),
-                                        None,
-                                        current_time,
-                                        None,
-                                        None,
-                                        None,
-                                    ));
-                                yield Ok(event.json_data(chat_complete).unwrap_or_else(|e| {
-                                    InferError::StreamSerializationError(e.to_string()).into()
-                                }));
-                            }
-                            // cleanup the buffers
-                            buffer.clear();
-                            json_buffer.clear();
-                            state = StreamState::Content {
-                                skip_close_quote: true,
-                            };
-                        }
-                        StreamState::Content { skip_close_quote } => {
-                            if skip_close_quote && token_text.contains('"') {
-                                break;
-                            }
-
-                            // send the content
-                            let event = create_event_from_stream_token(
-                                &stream_token,
-                                logprobs,
-                                stream_options.clone(),
-                                response_as_tool,
-                                system_fingerprint.clone(),
-                                model_id.clone(),
-                            );
-
-                            yield Ok::<Event, Infallible>(event);
-                        }
+            let mut chunks = Box::pin(chunks);
+            while let Some(chunk) = chunks.next().await {
+                let event = match chunk {
+                    Ok(chunk) => {
+                        let chat_complete = CompletionType::ChatCompletionChunk(chunk);
+                        Event::default().json_data(chat_complete).unwrap_or_else(|e| {
+                            OpenAIError::from(InferError::StreamSerializationError(e.to_string())).into()
+                        })
                     }
-                }
+                    Err(err) => Event::from(OpenAIError::from(err)),
+                };
+                yield Ok::<Event, Infallible>(event);
             }
             yield Ok::<Event, Infallible>(Event::default().data("[DONE]"));
         };
 
+        let (request_id, response_stream) = streams.register(response_stream);
+        headers.insert(REQUEST_ID_HEADER, request_id.parse().unwrap());
         let sse = Sse::new(response_stream).keep_alive(KeepAlive::default());
         Ok((headers, sse).into_response())
     } else {
-        let (headers, Json(generation)) =
-            generate_internal(Extension(infer), compute_type, Json(generate_request), span).await?;
+        let ChatSetup {
+            generate_request,
+            using_tools,
+            dropped_messages,
+            reasoning,
+            reasoning_budget,
+            prefill,
+            strict_schema,
+            logprobs,
+            system_fingerprint,
+            model_id,
+            ..
+        } = setup;
+        let (mut headers, Json(generation)) = generate_with_reasoning_budget(
+            infer,
+            compute_type,
+            Json(generate_request),
+            reasoning_budget,
+            span,
+        )
+        .await?;
+        if let Some(dropped_messages) = dropped_messages {
+            headers.insert("x-dropped-messages", dropped_messages.into());
+        }
 
         let current_time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap_or_else(|_| std::time::Duration::from_secs(0))
             .as_secs();
 
-        let (tool_calls, output) = if using_tools {
-            let gen_text_value: Value =
-                serde_json::from_str(&generation.generated_text).map_err(|e| {
-                    InferError::ToolError(format!(
-                        "Failed to parse generated text: {} {:?}",
-                        e, generation.generated_text
-                    ))
-                })?;
-            let function = gen_text_value.get("function").ok_or(InferError::ToolError(
-                "No function found in generated text".to_string(),
-            ))?;
-
-            let name = function
-                .get("_name")
-                .and_then(Value::as_str)
-                .ok_or(InferError::ToolError(
-                    "No _name found in generated text".to_string(),
-                ))?
-                .to_string();
-
-            let mut arguments = function.clone();
-            if let Value::Object(ref mut props) = arguments {
-                props.remove("_name");
-            }
-            match name.as_str() {
-                "no_tool" => {
-                    // parse the content message
-                    let content_message = arguments
-                        .get("content")
-                        .and_then(Value::as_str)
-                        .ok_or_else(|| {
-                            InferError::ToolError(
-                                "No `content` found in generated text".to_string(),
-                            )
-                        })?
-                        .to_string();
-                    (None, Some(content_message))
-                }
-                _ => {
-                    let tool_calls = vec![ToolCall {
-                        id: "0".to_string(),
-                        r#type: "function".to_string(),
-                        function: FunctionDefinition {
-                            description: None,
-                            name,
-                            arguments,
-                        },
-                    }];
-                    (Some(tool_calls), None)
-                }
-            }
+        let (tool_calls, output, reasoning) = if using_tools {
+            let (tool_calls, output) = parse_tool_call(&generation.generated_text)?;
+            (tool_calls, output, None)
         } else {
-            (None, Some(generation.generated_text))
+            let (reasoning, content) = match reasoning {
+                Some(splitter) => splitter.split(&generation.generated_text),
+                None => (None, generation.generated_text),
+            };
+            if let Some(schema) = &strict_schema {
+                check_strict_schema(schema, &content)?;
+            }
+            let output = match prefill {
+                Some(prefill) => prefill + &content,
+                None => content,
+            };
+            (None, Some(output), reasoning)
         };
         // build the complete response object with the full text
-        let response = CompletionType::ChatCompletion(ChatCompletion::new(
+        let mut completion = ChatCompletion::new(
             model_id,
             system_fingerprint,
             output,
+            reasoning,
             current_time,
             generation.details.unwrap(),
             logprobs,
             tool_calls,
-        ));
+        );
+        completion.dropped_messages = dropped_messages;
+        let response = CompletionType::ChatCompletion(completion);
 
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(response)).into_response())
@@ -1488,7 +2287,7 @@ async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
 }
 
 #[derive(Clone, Debug)]
-pub(crate) struct ComputeType(String);
+pub(crate) struct ComputeType(pub(crate) String);
 
 // OpenAPI documentation
 #[derive(OpenApi)]
@@ -1506,15 +2305,34 @@ metrics,
 openai_get_model_info,
 sagemaker_compatibility,
 get_chat_tokenize,
+upload_file,
+get_file,
+get_file_content,
+create_batch,
+list_batches,
+get_batch,
+cancel_batch,
+create_job,
+get_job,
+resume_stream,
+websocket,
+ollama_generate,
+ollama_chat,
+ollama_tags,
+ollama_show,
+score,
+detect_watermark,
 ),
 components(
 schemas(
 Info,
+GenerationDefaults,
 CompatGenerateRequest,
 SagemakerRequest,
 GenerateRequest,
 GrammarType,
 ChatRequest,
+Document,
 Message,
 MessageContent,
 MessageChunk,
@@ -1534,6 +2352,7 @@ ChatCompletionTopLogprob,
 ChatCompletion,
 CompletionRequest,
 CompletionComplete,
+CompletionLogprobs,
 SagemakerResponse,
 SagemakerStreamResponse,
 Chunk,
@@ -1553,8 +2372,12 @@ StreamResponse,
 StreamDetails,
 ErrorResponse,
 GrammarType,
+ResponseFormat,
+OpenAIResponseFormat,
+JsonSchemaFormat,
 Usage,
 StreamOptions,
+TruncationStrategy,
 DeltaToolCall,
 ToolType,
 Tool,
@@ -1564,6 +2387,38 @@ FunctionDefinition,
 ToolChoice,
 ModelInfo,
 ChatTokenizeResponse,
+FileObject,
+CreateBatchRequest,
+Batch,
+BatchStatus,
+BatchErrors,
+BatchError,
+BatchRequestCounts,
+BatchList,
+JobRequest,
+Job,
+JobStatus,
+OpenAIErrorResponse,
+OpenAIErrorObject,
+OllamaOptions,
+OllamaGenerateRequest,
+OllamaGenerateResponse,
+OllamaChatRequest,
+OllamaChatResponse,
+OllamaMessage,
+OllamaToolCall,
+OllamaFunction,
+OllamaStats,
+OllamaTags,
+OllamaModel,
+OllamaModelDetails,
+OllamaShowRequest,
+OllamaShowResponse,
+ScoreRequest,
+Score,
+ScoreResponse,
+WatermarkDetectRequest,
+WatermarkDetectResponse,
 )
 ),
 tags(
@@ -1658,10 +2513,13 @@ pub async fn run(
     max_top_n_tokens: u32,
     max_input_tokens: usize,
     max_total_tokens: usize,
+    default_max_new_tokens: Option<u32>,
     validation_workers: usize,
     api_key: Option<String>,
     tokenizer_name: String,
     tokenizer_config_path: Option<String>,
+    chat_template: Option<String>,
+    disable_generation_config: bool,
     revision: Option<String>,
     trust_remote_code: bool,
     hostname: String,
@@ -1673,6 +2531,16 @@ pub async fn run(
     disable_grammar_support: bool,
     max_client_batch_size: usize,
     usage_stats_level: usage_stats::UsageStatsLevel,
+    batch_api_dir: Option<String>,
+    batch_api_concurrency: usize,
+    max_stored_jobs: usize,
+    job_retention_seconds: u64,
+    job_callback_hosts: Vec<String>,
+    stream_resume_ttl_seconds: u64,
+    fim_mode: FimMode,
+    reasoning_markers: ReasoningMarkers,
+    watermark_gamma: f64,
+    watermark_detectable: bool,
 ) -> Result<(), WebServerError> {
     // CORS allowed origins
     // map to go inside the option and then map to parse from String to HeaderValue
@@ -1745,6 +2613,8 @@ pub async fn run(
         tokenizer_config_filename,
         preprocessor_config_filename,
         processor_config_filename,
+        chat_template_filenames,
+        generation_config_filename,
         model_info,
     ) = match api {
         Type::None => (
@@ -1752,6 +2622,11 @@ pub async fn run(
             Some(local_path.join("tokenizer_config.json")),
             Some(local_path.join("preprocessor_config.json")),
             Some(local_path.join("processor_config.json")),
+            (
+                Some(local_path.join("chat_template.jinja")),
+                Some(local_path.join("chat_template.json")),
+            ),
+            Some(local_path.join("generation_config.json")),
             None,
         ),
         Type::Api(api) => {
@@ -1765,6 +2640,11 @@ pub async fn run(
             let tokenizer_config_filename = api_repo.get("tokenizer_config.json").await.ok();
             let preprocessor_config_filename = api_repo.get("preprocessor_config.json").await.ok();
             let processor_config_filename = api_repo.get("processor_config.json").await.ok();
+            let chat_template_filenames = (
+                api_repo.get("chat_template.jinja").await.ok(),
+                api_repo.get("chat_template.json").await.ok(),
+            );
+            let generation_config_filename = api_repo.get("generation_config.json").await.ok();
 
             let model_info = if let Some(model_info) = get_hub_model_info(&api_repo).await {
                 Some(model_info)
@@ -1777,6 +2657,8 @@ pub async fn run(
                 tokenizer_config_filename,
                 preprocessor_config_filename,
                 processor_config_filename,
+                chat_template_filenames,
+                generation_config_filename,
                 model_info,
             )
         }
@@ -1791,6 +2673,11 @@ pub async fn run(
                 repo.get("tokenizer_config.json"),
                 repo.get("preprocessor_config.json"),
                 repo.get("processor_config.json"),
+                (
+                    repo.get("chat_template.jinja"),
+                    repo.get("chat_template.json"),
+                ),
+                repo.get("generation_config.json"),
                 None,
             )
         }
@@ -1803,7 +2690,7 @@ pub async fn run(
     } else {
         tokenizer_config_filename.and_then(HubTokenizerConfig::from_file)
     };
-    let tokenizer_config = tokenizer_config.unwrap_or_else(|| {
+    let mut tokenizer_config = tokenizer_config.unwrap_or_else(|| {
         tracing::warn!("Could not find tokenizer config locally and no API specified");
         HubTokenizerConfig::default()
     });
@@ -1834,6 +2721,37 @@ pub async fn run(
         }
     };
 
+    let generation_config = if disable_generation_config {
+        None
+    } else {
+        generation_config_filename.and_then(HubGenerationConfig::from_file)
+    };
+    let generation_defaults = generation_config
+        .map(GenerationDefaults::new)
+        .unwrap_or_default();
+    tracing::info!("Using generation defaults {generation_defaults:?}");
+
+    // The green lists of the watermark are drawn over the logits of the model, the size of the
+    // tokenizer does not match them when the model pads its vocabulary
+    let vocab_size = config_filename
+        .as_ref()
+        .and_then(|filename| std::fs::read_to_string(filename).ok())
+        .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        .and_then(|config| {
+            config["vocab_size"]
+                .as_u64()
+                .or_else(|| config["text_config"]["vocab_size"].as_u64())
+        })
+        .map(|vocab_size| vocab_size as usize);
+    let watermark_detector = if watermark_detectable {
+        if vocab_size.is_none() {
+            tracing::warn!("Could not find the vocabulary size, watermark detection is disabled");
+        }
+        vocab_size.map(|vocab_size| WatermarkDetector::new(watermark_gamma, vocab_size))
+    } else {
+        None
+    };
+
     let config: Option<Config> = config_filename.and_then(|filename| {
         std::fs::read_to_string(filename)
             .ok()
@@ -1856,6 +2774,37 @@ pub async fn run(
         .and_then(HubProcessorConfig::from_file)
         .unwrap_or_default();
 
+    // The chat template given to the router or shipped as a standalone file takes precedence
+    // over the one of the configs
+    let (chat_template_jinja_filename, chat_template_json_filename) = chat_template_filenames;
+    let chat_template = match chat_template {
+        Some(chat_template) => Some(chat_template_override(chat_template)?),
+        None => chat_template_jinja_filename
+            .and_then(|filename| std::fs::read_to_string(filename).ok())
+            .map(|template| {
+                (
+                    ChatTemplateVersions::Single(template),
+                    "chat_template.jinja".to_string(),
+                )
+            })
+            .or_else(|| {
+                chat_template_json_filename
+                    .and_then(HubChatTemplateConfig::from_file)
+                    .map(|config| (config.chat_template, "chat_template.json".to_string()))
+            }),
+    };
+    match (chat_template, &tokenizer_config.chat_template) {
+        (Some((chat_template, source)), _) => {
+            tracing::info!("Using the chat template of {source}");
+            tokenizer_config.chat_template = Some(chat_template);
+        }
+        (None, Some(_)) => tracing::info!("Using the chat template of tokenizer_config.json"),
+        (None, None) if processor_config.chat_template.is_some() => {
+            tracing::info!("Using the chat template of processor_config.json")
+        }
+        (None, None) => tracing::warn!("Could not find a chat template"),
+    }
+
     let preprocessor_config: Option<HubPreprocessorConfig> =
         preprocessor_config_filename.and_then(HubPreprocessorConfig::from_file);
 
@@ -1912,6 +2861,7 @@ pub async fn run(
         max_top_n_tokens,
         max_input_tokens,
         max_total_tokens,
+        default_max_new_tokens,
         validation_workers,
         api_key,
         config,
@@ -1927,6 +2877,16 @@ pub async fn run(
         model_info,
         compat_return_full_text,
         allow_origin,
+        batch_api_dir,
+        batch_api_concurrency,
+        max_stored_jobs,
+        job_retention_seconds,
+        job_callback_hosts,
+        stream_resume_ttl_seconds,
+        fim_mode,
+        reasoning_markers,
+        watermark_detector,
+        generation_defaults,
     )
     .await;
 
@@ -1971,6 +2931,7 @@ async fn start(
     max_top_n_tokens: u32,
     max_input_tokens: usize,
     max_total_tokens: usize,
+    default_max_new_tokens: Option<u32>,
     validation_workers: usize,
     api_key: Option<String>,
     config: Option<Config>,
@@ -1986,6 +2947,16 @@ async fn start(
     model_info: HubModelInfo,
     compat_return_full_text: bool,
     allow_origin: Option<AllowOrigin>,
+    batch_api_dir: Option<String>,
+    batch_api_concurrency: usize,
+    max_stored_jobs: usize,
+    job_retention_seconds: u64,
+    job_callback_hosts: Vec<String>,
+    stream_resume_ttl_seconds: u64,
+    fim_mode: FimMode,
+    reasoning_markers: ReasoningMarkers,
+    watermark_detector: Option<WatermarkDetector>,
+    generation_defaults: GenerationDefaults,
 ) -> Result<(), WebServerError> {
     // Determine the server port based on the feature and environment variable.
     let port = if cfg!(feature = "google") {
@@ -2005,6 +2976,12 @@ async fn start(
     };
 
     // Create state
+    let fim_template = FimTemplate::new(
+        &tokenizer,
+        tokenizer_config.completion_template.clone(),
+        fim_mode,
+    );
+    let reasoning_parser = ReasoningParser::new(&tokenizer, reasoning_markers);
     let validation = Validation::new(
         validation_workers,
         tokenizer,
@@ -2015,6 +2992,7 @@ async fn start(
         max_top_n_tokens,
         max_input_tokens,
         max_total_tokens,
+        default_max_new_tokens,
         disable_grammar_support,
     );
 
@@ -2024,6 +3002,9 @@ async fn start(
         max_concurrent_requests,
         tokenizer_config,
         processor_config,
+        fim_template,
+        generation_defaults.clone(),
+        reasoning_parser,
     );
 
     // Duration buckets
@@ -2198,6 +3179,11 @@ async fn start(
         metrics::Unit::Count,
         "Batch size of the next batch"
     );
+    metrics::describe_counter!(
+        "tgi_request_coalesced",
+        metrics::Unit::Count,
+        "Number of requests attached to an identical in-flight greedy request"
+    );
 
     // CORS layer
     let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
@@ -2218,12 +3204,15 @@ async fn start(
         max_stop_sequences,
         max_input_tokens,
         max_total_tokens,
+        default_max_new_tokens,
         // waiting_served_ratio,
         // max_batch_total_tokens,
         // max_waiting_tokens,
         // max_batch_size,
         validation_workers,
         max_client_batch_size,
+        chat_templates: infer.chat_template_names(),
+        generation_defaults,
         router: env!("CARGO_PKG_NAME"),
         version: env!("CARGO_PKG_VERSION"),
         sha: option_env!("VERGEN_GIT_SHA"),
@@ -2248,6 +3237,35 @@ async fn start(
         doc.merge(VertexApiDoc::openapi());
     }
 
+    #[cfg(feature = "anthropic")]
+    {
+        use crate::anthropic::__path_anthropic_messages;
+        use crate::anthropic::{
+            AnthropicContent, AnthropicMessage, AnthropicTool, AnthropicToolChoice, AnthropicUsage,
+            ContentBlock, ImageSource, MessagesRequest, MessagesResponse, ResponseBlock,
+        };
+
+        #[derive(OpenApi)]
+        #[openapi(
+            paths(anthropic_messages),
+            components(schemas(
+                AnthropicContent,
+                AnthropicMessage,
+                AnthropicTool,
+                AnthropicToolChoice,
+                AnthropicUsage,
+                ContentBlock,
+                ImageSource,
+                MessagesRequest,
+                MessagesResponse,
+                ResponseBlock,
+            ))
+        )]
+        struct AnthropicApiDoc;
+
+        doc.merge(AnthropicApiDoc::openapi());
+    }
+
     #[cfg(feature = "kserve")]
     {
         use crate::kserve::{
@@ -2296,7 +3314,61 @@ async fn start(
         .route("/v1/completions", post(completions))
         .route("/vertex", post(vertex_compatibility))
         .route("/invocations", post(sagemaker_compatibility))
-        .route("/tokenize", post(tokenize));
+        .route("/tokenize", post(tokenize))
+        .route("/score", post(score))
+        .route("/watermark/detect", post(detect_watermark))
+        .route("/jobs", post(create_job))
+        .route("/jobs/:job_id", get(get_job))
+        .route("/streams/:request_id", get(resume_stream))
+        .route("/ws", get(websocket))
+        .route("/api/generate", post(ollama_generate))
+        .route("/api/chat", post(ollama_chat))
+        .route("/api/tags", get(ollama_tags))
+        .route("/api/show", post(ollama_show));
+
+    let compute_type =
+        ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
+
+    let job_store = JobStore::new(
+        max_stored_jobs,
+        std::time::Duration::from_secs(job_retention_seconds),
+        job_callback_hosts,
+    );
+
+    let streams = ResumableStreams::new(std::time::Duration::from_secs(stream_resume_ttl_seconds));
+
+    // Batch API, only enabled when a storage directory is given
+    if let Some(batch_api_dir) = batch_api_dir {
+        tracing::info!("Batch API enabled, storing batches in {batch_api_dir}");
+        let batch_api = BatchApi::new(
+            PathBuf::from(batch_api_dir),
+            batch_api_concurrency,
+            infer.clone(),
+            compute_type.clone(),
+            info.clone(),
+            streams.clone(),
+        )
+        .await
+        .map_err(WebServerError::Batch)?;
+        let batch_routes = Router::new()
+            .route(
+                "/v1/files",
+                post(upload_file).layer(DefaultBodyLimit::disable()),
+            )
+            .route("/v1/files/:file_id", get(get_file))
+            .route("/v1/files/:file_id/content", get(get_file_content))
+            .route("/v1/batches", post(create_batch).get(list_batches))
+            .route("/v1/batches/:batch_id", get(get_batch))
+            .route("/v1/batches/:batch_id/cancel", post(cancel_batch))
+            .layer(Extension(batch_api));
+        base_routes = base_routes.merge(batch_routes);
+    }
+
+    #[cfg(feature = "anthropic")]
+    {
+        tracing::info!("Built with `anthropic` feature");
+        base_routes = base_routes.route("/v1/messages", post(anthropic_messages));
+    }
 
     if let Some(api_key) = api_key {
         let mut prefix = "Bearer ".to_string();
@@ -2331,9 +3403,6 @@ async fn start(
         .route("/metrics", get(metrics))
         .route("/v1/models", get(openai_get_model_info));
 
-    let compute_type =
-        ComputeType(std::env::var("COMPUTE_TYPE").unwrap_or("gpu+optimized".to_string()));
-
     // Combine routes and layers
     let mut app = Router::new()
         .merge(swagger_ui)
@@ -2381,6 +3450,9 @@ async fn start(
         .layer(Extension(compat_return_full_text))
         .layer(Extension(infer))
         .layer(Extension(compute_type))
+        .layer(Extension(job_store))
+        .layer(Extension(streams))
+        .layer(Extension(watermark_detector))
         .layer(Extension(prom_handle.clone()))
         .layer(OtelAxumLayer::default())
         .layer(cors_layer);
@@ -2434,6 +3506,36 @@ pub async fn get_hub_model_info(api: &ApiRepo) -> Option<HubModelInfo> {
     }
 }
 
+/// Read the `--chat-template` option: the path of a `.jinja` or `chat_template.json` file, or an
+/// inline template. Values ending with `.jinja` or `.json` must be existing files.
+fn chat_template_override(
+    chat_template: String,
+) -> Result<(ChatTemplateVersions, String), WebServerError> {
+    let path = Path::new(&chat_template);
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let source = format!("--chat-template {chat_template}");
+    if !path.is_file() {
+        if matches!(extension, Some("jinja" | "json")) {
+            return Err(WebServerError::ChatTemplate(format!(
+                "{source}: the file does not exist"
+            )));
+        }
+        return Ok((
+            ChatTemplateVersions::Single(chat_template),
+            "--chat-template".to_string(),
+        ));
+    }
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| WebServerError::ChatTemplate(format!("{source}: {err}")))?;
+    if extension == Some("json") {
+        let config: HubChatTemplateConfig = serde_json::from_str(&content)
+            .map_err(|err| WebServerError::ChatTemplate(format!("{source}: {err}")))?;
+        Ok((config.chat_template, source))
+    } else {
+        Ok((ChatTemplateVersions::Single(content), source))
+    }
+}
+
 /// get tokenizer_config from the Huggingface Hub
 pub async fn get_tokenizer_config(api_repo: &ApiRepo) -> Option<HubTokenizerConfig> {
     let tokenizer_config_filename = api_repo.get("tokenizer_config.json").await.ok()?;
@@ -2484,65 +3586,151 @@ async fn shutdown_signal() {
 /// Convert to Axum supported formats
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
-        let status_code = match err {
-            InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
-            InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
-            InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::IncompleteGenerationStream => StatusCode::INTERNAL_SERVER_ERROR,
-            InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::MissingTemplateVariable(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
-            InferError::StreamSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-        };
+        (status_code(&err), Json(ErrorResponse::from(err)))
+    }
+}
 
-        (
-            status_code,
-            Json(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            }),
-        )
+fn status_code(err: &InferError) -> StatusCode {
+    match err {
+        InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
+        InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+        InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
+        InferError::IncompleteGenerationStream => StatusCode::INTERNAL_SERVER_ERROR,
+        InferError::TemplateError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::MissingTemplateVariable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::ToolError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::StreamSerializationError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        InferError::SchemaMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        InferError::Coalesced(err) => status_code(err),
+    }
+}
+
+impl From<InferError> for ErrorResponse {
+    fn from(err: InferError) -> Self {
+        ErrorResponse {
+            error: err.to_string(),
+            error_type: err.error_type().to_string(),
+            code: err.code().to_string(),
+            param: err.param().map(String::from),
+        }
     }
 }
 
 impl From<InferError> for Event {
     fn from(err: InferError) -> Self {
         Event::default()
-            .json_data(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            })
+            .json_data(ErrorResponse::from(err))
+            .unwrap()
+    }
+}
+
+/// Error of the OpenAI compatible routes, sent in the OpenAI envelope
+pub(crate) struct OpenAIError(pub StatusCode, pub ErrorResponse);
+
+impl From<(StatusCode, Json<ErrorResponse>)> for OpenAIError {
+    fn from((status_code, Json(err)): (StatusCode, Json<ErrorResponse>)) -> Self {
+        OpenAIError(status_code, err)
+    }
+}
+
+impl From<InferError> for OpenAIError {
+    fn from(err: InferError) -> Self {
+        <(StatusCode, Json<ErrorResponse>)>::from(err).into()
+    }
+}
+
+impl From<OpenAIError> for OpenAIErrorResponse {
+    fn from(OpenAIError(status_code, err): OpenAIError) -> Self {
+        let r#type = match status_code {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            status_code if status_code.is_client_error() => "invalid_request_error",
+            _ => "server_error",
+        };
+        // Use the name of the OpenAI parameters
+        let param = err.param.map(|param| {
+            match param.as_str() {
+                "max_new_tokens" => "max_tokens",
+                "top_n_tokens" => "top_logprobs",
+                "repetition_penalty" => "presence_penalty",
+                "grammar" => "response_format",
+                param => param,
+            }
+            .to_string()
+        });
+        OpenAIErrorResponse {
+            error: OpenAIErrorObject {
+                message: err.error,
+                r#type: r#type.to_string(),
+                param,
+                code: Some(err.code),
+            },
+        }
+    }
+}
+
+impl From<OpenAIError> for Event {
+    fn from(err: OpenAIError) -> Self {
+        Event::default()
+            .json_data(OpenAIErrorResponse::from(err))
             .unwrap()
     }
 }
 
+impl IntoResponse for OpenAIError {
+    fn into_response(self) -> Response {
+        let status_code = self.0;
+        (status_code, Json(OpenAIErrorResponse::from(self))).into_response()
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum WebServerError {
     #[error("Axum error: {0}")]
     Axum(#[from] axum::BoxError),
+    #[error("Batch API error: {0}")]
+    Batch(std::io::Error),
+    #[error("Chat template error: {0}")]
+    ChatTemplate(String),
 }
 
 type PreparedInput = (String, Option<GrammarType>, bool);
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn prepare_chat_input(
     infer: &Infer,
     response_format: Option<GrammarType>,
     tools: Option<Vec<Tool>>,
     tool_choice: ToolChoice,
     tool_prompt: &str,
+    chat_template: Option<&str>,
     guideline: Option<String>,
+    documents: Option<Vec<Document>>,
     messages: Vec<Message>,
+    continue_final_message: bool,
 ) -> Result<PreparedInput, InferError> {
     if response_format.is_some() && tools.is_some() {
         return Err(InferError::ToolError(
             "Grammar and tools are mutually exclusive".into(),
         ));
     }
+    if continue_final_message && tools.is_some() {
+        return Err(InferError::ValidationError(
+            ValidationError::ContinueFinalMessage("cannot be used with tools"),
+        ));
+    }
 
     // when response_format is set, tools are not included when applying the chat template to generate inputs
     if let Some(format) = response_format {
-        let inputs = infer.apply_chat_template(guideline, messages, None)?;
+        let inputs = infer.apply_chat_template(
+            chat_template,
+            guideline,
+            documents,
+            messages,
+            None,
+            continue_final_message,
+        )?;
         return Ok((inputs, Some(format), false));
     }
 
@@ -2556,15 +3744,25 @@ pub(crate) fn prepare_chat_input(
             .map(|t| GrammarType::Json(serde_json::json!(t)));
 
         let inputs: String = infer.apply_chat_template(
+            chat_template,
             guideline,
+            documents,
             messages,
             Some((updated_tools, tool_prompt.into())),
+            false,
         )?;
         return Ok((inputs, grammar, tool_schema.is_some()));
     }
 
     // if no response_format or tools are set simply apply the chat template to generate inputs
-    let inputs = infer.apply_chat_template(guideline, messages, None)?;
+    let inputs = infer.apply_chat_template(
+        chat_template,
+        guideline,
+        documents,
+        messages,
+        None,
+        continue_final_message,
+    )?;
     Ok((inputs, None, false))
 }
 
@@ -2576,7 +3774,7 @@ mod tests {
     use crate::TokenizerConfigToken;
     use crate::Tool;
 
-    use crate::tests::get_tokenizer;
+    use crate::tests::{get_tokenizer, mock_infer, mock_infer_with_tokenizer_config, mock_info};
     use serde_json::json;
 
     #[tokio::test]
@@ -2625,10 +3823,13 @@ mod tests {
 
         let infer = Infer::new(
             backend,
-            Validation::new(1, tokenizer, None, None, 1, 1, 1, 1, 1, false),
+            Validation::new(1, tokenizer, None, None, 1, 1, 1, 1, 1, None, false),
             1,
             tokenizer_config,
             HubProcessorConfig::default(),
+            None,
+            GenerationDefaults::default(),
+            None,
         );
         let response_format = None;
         let tools = Some(vec![Tool {
@@ -2669,8 +3870,11 @@ mod tests {
             tools,
             ToolChoice(None),
             tool_prompt,
+            None,
             guideline,
+            None,
             messages,
+            false,
         );
 
         assert!(result.is_ok());
@@ -2678,4 +3882,326 @@ mod tests {
         assert_eq!(using_tools, true);
         assert_eq!(inputs, "<s>[AVAILABLE_TOOLS] [{\"type\": \"function\", \"function\": {\"arguments\": {\"properties\":{\"format\":{\"description\":\"The temperature unit to use. Infer this from the users location.\",\"enum\":[\"celsius\",\"fahrenheit\"],\"type\":\"string\"},\"location\":{\"description\":\"The city and state, e.g. San Francisco, CA\",\"type\":\"string\"}},\"required\":[\"location\",\"format\"],\"type\":\"object\"}, \"description\": \"Get the current weather\", \"name\": \"get_current_weather\"}}, {\"type\": \"function\", \"function\": {\"arguments\": {\"properties\":{\"content\":{\"description\":\"The response content\",\"type\":\"string\"}},\"required\":[\"content\"],\"type\":\"object\"}, \"description\": \"Open ened response with no specific tool selected\", \"name\": \"no_tool\"}}][/AVAILABLE_TOOLS][INST] What is the weather like in New York?\n---\nGiven the functions available, please respond with a JSON for a function call with its proper arguments that best answers the given prompt. Respond in the format {name: function name, parameters: dictionary of argument name and its value}.Do not use variables.[/INST]".to_string());
     }
+
+    #[tokio::test]
+    async fn test_named_chat_templates() {
+        let template = |name: &str| crate::ChatTemplate {
+            name: name.to_string(),
+            template: format!("{name}:{{{{ messages[0].content }}}}"),
+        };
+        let tokenizer_config = HubTokenizerConfig {
+            chat_template: Some(ChatTemplateVersions::Multiple(vec![
+                template("default"),
+                template("tool_use"),
+                template("rag"),
+            ])),
+            ..Default::default()
+        };
+        let infer = mock_infer_with_tokenizer_config(
+            crate::tests::MockBackend::new(|_| String::new()),
+            64,
+            1,
+            tokenizer_config,
+        );
+        assert_eq!(
+            infer.chat_template_names(),
+            vec!["default", "rag", "tool_use"]
+        );
+
+        let messages = vec![Message {
+            name: None,
+            role: "user".to_string(),
+            content: MessageContent::SingleText("Hello".to_string()),
+        }];
+        let apply = |chat_template: Option<&str>, tools: Option<(Vec<Tool>, String)>| {
+            infer.apply_chat_template(chat_template, None, None, messages.clone(), tools, false)
+        };
+        assert_eq!(apply(None, None).unwrap(), "default:Hello");
+        assert_eq!(apply(Some("rag"), None).unwrap(), "rag:Hello");
+        assert!(apply(None, Some((vec![], String::new())))
+            .unwrap()
+            .starts_with("tool_use:Hello"));
+        assert!(apply(Some("default"), Some((vec![], String::new())))
+            .unwrap()
+            .starts_with("default:Hello"));
+        assert!(matches!(
+            apply(Some("missing"), None),
+            Err(InferError::TemplateError(_))
+        ));
+    }
+
+    #[test]
+    fn test_chat_template_override() {
+        let (template, source) = chat_template_override("{{ bos_token }}".to_string()).unwrap();
+        assert_eq!(
+            template,
+            ChatTemplateVersions::Single("{{ bos_token }}".to_string())
+        );
+        assert_eq!(source, "--chat-template");
+
+        let name = format!("tgi-chat-template-{}", uuid::Uuid::new_v4().simple());
+        let path = std::env::temp_dir().join(format!("{name}.jinja"));
+        std::fs::write(&path, "{{ eos_token }}").unwrap();
+        let (template, _) = chat_template_override(path.display().to_string()).unwrap();
+        assert_eq!(
+            template,
+            ChatTemplateVersions::Single("{{ eos_token }}".to_string())
+        );
+        std::fs::remove_file(&path).unwrap();
+        // A mistyped path is not used as a template
+        assert!(matches!(
+            chat_template_override(path.display().to_string()),
+            Err(WebServerError::ChatTemplate(_))
+        ));
+
+        let path = std::env::temp_dir().join(format!("{name}.json"));
+        std::fs::write(&path, r#"{"chat_template": "{{ messages }}"}"#).unwrap();
+        let (template, _) = chat_template_override(path.display().to_string()).unwrap();
+        assert_eq!(
+            template,
+            ChatTemplateVersions::Single("{{ messages }}".to_string())
+        );
+        std::fs::write(&path, "{{ messages }}").unwrap();
+        assert!(matches!(
+            chat_template_override(path.display().to_string()),
+            Err(WebServerError::ChatTemplate(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_error_envelopes() {
+        let err = InferError::ValidationError(ValidationError::MaxNewTokens(1024, 2048));
+        let (status_code, Json(native)) = <(StatusCode, Json<ErrorResponse>)>::from(err);
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            serde_json::to_value(&native).unwrap(),
+            json!({
+                "error": "Input validation error: `max_new_tokens` must be <= 1024. Given: 2048",
+                "error_type": "validation",
+                "code": "max_new_tokens_too_large",
+                "param": "max_new_tokens",
+            })
+        );
+
+        let openai = OpenAIErrorResponse::from(OpenAIError(status_code, native));
+        assert_eq!(
+            serde_json::to_value(&openai).unwrap(),
+            json!({
+                "error": {
+                    "message": "Input validation error: `max_new_tokens` must be <= 1024. Given: 2048",
+                    "type": "invalid_request_error",
+                    "param": "max_tokens",
+                    "code": "max_new_tokens_too_large",
+                }
+            })
+        );
+
+        let openai = OpenAIErrorResponse::from(OpenAIError::from(InferError::IncompleteGeneration));
+        assert_eq!(openai.error.r#type, "server_error");
+        assert_eq!(openai.error.code.as_deref(), Some("incomplete_generation"));
+        assert_eq!(openai.error.param, None);
+    }
+
+    #[test]
+    fn test_drop_turns() {
+        let message = |role: &str, text: &str| Message {
+            role: role.to_string(),
+            content: MessageContent::SingleText(text.to_string()),
+            name: None,
+        };
+        let messages = vec![
+            message("system", "You are a helpful assistant."),
+            message("user", "Hi"),
+            message("assistant", "Hello!"),
+            message("user", "What is Deep Learning?"),
+            message("assistant", "A subfield of machine learning."),
+            message("user", "Tell me more."),
+        ];
+        assert_eq!(turn_starts(&messages), vec![3, 5]);
+
+        let (kept, dropped) = drop_turns(&messages, 3);
+        let roles: Vec<&str> = kept.iter().map(|message| message.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+        assert_eq!(kept[1], messages[3]);
+        assert_eq!(dropped, messages[1..3]);
+
+        // The first turn is never dropped on its own
+        assert_eq!(turn_starts(&messages[..2]), Vec::<usize>::new());
+    }
+
+    fn words(count: usize) -> String {
+        vec!["w"; count].join(" ")
+    }
+
+    fn long_chat(lengths: &[usize], strategy: TruncationStrategy) -> ChatRequest {
+        let roles = ["user", "assistant"];
+        let messages: Vec<_> = lengths
+            .iter()
+            .enumerate()
+            .map(|(index, length)| json!({"role": roles[index % 2], "content": words(*length)}))
+            .collect();
+        serde_json::from_value(json!({
+            "messages": messages,
+            "max_tokens": 8,
+            "truncation_strategy": strategy,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_truncation_drop_oldest() {
+        let infer = mock_infer(crate::tests::MockBackend::new(|_| String::new()), 1000, 4);
+        // A message is its words and 3 tokens for the role
+        let chat = long_chat(&[100, 100, 50, 50, 730], TruncationStrategy::DropOldest);
+        let (request, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        // Dropping the first turn is enough
+        assert_eq!(dropped, 2);
+        assert!(request
+            .inputs
+            .starts_with(&format!("<|user|> {} ", words(50))));
+
+        // Conversations that fit are left untouched
+        let chat = long_chat(&[100, 100], TruncationStrategy::DropOldest);
+        let (_, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        assert_eq!(dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn test_truncation_summarize() {
+        let summary = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+        let reply = summary.clone();
+        let backend = crate::tests::MockBackend::new(move |_| reply.lock().unwrap().clone());
+        let infer = mock_infer(backend.clone(), 1000, 4);
+        // The last turn leaves room for exactly `SUMMARY_MAX_TOKENS`
+        let lengths = [150, 150, 730];
+
+        *summary.lock().unwrap() = "They said hi".to_string();
+        let chat = long_chat(&lengths, TruncationStrategy::Summarize);
+        let (request, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        assert_eq!(dropped, 2);
+        assert!(request
+            .inputs
+            .starts_with("<|system|> Summary of the earlier conversation: They said hi <|user|>"));
+        assert!(backend.requests.lock().unwrap()[0].contains(SUMMARY_PROMPT));
+
+        // The text around the longest summary does not fit, the turns are dropped without it
+        *summary.lock().unwrap() = words(SUMMARY_MAX_TOKENS as usize);
+        let chat = long_chat(&lengths, TruncationStrategy::Summarize);
+        let (request, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        assert_eq!(dropped, 2);
+        assert!(request
+            .inputs
+            .starts_with(&format!("<|user|> {} ", words(730))));
+        assert_eq!(backend.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_truncation_summary_fallbacks() {
+        let summary = std::sync::Arc::new(std::sync::Mutex::new("They said hi".to_string()));
+        let reply = summary.clone();
+        let backend = crate::tests::MockBackend::new(move |_| reply.lock().unwrap().clone());
+        let infer = mock_infer(backend.clone(), 1000, 4);
+
+        // Only the most recent part of the dropped turns is summarized
+        let chat = long_chat(&[800, 100, 600], TruncationStrategy::Summarize);
+        let (request, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        assert_eq!(dropped, 2);
+        assert!(request
+            .inputs
+            .starts_with("<|system|> Summary of the earlier conversation: They said hi <|user|>"));
+        let prompt = backend.requests.lock().unwrap()[0].clone();
+        assert!(prompt.contains(&format!("{SUMMARY_PROMPT}\n\n… w w")));
+        assert!(!prompt.contains("user: w"));
+        assert!(prompt.ends_with(&format!("\n\nassistant: {} <|assistant|>", words(100))));
+        assert!(prompt.split(' ').count() <= 1000 - SUMMARY_MAX_TOKENS as usize);
+
+        // Empty summaries, from thinking models out of budget, are not added
+        *summary.lock().unwrap() = " ".to_string();
+        let chat = long_chat(&[150, 150, 730], TruncationStrategy::Summarize);
+        let (request, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        assert_eq!(dropped, 2);
+        assert!(request.inputs.starts_with("<|user|>"));
+
+        // Nor with templates rejecting the system role
+        *summary.lock().unwrap() = "They said hi".to_string();
+        let tokenizer_config = HubTokenizerConfig {
+            chat_template: Some(ChatTemplateVersions::Single(
+                "{% for message in messages %}{% if message['role'] == 'system' %}{{ raise_exception('No system role') }}{% endif %}<|{{ message['role'] }}|> {{ message['content'] }} {% endfor %}".to_string(),
+            )),
+            ..Default::default()
+        };
+        let infer = mock_infer_with_tokenizer_config(backend.clone(), 1000, 4, tokenizer_config);
+        let chat = long_chat(&[150, 150, 730], TruncationStrategy::Summarize);
+        let (request, _, dropped) = prepare_chat_request(&infer, chat).await.unwrap();
+        assert_eq!(dropped, 2);
+        assert!(request.inputs.starts_with("<|user|>"));
+    }
+
+    #[tokio::test]
+    async fn test_reasoning_budget() {
+        let backend = crate::tests::MockBackend::new(|inputs| {
+            if inputs.contains("</think>") {
+                "Four".to_string()
+            } else {
+                "<think> two plus two is four".to_string()
+            }
+        });
+        let infer = mock_infer(backend.clone(), 64, 4);
+        let splitter = ReasoningParser::new(
+            &crate::tests::get_offline_tokenizer(),
+            "<think>,</think>".parse().unwrap(),
+        )
+        .unwrap()
+        .splitter("", true);
+        let request = GenerateRequest {
+            inputs: "What is 2 + 2 ?".to_string(),
+            add_special_tokens: true,
+            parameters: GenerateParameters {
+                max_new_tokens: Some(10),
+                details: true,
+                decoder_input_details: true,
+                ..Default::default()
+            },
+        };
+        let (headers, Json(response)) = generate_with_reasoning_budget(
+            infer,
+            ComputeType("cpu".to_string()),
+            Json(request),
+            Some((4, splitter)),
+            tracing::Span::none(),
+        )
+        .await
+        .unwrap();
+
+        // The reasoning is cut after 4 tokens and a second request generates the answer
+        assert_eq!(response.generated_text, "<think> two plus two </think>Four");
+        assert_eq!(backend.requests.lock().unwrap().len(), 2);
+        let details = response.details.unwrap();
+        assert_eq!(details.generated_tokens, 5);
+        assert!(matches!(
+            details.finish_reason,
+            FinishReason::EndOfSequenceToken
+        ));
+        // Only the original prompt is counted
+        assert_eq!(headers["x-prompt-tokens"], "6");
+        assert_eq!(headers["x-generated-tokens"], "5");
+    }
+
+    #[tokio::test]
+    async fn test_echo_final_message() {
+        let infer = mock_infer(crate::tests::MockBackend::new(|_| String::new()), 64, 4);
+        let chat: ChatRequest = serde_json::from_value(json!({
+            "messages": [
+                {"role": "user", "content": "Count to three"},
+                {"role": "assistant", "content": "One, two, "},
+            ],
+            "continue_final_message": true,
+            "echo": true,
+        }))
+        .unwrap();
+        let setup = ChatSetup::new(&infer, &mock_info(64), chat).await.unwrap();
+        assert_eq!(setup.prefill.as_deref(), Some("One, two,"));
+        assert!(setup.generate_request.inputs.ends_with("One, two,"));
+    }
 }
@@ -1,16 +1,15 @@
 /// Payload validation logic
 use crate::config::Config;
+use crate::grammar::GrammarCompiler;
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
 use crate::{
-    GenerateParameters, GenerateRequest, GrammarType, HubPreprocessorConfig, Idefics2Preprocessor,
+    GenerateParameters, GenerateRequest, HubPreprocessorConfig, Idefics2Preprocessor,
     TokenizerTrait,
 };
 use crate::{PyTokenizer, Tokenizer};
 use base64::{engine::general_purpose::STANDARD, Engine};
 use image::{ImageFormat, ImageReader};
-use jsonschema::{Draft, JSONSchema};
 use rand::{thread_rng, Rng};
-use serde_json::Value;
 use std::io::Cursor;
 use std::iter;
 use std::sync::Arc;
@@ -29,7 +28,10 @@ pub struct Validation {
     max_top_n_tokens: u32,
     max_input_length: usize,
     max_total_tokens: usize,
+    /// Cap of the tokens generated by the requests without `max_new_tokens`
+    default_max_new_tokens: Option<u32>,
     disable_grammar_support: bool,
+    grammars: GrammarCompiler,
     /// Channel to communicate with the background tokenization task
     sender: mpsc::UnboundedSender<TokenizerRequest>,
 }
@@ -46,6 +48,7 @@ impl Validation {
         max_top_n_tokens: u32,
         max_input_length: usize,
         max_total_tokens: usize,
+        default_max_new_tokens: Option<u32>,
         disable_grammar_support: bool,
     ) -> Self {
         let workers = if let Tokenizer::Python { .. } = &tokenizer {
@@ -53,6 +56,8 @@ impl Validation {
         } else {
             workers
         };
+        let grammars = GrammarCompiler::new();
+
         // If we have a fast tokenizer
         let sender = {
             // Create round robin channel
@@ -91,7 +96,9 @@ impl Validation {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            default_max_new_tokens,
             disable_grammar_support,
+            grammars,
         }
     }
 
@@ -122,6 +129,12 @@ impl Validation {
         Ok(encoding)
     }
 
+    /// Whether a prompt of `input_length` tokens and `max_new_tokens` fit the context window
+    pub(crate) fn fits_context(&self, input_length: usize, max_new_tokens: u32) -> bool {
+        input_length <= self.max_input_length
+            && input_length + max_new_tokens as usize <= self.max_total_tokens
+    }
+
     #[allow(clippy::type_complexity)]
     #[instrument(skip(self, inputs))]
     async fn validate_input(
@@ -146,7 +159,10 @@ impl Validation {
         let max_new_tokens: u32 = if let Some(max_new_tokens) = max_new_tokens {
             max_new_tokens
         } else {
-            self.max_total_tokens.saturating_sub(input_length) as u32
+            // Generate until the context window is full, within the cap set by the server
+            let remaining = self.max_total_tokens.saturating_sub(input_length) as u32;
+            self.default_max_new_tokens
+                .map_or(remaining, |cap| remaining.min(cap))
         };
         let total_tokens = input_length + max_new_tokens as usize;
 
@@ -313,53 +329,15 @@ impl Validation {
             )
             .await?;
 
-        // TODO: we should build the FSM here and pass the compiled FSM instead of the grammar
-        // NOTE: this is currently difficult because we need the tokenizer in Python to build
-        // the FSM and we'd have to load a copy of the tokenizer into our Pyo3 instance which
-        // may be slow and memory intensive. Best case is to have a Rust implementation of the FSM
-        // compiler and use that to build the FSM here.
-
-        // Validate grammar and unpack the grammar and type for the proto message
+        // The grammars are checked in the router, which bounds the size of the regular ones. The
+        // shards still build their own FSM.
         let grammar = match grammar {
             Some(grammar) => {
                 // Ensure that grammar is not set if it's not supported
                 if self.disable_grammar_support {
                     return Err(ValidationError::Grammar);
                 }
-                let valid_grammar = match grammar {
-                    GrammarType::Json(json) => {
-                        let json = match json {
-                            // if value is a string, we need to parse it again to make sure its
-                            // a valid json
-                            Value::String(s) => serde_json::from_str(&s)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string())),
-                            Value::Object(_) => Ok(json),
-                            _ => Err(ValidationError::Grammar),
-                        }?;
-
-                        // Check if the json is a valid JSONSchema
-                        JSONSchema::options()
-                            .with_draft(Draft::Draft202012)
-                            .compile(&json)
-                            .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?;
-
-                        // The schema can be valid but lack properties.
-                        // We need properties for the grammar to be successfully parsed in Python.
-                        // Therefore, we must check and throw an error if properties are missing.
-                        json.get("properties")
-                            .ok_or(ValidationError::InvalidGrammar(
-                                "Grammar must have a 'properties' field".to_string(),
-                            ))?;
-
-                        // Serialize json to string
-                        ValidGrammar::Json(
-                            serde_json::to_string(&json)
-                                .map_err(|e| ValidationError::InvalidGrammar(e.to_string()))?,
-                        )
-                    }
-                    GrammarType::Regex(regex) => ValidGrammar::Regex(regex),
-                };
-                Some(valid_grammar)
+                Some(self.grammars.compile(grammar).await?)
             }
             None => None,
         };
@@ -670,13 +648,13 @@ type TokenizerRequest = (
     Span,
 );
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Image {
     pub data: Vec<u8>,
     pub mimetype: String,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Chunk {
     Text(String),
     Image(Image),
@@ -703,10 +681,12 @@ impl ChunksToString for Vec<Chunk> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ValidGrammar {
     Json(String),
     Regex(String),
+    /// A context-free grammar in Lark syntax
+    Cfg(String),
 }
 
 #[derive(Debug, Clone)]
@@ -822,6 +802,94 @@ pub enum ValidationError {
     FailedFetchImage(#[from] reqwest::Error),
     #[error("{0} modality is not supported")]
     UnsupportedModality(&'static str),
+    #[error("`suffix` is not supported: the model has no fill-in-the-middle tokens or `completion_template`")]
+    FimNotSupported,
+    #[error("`continue_final_message` {0}")]
+    ContinueFinalMessage(&'static str),
+    #[error("`max_reasoning_tokens` requires the reasoning markers of the model and cannot be used with a grammar")]
+    ReasoningBudget,
+}
+
+impl ValidationError {
+    /// Stable machine-readable code of the error
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ValidationError::BestOf(..) => "invalid_best_of",
+            ValidationError::BestOfDisabled => "best_of_disabled",
+            ValidationError::BestOfSampling => "best_of_requires_sampling",
+            ValidationError::BestOfSeed => "best_of_with_seed",
+            ValidationError::BestOfStream => "best_of_with_stream",
+            ValidationError::TopNTokens(..) => "invalid_top_n_tokens",
+            ValidationError::TopNTokensDisabled => "top_n_tokens_disabled",
+            ValidationError::PrefillDetailsStream => "decoder_input_details_with_stream",
+            ValidationError::Temperature => "invalid_temperature",
+            ValidationError::RepetitionPenalty => "invalid_repetition_penalty",
+            ValidationError::FrequencyPenalty => "invalid_frequency_penalty",
+            ValidationError::TopP => "invalid_top_p",
+            ValidationError::TopK => "invalid_top_k",
+            ValidationError::Truncate(..) => "invalid_truncate",
+            ValidationError::TypicalP => "invalid_typical_p",
+            ValidationError::UnsetMaxNewTokens => "max_new_tokens_required",
+            ValidationError::NegativeMaxNewTokens => "invalid_max_new_tokens",
+            ValidationError::MaxNewTokens(..) => "max_new_tokens_too_large",
+            ValidationError::MaxTotalTokens(..) => "context_length_exceeded",
+            ValidationError::InputLength(..) => "context_length_exceeded",
+            ValidationError::EmptyInput => "empty_input",
+            ValidationError::StopSequence(..) => "too_many_stop_sequences",
+            ValidationError::Tokenizer(_) => "tokenizer_error",
+            ValidationError::Grammar => "grammar_not_supported",
+            ValidationError::InvalidGrammar(_) => "invalid_grammar",
+            ValidationError::InvalidBase64(_) => "invalid_base64",
+            ValidationError::InvalidImage(_) => "invalid_image",
+            ValidationError::InvalidInt(_) => "invalid_integer",
+            ValidationError::InvalidImageContent(_) => "invalid_image_content",
+            ValidationError::FailedFetchImage(_) => "image_fetch_failed",
+            ValidationError::UnsupportedModality(_) => "unsupported_modality",
+            ValidationError::FimNotSupported => "fim_not_supported",
+            ValidationError::ContinueFinalMessage(_) => "invalid_continue_final_message",
+            ValidationError::ReasoningBudget => "invalid_max_reasoning_tokens",
+        }
+    }
+
+    /// Name of the request parameter that caused the error
+    pub(crate) fn param(&self) -> Option<&'static str> {
+        match self {
+            ValidationError::BestOf(..)
+            | ValidationError::BestOfDisabled
+            | ValidationError::BestOfStream => Some("best_of"),
+            ValidationError::BestOfSampling => Some("do_sample"),
+            ValidationError::BestOfSeed => Some("seed"),
+            ValidationError::TopNTokens(..) | ValidationError::TopNTokensDisabled => {
+                Some("top_n_tokens")
+            }
+            ValidationError::PrefillDetailsStream => Some("decoder_input_details"),
+            ValidationError::Temperature => Some("temperature"),
+            ValidationError::RepetitionPenalty => Some("repetition_penalty"),
+            ValidationError::FrequencyPenalty => Some("frequency_penalty"),
+            ValidationError::TopP => Some("top_p"),
+            ValidationError::TopK => Some("top_k"),
+            ValidationError::Truncate(..) => Some("truncate"),
+            ValidationError::TypicalP => Some("typical_p"),
+            ValidationError::UnsetMaxNewTokens
+            | ValidationError::NegativeMaxNewTokens
+            | ValidationError::MaxNewTokens(..) => Some("max_new_tokens"),
+            ValidationError::MaxTotalTokens(..)
+            | ValidationError::InputLength(..)
+            | ValidationError::EmptyInput
+            | ValidationError::Tokenizer(_)
+            | ValidationError::InvalidBase64(_)
+            | ValidationError::InvalidImage(_)
+            | ValidationError::InvalidImageContent(_)
+            | ValidationError::FailedFetchImage(_)
+            | ValidationError::UnsupportedModality(_) => Some("inputs"),
+            ValidationError::StopSequence(..) => Some("stop"),
+            ValidationError::Grammar | ValidationError::InvalidGrammar(_) => Some("grammar"),
+            ValidationError::FimNotSupported => Some("suffix"),
+            ValidationError::ContinueFinalMessage(_) => Some("continue_final_message"),
+            ValidationError::ReasoningBudget => Some("max_reasoning_tokens"),
+            ValidationError::InvalidInt(_) => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -829,7 +897,7 @@ mod tests {
     use super::*;
     use crate::config::{Idefics2, PaliTextConfig, Paligemma};
     use crate::default_parameters;
-    use crate::tests::get_tokenizer;
+    use crate::tests::{get_offline_tokenizer, get_tokenizer};
 
     #[tokio::test]
     async fn test_validation_max_new_tokens() {
@@ -852,6 +920,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
 
@@ -866,6 +935,48 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validation_default_max_new_tokens() {
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let validation = |default_max_new_tokens| {
+            Validation::new(
+                1,
+                get_offline_tokenizer(),
+                None,
+                None,
+                2,
+                3,
+                4,
+                max_input_length,
+                max_total_tokens,
+                default_max_new_tokens,
+                true,
+            )
+        };
+
+        // The tokens left in the context window
+        let (_, _, input_length, max_new_tokens) = validation(None)
+            .validate_input("Hello".to_string(), true, None, None)
+            .await
+            .unwrap();
+        assert_eq!((input_length, max_new_tokens), (1, 5));
+
+        // Within the cap
+        let (_, _, _, max_new_tokens) = validation(Some(3))
+            .validate_input("Hello".to_string(), true, None, None)
+            .await
+            .unwrap();
+        assert_eq!(max_new_tokens, 3);
+
+        // The cap does not apply to the requests setting `max_new_tokens`
+        let (_, _, _, max_new_tokens) = validation(Some(3))
+            .validate_input("Hello".to_string(), true, None, Some(4))
+            .await
+            .unwrap();
+        assert_eq!(max_new_tokens, 4);
+    }
+
     #[tokio::test]
     async fn test_validation_input_length() {
         let tokenizer = get_tokenizer();
@@ -887,6 +998,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
 
@@ -921,6 +1033,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
         match validation
@@ -961,6 +1074,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
         match validation
@@ -1032,6 +1146,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
         match validation
@@ -1122,6 +1237,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
 
@@ -1179,6 +1295,7 @@ mod tests {
             max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            None,
             disable_grammar_support,
         );
 
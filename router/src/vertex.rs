@@ -82,6 +82,8 @@ pub(crate) async fn vertex_compatibility(
             Json(ErrorResponse {
                 error: "Input validation error".to_string(),
                 error_type: "Input validation error".to_string(),
+                code: "empty_input".to_string(),
+                param: Some("instances".to_string()),
             }),
         ));
     }
@@ -129,6 +131,8 @@ pub(crate) async fn vertex_compatibility(
                     Json(ErrorResponse {
                         error: "Incomplete generation".into(),
                         error_type: "Incomplete generation".into(),
+                        code: "incomplete_generation".to_string(),
+                        param: None,
                     }),
                 )
             })
@@ -176,6 +180,7 @@ mod tests {
                     max_tokens: Some(128),
                     top_p: Some(0.95),
                     temperature: Some(0.7),
+                    include_reasoning: true,
                     ..Default::default()
                 })]
             }
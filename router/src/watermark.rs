@@ -0,0 +1,363 @@
+/// Detection of the "A Watermark for Large Language Models" scheme (https://arxiv.org/abs/2301.10226)
+use crate::infer::{Infer, InferError};
+use crate::server::encoding_to_tokens;
+use crate::validation::ValidationError;
+use crate::{ErrorResponse, GenerateParameters, GenerateRequest, Info, SimpleToken};
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+/// Seeds the green lists with the previous token, same key as the shards
+const HASH_KEY: u64 = 15485863;
+
+/// Recomputes the green lists of the shards
+///
+/// With `--watermark-detectable`, the shards draw the green list of a token from a
+/// `torch.randperm` of the vocabulary on a CPU generator seeded with `HASH_KEY * previous_token`. The permutation is replayed here with the
+/// same Mersenne Twister and Fisher-Yates shuffle.
+#[derive(Clone, Debug)]
+pub(crate) struct WatermarkDetector {
+    gamma: f64,
+    vocab_size: usize,
+}
+
+impl WatermarkDetector {
+    /// `vocab_size` is the number of logits of the model, not the size of the tokenizer
+    pub(crate) fn new(gamma: f64, vocab_size: usize) -> Self {
+        Self { gamma, vocab_size }
+    }
+
+    fn greenlist_size(&self) -> usize {
+        (self.vocab_size as f64 * self.gamma) as usize
+    }
+
+    /// Whether `token` is in the green list seeded by `previous`
+    fn is_green(&self, previous: u32, token: u32) -> bool {
+        let n = self.vocab_size;
+        let greenlist_size = self.greenlist_size();
+        let mut position = token as usize;
+        if position >= n {
+            return false;
+        }
+
+        // torch only keeps the lower 32 bits of the seed
+        let mut rng = Mt19937::new((HASH_KEY * previous as u64) as u32);
+        // Step `i` of the shuffle swaps the positions `i` and `i + z` and fixes position `i`,
+        // only the position of `token` is tracked
+        for i in 0..greenlist_size.min(n - 1) {
+            let z = rng.next_u32() as usize % (n - i);
+            if position == i + z {
+                return true;
+            }
+            if position == i {
+                position = i + z;
+            }
+        }
+        position < greenlist_size
+    }
+
+    /// Flag the green tokens of `ids` and compute the z-score of their count
+    fn detect(&self, ids: &[u32]) -> Detection {
+        let green: Vec<Option<bool>> = std::iter::once(None)
+            .chain(
+                ids.windows(2)
+                    .map(|window| Some(self.is_green(window[0], window[1]))),
+            )
+            .take(ids.len())
+            .collect();
+
+        let scored_tokens = ids.len().saturating_sub(1) as u32;
+        let green_tokens = green.iter().filter(|green| **green == Some(true)).count() as u32;
+        let (z_score, p_value) = if scored_tokens == 0 {
+            (0.0, 1.0)
+        } else {
+            let t = scored_tokens as f64;
+            let z = (green_tokens as f64 - self.gamma * t)
+                / (t * self.gamma * (1.0 - self.gamma)).sqrt();
+            (z, 0.5 * erfc(z / std::f64::consts::SQRT_2))
+        };
+        Detection {
+            green,
+            scored_tokens,
+            green_tokens,
+            z_score,
+            p_value,
+        }
+    }
+}
+
+struct Detection {
+    green: Vec<Option<bool>>,
+    scored_tokens: u32,
+    green_tokens: u32,
+    z_score: f64,
+    p_value: f64,
+}
+
+/// 32-bit Mersenne Twister, as seeded by `torch.Generator.manual_seed`
+struct Mt19937 {
+    state: [u32; 624],
+    index: usize,
+}
+
+impl Mt19937 {
+    fn new(seed: u32) -> Self {
+        let mut state = [0u32; 624];
+        state[0] = seed;
+        for i in 1..624 {
+            state[i] = 1812433253u32
+                .wrapping_mul(state[i - 1] ^ (state[i - 1] >> 30))
+                .wrapping_add(i as u32);
+        }
+        Self { state, index: 624 }
+    }
+
+    fn twist(&mut self) {
+        for i in 0..624 {
+            let y = (self.state[i] & 0x80000000) | (self.state[(i + 1) % 624] & 0x7fffffff);
+            let mut next = self.state[(i + 397) % 624] ^ (y >> 1);
+            if y & 1 != 0 {
+                next ^= 0x9908b0df;
+            }
+            self.state[i] = next;
+        }
+        self.index = 0;
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        if self.index >= 624 {
+            self.twist();
+        }
+        let mut y = self.state[self.index];
+        self.index += 1;
+        y ^= y >> 11;
+        y ^= (y << 7) & 0x9d2c5680;
+        y ^= (y << 15) & 0xefc60000;
+        y ^ (y >> 18)
+    }
+}
+
+/// Complementary error function, with a fractional error below 1.2e-7
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct WatermarkDetectRequest {
+    /// The generated text, without its prompt
+    #[schema(example = "Deep learning is a subset of machine learning")]
+    pub inputs: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct WatermarkDetectResponse {
+    /// Number of standard deviations between the green tokens and the count expected without
+    /// a watermark
+    #[schema(example = 4.2)]
+    pub z_score: f64,
+    /// Probability of seeing as many green tokens in a text without watermark
+    #[schema(example = 1.3e-5)]
+    pub p_value: f64,
+    /// Number of tokens that have a green flag
+    #[schema(example = 19)]
+    pub scored_tokens: u32,
+    #[schema(example = 17)]
+    pub green_tokens: u32,
+    pub tokens: Vec<SimpleToken>,
+    /// Green flag of every token, `null` for the first one which has no previous token
+    #[schema(example = json ! ([null, true, false]))]
+    pub green: Vec<Option<bool>>,
+}
+
+/// Check whether a text carries the watermark of `watermark: true` generations
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/watermark/detect",
+request_body = WatermarkDetectRequest,
+responses(
+(status = 200, description = "Watermark detection", body = WatermarkDetectResponse),
+(status = 404, description = "Watermark detection disabled", body = ErrorResponse,
+example = json ! ({"error": "Watermark detection needs `--watermark-detectable` and the `vocab_size` of the model", "error_type": "watermark_unavailable"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error: `inputs` must have less than 4095 tokens. Given: 4096", "error_type": "validation"})),
+)
+)]
+#[instrument(skip_all)]
+pub(crate) async fn detect_watermark(
+    Extension(infer): Extension<Infer>,
+    Extension(info): Extension<Info>,
+    Extension(detector): Extension<Option<WatermarkDetector>>,
+    Json(req): Json<WatermarkDetectRequest>,
+) -> Result<Json<WatermarkDetectResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let detector = detector.ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Watermark detection needs `--watermark-detectable` and the `vocab_size` of the model".to_string(),
+                error_type: "watermark_unavailable".to_string(),
+                code: "watermark_unavailable".to_string(),
+                param: None,
+            }),
+        )
+    })?;
+
+    let encoding = infer
+        .tokenize(GenerateRequest {
+            inputs: req.inputs.clone(),
+            add_special_tokens: false,
+            parameters: GenerateParameters::default(),
+        })
+        .await?;
+    // Each token replays a shuffle of the vocabulary
+    if encoding.len() > info.max_input_tokens {
+        let err = ValidationError::InputLength(info.max_input_tokens, encoding.len());
+        return Err(InferError::from(err).into());
+    }
+    let ids = encoding.get_ids().to_vec();
+    let tokens = encoding_to_tokens(&encoding, &req.inputs);
+
+    // Replaying the permutations is CPU bound
+    let detection = tokio::task::spawn_blocking(move || detector.detect(&ids))
+        .await
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: err.to_string(),
+                    error_type: "watermark".to_string(),
+                    code: "internal_error".to_string(),
+                    param: None,
+                }),
+            )
+        })?;
+
+    Ok(Json(WatermarkDetectResponse {
+        z_score: detection.z_score,
+        p_value: detection.p_value,
+        scored_tokens: detection.scored_tokens,
+        green_tokens: detection.green_tokens,
+        tokens,
+        green: detection.green,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mt19937() {
+        // Reference outputs of std::mt19937 with its default seed
+        let mut rng = Mt19937::new(5489);
+        assert_eq!(rng.next_u32(), 3499211612);
+        assert_eq!(rng.next_u32(), 581869302);
+        let mut rng = Mt19937::new(5489);
+        assert_eq!((0..10000).map(|_| rng.next_u32()).last(), Some(4123659995));
+    }
+
+    #[test]
+    fn test_greenlist_matches_randperm() {
+        let detector = WatermarkDetector::new(0.25, 50);
+        for previous in [0, 1, 7, 49] {
+            // Full Fisher-Yates shuffle of torch's CPU `randperm`
+            let mut permutation: Vec<usize> = (0..50).collect();
+            let mut rng = Mt19937::new((HASH_KEY * previous as u64) as u32);
+            for i in 0..49 {
+                let z = rng.next_u32() as usize % (50 - i);
+                permutation.swap(i, i + z);
+            }
+            let greenlist = &permutation[..detector.greenlist_size()];
+
+            for token in 0..50 {
+                assert_eq!(
+                    detector.is_green(previous, token),
+                    greenlist.contains(&(token as usize))
+                );
+            }
+        }
+        assert!(!detector.is_green(0, 50));
+    }
+
+    #[test]
+    fn test_detect() {
+        let detector = WatermarkDetector::new(0.5, 50);
+        let detection = detector.detect(&[3, 1, 4, 1, 5]);
+        assert_eq!(detection.green.len(), 5);
+        assert_eq!(detection.green[0], None);
+        assert_eq!(detection.scored_tokens, 4);
+        let green_tokens = detection.green_tokens as f64;
+        assert!((detection.z_score - (green_tokens - 2.0)).abs() < 1e-9);
+
+        let detection = detector.detect(&[3]);
+        assert_eq!(detection.scored_tokens, 0);
+        assert_eq!(detection.p_value, 1.0);
+
+        assert!((erfc(0.0) - 1.0).abs() < 1e-7);
+        assert!((0.5 * erfc(4.0 / std::f64::consts::SQRT_2) - 3.167e-5).abs() < 1e-8);
+    }
+
+    #[tokio::test]
+    async fn test_detect_watermark_limits() {
+        let infer =
+            crate::tests::mock_infer(crate::tests::MockBackend::new(|_| String::new()), 8, 1);
+        let info = crate::tests::mock_info(8);
+        let request = |inputs: &str| {
+            Json(WatermarkDetectRequest {
+                inputs: inputs.to_string(),
+            })
+        };
+
+        // Disabled without `--watermark-detectable`
+        let (status_code, _) = detect_watermark(
+            Extension(infer.clone()),
+            Extension(info.clone()),
+            Extension(None),
+            request("1 2 3"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status_code, StatusCode::NOT_FOUND);
+
+        let detector = Some(WatermarkDetector::new(0.5, 11));
+        let (status_code, Json(err)) = detect_watermark(
+            Extension(infer.clone()),
+            Extension(info.clone()),
+            Extension(detector.clone()),
+            request("1 2 3 4 5 6 7 8"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(err.code, "context_length_exceeded");
+
+        let Json(detection) = detect_watermark(
+            Extension(infer),
+            Extension(info),
+            Extension(detector),
+            request("1 2 3"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(detection.scored_tokens, 2);
+    }
+}
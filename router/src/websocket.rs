@@ -0,0 +1,463 @@
+/// WebSocket streaming endpoint
+use crate::infer::Infer;
+use crate::server::{generate_stream_internal, ChatSetup, ComputeType};
+use crate::{ChatCompletionChunk, ChatRequest, ErrorResponse, GenerateRequest, Info};
+use crate::{InferError, StreamResponse};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::Json;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+/// Number of messages waiting for the socket before the requests stop reading their streams
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// Message sent by the client
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Start a `/generate` request
+    Generate {
+        id: String,
+        request: GenerateRequest,
+    },
+    /// Start a `/v1/chat/completions` request
+    Chat { id: String, request: ChatRequest },
+    /// Cancel an in-flight request
+    Cancel { id: String },
+}
+
+/// Message sent by the server, tagged with the id chosen by the client
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Token {
+        id: String,
+        data: StreamResponse,
+    },
+    Chunk {
+        id: String,
+        data: ChatCompletionChunk,
+    },
+    Done {
+        id: String,
+    },
+    Cancelled {
+        id: String,
+    },
+    Error {
+        id: Option<String>,
+        #[serde(flatten)]
+        error: ErrorResponse,
+    },
+}
+
+impl ServerMessage {
+    fn error(id: Option<String>, err: InferError) -> Self {
+        let (_, Json(error)) = <(StatusCode, Json<ErrorResponse>)>::from(err);
+        Self::Error { id, error }
+    }
+
+    fn invalid(id: Option<String>, error: String) -> Self {
+        Self::Error {
+            id,
+            error: ErrorResponse {
+                error,
+                error_type: "validation".to_string(),
+                code: "invalid_message".to_string(),
+                param: None,
+            },
+        }
+    }
+}
+
+/// Stream tokens over a WebSocket
+///
+/// Text frames are JSON messages. Clients send `{"type": "generate", "id": ..., "request": ...}`
+/// with a `/generate` body or `{"type": "chat", "id": ..., "request": ...}` with a
+/// `/v1/chat/completions` body, and `{"type": "cancel", "id": ...}` to stop a request.
+/// The server answers with `token` (`StreamResponse`) or `chunk` (`ChatCompletionChunk`)
+/// messages followed by `done`, `cancelled` or `error`. Several requests can share a socket.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/ws",
+responses(
+(status = 101, description = "Switching to the WebSocket protocol"),
+)
+)]
+pub(crate) async fn websocket(
+    Extension(infer): Extension<Infer>,
+    Extension(compute_type): Extension<ComputeType>,
+    Extension(info): Extension<Info>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, infer, compute_type, info))
+}
+
+async fn handle_socket(socket: WebSocket, infer: Infer, compute_type: ComputeType, info: Info) {
+    let (sink, receiver) = socket.split();
+    handle_messages(sink, receiver, infer, compute_type, info).await;
+}
+
+async fn handle_messages<E>(
+    mut sink: impl Sink<Message> + Unpin + Send + 'static,
+    mut receiver: impl Stream<Item = Result<Message, E>> + Unpin,
+    infer: Infer,
+    compute_type: ComputeType,
+    info: Info,
+) {
+    // Single writer, the requests send their messages through this channel. It is bounded so
+    // that a slow client slows its requests down instead of buffering their whole output.
+    let (sender, mut messages) = mpsc::channel::<ServerMessage>(MAX_PENDING_MESSAGES);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = messages.recv().await {
+            // Unwrap is safe here, the messages only contain serializable types
+            let text = serde_json::to_string(&message).unwrap();
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut requests: HashMap<String, AbortHandle> = HashMap::new();
+    while let Some(Ok(message)) = receiver.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // Pings are answered by axum
+            _ => continue,
+        };
+        requests.retain(|_, handle| !handle.is_finished());
+
+        let message = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(message) => message,
+            Err(err) => {
+                let message = ServerMessage::invalid(None, format!("Invalid message: {err}"));
+                let _ = sender.send(message).await;
+                continue;
+            }
+        };
+
+        let (id, handle) = match message {
+            ClientMessage::Cancel { id } => {
+                if let Some(handle) = requests.remove(&id) {
+                    // Dropping the stream cancels the request
+                    handle.abort();
+                    let _ = sender.send(ServerMessage::Cancelled { id }).await;
+                }
+                continue;
+            }
+            ClientMessage::Generate { id, .. } | ClientMessage::Chat { id, .. }
+                if requests.contains_key(&id) =>
+            {
+                let message = ServerMessage::invalid(
+                    Some(id.clone()),
+                    format!("Request {id} is already in flight"),
+                );
+                let _ = sender.send(message).await;
+                continue;
+            }
+            ClientMessage::Generate { id, request } => {
+                let stream = generate_stream_internal(
+                    infer.clone(),
+                    compute_type.clone(),
+                    Json(request),
+                    tracing::info_span!("websocket_generate"),
+                );
+                let task = tokio::spawn(forward(
+                    id.clone(),
+                    sender.clone(),
+                    async move { Ok(stream.await.1) },
+                    |id, data| ServerMessage::Token { id, data },
+                ));
+                (id, task.abort_handle())
+            }
+            ClientMessage::Chat { id, request } => {
+                let stream =
+                    chat_stream(infer.clone(), compute_type.clone(), info.clone(), request);
+                let task = tokio::spawn(forward(id.clone(), sender.clone(), stream, |id, data| {
+                    ServerMessage::Chunk { id, data }
+                }));
+                (id, task.abort_handle())
+            }
+        };
+        requests.insert(id, handle);
+    }
+
+    // The client is gone, cancel everything
+    for handle in requests.values() {
+        handle.abort();
+    }
+    writer.abort();
+}
+
+/// Start a chat request and return its stream of chunks
+async fn chat_stream(
+    infer: Infer,
+    compute_type: ComputeType,
+    info: Info,
+    mut chat: ChatRequest,
+) -> Result<impl Stream<Item = Result<ChatCompletionChunk, InferError>>, ServerMessage> {
+    // The socket always streams, whatever the request says
+    chat.stream = true;
+    let setup = ChatSetup::new(&infer, &info, chat)
+        .await
+        .map_err(|err| ServerMessage::error(None, err))?;
+    let (_, chunks) = setup
+        .stream(infer, compute_type, tracing::info_span!("websocket_chat"))
+        .await
+        .map_err(|(_, Json(error))| ServerMessage::Error { id: None, error })?;
+    Ok(chunks)
+}
+
+/// Forward the items of a request stream to the socket writer
+async fn forward<T, S>(
+    id: String,
+    sender: mpsc::Sender<ServerMessage>,
+    stream: impl std::future::Future<Output = Result<S, ServerMessage>>,
+    to_message: impl Fn(String, T) -> ServerMessage,
+) where
+    S: Stream<Item = Result<T, InferError>>,
+{
+    let stream = match stream.await {
+        Ok(stream) => stream,
+        Err(ServerMessage::Error { error, .. }) => {
+            let message = ServerMessage::Error {
+                id: Some(id),
+                error,
+            };
+            let _ = sender.send(message).await;
+            return;
+        }
+        Err(message) => {
+            let _ = sender.send(message).await;
+            return;
+        }
+    };
+
+    let mut stream = Box::pin(stream);
+    while let Some(item) = stream.next().await {
+        let message = match item {
+            Ok(data) => to_message(id.clone(), data),
+            Err(err) => {
+                let _ = sender.send(ServerMessage::error(Some(id), err)).await;
+                return;
+            }
+        };
+        if sender.send(message).await.is_err() {
+            return;
+        }
+    }
+    let _ = sender.send(ServerMessage::Done { id }).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_messages() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type": "generate", "id": "a", "request": {"inputs": "Hello"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(message, ClientMessage::Generate { id, .. } if id == "a"));
+
+        let message: ClientMessage =
+            serde_json::from_str(r#"{"type": "cancel", "id": "a"}"#).unwrap();
+        assert!(matches!(message, ClientMessage::Cancel { id } if id == "a"));
+    }
+
+    #[test]
+    fn test_server_messages() {
+        let message = serde_json::to_value(ServerMessage::Done {
+            id: "a".to_string(),
+        })
+        .unwrap();
+        assert_eq!(message, serde_json::json!({"type": "done", "id": "a"}));
+
+        let message = serde_json::to_value(ServerMessage::invalid(
+            Some("a".to_string()),
+            "Invalid".to_string(),
+        ))
+        .unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({
+                "type": "error",
+                "id": "a",
+                "error": "Invalid",
+                "error_type": "validation",
+                "code": "invalid_message",
+                "param": null,
+            })
+        );
+    }
+
+    use crate::tests::{mock_infer, mock_info, MockBackend};
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+    use serde_json::{json, Value};
+    use std::convert::Infallible;
+    use std::time::Duration;
+
+    /// Client side of a socket served by `backend`
+    fn connect(
+        backend: MockBackend,
+    ) -> (
+        UnboundedSender<Result<Message, Infallible>>,
+        UnboundedReceiver<Message>,
+    ) {
+        let (client, receiver) = unbounded();
+        let (sink, server) = unbounded();
+        let infer = mock_infer(backend, 64, 4);
+        tokio::spawn(handle_messages(
+            sink,
+            receiver,
+            infer,
+            ComputeType("cpu".to_string()),
+            mock_info(64),
+        ));
+        (client, server)
+    }
+
+    fn send(client: &UnboundedSender<Result<Message, Infallible>>, message: Value) {
+        client
+            .unbounded_send(Ok(Message::Text(message.to_string())))
+            .unwrap();
+    }
+
+    async fn receive(server: &mut UnboundedReceiver<Message>) -> Value {
+        let message = tokio::time::timeout(Duration::from_secs(5), server.next())
+            .await
+            .unwrap()
+            .unwrap();
+        match message {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            message => panic!("Unexpected message {message:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_requests() {
+        let (client, mut server) = connect(MockBackend::new(|inputs| inputs.to_string()));
+        send(
+            &client,
+            json!({"type": "generate", "id": "a", "request": {"inputs": "one two"}}),
+        );
+        send(
+            &client,
+            json!({"type": "generate", "id": "b", "request": {"inputs": "three four"}}),
+        );
+        send(
+            &client,
+            json!({"type": "chat", "id": "c", "request": {"messages": [{"role": "user", "content": "five"}]}}),
+        );
+
+        let mut texts: HashMap<String, String> = HashMap::new();
+        let mut done = Vec::new();
+        while done.len() < 3 {
+            let message = receive(&mut server).await;
+            let id = message["id"].as_str().unwrap().to_string();
+            match message["type"].as_str().unwrap() {
+                "token" => texts
+                    .entry(id)
+                    .or_default()
+                    .push_str(message["data"]["token"]["text"].as_str().unwrap()),
+                "chunk" => texts.entry(id).or_default().push_str(
+                    message["data"]["choices"][0]["delta"]["content"]
+                        .as_str()
+                        .unwrap_or_default(),
+                ),
+                "done" => done.push(id),
+                other => panic!("Unexpected message {other}: {message}"),
+            }
+        }
+        done.sort();
+        assert_eq!(done, ["a", "b", "c"]);
+        assert_eq!(texts["a"], "one two");
+        assert_eq!(texts["b"], "three four");
+        assert_eq!(texts["c"], "<|user|> five <|assistant|>");
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request() {
+        let backend = MockBackend::new(|inputs| inputs.to_string()).pending();
+        let (client, mut server) = connect(backend.clone());
+        send(
+            &client,
+            json!({"type": "generate", "id": "a", "request": {"inputs": "one two"}}),
+        );
+        let message = receive(&mut server).await;
+        assert_eq!(message["type"], "token");
+        assert_eq!(message["id"], "a");
+
+        // The id is taken while the request is in flight
+        send(
+            &client,
+            json!({"type": "generate", "id": "a", "request": {"inputs": "one"}}),
+        );
+        send(&client, json!({"type": "cancel", "id": "a"}));
+        let mut types = Vec::new();
+        while types.last() != Some(&"cancelled".to_string()) {
+            let message = receive(&mut server).await;
+            assert_eq!(message["id"], "a");
+            types.push(message["type"].as_str().unwrap().to_string());
+        }
+        assert!(types.contains(&"error".to_string()));
+
+        // The backend stream is dropped
+        let stream = backend.open_streams.lock().unwrap()[0].clone();
+        tokio::time::timeout(Duration::from_secs(5), stream.closed())
+            .await
+            .unwrap();
+
+        // Unknown ids are ignored, the socket keeps serving requests
+        send(&client, json!({"type": "cancel", "id": "b"}));
+        send(
+            &client,
+            json!({"type": "generate", "id": "a", "request": {"inputs": "one"}}),
+        );
+        let message = receive(&mut server).await;
+        assert_eq!(message["type"], "token");
+        assert_eq!(message["id"], "a");
+    }
+
+    #[tokio::test]
+    async fn test_chat_strict_schema() {
+        let backend = MockBackend::new(|inputs| {
+            if inputs.contains("number") {
+                "42".to_string()
+            } else {
+                "Hello".to_string()
+            }
+        });
+        let (client, mut server) = connect(backend);
+        let chat = |id: &str, content: &str| {
+            json!({"type": "chat", "id": id, "request": {
+                "messages": [{"role": "user", "content": content}],
+                "response_format": {"type": "json_schema", "json_schema": {
+                    "name": "number", "schema": {"type": "integer"}, "strict": true
+                }},
+            }})
+        };
+        send(&client, chat("a", "A number"));
+        send(&client, chat("b", "A word"));
+
+        let mut last = HashMap::new();
+        while last.len() < 2 {
+            let message = receive(&mut server).await;
+            if matches!(message["type"].as_str(), Some("done" | "error")) {
+                last.insert(message["id"].as_str().unwrap().to_string(), message);
+            }
+        }
+        assert_eq!(last["a"]["type"], "done");
+        assert_eq!(last["b"]["type"], "error");
+        assert_eq!(last["b"]["code"], "schema_mismatch");
+    }
+}
@@ -5,7 +5,7 @@ from loguru import logger
 from typing import Dict, Union
 from text_generation_server.pb.generate_pb2 import GrammarType
 
-from outlines.fsm.fsm import RegexFSM
+from outlines.fsm.fsm import CFGFSM, RegexFSM
 from outlines.fsm.json_schema import build_regex_from_schema
 from functools import lru_cache
 from typing import List, Optional, DefaultDict
@@ -487,7 +487,7 @@ class GrammarLogitProcessor(LogitsProcessor):
     def __init__(self, tokenizer, device, grammar, grammar_type):
         self.device = device
         self.tokenizer = GrammarLogitProcessor._cached_adapt_tokenizer(tokenizer)
-        self.fsm = GrammarLogitProcessor._cached_compile_fsm(
+        self.fsm = GrammarLogitProcessor._compile_fsm(
             grammar_type, grammar, self.tokenizer
         )
 
@@ -515,6 +515,21 @@ class GrammarLogitProcessor(LogitsProcessor):
             return fsm_grammar_state
         return fsm.next_state(fsm_grammar_state, next_token_id)
 
+    @staticmethod
+    def _compile_fsm(grammar_type, grammar, tokenizer):
+        # A CFG FSM holds the parser state of its sequence and cannot be shared. It is not
+        # cached either, and outlines rebuilds a `RegexFSM` of the allowed terminals at every
+        # step, so CFG grammars are much slower than regular expressions. The router rejects
+        # the grammars Lark cannot build an LALR parser for, which would fail the whole batch.
+        if grammar_type == GrammarType.GRAMMAR_TYPE_CFG:
+            start_time = time.time()
+            fsm = CFGFSM(grammar, tokenizer)
+            logger.debug(f"Compiled CFG FSM in {time.time() - start_time:.2f}s")
+            return fsm
+        return GrammarLogitProcessor._cached_compile_fsm(
+            grammar_type, grammar, tokenizer
+        )
+
     # TODO: move grammar compilation into the router
     @staticmethod
     @lru_cache(maxsize=32, typed=True)
@@ -573,7 +588,7 @@ class HeterogeneousGrammarLogitProcessor(LogitsProcessor):
             if len(grammar) == 0:
                 self.fsms.append(None)
                 continue
-            fsm = GrammarLogitProcessor._cached_compile_fsm(
+            fsm = GrammarLogitProcessor._compile_fsm(
                 grammar_type, grammar, self.tokenizer
             )
             self.fsms.append(fsm)
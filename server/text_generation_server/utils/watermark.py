@@ -21,6 +21,9 @@ from typing import List, Union
 
 GAMMA = float(os.getenv("WATERMARK_GAMMA", 0.5))
 DELTA = float(os.getenv("WATERMARK_DELTA", 2.0))
+# Drawing the green lists on CPU lets the router replay them in `/watermark/detect`, at the cost
+# of a CPU randperm over the vocabulary and a host to device copy per watermarked token
+CPU_GENERATOR = os.getenv("WATERMARK_CPU_GENERATOR", "").lower() in {"1", "true"}
 
 
 class WatermarkLogitsProcessor(LogitsProcessor):
@@ -34,7 +37,7 @@ class WatermarkLogitsProcessor(LogitsProcessor):
         # watermarking parameters
         self.gamma = gamma
         self.delta = delta
-        self.rng = torch.Generator(device=device)
+        self.rng = torch.Generator(device="cpu" if CPU_GENERATOR else device)
         self.hash_key = hash_key
 
     def _seed_rng(self, input_ids: Union[List[int], torch.LongTensor]):
@@ -62,8 +65,10 @@ class WatermarkLogitsProcessor(LogitsProcessor):
         self._seed_rng(input_ids)
 
         greenlist_size = int(max_value * self.gamma)
-        vocab_permutation = torch.randperm(max_value, device=device, generator=self.rng)
-        greenlist_ids = vocab_permutation[:greenlist_size]
+        vocab_permutation = torch.randperm(
+            max_value, device=self.rng.device, generator=self.rng
+        )
+        greenlist_ids = vocab_permutation[:greenlist_size].to(device)
         return greenlist_ids
 
     @staticmethod